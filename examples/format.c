@@ -0,0 +1,42 @@
+/*
+ * Reformat standard input to standard output through the C ABI, Markdown on
+ * unless the first argument is --no-markdown.
+ *
+ *   cargo rustc --release --lib --no-default-features --features ffi --crate-type cdylib
+ *   cc -Iinclude examples/format.c -Ltarget/release -lreformahtml -o format
+ *   LD_LIBRARY_PATH=target/release ./format < tests/fixtures/inputs/markdown-ul.bs
+ */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "reformahtml.h"
+
+int main(int argc, char **argv) {
+    bool markdown = !(argc > 1 && strcmp(argv[1], "--no-markdown") == 0);
+    size_t len = 0, cap = 4096;
+    uint8_t *input = malloc(cap);
+    size_t n;
+    while (input && (n = fread(input + len, 1, cap - len, stdin)) > 0) {
+        len += n;
+        if (len == cap) {
+            cap *= 2;
+            input = realloc(input, cap);
+        }
+    }
+    if (!input) {
+        fputs("format: out of memory\n", stderr);
+        return 2;
+    }
+
+    size_t out_len;
+    uint8_t *out = reformahtml_format(input, len, markdown, &out_len);
+    free(input);
+    if (!out) {
+        fputs("format: input is not UTF-8\n", stderr);
+        return 1;
+    }
+    fwrite(out, 1, out_len, stdout);
+    reformahtml_free(out, out_len);
+    return 0;
+}
@@ -0,0 +1,29 @@
+#ifndef REFORMAHTML_H
+#define REFORMAHTML_H
+
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// Reformat the `len` bytes of UTF-8 at `input` with the default options, Markdown
+// on or off, and return the output, `*out_len` bytes long and not NUL-terminated.
+// Returns null if the input isn't UTF-8 or the reflow panics. The output must be
+// released with `reformahtml_free`.
+//
+// # Safety
+//
+// `input` must point to `len` readable bytes (it may be null if `len` is 0) and
+// `out_len` to a writable `usize`.
+uint8_t *reformahtml_format(const uint8_t *input, uintptr_t len, bool markdown, uintptr_t *out_len);
+
+// Release an output of `reformahtml_format`. Null is ignored.
+//
+// # Safety
+//
+// `output` must be null or a pointer returned by `reformahtml_format`, with the
+// `out_len` it reported, not yet released.
+void reformahtml_free(uint8_t *output, uintptr_t len);
+
+#endif  /* REFORMAHTML_H */
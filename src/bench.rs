@@ -0,0 +1,125 @@
+// src/bench.rs
+//
+// `reformahtml bench PATHS --iterations N`: time `transform` on real documents, with
+// the `Formatter` a normal run would use (or, with --rebuild-options, options built
+// for every run and tags looked up in their lists).
+// Allocation counts are reported when built with `--features count-allocs`,
+// which installs the counting global allocator below.
+
+use crate::{walk, FormatArgs};
+use crate::{read_input, transform};
+use clap::Args;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Args)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    format: FormatArgs,
+
+    /// Number of timed runs per file (after one warm-up run)
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    iterations: u32,
+
+    /// Build the options again for every run and look tags up in their lists, as
+    /// `transform` does, instead of formatting with one `Formatter` and its tag
+    /// sets, to see what that costs
+    #[arg(long = "rebuild-options", action = clap::ArgAction::SetTrue)]
+    rebuild_options: bool,
+
+    /// Files or directories (walked recursively) to benchmark
+    #[arg(required = true, value_name = "PATHS")]
+    paths: Vec<PathBuf>,
+}
+
+pub fn run(args: &BenchArgs) -> io::Result<()> {
+    let mut files = Vec::new();
+    for path in &args.paths {
+        if path.is_dir() {
+            files.extend(walk::collect_files(path, walk::DEFAULT_EXTENSIONS, &[], walk::Symlinks::default())?);
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    let iterations = args.iterations;
+    println!("{:>10} {:>12} {:>10} {:>14}  file", "bytes", "time/iter", "MB/s", "allocs/iter");
+    let (mut total_bytes, mut total_time) = (0u64, Duration::ZERO);
+    let mut total_allocs = allocations().map(|_| 0u64);
+    for path in &files {
+        let src = read_input(path)?;
+        let formatter = args.format.formatter_for(path, None)?;
+        let mut out = Vec::with_capacity(src.len());
+        formatter.format_into(&src, &mut out);
+
+        let allocs_before = allocations();
+        let started = Instant::now();
+        for _ in 0..iterations {
+            out.clear();
+            if args.rebuild_options {
+                transform(&src, &mut out, &args.format.options_for(path)?);
+            } else {
+                formatter.format_into(&src, &mut out);
+            }
+        }
+        let elapsed = started.elapsed();
+        let allocs = allocs_before.zip(allocations()).map(|(a, b)| (b - a) / u64::from(iterations));
+
+        total_bytes += src.len() as u64;
+        total_time += elapsed / iterations;
+        if let (Some(total), Some(n)) = (&mut total_allocs, allocs) {
+            *total += n;
+        }
+        print_row(src.len() as u64, elapsed / iterations, allocs, &path.display().to_string());
+    }
+    if files.len() > 1 {
+        print_row(total_bytes, total_time, total_allocs, "(total)");
+    }
+    Ok(())
+}
+
+fn print_row(bytes: u64, per_iter: Duration, allocs: Option<u64>, label: &str) {
+    let secs = per_iter.as_secs_f64();
+    let throughput = if secs > 0.0 { bytes as f64 / secs / 1e6 } else { f64::INFINITY };
+    let allocs = allocs.map_or_else(|| "-".to_string(), |n| n.to_string());
+    println!("{bytes:>10} {:>12} {throughput:>10.1} {allocs:>14}  {label}", format!("{per_iter:.2?}"));
+}
+
+/// Allocations made so far, if the counting allocator is installed.
+#[cfg(feature = "count-allocs")]
+fn allocations() -> Option<u64> {
+    Some(counting::ALLOCATIONS.load(std::sync::atomic::Ordering::Relaxed))
+}
+
+#[cfg(not(feature = "count-allocs"))]
+fn allocations() -> Option<u64> {
+    None
+}
+
+#[cfg(feature = "count-allocs")]
+pub mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    pub static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+    /// The system allocator, counting calls to `alloc` and `realloc`.
+    pub struct CountingAlloc;
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+}
@@ -0,0 +1,141 @@
+// src/checks.rs
+//
+// Named checks behind every diagnostic, their severities, and the
+// `--allow`/`--deny`/`--fail-on` flags that remap them, like rustc lints.
+
+/// Severity of an emitted diagnostic, from least to most severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Level {
+    Note,
+    Warning,
+    Error,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Note => "note",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// Everything the formatter can warn about. The value names are what
+/// `--allow`/`--deny` take and what reports show.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Check {
+    /// Text that looks like Markdown while Markdown mode is off
+    MarkdownLikely,
+    /// Markdown mode is on but nothing in the file uses it
+    MarkdownUnused,
+    /// A partially supported construct (a PHP block) was copied verbatim
+    VerbatimConstruct,
+    /// A setting in a <!-- reformahtml: ... --> comment was not understood
+    InvalidSetting,
+    /// A tag isn't closed with `>` (or a processing instruction with `?>`), so the rest of the file was copied verbatim
+    UnterminatedTag,
+    /// A comment isn't closed with `-->`, so the rest of the file was copied verbatim
+    UnterminatedComment,
+    /// A raw-text element (`<pre>`, `<script>`, ...) has no end tag, so the rest of the file was copied verbatim
+    UnterminatedRawText,
+    /// A CDATA section isn't closed with `]]>`, so the rest of the file was copied verbatim
+    UnterminatedCdata,
+    /// A <!-- reformahtml off --> comment has no matching <!-- reformahtml on -->, so the rest of the file was copied verbatim
+    UnmatchedOff,
+    /// Markup the HTML parser treats as a comment (`<!foo>`, `</ >`, `</%x>`), almost always a mistake
+    BogusComment,
+    /// A comment closed with `--!>`, which HTML accepts but reports as an error
+    IncorrectlyClosedComment,
+    /// An end tag with attributes, such as `</div class="x">`, which HTML ignores
+    EndTagWithAttributes,
+    /// An end tag without an open element of its name, which HTML ignores
+    StrayEndTag,
+    /// `lint --structure`: a heading skips a level
+    HeadingLevel,
+    /// `lint --structure`: <dd> without a preceding <dt>
+    DdWithoutDt,
+    /// `lint --structure`: <li> outside of a list
+    LiOutsideList,
+}
+
+impl Check {
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::MarkdownLikely => "markdown-likely",
+            Check::MarkdownUnused => "markdown-unused",
+            Check::VerbatimConstruct => "verbatim-construct",
+            Check::InvalidSetting => "invalid-setting",
+            Check::UnterminatedTag => "unterminated-tag",
+            Check::UnterminatedComment => "unterminated-comment",
+            Check::UnterminatedRawText => "unterminated-raw-text",
+            Check::UnterminatedCdata => "unterminated-cdata",
+            Check::UnmatchedOff => "unmatched-off",
+            Check::BogusComment => "bogus-comment",
+            Check::IncorrectlyClosedComment => "incorrectly-closed-comment",
+            Check::EndTagWithAttributes => "end-tag-with-attributes",
+            Check::StrayEndTag => "stray-end-tag",
+            Check::HeadingLevel => "heading-level",
+            Check::DdWithoutDt => "dd-without-dt",
+            Check::LiOutsideList => "li-outside-list",
+        }
+    }
+
+    /// Level when neither `--allow` nor `--deny` names the check.
+    pub fn default_level(self) -> Level {
+        match self {
+            Check::MarkdownUnused => Level::Note,
+            _ => Level::Warning,
+        }
+    }
+}
+
+/// `--allow`, `--deny` and `--fail-on`, shared by the formatter and `lint`.
+#[cfg(feature = "cli")]
+#[derive(clap::Args, Default)]
+pub struct LintLevels {
+    /// Don't report diagnostics of this check (repeatable)
+    #[arg(long, value_enum, value_name = "CHECK")]
+    allow: Vec<Check>,
+
+    /// Report diagnostics of this check as errors (repeatable; wins over --allow)
+    #[arg(long, value_enum, value_name = "CHECK")]
+    deny: Vec<Check>,
+
+    /// Exit with status 1 if a diagnostic of this level or higher is reported
+    /// (default: error; for `lint`, note)
+    #[arg(long = "fail-on", value_enum, value_name = "LEVEL")]
+    fail_on: Option<Level>,
+}
+
+#[cfg(feature = "cli")]
+impl LintLevels {
+    /// Level `check` is reported at, or `None` if it is allowed.
+    pub fn level(&self, check: Check) -> Option<Level> {
+        if self.deny.contains(&check) {
+            Some(Level::Error)
+        } else if self.allow.contains(&check) {
+            None
+        } else {
+            Some(check.default_level())
+        }
+    }
+
+    /// Whether a diagnostic at `level` fails the run, `default` being the
+    /// `--fail-on` level when it isn't given.
+    pub fn fails(&self, level: Level, default: Level) -> bool {
+        level >= self.fail_on.unwrap_or(default)
+    }
+}
+
+/// A diagnostic located in its file, after the `--allow`/`--deny` mapping.
+#[derive(Clone, Debug)]
+pub struct Finding {
+    pub line: usize,
+    pub column: usize,
+    pub check: Check,
+    pub level: Level,
+    pub message: String,
+}
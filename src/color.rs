@@ -0,0 +1,82 @@
+// src/color.rs
+//
+// `--color`: ANSI styling for diffs and diagnostics. Only the human-facing
+// printers use it; formatted output and reports are always written as is.
+
+use crate::checks::{Finding, Level};
+use clap::ValueEnum;
+use std::io::IsTerminal;
+
+pub const BOLD: &str = "\x1b[1m";
+pub const RED: &str = "\x1b[31m";
+pub const GREEN: &str = "\x1b[32m";
+pub const YELLOW: &str = "\x1b[33m";
+pub const CYAN: &str = "\x1b[36m";
+pub const RESET: &str = "\x1b[0m";
+
+/// When to color output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color if the stream is a terminal and NO_COLOR isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to color what is written to `stream`.
+    pub fn enabled(self, stream: &impl IsTerminal) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && stream.is_terminal(),
+        }
+    }
+}
+
+/// `text` wrapped in `style` when `color` is set.
+pub fn paint(text: &str, style: &str, color: bool) -> String {
+    if color { format!("{style}{text}{RESET}") } else { text.to_string() }
+}
+
+/// A diagnostic line, `LOCATION: LEVEL: MESSAGE [CHECK]`, with the location in cyan
+/// and the level in bold red (error), yellow (warning) or plain bold (note) when
+/// `color` is set.
+pub fn diagnostic(location: &str, d: &Finding, color: bool) -> String {
+    let style = match d.level {
+        Level::Error => format!("{BOLD}{RED}"),
+        Level::Warning => format!("{BOLD}{YELLOW}"),
+        Level::Note => BOLD.to_string(),
+    };
+    let level = format!("{}:", d.level.name());
+    format!("{}: {} {} [{}]", paint(location, CYAN, color), paint(&level, &style, color), d.message, d.check.name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checks::Check;
+    use crate::diff;
+
+    #[test]
+    fn renderings() {
+        let mut d = Finding { line: 3, column: 7, check: Check::VerbatimConstruct, level: Level::Warning, message: "PHP block copied verbatim".to_string() };
+        assert_eq!(diagnostic("a.bs:3:7", &d, false), "a.bs:3:7: warning: PHP block copied verbatim [verbatim-construct]");
+        assert_eq!(
+            diagnostic("a.bs:3:7", &d, true),
+            "\x1b[36ma.bs:3:7\x1b[0m: \x1b[1m\x1b[33mwarning:\x1b[0m PHP block copied verbatim [verbatim-construct]"
+        );
+        d.level = Level::Error;
+        assert_eq!(diagnostic("a.bs:3:7", &d, true), "\x1b[36ma.bs:3:7\x1b[0m: \x1b[1m\x1b[31merror:\x1b[0m PHP block copied verbatim [verbatim-construct]");
+
+        let (old, new) = (b"one\ntwo\n-- x\n".as_slice(), b"one\n2\n-- x".as_slice());
+        let plain = "--- a.html\n+++ a.html\n@@ -1,3 +1,3 @@\n one\n-two\n--- x\n+2\n+-- x\n\\ No newline at end of file\n";
+        assert_eq!(String::from_utf8(diff::unified("a.html", old, new, 1, false)).unwrap(), plain);
+        let colored = concat!(
+            "\x1b[1m--- a.html\x1b[0m\n\x1b[1m+++ a.html\x1b[0m\n\x1b[36m@@ -1,3 +1,3 @@\x1b[0m\n one\n",
+            "\x1b[31m-two\x1b[0m\n\x1b[31m--- x\x1b[0m\n\x1b[32m+2\x1b[0m\n\x1b[32m+-- x\x1b[0m\n\\ No newline at end of file\n",
+        );
+        assert_eq!(String::from_utf8(diff::unified("a.html", old, new, 1, true)).unwrap(), colored);
+    }
+}
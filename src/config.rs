@@ -0,0 +1,976 @@
+// src/config.rs
+//
+// Project configuration: `.reformahtml.toml` (or `reformahtml.toml`), found by
+// walking up from each input file. Values are defaults that command line flags
+// override. Keys are named after the flags:
+//
+//   format = "html"
+//   markdown = true
+//   honor-white-space = true
+//   pre-class = ["highlight"]
+//   skip-attr = ["data-keep-formatting", "data-format=off"]
+//   skip-class = ["manual-format"]
+//   structural-tag = ["spec-section"]
+//   not-structural-tag = ["ruby"]
+//   inline-tag = ["l"]
+//   not-inline-tag = ["ref"]
+//   raw-tag = ["code-example"]
+//   not-raw-tag = ["style"]
+//   break-tag = ["wbr"]
+//   void-tag = ["x-icon"]
+//   keep-attr-newlines = ["sizes"]
+//   break-comment = ["keep-break"]
+//   self-closing-style = "spaced"
+//   no-self-closing-void = true
+//   quote-attrs = "double"
+//   escape-quotes = true
+//   sort-attrs = ["id", "class", "href"]   (or true for the default order)
+//   wrap-attrs = 100
+//   keep-long-tags = 100
+//   tag-per-line = true
+//   foreign-content = "reformat"
+//   rcdata = "verbatim"
+//   format-metadata = true
+//   collapse-doctype = true
+//   newline = "lf"
+//   ensure-final-newline = true
+//   trim-final-newlines = true
+//   max-blank-lines = 2
+//   strip-trailing-whitespace = true
+//   indent = "spaces:4"
+//   best-effort = ["php"]
+//
+//   [ext-mode]
+//   inc = "markdown"
+//
+// Only the part of TOML these settings need is supported: `key = value` pairs
+// with booleans, integers, strings and arrays, `[table]` headers (one level),
+// and `#` comments. Anything
+// else — including unknown keys, values of the wrong type and settings that
+// contradict each other — is an error that names the file, line and column.
+
+use crate::{
+    Construct, Context, ExtMode, ForeignContent, Indent, InputFormat, Newline, QuoteStyle, Rcdata, SelfClosingStyle,
+};
+use reformahtml::ATTR_ORDER;
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// File names looked for in each directory, in order of preference.
+pub const FILE_NAMES: &[&str] = &[".reformahtml.toml", "reformahtml.toml"];
+
+/// Settings read from a configuration file. `None` means "not set".
+#[derive(Debug, Default)]
+pub struct Config {
+    pub format: Option<InputFormat>,
+    pub markdown: Option<bool>,
+    /// `[ext-mode]`: Markdown mode per file extension
+    pub ext_mode: Option<Vec<(String, ExtMode)>>,
+    pub best_effort: Option<Vec<Construct>>,
+    pub honor_white_space: Option<bool>,
+    pub pre_class: Option<Vec<String>>,
+    pub skip_attr: Option<Vec<String>>,
+    pub skip_class: Option<Vec<String>>,
+    pub structural_tag: Option<Vec<String>>,
+    pub not_structural_tag: Option<Vec<String>>,
+    pub inline_tag: Option<Vec<String>>,
+    pub not_inline_tag: Option<Vec<String>>,
+    pub raw_tag: Option<Vec<String>>,
+    pub not_raw_tag: Option<Vec<String>>,
+    pub break_tag: Option<Vec<String>>,
+    pub void_tag: Option<Vec<String>>,
+    pub keep_attr_newlines: Option<Vec<String>>,
+    pub break_comment: Option<Vec<String>>,
+    pub self_closing_style: Option<SelfClosingStyle>,
+    pub no_self_closing_void: Option<bool>,
+    pub quote_attrs: Option<QuoteStyle>,
+    pub escape_quotes: Option<bool>,
+    /// `Some(None)` for `sort-attrs = false`
+    pub sort_attrs: Option<Option<Vec<String>>>,
+    pub wrap_attrs: Option<usize>,
+    pub keep_long_tags: Option<usize>,
+    pub tag_per_line: Option<bool>,
+    pub foreign_content: Option<ForeignContent>,
+    pub rcdata: Option<Rcdata>,
+    pub format_metadata: Option<bool>,
+    pub collapse_doctype: Option<bool>,
+    pub newline: Option<Newline>,
+    pub ensure_final_newline: Option<bool>,
+    pub trim_final_newlines: Option<bool>,
+    pub max_blank_lines: Option<usize>,
+    pub strip_trailing_whitespace: Option<bool>,
+    pub indent: Option<Indent>,
+}
+
+/// Every key a configuration file may contain.
+const KEYS: &[&str] = &[
+    "format",
+    "markdown",
+    "ext-mode",
+    "best-effort",
+    "honor-white-space",
+    "pre-class",
+    "skip-attr",
+    "skip-class",
+    "structural-tag",
+    "not-structural-tag",
+    "inline-tag",
+    "not-inline-tag",
+    "raw-tag",
+    "not-raw-tag",
+    "break-tag",
+    "void-tag",
+    "keep-attr-newlines",
+    "break-comment",
+    "self-closing-style",
+    "no-self-closing-void",
+    "quote-attrs",
+    "escape-quotes",
+    "sort-attrs",
+    "wrap-attrs",
+    "keep-long-tags",
+    "tag-per-line",
+    "foreign-content",
+    "rcdata",
+    "format-metadata",
+    "collapse-doctype",
+    "newline",
+    "ensure-final-newline",
+    "trim-final-newlines",
+    "max-blank-lines",
+    "strip-trailing-whitespace",
+    "indent",
+];
+
+/// Keys whose value is a list; in a reformahtml comment, it is comma-separated.
+const LIST_KEYS: &[&str] = &[
+    "best-effort",
+    "pre-class",
+    "skip-attr",
+    "skip-class",
+    "structural-tag",
+    "not-structural-tag",
+    "inline-tag",
+    "not-inline-tag",
+    "raw-tag",
+    "not-raw-tag",
+    "break-tag",
+    "void-tag",
+    "keep-attr-newlines",
+    "break-comment",
+    "sort-attrs",
+];
+
+/// A problem in a configuration file, at a 1-based line and column.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+    /// For settings that conflict, where the other one is and what it does
+    pub note: Option<(usize, usize, String)>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.path.display(), self.line, self.col, self.message)?;
+        if let Some((line, col, note)) = &self.note {
+            write!(f, "\n{}:{line}:{col}: note: {note}", self.path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(e: ConfigError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl Config {
+    /// No settings at all.
+    pub const EMPTY: Config = Config {
+        format: None,
+        markdown: None,
+        ext_mode: None,
+        best_effort: None,
+        honor_white_space: None,
+        pre_class: None,
+        skip_attr: None,
+        skip_class: None,
+        structural_tag: None,
+        not_structural_tag: None,
+        inline_tag: None,
+        not_inline_tag: None,
+        raw_tag: None,
+        not_raw_tag: None,
+        break_tag: None,
+        void_tag: None,
+        keep_attr_newlines: None,
+        break_comment: None,
+        self_closing_style: None,
+        no_self_closing_void: None,
+        quote_attrs: None,
+        escape_quotes: None,
+        sort_attrs: None,
+        wrap_attrs: None,
+        keep_long_tags: None,
+        tag_per_line: None,
+        foreign_content: None,
+        rcdata: None,
+        format_metadata: None,
+        collapse_doctype: None,
+        newline: None,
+        ensure_final_newline: None,
+        trim_final_newlines: None,
+        max_blank_lines: None,
+        strip_trailing_whitespace: None,
+        indent: None,
+    };
+
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let src = fs::read_to_string(path).context("read configuration", path)?;
+        Ok(Config::parse(&src).map_err(|e| ConfigError {
+            path: path.to_path_buf(),
+            line: e.pos.line,
+            col: e.pos.col,
+            message: e.message,
+            note: e.note.map(|(pos, note)| (pos.line, pos.col, note)),
+        })?)
+    }
+
+    fn parse(src: &str) -> Result<Config, ParseError> {
+        let entries = parse_toml(src)?;
+        check_conflicts(&entries)?;
+        let mut config = Config::default();
+        for entry in entries {
+            config.set(entry)?;
+        }
+        Ok(config)
+    }
+
+    /// Settings from the `key=value` pairs of a `<!-- reformahtml: ... -->` comment,
+    /// and a warning for each pair that can't be used. Values are `on`/`off` (or
+    /// `true`/`false`), numbers, words, or comma-separated lists.
+    pub fn from_directive(text: &str) -> (Config, Vec<String>) {
+        let mut config = Config::default();
+        let mut warnings = Vec::new();
+        for pair in text.split_whitespace() {
+            let Some((key, value)) = pair.split_once('=') else {
+                warnings.push(format!("expected key=value in the reformahtml comment, found `{pair}`"));
+                continue;
+            };
+            let value = match value {
+                "on" | "true" => Value::Bool(true),
+                "off" | "false" => Value::Bool(false),
+                _ if LIST_KEYS.contains(&key) => {
+                    Value::Array(value.split(',').map(|item| (Pos::default(), Value::Str(item.to_string()))).collect())
+                }
+                _ => value.parse().map_or_else(|_| Value::Str(value.to_string()), Value::Int),
+            };
+            let entry = Entry { key: key.to_string(), key_pos: Pos::default(), value, value_pos: Pos::default() };
+            if let Err((_, message)) = config.set(entry) {
+                warnings.push(format!("{message} (in the reformahtml comment)"));
+            }
+        }
+        (config, warnings)
+    }
+
+    /// These settings, with those of `base` where these are not set.
+    pub fn or(&self, base: &Config) -> Config {
+        Config {
+            format: self.format.or(base.format),
+            markdown: self.markdown.or(base.markdown),
+            ext_mode: self.ext_mode.clone().or_else(|| base.ext_mode.clone()),
+            best_effort: self.best_effort.clone().or_else(|| base.best_effort.clone()),
+            honor_white_space: self.honor_white_space.or(base.honor_white_space),
+            pre_class: self.pre_class.clone().or_else(|| base.pre_class.clone()),
+            skip_attr: self.skip_attr.clone().or_else(|| base.skip_attr.clone()),
+            skip_class: self.skip_class.clone().or_else(|| base.skip_class.clone()),
+            structural_tag: self.structural_tag.clone().or_else(|| base.structural_tag.clone()),
+            not_structural_tag: self.not_structural_tag.clone().or_else(|| base.not_structural_tag.clone()),
+            inline_tag: self.inline_tag.clone().or_else(|| base.inline_tag.clone()),
+            not_inline_tag: self.not_inline_tag.clone().or_else(|| base.not_inline_tag.clone()),
+            raw_tag: self.raw_tag.clone().or_else(|| base.raw_tag.clone()),
+            not_raw_tag: self.not_raw_tag.clone().or_else(|| base.not_raw_tag.clone()),
+            break_tag: self.break_tag.clone().or_else(|| base.break_tag.clone()),
+            void_tag: self.void_tag.clone().or_else(|| base.void_tag.clone()),
+            keep_attr_newlines: self.keep_attr_newlines.clone().or_else(|| base.keep_attr_newlines.clone()),
+            break_comment: self.break_comment.clone().or_else(|| base.break_comment.clone()),
+            self_closing_style: self.self_closing_style.or(base.self_closing_style),
+            no_self_closing_void: self.no_self_closing_void.or(base.no_self_closing_void),
+            quote_attrs: self.quote_attrs.or(base.quote_attrs),
+            escape_quotes: self.escape_quotes.or(base.escape_quotes),
+            sort_attrs: self.sort_attrs.clone().or_else(|| base.sort_attrs.clone()),
+            wrap_attrs: self.wrap_attrs.or(base.wrap_attrs),
+            keep_long_tags: self.keep_long_tags.or(base.keep_long_tags),
+            tag_per_line: self.tag_per_line.or(base.tag_per_line),
+            foreign_content: self.foreign_content.or(base.foreign_content),
+            rcdata: self.rcdata.or(base.rcdata),
+            format_metadata: self.format_metadata.or(base.format_metadata),
+            collapse_doctype: self.collapse_doctype.or(base.collapse_doctype),
+            newline: self.newline.or(base.newline),
+            ensure_final_newline: self.ensure_final_newline.or(base.ensure_final_newline),
+            trim_final_newlines: self.trim_final_newlines.or(base.trim_final_newlines),
+            max_blank_lines: self.max_blank_lines.or(base.max_blank_lines),
+            strip_trailing_whitespace: self.strip_trailing_whitespace.or(base.strip_trailing_whitespace),
+            indent: self.indent.or(base.indent),
+        }
+    }
+
+    fn set(&mut self, entry: Entry) -> Result<(), (Pos, String)> {
+        let Entry { key, key_pos, value, value_pos } = entry;
+        let wrong_type = |expected: &str| (value_pos, format!("`{key}` must be {expected}, found {}", value.describe()));
+        match key.as_str() {
+            "markdown" => self.markdown = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?),
+            "honor-white-space" => {
+                self.honor_white_space = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?)
+            }
+            "pre-class" => self.pre_class = Some(strings(&key, value_pos, value)?),
+            "skip-attr" => self.skip_attr = Some(strings(&key, value_pos, value)?),
+            "skip-class" => self.skip_class = Some(strings(&key, value_pos, value)?),
+            "structural-tag" => self.structural_tag = Some(strings(&key, value_pos, value)?),
+            "not-structural-tag" => self.not_structural_tag = Some(strings(&key, value_pos, value)?),
+            "inline-tag" => self.inline_tag = Some(strings(&key, value_pos, value)?),
+            "not-inline-tag" => self.not_inline_tag = Some(strings(&key, value_pos, value)?),
+            "raw-tag" => self.raw_tag = Some(strings(&key, value_pos, value)?),
+            "not-raw-tag" => self.not_raw_tag = Some(strings(&key, value_pos, value)?),
+            "break-tag" => self.break_tag = Some(strings(&key, value_pos, value)?),
+            "void-tag" => self.void_tag = Some(strings(&key, value_pos, value)?),
+            "keep-attr-newlines" => self.keep_attr_newlines = Some(strings(&key, value_pos, value)?),
+            "break-comment" => self.break_comment = Some(strings(&key, value_pos, value)?),
+            "best-effort" => {
+                let items = strings_with_pos(&key, value_pos, value)?;
+                self.best_effort = Some(items.iter().map(|(pos, s)| choice(&key, *pos, s)).collect::<Result<_, _>>()?);
+            }
+            "self-closing-style" => {
+                let s = value.as_str().ok_or_else(|| wrong_type("a string"))?;
+                self.self_closing_style = Some(choice(&key, value_pos, s)?);
+            }
+            "no-self-closing-void" => {
+                self.no_self_closing_void = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?)
+            }
+            "quote-attrs" => {
+                let s = value.as_str().ok_or_else(|| wrong_type("a string"))?;
+                self.quote_attrs = Some(choice(&key, value_pos, s)?);
+            }
+            "escape-quotes" => {
+                self.escape_quotes = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?)
+            }
+            "sort-attrs" => {
+                self.sort_attrs = Some(match value.as_bool() {
+                    Some(on) => on.then(|| ATTR_ORDER.iter().map(|a| a.to_string()).collect()),
+                    None => Some(strings(&key, value_pos, value)?),
+                })
+            }
+            "wrap-attrs" => {
+                let n = value.as_int().ok_or_else(|| wrong_type("an integer"))?;
+                let n = usize::try_from(n).map_err(|_| (value_pos, format!("`{key}` can't be negative")))?;
+                self.wrap_attrs = Some(n);
+            }
+            "keep-long-tags" => {
+                let n = value.as_int().ok_or_else(|| wrong_type("an integer"))?;
+                let n = usize::try_from(n).map_err(|_| (value_pos, format!("`{key}` can't be negative")))?;
+                self.keep_long_tags = Some(n);
+            }
+            "tag-per-line" => self.tag_per_line = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?),
+            "foreign-content" => {
+                let s = value.as_str().ok_or_else(|| wrong_type("a string"))?;
+                self.foreign_content = Some(choice(&key, value_pos, s)?);
+            }
+            "rcdata" => {
+                let s = value.as_str().ok_or_else(|| wrong_type("a string"))?;
+                self.rcdata = Some(choice(&key, value_pos, s)?);
+            }
+            "format-metadata" => {
+                self.format_metadata = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?)
+            }
+            "collapse-doctype" => {
+                self.collapse_doctype = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?)
+            }
+            "ext-mode" => {
+                let Value::Table(entries) = value else { return Err(wrong_type("a table")) };
+                let modes = entries.into_iter().map(|e| {
+                    let mode = e.value.as_str().ok_or_else(|| {
+                        (e.value_pos, format!("`ext-mode.{}` must be a string, found {}", e.key, e.value.describe()))
+                    })?;
+                    Ok((e.key.trim_start_matches('.').to_string(), choice(&format!("ext-mode.{}", e.key), e.value_pos, mode)?))
+                });
+                self.ext_mode = Some(modes.collect::<Result<_, _>>()?);
+            }
+            "format" => {
+                let s = value.as_str().ok_or_else(|| wrong_type("a string"))?;
+                self.format = Some(choice(&key, value_pos, s)?);
+            }
+            "newline" => {
+                let s = value.as_str().ok_or_else(|| wrong_type("a string"))?;
+                self.newline = Some(choice(&key, value_pos, s)?);
+            }
+            "ensure-final-newline" => {
+                self.ensure_final_newline = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?)
+            }
+            "trim-final-newlines" => {
+                self.trim_final_newlines = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?)
+            }
+            "max-blank-lines" => {
+                let n = value.as_int().ok_or_else(|| wrong_type("an integer"))?;
+                let n = usize::try_from(n).map_err(|_| (value_pos, format!("`{key}` can't be negative")))?;
+                self.max_blank_lines = Some(n);
+            }
+            "strip-trailing-whitespace" => {
+                self.strip_trailing_whitespace = Some(value.as_bool().ok_or_else(|| wrong_type("a boolean"))?)
+            }
+            "indent" => {
+                let s = value.as_str().ok_or_else(|| wrong_type("a string"))?;
+                self.indent = Some(crate::parse_indent(s).map_err(|e| (value_pos, format!("invalid `{key}`: {e}")))?);
+            }
+            _ => {
+                let hint = suggest(&key).map(|k| format!("; did you mean `{k}`?")).unwrap_or_default();
+                return Err((key_pos, format!("unknown key `{key}`{hint}")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A string that must name a value of `T`, like a `value_enum` flag.
+fn choice<T: ValueEnum>(key: &str, pos: Pos, s: &str) -> Result<T, (Pos, String)> {
+    T::from_str(s, false).map_err(|_| {
+        let names: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|v| v.to_possible_value().map(|p| format!("\"{}\"", p.get_name())))
+            .collect();
+        (pos, format!("invalid value \"{s}\" for `{key}`; expected one of {}", names.join(", ")))
+    })
+}
+
+fn strings(key: &str, pos: Pos, value: Value) -> Result<Vec<String>, (Pos, String)> {
+    Ok(strings_with_pos(key, pos, value)?.into_iter().map(|(_, s)| s).collect())
+}
+
+fn strings_with_pos(key: &str, pos: Pos, value: Value) -> Result<Vec<(Pos, String)>, (Pos, String)> {
+    let Value::Array(items) = value else {
+        return Err((pos, format!("`{key}` must be an array of strings, found {}", value.describe())));
+    };
+    items
+        .into_iter()
+        .map(|(pos, v)| match v {
+            Value::Str(s) => Ok((pos, s)),
+            other => Err((pos, format!("`{key}` must be an array of strings, found {} in it", other.describe()))),
+        })
+        .collect()
+}
+
+/// An error in a configuration file; for settings that conflict, with where the
+/// other one is and what it does.
+#[derive(Debug)]
+struct ParseError {
+    pos: Pos,
+    message: String,
+    note: Option<(Pos, String)>,
+}
+
+impl From<(Pos, String)> for ParseError {
+    fn from((pos, message): (Pos, String)) -> ParseError {
+        ParseError { pos, message, note: None }
+    }
+}
+
+/// Lists that add elements to a default list, and the lists that take them out again.
+const OPPOSITE_LISTS: &[(&str, &str)] =
+    &[("structural-tag", "not-structural-tag"), ("inline-tag", "not-inline-tag"), ("raw-tag", "not-raw-tag")];
+
+/// Settings that contradict each other, looked for once the whole file is read.
+/// Values of the wrong type are left to `Config::set`.
+fn check_conflicts(entries: &[Entry]) -> Result<(), ParseError> {
+    let items = |key: &str| {
+        let entry = entries.iter().find(|e| e.key == key);
+        let items = entry.and_then(|e| match &e.value {
+            Value::Array(items) => Some(items.as_slice()),
+            _ => None,
+        });
+        items.unwrap_or_default().iter().filter_map(|(pos, v)| v.as_str().map(|s| (*pos, s)))
+    };
+    for &(added, removed) in OPPOSITE_LISTS {
+        for (add_pos, name) in items(added) {
+            if let Some((remove_pos, _)) = items(removed).find(|(_, n)| n.eq_ignore_ascii_case(name)) {
+                // Reported where the second of the two is.
+                let (at, other) = if remove_pos > add_pos {
+                    ((remove_pos, removed), (add_pos, added))
+                } else {
+                    ((add_pos, added), (remove_pos, removed))
+                };
+                return Err(ParseError {
+                    pos: at.0,
+                    message: format!("`{}` lists `{name}`, which conflicts with `{}`", at.1, other.1),
+                    note: Some((other.0, format!("`{}` lists `{name}` here", other.1))),
+                });
+            }
+        }
+    }
+    // `escape-quotes` only changes values written in a `quote-attrs` style.
+    let entry = |key: &str| entries.iter().find(|e| e.key == key);
+    if let Some(escape) = entry("escape-quotes").filter(|e| e.value.as_bool() == Some(true)) {
+        match entry("quote-attrs") {
+            None => {
+                let message = "`escape-quotes` has no effect without `quote-attrs`".to_string();
+                return Err((escape.key_pos, message).into());
+            }
+            Some(quote) if quote.value.as_str() == Some("keep") => {
+                return Err(ParseError {
+                    pos: escape.key_pos,
+                    message: "`escape-quotes` has no effect with `quote-attrs = \"keep\"`".to_string(),
+                    note: Some((quote.key_pos, "`quote-attrs` keeps the quotes of the source here".to_string())),
+                });
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// The known key closest to a misspelled one, if any is close enough.
+fn suggest(key: &str) -> Option<&'static str> {
+    KEYS.iter()
+        .map(|k| (levenshtein(key, k), *k))
+        .filter(|(d, k)| *d <= 2.max(k.len() / 4))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, k)| k)
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            cur[j + 1] = (prev[j] + usize::from(ca != *cb)).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/* =============================== Discovery ============================== */
+
+/// Outcome of looking up the configuration for a directory or loading a file:
+/// the config (`None`: there is none), or the error message.
+type Lookup = Result<Option<Arc<Config>>, String>;
+
+/// Finds and loads configuration files, caching the result per directory (and
+/// per explicitly named file). Shared by the worker threads of a run.
+#[derive(Default)]
+pub struct ConfigCache {
+    lookups: Mutex<HashMap<PathBuf, Lookup>>,
+}
+
+impl ConfigCache {
+    /// The configuration that applies to `input`: the nearest config file in its
+    /// directory or any parent directory. Stdin uses the current directory.
+    pub fn for_input(&self, input: &Path) -> io::Result<Option<Arc<Config>>> {
+        let start = std::path::absolute(input)?;
+        let dir = if input.as_os_str() == "-" { start } else { start.parent().map_or(start.clone(), Path::to_path_buf) };
+        self.for_dir(&dir)
+    }
+
+    /// The configuration in the file at `path` (`--config`).
+    pub fn file(&self, path: &Path) -> io::Result<Option<Arc<Config>>> {
+        self.cached(path, || Config::load(path).map(|c| Some(Arc::new(c))))
+    }
+
+    fn for_dir(&self, dir: &Path) -> io::Result<Option<Arc<Config>>> {
+        self.cached(dir, || {
+            let found = FILE_NAMES.iter().map(|name| dir.join(name)).find(|p| p.is_file());
+            match (found, dir.parent()) {
+                (Some(path), _) => self.file(&path),
+                (None, Some(parent)) => self.for_dir(parent),
+                (None, None) => Ok(None),
+            }
+        })
+    }
+
+    fn cached(&self, key: &Path, compute: impl FnOnce() -> io::Result<Option<Arc<Config>>>) -> io::Result<Option<Arc<Config>>> {
+        let known = self.lookups.lock().unwrap().get(key).cloned();
+        let lookup = match known {
+            Some(lookup) => lookup,
+            None => {
+                let lookup = compute().map_err(|e| e.to_string());
+                self.lookups.lock().unwrap().insert(key.to_path_buf(), lookup.clone());
+                lookup
+            }
+        };
+        lookup.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/* ============================== TOML subset ============================= */
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Pos {
+    line: usize,
+    col: usize,
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Array(Vec<(Pos, Value)>),
+    /// The `key = value` lines below a `[name]` header
+    Table(Vec<Entry>),
+}
+
+impl Value {
+    fn describe(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "a boolean",
+            Value::Int(_) => "an integer",
+            Value::Str(_) => "a string",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry {
+    key: String,
+    key_pos: Pos,
+    value: Value,
+    value_pos: Pos,
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    pos: Pos,
+}
+
+type ParseResult<T> = Result<T, (Pos, String)>;
+
+fn parse_toml(src: &str) -> ParseResult<Vec<Entry>> {
+    let mut p = Parser { chars: src.chars().peekable(), pos: Pos { line: 1, col: 1 } };
+    let mut entries: Vec<Entry> = Vec::new();
+    // Index in `entries` of the table that the following lines belong to.
+    let mut table: Option<usize> = None;
+    loop {
+        p.skip_blank_lines();
+        let Some(c) = p.peek() else { break };
+        let key_pos = p.pos;
+        let header = c == '[';
+        if header {
+            p.bump();
+            p.skip_spaces();
+        }
+        let key = p.key()?;
+        let list = match table {
+            Some(t) if !header => match &mut entries[t].value {
+                Value::Table(list) => list,
+                _ => unreachable!("`table` always points at a table"),
+            },
+            _ => &mut entries,
+        };
+        if list.iter().any(|e| e.key == key) {
+            return Err((key_pos, format!("duplicate key `{key}`")));
+        }
+        p.skip_spaces();
+        let (value, value_pos) = if header {
+            if p.peek() == Some('.') {
+                return Err((p.pos, "nested tables are not supported".to_string()));
+            }
+            p.expect(']')?;
+            (Value::Table(Vec::new()), key_pos)
+        } else {
+            p.expect('=')?;
+            p.skip_spaces();
+            let value_pos = p.pos;
+            (p.value()?, value_pos)
+        };
+        p.skip_spaces();
+        p.skip_comment();
+        match p.peek() {
+            None | Some('\n' | '\r') => {}
+            Some(c) => return Err((p.pos, format!("unexpected `{c}` after value"))),
+        }
+        list.push(Entry { key, key_pos, value, value_pos });
+        if header {
+            table = Some(entries.len() - 1);
+        }
+    }
+    Ok(entries)
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.col = 1;
+        } else {
+            self.pos.col += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, want: char) -> ParseResult<()> {
+        match self.peek() {
+            Some(c) if c == want => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => Err((self.pos, format!("expected `{want}`, found `{c}`"))),
+            None => Err((self.pos, format!("expected `{want}`, found end of file"))),
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip whitespace, newlines and comments.
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n' | '\r') => {
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn key(&mut self) -> ParseResult<String> {
+        if matches!(self.peek(), Some('"' | '\'')) {
+            return self.string();
+        }
+        let mut key = String::new();
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_') {
+            key.push(c);
+            self.bump();
+        }
+        if key.is_empty() {
+            let found = self.peek().map_or("end of file".to_string(), |c| format!("`{c}`"));
+            return Err((self.pos, format!("expected a key, found {found}")));
+        }
+        Ok(key)
+    }
+
+    fn value(&mut self) -> ParseResult<Value> {
+        let start = self.pos;
+        match self.peek() {
+            Some('"' | '\'') => self.string().map(Value::Str),
+            Some('[') => self.array(),
+            Some(c) if c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '_' => {
+                let mut word = String::new();
+                while let Some(c) = self.peek().filter(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '.')) {
+                    word.push(c);
+                    self.bump();
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => word
+                        .replace('_', "")
+                        .parse()
+                        .map(Value::Int)
+                        .map_err(|_| (start, format!("invalid value `{word}` (strings need quotes)"))),
+                }
+            }
+            Some(c) => Err((start, format!("expected a value, found `{c}`"))),
+            None => Err((start, "expected a value, found end of file".to_string())),
+        }
+    }
+
+    fn array(&mut self) -> ParseResult<Value> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            let pos = self.pos;
+            items.push((pos, self.value()?));
+            self.skip_blank_lines();
+            match self.peek() {
+                Some(',') => {
+                    self.bump();
+                }
+                Some(']') => {}
+                Some(c) => return Err((self.pos, format!("expected `,` or `]`, found `{c}`"))),
+                None => return Err((self.pos, "unterminated array".to_string())),
+            }
+        }
+    }
+
+    fn string(&mut self) -> ParseResult<String> {
+        let start = self.pos;
+        let quote = self.bump().unwrap_or('"');
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err((start, "unterminated string".to_string())),
+                Some(c) if c == quote => return Ok(s),
+                Some('\\') if quote == '"' => {
+                    let escape_pos = self.pos;
+                    match self.bump() {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some('r') => s.push('\r'),
+                        Some('u') => {
+                            let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                            let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                            s.push(c.ok_or((escape_pos, format!("invalid escape `\\u{hex}`")))?);
+                        }
+                        other => {
+                            let shown = other.map_or(String::new(), String::from);
+                            return Err((escape_pos, format!("invalid escape `\\{shown}`")));
+                        }
+                    }
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(src: &str) -> String {
+        let ParseError { pos, message, note } = Config::parse(src).unwrap_err();
+        let note = note.map_or(String::new(), |(at, note)| format!(" ({}:{}: {note})", at.line, at.col));
+        format!("{}:{}: {message}{note}", pos.line, pos.col)
+    }
+
+    #[test]
+    fn parses_settings() {
+        let config = Config::parse(
+            "# spec settings\nmarkdown = false\npre-class = [\n  \"highlight\", # comment\n  'idl',\n]\nself-closing-style = \"spaced\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.markdown, Some(false));
+        assert_eq!(config.pre_class, Some(vec!["highlight".to_string(), "idl".to_string()]));
+        assert_eq!(config.self_closing_style, Some(SelfClosingStyle::Spaced));
+        assert_eq!(config.honor_white_space, None);
+
+        let config = Config::parse("markdown = true\n\n[ext-mode]\ninc = \"markdown\"\n'.tmpl' = 'html'\n").unwrap();
+        assert_eq!(config.markdown, Some(true));
+        assert_eq!(
+            config.ext_mode,
+            Some(vec![("inc".to_string(), ExtMode::Markdown), ("tmpl".to_string(), ExtMode::Html)])
+        );
+    }
+
+    #[test]
+    fn directive_settings_override_the_file() {
+        let (directive, warnings) =
+            Config::from_directive(" markdown=off max-blank-lines=1 pre-class=a,b newline=crlf mardown=on indent ");
+        assert_eq!(directive.markdown, Some(false));
+        assert_eq!(directive.max_blank_lines, Some(1));
+        assert_eq!(directive.pre_class, Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(
+            warnings,
+            [
+                "unknown key `mardown`; did you mean `markdown`? (in the reformahtml comment)",
+                "expected key=value in the reformahtml comment, found `indent`"
+            ]
+        );
+
+        let file = Config::parse("markdown = true
+honor-white-space = true
+").unwrap();
+        let merged = directive.or(&file);
+        assert_eq!((merged.markdown, merged.honor_white_space, merged.newline), (Some(false), Some(true), Some(Newline::Crlf)));
+    }
+
+    #[test]
+    fn reports_mistakes_with_positions() {
+        assert_eq!(error("markdown = true\nmarkdwon = true\n"), "2:1: unknown key `markdwon`; did you mean `markdown`?");
+        assert_eq!(error("markdown = \"yes\"\n"), "1:12: `markdown` must be a boolean, found a string");
+        assert_eq!(error("break-tag = [\"wbr\", 1]\n"), "1:21: `break-tag` must be an array of strings, found an integer in it");
+        assert_eq!(
+            error("self-closing-style = \"tight\"\n"),
+            "1:22: invalid value \"tight\" for `self-closing-style`; expected one of \"spaced\", \"compact\""
+        );
+        assert_eq!(error("markdown = true\nmarkdown = false\n"), "2:1: duplicate key `markdown`");
+        assert_eq!(error("[markdown]\n"), "1:1: `markdown` must be a boolean, found a table");
+        assert_eq!(error("[ext-mode]\ninc = \"md\"\n"), "2:7: invalid value \"md\" for `ext-mode.inc`; expected one of \"html\", \"markdown\"");
+        assert_eq!(error("[ext-mode]\ninc = \"html\"\ninc = \"html\"\n"), "3:1: duplicate key `inc`");
+    }
+
+    #[test]
+    fn reports_conflicting_settings_at_both_keys() {
+        assert_eq!(
+            error("structural-tag = [\"spec-section\", \"ruby\"]\nnot-structural-tag = [\"RUBY\"]\n"),
+            "2:23: `not-structural-tag` lists `ruby`, which conflicts with `structural-tag` (1:35: `structural-tag` lists `ruby` here)"
+        );
+        assert!(Config::parse("inline-tag = [\"l\"]\nnot-inline-tag = [\"ref\"]\n").is_ok());
+        assert_eq!(error("escape-quotes = true\n"), "1:1: `escape-quotes` has no effect without `quote-attrs`");
+        assert_eq!(
+            error("quote-attrs = \"keep\"\nescape-quotes = true\n"),
+            "2:1: `escape-quotes` has no effect with `quote-attrs = \"keep\"` (1:1: `quote-attrs` keeps the quotes of the source here)"
+        );
+        assert!(Config::parse("escape-quotes = true\nquote-attrs = \"single\"\n").is_ok());
+        assert!(Config::parse("escape-quotes = false\n").is_ok());
+
+        let path = std::env::temp_dir().join(format!("reformahtml-conflict-{}.toml", std::process::id()));
+        fs::write(&path, "not-raw-tag = ['style']\nraw-tag = ['style']\n").unwrap();
+        let message = Config::load(&path).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+        let shown = path.display();
+        assert_eq!(
+            message,
+            format!("{shown}:2:12: `raw-tag` lists `style`, which conflicts with `not-raw-tag`\n{shown}:1:16: note: `not-raw-tag` lists `style` here")
+        );
+    }
+}
@@ -0,0 +1,390 @@
+// src/diff.rs
+//
+// Line-based diffing (Myers' O(ND) algorithm, linear-space variant) used by
+// the report and diff outputs.
+
+use crate::color::{BOLD, CYAN, GREEN, RED, RESET};
+use std::ops::Range;
+
+/// Kind of a diff operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tag {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// A run of lines: `old[old_index..old_index + len]` for Equal/Delete,
+/// `new[new_index..new_index + len]` for Equal/Insert.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiffOp {
+    pub tag: Tag,
+    pub old_index: usize,
+    pub new_index: usize,
+    pub len: usize,
+}
+
+/// A group of changes with surrounding context, as shown in a unified diff.
+#[derive(Clone, Debug)]
+pub struct Hunk {
+    pub ops: Vec<DiffOp>,
+}
+
+impl Hunk {
+    pub fn old_range(&self) -> Range<usize> {
+        let start = self.ops.first().map_or(0, |op| op.old_index);
+        let end = self.ops.last().map_or(0, |op| match op.tag {
+            Tag::Insert => op.old_index,
+            _ => op.old_index + op.len,
+        });
+        start..end.max(start)
+    }
+
+    pub fn new_range(&self) -> Range<usize> {
+        let start = self.ops.first().map_or(0, |op| op.new_index);
+        let end = self.ops.last().map_or(0, |op| match op.tag {
+            Tag::Delete => op.new_index,
+            _ => op.new_index + op.len,
+        });
+        start..end.max(start)
+    }
+
+    /// Number of deleted plus inserted lines.
+    pub fn changed_lines(&self) -> usize {
+        self.ops.iter().filter(|op| op.tag != Tag::Equal).map(|op| op.len).sum()
+    }
+}
+
+/// Split `s` into lines, each including its trailing '\n' (the last one may lack it).
+pub fn split_lines(s: &[u8]) -> Vec<&[u8]> {
+    s.split_inclusive(|&b| b == b'\n').collect()
+}
+
+/// Compute the line-level edit script turning `old` into `new`.
+pub fn diff_lines<T: PartialEq>(old: &[T], new: &[T]) -> Vec<DiffOp> {
+    let max_d = (old.len() + new.len()).div_ceil(2) + 1;
+    let mut vf = V::new(max_d);
+    let mut vb = V::new(max_d);
+    let mut ops = Vec::new();
+    conquer(old, 0..old.len(), new, 0..new.len(), &mut vf, &mut vb, &mut ops);
+    ops
+}
+
+/// Group an edit script into hunks with `context` lines of unchanged text around each change.
+pub fn hunks(ops: &[DiffOp], context: usize) -> Vec<Hunk> {
+    let mut out: Vec<Hunk> = Vec::new();
+    let mut cur: Vec<DiffOp> = Vec::new();
+    for (idx, op) in ops.iter().enumerate() {
+        if op.tag != Tag::Equal {
+            cur.push(*op);
+            continue;
+        }
+        let is_first = idx == 0;
+        let is_last = idx + 1 == ops.len();
+        if cur.is_empty() {
+            // Leading context for the next change.
+            if !is_last {
+                let take = op.len.min(context);
+                if take > 0 {
+                    cur.push(DiffOp {
+                        tag: Tag::Equal,
+                        old_index: op.old_index + op.len - take,
+                        new_index: op.new_index + op.len - take,
+                        len: take,
+                    });
+                }
+            }
+            continue;
+        }
+        if !is_last && !is_first && op.len <= 2 * context {
+            // Short equal run between two changes stays inside the hunk.
+            cur.push(*op);
+            continue;
+        }
+        let take = op.len.min(context);
+        if take > 0 {
+            cur.push(DiffOp { len: take, ..*op });
+        }
+        out.push(Hunk { ops: std::mem::take(&mut cur) });
+        if !is_last {
+            let take = op.len.min(context);
+            if take > 0 {
+                cur.push(DiffOp {
+                    tag: Tag::Equal,
+                    old_index: op.old_index + op.len - take,
+                    new_index: op.new_index + op.len - take,
+                    len: take,
+                });
+            }
+        }
+    }
+    if cur.iter().any(|op| op.tag != Tag::Equal) {
+        out.push(Hunk { ops: cur });
+    }
+    out
+}
+
+/// Render a unified diff of `old` against `new` with `context` lines around each
+/// change, or an empty vector if they are equal. Both sides are labelled `name`.
+/// With `color`, file headers are bold, hunk headers cyan, deletions red and
+/// insertions green.
+pub fn unified(name: &str, old: &[u8], new: &[u8], context: usize, color: bool) -> Vec<u8> {
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    let ops = diff_lines(&old_lines, &new_lines);
+    let hunks = hunks(&ops, context);
+    let mut out = Vec::new();
+    if hunks.is_empty() {
+        return out;
+    }
+    let style = |style: &'static str| if color { (style, RESET) } else { ("", "") };
+    let ((bold, reset), (cyan, _)) = (style(BOLD), style(CYAN));
+    out.extend_from_slice(format!("{bold}--- {name}{reset}\n{bold}+++ {name}{reset}\n").as_bytes());
+    let (delete, insert, equal) = (style(RED), style(GREEN), ("", ""));
+    for hunk in &hunks {
+        let (o, n) = (hunk.old_range(), hunk.new_range());
+        out.extend_from_slice(
+            format!("{cyan}@@ -{} +{} @@{reset}\n", hunk_range(&o), hunk_range(&n)).as_bytes(),
+        );
+        // Within each run of changes, deletions are listed before insertions.
+        let mut inserted: Vec<&[u8]> = Vec::new();
+        for op in &hunk.ops {
+            match op.tag {
+                Tag::Insert => inserted.extend(&new_lines[op.new_index..op.new_index + op.len]),
+                Tag::Delete => push_lines(&mut out, b'-', &old_lines[op.old_index..op.old_index + op.len], delete),
+                Tag::Equal => {
+                    push_lines(&mut out, b'+', &std::mem::take(&mut inserted), insert);
+                    push_lines(&mut out, b' ', &old_lines[op.old_index..op.old_index + op.len], equal);
+                }
+            }
+        }
+        push_lines(&mut out, b'+', &inserted, insert);
+    }
+    out
+}
+
+/// Write `lines` with `prefix`, each wrapped in the (start, end) escape codes of `style`.
+fn push_lines(out: &mut Vec<u8>, prefix: u8, lines: &[&[u8]], (start, end): (&str, &str)) {
+    for line in lines {
+        out.extend_from_slice(start.as_bytes());
+        out.push(prefix);
+        let (text, newline) = match line.strip_suffix(b"\n") {
+            Some(text) => (text, true),
+            None => (*line, false),
+        };
+        out.extend_from_slice(text);
+        out.extend_from_slice(end.as_bytes());
+        out.push(b'\n');
+        if !newline {
+            out.extend_from_slice(b"\\ No newline at end of file\n");
+        }
+    }
+}
+
+/// `start,len` as written in a hunk header (1-based; an empty range names the line before it).
+fn hunk_range(r: &Range<usize>) -> String {
+    if r.is_empty() {
+        format!("{},0", r.start)
+    } else {
+        format!("{},{}", r.start + 1, r.len())
+    }
+}
+
+/* ============================== Myers core ============================== */
+
+struct V {
+    offset: isize,
+    v: Vec<usize>,
+}
+
+impl V {
+    fn new(max_d: usize) -> V {
+        V { offset: max_d as isize, v: vec![0; 2 * max_d + 2] }
+    }
+}
+
+impl std::ops::Index<isize> for V {
+    type Output = usize;
+    fn index(&self, k: isize) -> &usize {
+        &self.v[(k + self.offset) as usize]
+    }
+}
+
+impl std::ops::IndexMut<isize> for V {
+    fn index_mut(&mut self, k: isize) -> &mut usize {
+        &mut self.v[(k + self.offset) as usize]
+    }
+}
+
+fn push_op(ops: &mut Vec<DiffOp>, tag: Tag, old_index: usize, new_index: usize, len: usize) {
+    if len == 0 {
+        return;
+    }
+    if let Some(last) = ops.last_mut() {
+        if last.tag == tag
+            && match tag {
+                Tag::Equal => {
+                    last.old_index + last.len == old_index && last.new_index + last.len == new_index
+                }
+                Tag::Delete => last.old_index + last.len == old_index,
+                Tag::Insert => last.new_index + last.len == new_index,
+            }
+        {
+            last.len += len;
+            return;
+        }
+    }
+    ops.push(DiffOp { tag, old_index, new_index, len });
+}
+
+fn common_prefix<T: PartialEq>(old: &[T], o: Range<usize>, new: &[T], n: Range<usize>) -> usize {
+    old[o].iter().zip(&new[n]).take_while(|(a, b)| a == b).count()
+}
+
+fn common_suffix<T: PartialEq>(old: &[T], o: Range<usize>, new: &[T], n: Range<usize>) -> usize {
+    old[o].iter().rev().zip(new[n].iter().rev()).take_while(|(a, b)| a == b).count()
+}
+
+/// Find the start of the middle snake of the optimal path through the edit graph.
+fn middle_snake<T: PartialEq>(
+    old: &[T],
+    o: Range<usize>,
+    new: &[T],
+    n: Range<usize>,
+    vf: &mut V,
+    vb: &mut V,
+) -> Option<(usize, usize)> {
+    let (len_o, len_n) = (o.len(), n.len());
+    let delta = len_o as isize - len_n as isize;
+    let odd = delta & 1 == 1;
+    vf[1] = 0;
+    vb[1] = 0;
+    let d_max = (len_o + len_n).div_ceil(2) + 1;
+    for d in 0..d_max as isize {
+        let mut k = d;
+        while k >= -d {
+            let mut x = if k == -d || (k != d && vf[k - 1] < vf[k + 1]) {
+                vf[k + 1]
+            } else {
+                vf[k - 1] + 1
+            };
+            let y = (x as isize - k) as usize;
+            let (x0, y0) = (x, y);
+            if x < len_o && y < len_n {
+                x += common_prefix(old, o.start + x..o.end, new, n.start + y..n.end);
+            }
+            vf[k] = x;
+            if odd && (k - delta).abs() < d && vf[k] + vb[-(k - delta)] >= len_o {
+                return Some((o.start + x0, n.start + y0));
+            }
+            k -= 2;
+        }
+        let mut k = d;
+        while k >= -d {
+            let mut x = if k == -d || (k != d && vb[k - 1] < vb[k + 1]) {
+                vb[k + 1]
+            } else {
+                vb[k - 1] + 1
+            };
+            let mut y = (x as isize - k) as usize;
+            if x < len_o && y < len_n {
+                let adv = common_suffix(old, o.start..o.start + len_o - x, new, n.start..n.start + len_n - y);
+                x += adv;
+                y += adv;
+            }
+            vb[k] = x;
+            if !odd && (k - delta).abs() <= d && vb[k] + vf[-(k - delta)] >= len_o {
+                return Some((o.start + len_o - x, n.start + len_n - y));
+            }
+            k -= 2;
+        }
+    }
+    None
+}
+
+fn conquer<T: PartialEq>(
+    old: &[T],
+    mut o: Range<usize>,
+    new: &[T],
+    mut n: Range<usize>,
+    vf: &mut V,
+    vb: &mut V,
+    ops: &mut Vec<DiffOp>,
+) {
+    let prefix = common_prefix(old, o.clone(), new, n.clone());
+    push_op(ops, Tag::Equal, o.start, n.start, prefix);
+    o.start += prefix;
+    n.start += prefix;
+
+    let suffix = common_suffix(old, o.clone(), new, n.clone());
+    o.end -= suffix;
+    n.end -= suffix;
+
+    if o.is_empty() {
+        push_op(ops, Tag::Insert, o.start, n.start, n.len());
+    } else if n.is_empty() {
+        push_op(ops, Tag::Delete, o.start, n.start, o.len());
+    } else if let Some((x, y)) = middle_snake(old, o.clone(), new, n.clone(), vf, vb) {
+        conquer(old, o.start..x, new, n.start..y, vf, vb, ops);
+        conquer(old, x..o.end, new, y..n.end, vf, vb, ops);
+    } else {
+        push_op(ops, Tag::Delete, o.start, n.start, o.len());
+        push_op(ops, Tag::Insert, o.end, n.start, n.len());
+    }
+
+    push_op(ops, Tag::Equal, o.end, n.end, suffix);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(old: &[&str], new: &[&str], ops: &[DiffOp]) -> Vec<String> {
+        let mut out = Vec::new();
+        for op in ops {
+            match op.tag {
+                Tag::Equal => {
+                    for i in 0..op.len {
+                        assert_eq!(old[op.old_index + i], new[op.new_index + i]);
+                        out.push(old[op.old_index + i].to_string());
+                    }
+                }
+                Tag::Insert => out.extend(new[op.new_index..op.new_index + op.len].iter().map(|s| s.to_string())),
+                Tag::Delete => {}
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn diff_reconstructs_new() {
+        let old = ["a", "b", "c", "d", "e", "f"];
+        let new = ["a", "x", "c", "d", "f", "g"];
+        let ops = diff_lines(&old, &new);
+        assert_eq!(apply(&old, &new, &ops), new);
+        let changed: usize = ops.iter().filter(|o| o.tag != Tag::Equal).map(|o| o.len).sum();
+        assert_eq!(changed, 4); // -b +x -e +g
+    }
+
+    #[test]
+    fn unified_marks_missing_final_newline() {
+        let out = unified("a.html", b"one\ntwo\nthree", b"one\n2\nthree\n", 1, false);
+        let expected = "--- a.html\n+++ a.html\n@@ -1,3 +1,3 @@\n one\n-two\n-three\n\\ No newline at end of file\n+2\n+three\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert!(unified("a.html", b"same\n", b"same\n", 3, false).is_empty());
+    }
+
+    #[test]
+    fn hunks_merge_close_changes() {
+        let old: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        let mut new = old.clone();
+        new[5] = "five".into();
+        new[8] = "eight".into();
+        new[18] = "eighteen".into();
+        let ops = diff_lines(&old, &new);
+        let hs = hunks(&ops, 2);
+        assert_eq!(hs.len(), 2);
+        assert_eq!(hs[0].old_range(), 3..11);
+        assert_eq!(hs[1].old_range(), 16..20);
+    }
+}
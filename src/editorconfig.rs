@@ -0,0 +1,237 @@
+// src/editorconfig.rs
+//
+// `.editorconfig` support. The properties that have a reformahtml equivalent
+// are used as defaults, below `.reformahtml.toml` and command line flags:
+//
+//   end_of_line = lf | crlf        → --newline
+//   insert_final_newline = true    → --ensure-final-newline
+//   trim_trailing_whitespace = true → --strip-trailing-whitespace
+//
+// As in other editors, every `.editorconfig` from the file's directory up to
+// one with `root = true` applies, nearer files and later sections winning.
+
+use crate::{walk, Context, Newline};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const FILE_NAME: &str = ".editorconfig";
+
+/// The properties reformahtml uses, for one file. `None` means "not set".
+#[derive(Debug, Default, PartialEq)]
+pub struct Properties {
+    pub end_of_line: Option<Newline>,
+    pub insert_final_newline: Option<bool>,
+    pub trim_trailing_whitespace: Option<bool>,
+}
+
+impl Properties {
+    /// Apply one `key = value` line; unknown keys and values are ignored, and
+    /// `unset` clears a property.
+    fn set(&mut self, key: &str, value: &str) {
+        let flag = match value {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        };
+        match key {
+            "end_of_line" => {
+                self.end_of_line = match value {
+                    "lf" => Some(Newline::Lf),
+                    "crlf" => Some(Newline::Crlf),
+                    _ => None,
+                }
+            }
+            "insert_final_newline" => self.insert_final_newline = flag,
+            "trim_trailing_whitespace" => self.trim_trailing_whitespace = flag,
+            _ => {}
+        }
+    }
+}
+
+/// One parsed `.editorconfig` file.
+#[derive(Debug, Default)]
+struct EditorConfig {
+    root: bool,
+    /// Section globs and their `key = value` pairs (lowercased), in file order
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl EditorConfig {
+    fn parse(src: &str) -> EditorConfig {
+        let mut config = EditorConfig::default();
+        for line in src.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', ';']) {
+                continue;
+            }
+            if let Some(glob) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                config.sections.push((glob.to_string(), Vec::new()));
+            } else if let Some((key, value)) = line.split_once('=') {
+                let (key, value) = (key.trim().to_ascii_lowercase(), value.trim().to_ascii_lowercase());
+                match config.sections.last_mut() {
+                    Some((_, pairs)) => pairs.push((key, value)),
+                    None => config.root |= key == "root" && value == "true",
+                }
+            }
+        }
+        config
+    }
+
+    /// Apply the sections matching `rel`, the file's path relative to this file's directory.
+    fn apply(&self, rel: &str, props: &mut Properties) {
+        for (glob, pairs) in &self.sections {
+            if section_matches(glob, rel) {
+                for (key, value) in pairs {
+                    props.set(key, value);
+                }
+            }
+        }
+    }
+}
+
+/// Whether a section header glob matches `rel`. A glob without `/` matches the
+/// file name in any directory; `{a,b}` alternatives are supported.
+fn section_matches(glob: &str, rel: &str) -> bool {
+    let glob = match glob.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if !glob.contains('/') => format!("**/{glob}"),
+        None => glob.to_string(),
+    };
+    expand_braces(&glob).iter().any(|g| walk::glob_match(g.as_bytes(), rel.as_bytes()))
+}
+
+/// The patterns `{a,b}` stands for, expanded left to right. Braces without a
+/// comma (or unclosed ones) are literal.
+fn expand_braces(glob: &str) -> Vec<String> {
+    let Some(open) = glob.find('{') else { return vec![glob.to_string()] };
+    let mut depth = 0;
+    let mut parts = Vec::new();
+    let mut start = open + 1;
+    for (i, c) in glob[open..].char_indices().map(|(i, c)| (open + i, c)) {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    parts.push(&glob[start..i]);
+                    if parts.len() == 1 {
+                        break;
+                    }
+                    let (prefix, suffix) = (&glob[..open], &glob[i + 1..]);
+                    return parts
+                        .iter()
+                        .flat_map(|part| expand_braces(&format!("{prefix}{part}{suffix}")))
+                        .collect();
+                }
+            }
+            ',' if depth == 1 => {
+                parts.push(&glob[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    // Literal brace: keep it and expand what follows.
+    let (prefix, rest) = glob.split_at(open + 1);
+    expand_braces(rest).into_iter().map(|r| format!("{prefix}{r}")).collect()
+}
+
+/// Loads `.editorconfig` files, caching them per directory. Shared by the worker
+/// threads of a run.
+#[derive(Default)]
+pub struct EditorConfigCache {
+    dirs: Mutex<HashMap<PathBuf, Option<Arc<EditorConfig>>>>,
+}
+
+impl EditorConfigCache {
+    /// The properties that apply to `input`. Stdin (`-`) gets none.
+    pub fn properties(&self, input: &Path) -> io::Result<Properties> {
+        let mut props = Properties::default();
+        if input.as_os_str() == "-" {
+            return Ok(props);
+        }
+        let path = std::path::absolute(input)?;
+        let mut chain = Vec::new();
+        for dir in path.ancestors().skip(1) {
+            if let Some(config) = self.in_dir(dir)? {
+                let root = config.root;
+                chain.push((dir, config));
+                if root {
+                    break;
+                }
+            }
+        }
+        // The farthest file first, so nearer ones override it.
+        for (dir, config) in chain.iter().rev() {
+            let rel = path.strip_prefix(dir).unwrap_or(&path);
+            let rel = rel.to_string_lossy().replace('\\', "/");
+            config.apply(&rel, &mut props);
+        }
+        Ok(props)
+    }
+
+    fn in_dir(&self, dir: &Path) -> io::Result<Option<Arc<EditorConfig>>> {
+        if let Some(known) = self.dirs.lock().unwrap().get(dir) {
+            return Ok(known.clone());
+        }
+        let path = dir.join(FILE_NAME);
+        let config = match fs::read_to_string(&path) {
+            Ok(src) => Some(Arc::new(EditorConfig::parse(&src))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).context("read", &path),
+        };
+        self.dirs.lock().unwrap().insert(dir.to_path_buf(), config.clone());
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn section_globs() {
+        assert!(section_matches("*", "a/b.html"));
+        assert!(section_matches("*.{bs,html}", "specs/index.bs"));
+        assert!(!section_matches("*.{bs,html}", "README.md"));
+        assert!(section_matches("/docs/*.md", "docs/a.md"));
+        assert!(!section_matches("/docs/*.md", "x/docs/a.md"));
+        assert!(section_matches("docs/**.md", "docs/a/b.md"));
+        assert_eq!(expand_braces("{a,b{c,d}}.x"), ["a.x", "bc.x", "bd.x"]);
+        assert_eq!(expand_braces("{a}.x"), ["{a}.x"]);
+    }
+
+    #[test]
+    fn nearer_files_override_the_root() {
+        let root = std::env::temp_dir().join(format!("reformahtml-editorconfig-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(
+            root.join(FILE_NAME),
+            "root = true\n\n[*]\nend_of_line = crlf\ninsert_final_newline = true\n\n[*.html]\ntrim_trailing_whitespace = true\n",
+        )
+        .unwrap();
+        fs::write(root.join("sub").join(FILE_NAME), "[*.html]\nend_of_line = LF\ntrim_trailing_whitespace = unset\n").unwrap();
+
+        let cache = EditorConfigCache::default();
+        let top = cache.properties(&root.join("a.html")).unwrap();
+        assert_eq!(
+            top,
+            Properties {
+                end_of_line: Some(Newline::Crlf),
+                insert_final_newline: Some(true),
+                trim_trailing_whitespace: Some(true)
+            }
+        );
+        let nested = cache.properties(&root.join("sub/b.html")).unwrap();
+        assert_eq!(
+            nested,
+            Properties { end_of_line: Some(Newline::Lf), insert_final_newline: Some(true), trim_trailing_whitespace: None }
+        );
+        let other = cache.properties(&root.join("sub/c.bs")).unwrap();
+        assert_eq!(other.end_of_line, Some(Newline::Crlf));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
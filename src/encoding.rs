@@ -0,0 +1,172 @@
+// src/encoding.rs
+//
+// `--encoding`: legacy single-byte inputs are decoded to UTF-8 before
+// `transform` and the result is encoded back, so only the reflowed
+// whitespace changes. `auto` sniffs a BOM or `<meta charset>` like browsers do.
+
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::io;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Encoding {
+    /// BOM, then `<meta charset>` in the first 1024 bytes, then UTF-8 if the
+    /// input is valid UTF-8 and windows-1252 otherwise
+    #[default]
+    Auto,
+    #[value(name = "utf-8", alias = "utf8")]
+    Utf8,
+    #[value(name = "windows-1252", alias = "cp1252")]
+    Windows1252,
+    /// ISO-8859-1: every byte is the code point of the same value
+    #[value(name = "latin1", alias = "iso-8859-1")]
+    Latin1,
+}
+
+/// How far into the document `<meta charset>` is looked for.
+const PRESCAN_LIMIT: usize = 1024;
+
+/// Code points for windows-1252 bytes 0x80..=0x9F. The five bytes the code page
+/// leaves undefined map to the C1 control of the same value, as in browsers.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+impl Encoding {
+    /// The concrete encoding of `bytes`: `self`, or the sniffed one for `Auto`.
+    pub fn resolve(self, bytes: &[u8]) -> io::Result<Encoding> {
+        if self != Encoding::Auto {
+            return Ok(self);
+        }
+        if bytes.starts_with(b"\xEF\xBB\xBF") {
+            return Ok(Encoding::Utf8);
+        }
+        if bytes.starts_with(b"\xFF\xFE") || bytes.starts_with(b"\xFE\xFF") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "UTF-16 input is not supported"));
+        }
+        if let Some(declared) = meta_charset(&bytes[..bytes.len().min(PRESCAN_LIMIT)]) {
+            return Ok(declared);
+        }
+        Ok(if std::str::from_utf8(bytes).is_ok() { Encoding::Utf8 } else { Encoding::Windows1252 })
+    }
+
+    /// Decode `bytes` (in a resolved encoding) to UTF-8.
+    pub fn decode(self, bytes: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            Encoding::Auto => self.resolve(bytes)?.decode(bytes),
+            Encoding::Utf8 => match std::str::from_utf8(bytes) {
+                Ok(_) => Ok(Cow::Borrowed(bytes)),
+                Err(e) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid UTF-8 at byte {}; pass --encoding for legacy files", e.valid_up_to()),
+                )),
+            },
+            Encoding::Windows1252 | Encoding::Latin1 if bytes.is_ascii() => Ok(Cow::Borrowed(bytes)),
+            Encoding::Windows1252 => Ok(Cow::Owned(decode_single_byte(bytes, |b| match b {
+                0x80..=0x9F => WINDOWS_1252_HIGH[usize::from(b - 0x80)],
+                _ => char::from(b),
+            }))),
+            Encoding::Latin1 => Ok(Cow::Owned(decode_single_byte(bytes, char::from))),
+        }
+    }
+
+    /// Encode UTF-8 `text` back to this (resolved) encoding. Characters the
+    /// encoding can't represent are written as numeric character references.
+    pub fn encode(self, text: Vec<u8>) -> Vec<u8> {
+        let byte_for: fn(char) -> Option<u8> = match self {
+            Encoding::Auto | Encoding::Utf8 => return text,
+            Encoding::Windows1252 => |c| match u32::from(c) {
+                n @ (0..=0x7F | 0xA0..=0xFF) => Some(n as u8),
+                n => WINDOWS_1252_HIGH.iter().position(|&h| u32::from(h) == n).map(|i| 0x80 + i as u8),
+            },
+            Encoding::Latin1 => |c| u8::try_from(u32::from(c)).ok(),
+        };
+        if text.is_ascii() {
+            return text;
+        }
+        let mut out = Vec::with_capacity(text.len());
+        for c in String::from_utf8_lossy(&text).chars() {
+            match byte_for(c) {
+                Some(b) => out.push(b),
+                None => out.extend_from_slice(format!("&#{};", u32::from(c)).as_bytes()),
+            }
+        }
+        out
+    }
+}
+
+fn decode_single_byte(bytes: &[u8], char_for: impl Fn(u8) -> char) -> Vec<u8> {
+    let mut out = String::with_capacity(bytes.len() + bytes.len() / 8);
+    out.extend(bytes.iter().map(|&b| char_for(b)));
+    out.into_bytes()
+}
+
+/// The encoding declared by the first `<meta>` tag with a `charset=` in it
+/// (`<meta charset=...>` or `<meta http-equiv content="...; charset=...">`).
+fn meta_charset(head: &[u8]) -> Option<Encoding> {
+    let lower = head.to_ascii_lowercase();
+    let mut rest = &lower[..];
+    while let Some(start) = find(rest, b"<meta") {
+        let tag = &rest[start..];
+        let tag = &tag[..tag.iter().position(|&b| b == b'>').unwrap_or(tag.len())];
+        if let Some(at) = find(tag, b"charset") {
+            let value = tag[at + 7..].trim_ascii_start();
+            let value = value.strip_prefix(b"=")?.trim_ascii_start();
+            let value = value.strip_prefix(b"\"").or_else(|| value.strip_prefix(b"'")).unwrap_or(value);
+            let end = value.iter().position(|b| b"\"'; \t\n\r/".contains(b)).unwrap_or(value.len());
+            return label(&value[..end]);
+        }
+        rest = &rest[start + 5..];
+    }
+    None
+}
+
+/// Map an encoding label to an encoding. Like browsers, ASCII and Latin-1
+/// labels mean windows-1252; labels for other encodings are ignored.
+fn label(name: &[u8]) -> Option<Encoding> {
+    match name {
+        b"utf-8" | b"utf8" | b"unicode-1-1-utf-8" => Some(Encoding::Utf8),
+        b"windows-1252" | b"cp1252" | b"x-cp1252" | b"iso-8859-1" | b"iso8859-1" | b"iso_8859-1" | b"latin1"
+        | b"l1" | b"ascii" | b"us-ascii" | b"cp819" | b"ibm819" | b"csisolatin1" | b"iso-ir-100" => {
+            Some(Encoding::Windows1252)
+        }
+        _ => None,
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_bom_meta_and_fallback() {
+        let sniff = |s: &[u8]| Encoding::Auto.resolve(s).unwrap();
+        assert_eq!(sniff(b"\xEF\xBB\xBF<p>caf\xC3\xA9"), Encoding::Utf8);
+        assert_eq!(sniff(b"<meta charset=\"windows-1252\"><p>caf\xE9"), Encoding::Windows1252);
+        assert_eq!(sniff(b"<META http-equiv=Content-Type content='text/html; charset=ISO-8859-1'>"), Encoding::Windows1252);
+        assert_eq!(sniff(b"<meta charset=utf-8><p>caf\xC3\xA9"), Encoding::Utf8);
+        assert_eq!(sniff(b"<p>caf\xC3\xA9"), Encoding::Utf8);
+        assert_eq!(sniff(b"<p>caf\xE9"), Encoding::Windows1252);
+        assert!(Encoding::Auto.resolve(b"\xFF\xFE<\0").is_err());
+    }
+
+    #[test]
+    fn single_byte_round_trip_and_references() {
+        let all: Vec<u8> = (0..=255).collect();
+        for enc in [Encoding::Windows1252, Encoding::Latin1] {
+            let text = enc.decode(&all).unwrap().into_owned();
+            assert!(std::str::from_utf8(&text).is_ok());
+            assert_eq!(enc.encode(text), all);
+        }
+        assert_eq!(Encoding::Windows1252.decode(b"\x80\x93").unwrap().as_ref(), "€“".as_bytes());
+        assert_eq!(Encoding::Windows1252.encode("€ ☃".as_bytes().to_vec()), b"\x80 &#9731;");
+        assert_eq!(Encoding::Latin1.encode("é€".as_bytes().to_vec()), b"\xE9&#8364;");
+    }
+}
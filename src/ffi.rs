@@ -0,0 +1,114 @@
+// src/ffi.rs
+//
+// C ABI for embedding the reflow in non-Rust tooling, with the `ffi` feature:
+// `cargo rustc --release --lib --no-default-features --features ffi --crate-type cdylib`
+// builds the shared library. `include/reformahtml.h` declares these; regenerate it with
+// `cbindgen --config cbindgen.toml --output include/reformahtml.h`.
+
+use crate::reformat;
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+// A panic must come back to the host as a null return, never end its process.
+#[cfg(panic = "abort")]
+compile_error!("the C ABI must be built with `panic = \"unwind\"` to turn panics into null returns");
+
+/// Reformat the `len` bytes of UTF-8 at `input` with the default options, Markdown
+/// on or off, and return the output, `*out_len` bytes long and not NUL-terminated.
+/// Returns null if the input isn't UTF-8 or the reflow panics. The output must be
+/// released with `reformahtml_free`.
+///
+/// # Safety
+///
+/// `input` must point to `len` readable bytes (it may be null if `len` is 0) and
+/// `out_len` to a writable `usize`.
+#[no_mangle]
+pub unsafe extern "C" fn reformahtml_format(input: *const u8, len: usize, markdown: bool, out_len: *mut usize) -> *mut u8 {
+    let input = if len == 0 { &[][..] } else { slice::from_raw_parts(input, len) };
+    if std::str::from_utf8(input).is_err() {
+        return ptr::null_mut();
+    }
+    match panic::catch_unwind(AssertUnwindSafe(|| reformat(input, markdown))) {
+        Ok(out) => {
+            let out = out.into_boxed_slice();
+            *out_len = out.len();
+            Box::into_raw(out).cast()
+        }
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Release an output of `reformahtml_format`. Null is ignored.
+///
+/// # Safety
+///
+/// `output` must be null or a pointer returned by `reformahtml_format`, with the
+/// `out_len` it reported, not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn reformahtml_free(output: *mut u8, len: usize) {
+    if !output.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(output, len)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn fixture_round_trips_through_the_c_abi() {
+        let src = fs::read("tests/fixtures/inputs/markdown-dl.bs").unwrap();
+        let expected = fs::read("tests/fixtures/expected/markdown-dl.bs").unwrap();
+        unsafe {
+            let mut len = 0;
+            let out = reformahtml_format(src.as_ptr(), src.len(), true, &mut len);
+            assert!(!out.is_null());
+            assert_eq!(slice::from_raw_parts(out, len), expected);
+            reformahtml_free(out, len);
+
+            let out = reformahtml_format(ptr::null(), 0, false, &mut len);
+            assert_eq!((out.is_null(), len), (false, 0));
+            reformahtml_free(out, len);
+
+            assert!(reformahtml_format(b"<p>\xff</p>".as_ptr(), 9, false, &mut len).is_null());
+        }
+    }
+
+    /// `include/reformahtml.h` must be what cbindgen makes of this file. Where
+    /// cbindgen isn't installed, the header is still checked to declare these
+    /// functions, and only these, with the same parameters.
+    #[test]
+    fn header_is_up_to_date() {
+        let header = fs::read_to_string("include/reformahtml.h").unwrap();
+        match std::process::Command::new("cbindgen").args(["--config", "cbindgen.toml", "--quiet"]).output() {
+            Ok(generated) => {
+                assert!(generated.status.success(), "{}", String::from_utf8_lossy(&generated.stderr));
+                assert!(
+                    generated.stdout == header.as_bytes(),
+                    "include/reformahtml.h is out of date; run `cbindgen --config cbindgen.toml --output include/reformahtml.h`"
+                );
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => eprintln!("cbindgen isn't installed; only comparing the declarations"),
+            Err(e) => panic!("cbindgen: {e}"),
+        }
+        let name_and_params = |line: &str, param_name: fn(&str) -> &str| {
+            let (name, rest) = line.split_once('(')?;
+            let params = rest.split(')').next()?.split(", ").map(|p| param_name(p.trim())).collect::<Vec<_>>();
+            Some((name.rsplit([' ', '*']).next()?.to_string(), params.join(", ")))
+        };
+        let source = fs::read_to_string("src/ffi.rs").unwrap();
+        let defined: Vec<_> = source
+            .lines()
+            .filter_map(|l| l.strip_prefix("pub unsafe extern \"C\" fn "))
+            .filter_map(|l| name_and_params(l, |p| p.split(':').next().unwrap_or(p)))
+            .collect();
+        let declared: Vec<_> = header
+            .lines()
+            .filter(|l| !l.starts_with("//") && l.ends_with(");"))
+            .filter_map(|l| name_and_params(l, |p| p.rsplit([' ', '*']).next().unwrap_or(p)))
+            .collect();
+        assert_eq!(defined.len(), 2);
+        assert_eq!(declared, defined);
+    }
+}
@@ -0,0 +1,71 @@
+// src/git.rs
+//
+// `--changed`/`--since REF`: ask git which lines of a file differ from a
+// commit, so that only the chunks around them are reformatted.
+
+use crate::{display_path, Context};
+use std::io;
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Lines of `path` (1-based, inclusive ranges) added or modified since `rev`,
+/// from `git diff -U0`. A file git doesn't track is new as a whole: `None`.
+pub fn changed_lines(path: &Path, rev: &str) -> io::Result<Option<Vec<(usize, usize)>>> {
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    let git = |args: &[&str]| -> io::Result<Output> {
+        Command::new("git").current_dir(dir).args(args).arg("--").arg(name).output().context("run git on", path)
+    };
+    let failed = |output: &Output| {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        io::Error::other(format!("git failed on '{}': {}", display_path(path), stderr.trim()))
+    };
+
+    // `--error-unmatch` exits with 1 for an untracked file, 128 outside a repository.
+    let ls = git(&["ls-files", "--error-unmatch"])?;
+    match ls.status.code() {
+        Some(0) => {}
+        Some(1) => return Ok(None),
+        _ => return Err(failed(&ls)),
+    }
+    let diff = git(&["diff", "--no-ext-diff", "--no-color", "-U0", rev])?;
+    if !diff.status.success() {
+        return Err(failed(&diff));
+    }
+    Ok(Some(parse_hunks(&diff.stdout)))
+}
+
+/// The new-side line ranges of the `@@ -a,b +c,d @@` hunk headers in a diff.
+fn parse_hunks(diff: &[u8]) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    for line in diff.split(|&b| b == b'\n') {
+        let Some(header) = line.strip_prefix(b"@@ ") else { continue };
+        let Some(new) = header.split(|&b| b == b' ').find_map(|field| field.strip_prefix(b"+")) else {
+            continue;
+        };
+        let new = String::from_utf8_lossy(new);
+        let (start, count) = match new.split_once(',') {
+            Some((start, count)) => (start.parse().unwrap_or(0), count.parse().unwrap_or(0)),
+            None => (new.parse().unwrap_or(0), 1),
+        };
+        if count == 0 {
+            // Lines were only deleted, after line `start`: the lines on both
+            // sides of the gap may now belong to the same paragraph.
+            ranges.push((start.max(1), start + 1));
+        } else {
+            ranges.push((start, start + count - 1));
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hunk_headers_give_new_line_ranges() {
+        let diff = b"diff --git a/x.bs b/x.bs\n--- a/x.bs\n+++ b/x.bs\n@@ -3 +3 @@ intro\n-a\n+b\n@@ -10,2 +10,4 @@\n+c\n@@ -20,3 +21,0 @@\n-d\n";
+        assert_eq!(parse_hunks(diff), vec![(3, 3), (10, 13), (21, 22)]);
+    }
+}
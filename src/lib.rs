@@ -0,0 +1,4783 @@
+// src/lib.rs
+//
+// reformahtml — fast HTML/Bikeshed reflower
+//
+// - Collapses intra-paragraph line breaks while preserving indentation/blank lines
+//   around structural HTML tags (adjusted by --structural-tag/--not-structural-tag) and standalone comments.
+// - Inside tags:
+//     • Outside quotes: collapse any whitespace runs → single space, EXCEPT when a newline-run
+//       is immediately before/after '=' → insert nothing.
+//     • Inside quotes: collapse only runs that include a newline → single space.
+// - HTML comments:
+//     • Standalone (only whitespace before on its line, and next char after '-->' is '\n'):
+//         keep verbatim and treat as a structural boundary on BOTH sides.
+//     • Otherwise: reflow the comment inline (collapse newline-including runs inside it).
+// - Elements with data-noreformat (or another --skip-attr, or a --skip-class): copy their entire subtree verbatim.
+// - Standalone <!-- reformahtml off --> ... <!-- reformahtml on --> comments: copy everything between verbatim;
+//   a standalone <!-- reformahtml-ignore --> does the same for the next element or text.
+// - Inline <svg>/<math>: verbatim, or with --foreign-content reformat, reflowed with SVG-aware tag sets.
+// - RAW-TEXT tags (verbatim): pre, textarea, script, style, xmp, wpt (adjusted by --raw-tag/--not-raw-tag).
+//   Quoted attribute values on Bikeshed highlighted blocks (<pre highlight=...>,
+//   line-numbers, line-start, line-highlight) are kept byte-for-byte.
+// - RCDATA tags (no tags inside, text reflowed unless --rcdata verbatim): title, and textarea if not raw.
+// - Opt-in --format-metadata: the Bikeshed <pre class=metadata> block gets aligned values.
+// - Opt-in tag style: --self-closing-style, --no-self-closing-void, --quote-attrs double|single (with --escape-quotes),
+//   --sort-attrs[=ORDER] (skipped for tags with repeated attributes or template syntax),
+//   --wrap-attrs WIDTH (attributes of longer tags on lines of their own, aligned under the first),
+//   --keep-long-tags WIDTH (longer tags keep their line breaks).
+// - Opt-in --tag-per-line: a structural tag after other content on its line starts a new line.
+// - Bikeshed/Markdown-aware reflow in text nodes (bullets, ordered lists, dt/dd, quotes,
+//   hr, ATX/Setext headings, fenced code blocks). List items and dt/dd items reflow wrapped lines.
+// - INLINE start tags at start-of-line soft-join into previous text unless exceptions apply
+//   (the set is adjusted by --inline-tag/--not-inline-tag).
+// - <br> preserves an immediately following '\n' (so do --break-tag tags and --break-comment comments).
+// - UTF-8 safe.
+
+//! Reflows HTML and Bikeshed source so that each paragraph is on one line,
+//! keeping the indentation, blank lines and anything whose white space matters.
+//!
+//! [`reformat`] covers the common case; [`transform`] takes [`Options`] and
+//! reports [`Diagnostic`]s, and a [`Formatter`] keeps its options for many documents.
+
+pub mod checks;
+// Also compiled for the tests, which check it against include/reformahtml.h.
+#[cfg(any(feature = "ffi", test))]
+pub mod ffi;
+mod structure;
+
+use checks::Check;
+use memchr::{memchr, memrchr};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use structure::StructureLint;
+
+/// Reformat `input` with the default options, as `reformahtml FILE` does with
+/// `markdown` on for `.bs` files and off for `.html` files. Diagnostics are dropped.
+///
+/// ```
+/// let out = reformahtml::reformat(b"<p>One\ntwo</p>\n", false);
+/// assert_eq!(out, b"<p>One two</p>\n");
+/// ```
+pub fn reformat(input: &[u8], markdown: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() + input.len() / 20);
+    transform(input, &mut out, &Options::default().markdown(markdown));
+    out
+}
+
+/// Reformat `input` according to `opts` into a `String`. The transform only cuts
+/// text next to ASCII bytes and only inserts ASCII, so UTF-8 in is UTF-8 out; the
+/// output is still checked once, which panics if that ever fails to hold.
+/// Diagnostics are dropped.
+///
+/// ```
+/// let out = reformahtml::reformat_str("<p>Ünïcödé\ntext</p>\n", &reformahtml::Options::default());
+/// assert_eq!(out, "<p>Ünïcödé text</p>\n");
+/// ```
+pub fn reformat_str(input: &str, opts: &Options) -> String {
+    let mut out = Vec::with_capacity(input.len() + input.len() / 20);
+    transform(input.as_bytes(), &mut out, opts);
+    String::from_utf8(out).expect("reformatting UTF-8 gives UTF-8")
+}
+
+thread_local! {
+    /// Output buffer reused by `reformat_cow` while inputs come out unchanged.
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Reformat `input` according to `opts`, borrowing `input` back if nothing
+/// changed: `Cow::Borrowed` always means the output is identical to the input.
+/// The output is built in a per-thread buffer that is kept for the next call,
+/// so an already formatted input costs no allocation. Diagnostics are dropped.
+///
+/// ```
+/// use std::borrow::Cow;
+/// use reformahtml::{reformat_cow, Options};
+///
+/// let opts = Options::default();
+/// assert!(matches!(reformat_cow(b"<p>One two</p>\n", &opts), Cow::Borrowed(_)));
+/// assert_eq!(reformat_cow(b"<p>One\ntwo</p>\n", &opts).as_ref(), b"<p>One two</p>\n");
+/// ```
+pub fn reformat_cow<'a>(input: &'a [u8], opts: &Options) -> Cow<'a, [u8]> {
+    reformat_cow_with(input, opts, None, None).0
+}
+
+/// `reformat_cow`, counting into `stats` and only reformatting what overlaps
+/// `window` as `transform_with` does, and also returning the diagnostics.
+pub fn reformat_cow_with<'a>(
+    input: &'a [u8],
+    opts: &Options,
+    stats: Option<&mut Stats>,
+    window: Option<&mut Window>,
+) -> (Cow<'a, [u8]>, Vec<Diagnostic>) {
+    SCRATCH.with(|scratch| {
+        let mut scratch = scratch.borrow_mut();
+        scratch.clear();
+        let diagnostics = transform_with(input, &mut scratch, opts, stats, window);
+        let out = if *scratch == input { Cow::Borrowed(input) } else { Cow::Owned(std::mem::take(&mut *scratch)) };
+        (out, diagnostics)
+    })
+}
+
+/// Options set up once for formatting many documents, possibly from several
+/// threads at a time (it is `Send + Sync`, so it can be shared in an `Arc`).
+///
+/// ```
+/// use reformahtml::{Formatter, Options};
+///
+/// let formatter = Formatter::new(Options::default().markdown(true));
+/// let mut out = Vec::new();
+/// for doc in [&b"<p>One\ntwo</p>\n"[..], b"<div>\n* a\n  b\n</div>\n"] {
+///     out.clear();
+///     formatter.format_into(doc, &mut out);
+/// }
+/// assert_eq!(out, b"<div>\n* a b\n</div>\n");
+/// ```
+pub struct Formatter {
+    opts: Options,
+}
+
+impl Formatter {
+    /// Keeps `opts`, with its tag, attribute and class lists made into sorted
+    /// sets so that no document pays for scanning them.
+    pub fn new(mut opts: Options) -> Formatter {
+        opts.tag_sets = Some(TagSets::new(&opts));
+        Formatter { opts }
+    }
+
+    /// The options, for `transform` and its variants, which then look names up
+    /// in the sets built by `new`.
+    pub fn options(&self) -> &Options {
+        &self.opts
+    }
+
+    /// The formatted `input`. Diagnostics are dropped.
+    pub fn format(&self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len() + input.len() / 20);
+        self.format_into(input, &mut out);
+        out
+    }
+
+    /// Append the formatted `input` to `out`, so that one buffer can serve many
+    /// calls. Diagnostics are dropped.
+    pub fn format_into(&self, input: &[u8], out: &mut Vec<u8>) {
+        out.reserve(input.len() + input.len() / 20);
+        transform(input, out, &self.opts);
+    }
+}
+
+/* ============================ Options/diagnostics ======================= */
+
+/// Settings that affect how a document is transformed. Start from
+/// `Options::default()` and set what differs with the builder methods, e.g.
+/// `Options::default().markdown(true).max_blank_lines(Some(1))`; fields may be
+/// added in any release.
+#[non_exhaustive]
+pub struct Options {
+    /// Bikeshed/Markdown-aware reflow of text nodes
+    pub markdown: bool,
+    /// The input is a Markdown document (`--format markdown`): it is reflowed as a
+    /// whole, without HTML tokenization
+    pub markdown_document: bool,
+    /// Partially supported constructs to reformat on a best-effort basis
+    /// instead of copying them verbatim with a diagnostic.
+    pub best_effort: Vec<Construct>,
+    /// Treat elements whose inline style preserves white space like raw text
+    pub white_space_hints: bool,
+    /// Classes marking elements whose contents are kept verbatim
+    pub pre_classes: Vec<String>,
+    /// Attributes marking elements kept verbatim, tags included: `NAME`, or
+    /// `NAME=VALUE` to only skip elements where the attribute has that value
+    pub skip_attrs: Vec<String>,
+    /// Classes marking elements kept verbatim, tags included, like `data-noreformat`
+    pub skip_classes: Vec<String>,
+    /// Elements whose tags keep the indentation and newlines around them
+    pub structural_tags: Vec<String>,
+    /// Elements that soft-join into the previous line when a line starts with them
+    pub inline_tags: Vec<String>,
+    /// Elements whose contents are raw text, copied verbatim up to their end tag
+    pub raw_tags: Vec<String>,
+    /// Start tags that keep an immediately following newline, like `<br>`
+    pub break_tags: Vec<String>,
+    /// Elements without contents or an end tag, such as `<br>`
+    pub void_tags: Vec<String>,
+    /// Attributes whose quoted values keep their newlines and indentation
+    pub keep_attr_newlines: Vec<String>,
+    /// Comment texts (e.g. "keep-break") that keep an immediately following newline
+    pub break_comments: Vec<String>,
+    /// Normalize the space before `/>`; `None` keeps whatever the source has
+    pub self_closing: Option<SelfClosingStyle>,
+    /// Quote character for attribute values; a value containing it keeps its quotes
+    pub quote_attrs: QuoteStyle,
+    /// With `quote_attrs`, also quote unquoted values, and values containing the
+    /// preferred quote, escaping it as `&quot;` or `&#39;`
+    pub escape_quotes: bool,
+    /// Drop the `/` of self-closing void elements outside SVG and MathML, `<br/>` becoming `<br>`
+    pub strip_void_slash: bool,
+    /// Put the attributes of a tag that would end past this column on lines of their
+    /// own, aligned under the first; `None` keeps every tag on one line
+    pub wrap_attrs: Option<usize>,
+    /// Copy a tag as it is, trailing spaces aside, when on one line it would end past
+    /// this column (and `wrap_attrs` can't wrap it)
+    pub keep_long_tags: Option<usize>,
+    /// Start a new line before a structural start or end tag that follows other
+    /// content on its line, indented like that line
+    pub tag_per_line: bool,
+    /// Reorder attributes: these names first, in this order, then the rest
+    /// alphabetically (see [`ATTR_ORDER`]); `None` keeps the source order
+    pub sort_attrs: Option<Vec<String>>,
+    /// Join the lines of a DOCTYPE like those of a tag, unless it has an internal
+    /// subset; by default it is copied verbatim
+    pub collapse_doctype: bool,
+    /// What happens to inline `<svg>` and `<math>`
+    pub foreign_content: ForeignContent,
+    /// Whether the text of `<title>` (and of `<textarea>` when it isn't raw text)
+    /// is reflowed or copied verbatim; its tags are never parsed either way
+    pub rcdata: Rcdata,
+    /// Tidy the Bikeshed `<pre class=metadata>` block: align the values of its
+    /// `Key: value` lines, trim trailing white space and collapse blank lines
+    pub format_metadata: bool,
+    /// Line endings of the output; `None` keeps those of the first line
+    pub newline: Option<Newline>,
+    /// Add a newline at the end of the output if it lacks one
+    pub ensure_final_newline: bool,
+    /// Remove blank lines at the end of the output
+    pub trim_final_newlines: bool,
+    /// Most consecutive blank lines kept around text chunks; `None` keeps them all
+    pub max_blank_lines: Option<usize>,
+    /// Remove spaces and tabs before the newlines of reflowed text
+    pub strip_trailing_whitespace: bool,
+    /// Convert indentation to this style; `None` keeps it as is
+    pub indent: Option<Indent>,
+    /// Report structural problems (see `structure::StructureLint`) as diagnostics
+    pub lint_structure: bool,
+    /// Called with every start tag; if it returns true, the element is kept
+    /// verbatim as if it had `data-noreformat`
+    pub skip_element: Option<SkipElement>,
+    /// The lists above as sets, built by `Formatter::new`; the options of a
+    /// `Formatter` can't be changed afterwards
+    tag_sets: Option<TagSets>,
+}
+
+/// A callback deciding per element whether to keep it verbatim (see
+/// `Options::skip_element`). It gets the parsed tag and its bytes, `<` to `>`.
+pub type SkipElement = Box<dyn Fn(&TagInfo, &[u8]) -> bool + Send + Sync>;
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            markdown: false,
+            markdown_document: false,
+            best_effort: Vec::new(),
+            white_space_hints: false,
+            pre_classes: Vec::new(),
+            skip_attrs: vec!["data-noreformat".to_string()],
+            skip_classes: Vec::new(),
+            structural_tags: STRUCTURAL_TAGS.iter().map(|t| t.to_string()).collect(),
+            inline_tags: INLINE_TAGS.iter().map(|t| t.to_string()).collect(),
+            raw_tags: RAW_TEXT_TAGS.iter().map(|t| t.to_string()).collect(),
+            break_tags: vec!["br".to_string()],
+            void_tags: VOID_TAGS.iter().map(|t| t.to_string()).collect(),
+            keep_attr_newlines: KEEP_NEWLINE_ATTRS.iter().map(|a| a.to_string()).collect(),
+            break_comments: Vec::new(),
+            self_closing: None,
+            quote_attrs: QuoteStyle::Keep,
+            escape_quotes: false,
+            strip_void_slash: false,
+            sort_attrs: None,
+            wrap_attrs: None,
+            keep_long_tags: None,
+            tag_per_line: false,
+            collapse_doctype: false,
+            foreign_content: ForeignContent::Verbatim,
+            rcdata: Rcdata::Reflow,
+            format_metadata: false,
+            newline: None,
+            ensure_final_newline: false,
+            trim_final_newlines: false,
+            max_blank_lines: None,
+            strip_trailing_whitespace: false,
+            indent: None,
+            lint_structure: false,
+            skip_element: None,
+            tag_sets: None,
+        }
+    }
+}
+
+/// Builder methods, one per field.
+impl Options {
+    pub fn markdown(mut self, markdown: bool) -> Options {
+        self.markdown = markdown;
+        self
+    }
+
+    pub fn markdown_document(mut self, markdown_document: bool) -> Options {
+        self.markdown_document = markdown_document;
+        self
+    }
+
+    pub fn best_effort(mut self, best_effort: Vec<Construct>) -> Options {
+        self.best_effort = best_effort;
+        self
+    }
+
+    pub fn white_space_hints(mut self, white_space_hints: bool) -> Options {
+        self.white_space_hints = white_space_hints;
+        self
+    }
+
+    pub fn pre_classes(mut self, pre_classes: Vec<String>) -> Options {
+        self.pre_classes = pre_classes;
+        self
+    }
+
+    /// Replaces the default list, `data-noreformat`.
+    pub fn skip_attrs(mut self, skip_attrs: Vec<String>) -> Options {
+        self.skip_attrs = skip_attrs;
+        self
+    }
+
+    pub fn skip_classes(mut self, skip_classes: Vec<String>) -> Options {
+        self.skip_classes = skip_classes;
+        self
+    }
+
+    /// Replaces the default list, [`STRUCTURAL_TAGS`].
+    pub fn structural_tags(mut self, structural_tags: Vec<String>) -> Options {
+        self.structural_tags = structural_tags;
+        self
+    }
+
+    /// Replaces the default list, [`INLINE_TAGS`].
+    pub fn inline_tags(mut self, inline_tags: Vec<String>) -> Options {
+        self.inline_tags = inline_tags;
+        self
+    }
+
+    /// Replaces the default list, [`VOID_TAGS`].
+    pub fn void_tags(mut self, void_tags: Vec<String>) -> Options {
+        self.void_tags = void_tags;
+        self
+    }
+
+    /// Replaces the default list, [`KEEP_NEWLINE_ATTRS`].
+    pub fn keep_attr_newlines(mut self, keep_attr_newlines: Vec<String>) -> Options {
+        self.keep_attr_newlines = keep_attr_newlines;
+        self
+    }
+
+    /// Replaces the default list, [`RAW_TEXT_TAGS`].
+    pub fn raw_tags(mut self, raw_tags: Vec<String>) -> Options {
+        self.raw_tags = raw_tags;
+        self
+    }
+
+    /// Replaces the default list, `br`.
+    pub fn break_tags(mut self, break_tags: Vec<String>) -> Options {
+        self.break_tags = break_tags;
+        self
+    }
+
+    pub fn break_comments(mut self, break_comments: Vec<String>) -> Options {
+        self.break_comments = break_comments;
+        self
+    }
+
+    pub fn collapse_doctype(mut self, collapse_doctype: bool) -> Options {
+        self.collapse_doctype = collapse_doctype;
+        self
+    }
+
+    pub fn foreign_content(mut self, foreign_content: ForeignContent) -> Options {
+        self.foreign_content = foreign_content;
+        self
+    }
+
+    pub fn rcdata(mut self, rcdata: Rcdata) -> Options {
+        self.rcdata = rcdata;
+        self
+    }
+
+    pub fn format_metadata(mut self, format_metadata: bool) -> Options {
+        self.format_metadata = format_metadata;
+        self
+    }
+
+    pub fn self_closing(mut self, self_closing: Option<SelfClosingStyle>) -> Options {
+        self.self_closing = self_closing;
+        self
+    }
+
+    pub fn quote_attrs(mut self, quote_attrs: QuoteStyle) -> Options {
+        self.quote_attrs = quote_attrs;
+        self
+    }
+
+    pub fn escape_quotes(mut self, escape_quotes: bool) -> Options {
+        self.escape_quotes = escape_quotes;
+        self
+    }
+
+    pub fn strip_void_slash(mut self, strip_void_slash: bool) -> Options {
+        self.strip_void_slash = strip_void_slash;
+        self
+    }
+
+    pub fn sort_attrs(mut self, sort_attrs: Option<Vec<String>>) -> Options {
+        self.sort_attrs = sort_attrs;
+        self
+    }
+
+    pub fn wrap_attrs(mut self, wrap_attrs: Option<usize>) -> Options {
+        self.wrap_attrs = wrap_attrs;
+        self
+    }
+
+    pub fn keep_long_tags(mut self, keep_long_tags: Option<usize>) -> Options {
+        self.keep_long_tags = keep_long_tags;
+        self
+    }
+
+    pub fn tag_per_line(mut self, tag_per_line: bool) -> Options {
+        self.tag_per_line = tag_per_line;
+        self
+    }
+
+    pub fn newline(mut self, newline: Option<Newline>) -> Options {
+        self.newline = newline;
+        self
+    }
+
+    pub fn ensure_final_newline(mut self, ensure_final_newline: bool) -> Options {
+        self.ensure_final_newline = ensure_final_newline;
+        self
+    }
+
+    pub fn trim_final_newlines(mut self, trim_final_newlines: bool) -> Options {
+        self.trim_final_newlines = trim_final_newlines;
+        self
+    }
+
+    pub fn max_blank_lines(mut self, max_blank_lines: Option<usize>) -> Options {
+        self.max_blank_lines = max_blank_lines;
+        self
+    }
+
+    pub fn strip_trailing_whitespace(mut self, strip_trailing_whitespace: bool) -> Options {
+        self.strip_trailing_whitespace = strip_trailing_whitespace;
+        self
+    }
+
+    pub fn indent(mut self, indent: Option<Indent>) -> Options {
+        self.indent = indent;
+        self
+    }
+
+    pub fn lint_structure(mut self, lint_structure: bool) -> Options {
+        self.lint_structure = lint_structure;
+        self
+    }
+
+    pub fn skip_element(mut self, skip_element: Option<SkipElement>) -> Options {
+        self.skip_element = skip_element;
+        self
+    }
+}
+
+/// The tag, attribute and class lists of [`Options`], lowercased and sorted, so
+/// that a lookup is a binary search instead of a scan. [`Formatter::new`] builds
+/// them.
+#[derive(Debug)]
+struct TagSets {
+    structural: Vec<Name>,
+    inline: Vec<Name>,
+    raw: Vec<Name>,
+    void: Vec<Name>,
+    break_tags: Vec<Name>,
+    keep_attr_newlines: Vec<Name>,
+    /// `skip_attrs`, split into the attribute name and the value it must have, if any
+    skip_attrs: Vec<(Name, Option<Name>)>,
+    skip_classes: Vec<Name>,
+}
+
+impl TagSets {
+    fn new(opts: &Options) -> TagSets {
+        let set = |list: &[String]| {
+            let mut set: Vec<Name> = list.iter().map(|t| t.to_ascii_lowercase().into_bytes().into()).collect();
+            set.sort_unstable();
+            set.dedup();
+            set
+        };
+        let skip_attrs = opts
+            .skip_attrs
+            .iter()
+            .map(|skip| {
+                let (name, value) = skip.split_once('=').map_or((skip.as_str(), None), |(n, v)| (n, Some(v)));
+                (name.to_ascii_lowercase().into_bytes().into(), value.map(|v| v.as_bytes().into()))
+            })
+            .collect();
+        // Classes match case-sensitively, so they are only sorted.
+        let mut skip_classes: Vec<Name> = opts.skip_classes.iter().map(|c| c.as_bytes().into()).collect();
+        skip_classes.sort_unstable();
+        TagSets {
+            structural: set(&opts.structural_tags),
+            inline: set(&opts.inline_tags),
+            raw: set(&opts.raw_tags),
+            void: set(&opts.void_tags),
+            break_tags: set(&opts.break_tags),
+            keep_attr_newlines: set(&opts.keep_attr_newlines),
+            skip_attrs,
+            skip_classes,
+        }
+    }
+}
+
+/// A name in a [`TagSets`] list.
+type Name = Box<[u8]>;
+
+/// True if the lowercased, sorted `set` has `name` in any case.
+fn set_contains(set: &[Name], name: &[u8]) -> bool {
+    set.binary_search_by(|t| t.iter().copied().cmp(name.iter().map(u8::to_ascii_lowercase))).is_ok()
+}
+
+impl Options {
+    /// True if `name` is in the list `list` picks, looked up in its set if built.
+    fn listed(&self, name: &[u8], list: impl Fn(&Options) -> &[String], set: impl Fn(&TagSets) -> &[Name]) -> bool {
+        match &self.tag_sets {
+            Some(sets) => set_contains(set(sets), name),
+            None => list(self).iter().any(|t| name.eq_ignore_ascii_case(t.as_bytes())),
+        }
+    }
+
+    fn is_structural(&self, name: &[u8]) -> bool {
+        self.listed(name, |o| &o.structural_tags, |s| &s.structural)
+            || (self.foreign_content == ForeignContent::Reformat && matches_ignore_ascii_case(name, SVG_STRUCTURAL_TAGS))
+    }
+
+    fn is_inline(&self, name: &[u8]) -> bool {
+        self.listed(name, |o| &o.inline_tags, |s| &s.inline)
+    }
+
+    fn is_raw_text(&self, name: &[u8]) -> bool {
+        self.listed(name, |o| &o.raw_tags, |s| &s.raw)
+    }
+
+    fn keeps_attr_newlines(&self, name: &[u8]) -> bool {
+        self.listed(name, |o| &o.keep_attr_newlines, |s| &s.keep_attr_newlines)
+    }
+
+    fn is_void(&self, name: &[u8]) -> bool {
+        self.listed(name, |o| &o.void_tags, |s| &s.void)
+    }
+
+    fn is_break_tag(&self, name: &[u8]) -> bool {
+        self.listed(name, |o| &o.break_tags, |s| &s.break_tags)
+    }
+
+    /// True if `comment` (including `<!--` and `-->`) is a configured break comment.
+    fn is_break_comment(&self, comment: &[u8]) -> bool {
+        if self.break_comments.is_empty() {
+            return false;
+        }
+        let Some(inner) = comment_text(comment) else { return false };
+        let inner = String::from_utf8_lossy(inner);
+        let inner = inner.trim();
+        self.break_comments.iter().any(|c| c == inner)
+    }
+
+    /// True if the start tag carries one of `skip_attrs` (with the given value, if
+    /// any) or `skip_classes`.
+    fn skips(&self, tag: &[u8]) -> bool {
+        if let Some(sets) = &self.tag_sets {
+            let skip_class = |c: &[u8]| sets.skip_classes.binary_search_by(|s| (**s).cmp(c)).is_ok();
+            if !sets.skip_classes.is_empty() && class_tokens(tag).any(skip_class) {
+                return true;
+            }
+            return sets.skip_attrs.iter().any(|(name, value)| {
+                attrs(tag).any(|a| {
+                    a.name.eq_ignore_ascii_case(name) && value.as_deref().is_none_or(|v| a.value.unwrap_or(b"") == v)
+                })
+            });
+        }
+        let skip_class = |c: &[u8]| self.skip_classes.iter().any(|s| s.as_bytes() == c);
+        if !self.skip_classes.is_empty() && class_tokens(tag).any(skip_class) {
+            return true;
+        }
+        self.skip_attrs.iter().any(|skip| {
+            let (name, value) = skip.split_once('=').map_or((skip.as_str(), None), |(n, v)| (n, Some(v)));
+            attrs(tag).any(|a| {
+                a.name.eq_ignore_ascii_case(name.as_bytes())
+                    && value.is_none_or(|v| a.value.unwrap_or(b"") == v.as_bytes())
+            })
+        })
+    }
+
+    /// True if the start tag asks for its contents' white space to be preserved,
+    /// via an inline `white-space` style (when enabled) or one of `pre_classes`.
+    fn preserves_white_space(&self, tag: &[u8]) -> bool {
+        if self.white_space_hints {
+            if let Some(style) = tag_attr_value(tag, b"style") {
+                if style_preserves_white_space(style) {
+                    return true;
+                }
+            }
+        }
+        !self.pre_classes.is_empty() && class_tokens(tag).any(|c| self.pre_classes.iter().any(|p| p.as_bytes() == c))
+    }
+}
+
+/// True if a `style` attribute value sets `white-space` to a value that keeps line breaks.
+fn style_preserves_white_space(style: &[u8]) -> bool {
+    let style = String::from_utf8_lossy(style);
+    style.split(';').any(|decl| {
+        let Some((prop, value)) = decl.split_once(':') else { return false };
+        let value = value.trim().to_ascii_lowercase();
+        let value = value.trim_end_matches("!important").trim();
+        prop.trim().eq_ignore_ascii_case("white-space")
+            && matches!(value, "pre" | "pre-wrap" | "pre-line" | "break-spaces")
+    })
+}
+
+/// Constructs the engine recognizes but does not fully support.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Construct {
+    /// `<![CDATA[ ... ]]>`; no longer has an effect, as CDATA sections are always
+    /// copied verbatim without a diagnostic
+    #[cfg_attr(feature = "cli", value(hide = true))]
+    Cdata,
+    /// `<?php ... ?>` and `<?= ... ?>`
+    Php,
+    /// `<!--[if ...]> ... <![endif]-->`, `<![if ...]>`, `<![endif]>`; no longer has
+    /// an effect, as conditional comments are always copied verbatim without a diagnostic
+    #[cfg_attr(feature = "cli", value(hide = true))]
+    ConditionalComment,
+}
+
+impl Construct {
+    fn describe(self) -> &'static str {
+        match self {
+            Construct::Cdata => "CDATA section",
+            Construct::Php => "PHP block",
+            Construct::ConditionalComment => "conditional comment",
+        }
+    }
+}
+
+/// What happens to inline `<svg>` and `<math>` subtrees, whose white space and
+/// self-closing tags follow XML rather than HTML rules.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ForeignContent {
+    /// Copy them verbatim, tags included
+    #[default]
+    Verbatim,
+    /// Reformat them with SVG-aware tag sets, keeping the contents of SVG `<text>`,
+    /// `<desc>` and `<title>` verbatim
+    Reformat,
+}
+
+/// What happens to the text of RCDATA elements (`<title>`), where `<` never
+/// starts a tag.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Rcdata {
+    /// Join its lines like plain text
+    #[default]
+    Reflow,
+    /// Copy it verbatim
+    Verbatim,
+}
+
+/// How the `/` of self-closing tag syntax is written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SelfClosingStyle {
+    /// `<foo />`
+    Spaced,
+    /// `<foo/>`
+    Compact,
+}
+
+/// Which quotes attribute values are written with.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum QuoteStyle {
+    /// Whatever the source has
+    #[default]
+    Keep,
+    /// `a="b"`
+    Double,
+    /// `a='b'`
+    Single,
+}
+
+/// Indentation written by `--indent`, with the width of a tab in columns.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Indent {
+    Tabs(usize),
+    Spaces(usize),
+}
+
+/// Parse `tabs`, `spaces` or either with a `:WIDTH` (4 if not given).
+pub fn parse_indent(arg: &str) -> Result<Indent, String> {
+    let (style, width) = match arg.split_once(':') {
+        Some((style, width)) => {
+            let width = width.parse().ok().filter(|&w| w > 0).ok_or_else(|| format!("invalid width `{width}`"))?;
+            (style, width)
+        }
+        None => (arg, 4),
+    };
+    match style {
+        "tabs" => Ok(Indent::Tabs(width)),
+        "spaces" => Ok(Indent::Spaces(width)),
+        _ => Err(format!("expected tabs or spaces, found `{style}`")),
+    }
+}
+
+/// How the input is read, chosen with `--format`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum InputFormat {
+    /// HTML, or Bikeshed source with Markdown reflow in its text
+    Html,
+    /// A Markdown document; raw HTML blocks in it are kept as they are
+    Markdown,
+}
+
+/// Line endings written by `--newline`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Newline {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf,
+    /// CRLF on Windows, LF elsewhere
+    Native,
+}
+
+impl Newline {
+    fn is_crlf(self) -> bool {
+        match self {
+            Newline::Lf => false,
+            Newline::Crlf => true,
+            Newline::Native => cfg!(windows),
+        }
+    }
+}
+
+/// Something the transform wants to tell the user about, anchored at a byte offset in the input.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub offset: usize,
+    pub check: Check,
+    pub message: String,
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// 1-based line and column (in bytes) of `offset` in `src`. A byte order mark
+/// doesn't count towards the column.
+pub fn line_col(src: &[u8], offset: usize) -> (usize, usize) {
+    let offset = offset.min(src.len());
+    let line = memchr::memchr_iter(b'\n', &src[..offset]).count() + 1;
+    let first_line_start = if src.starts_with(UTF8_BOM) { UTF8_BOM.len().min(offset) } else { 0 };
+    let line_start = memrchr(b'\n', &src[..offset]).map(|x| x + 1).unwrap_or(first_line_start);
+    (line, offset - line_start + 1)
+}
+
+/* =============================== Core sets =============================== */
+
+/// Elements a line may start with and still soft-join into the previous line,
+/// unless [`Options::inline_tags`] says otherwise.
+pub const INLINE_TAGS: &[&str] = &[
+    "a", "abbr", "b", "bdi", "bdo", "cite", "code", "data", "del", "dfn", "em", "i", "ins", "kbd", "mark", "q",
+    "s", "samp", "small", "span", "strong", "sub", "sup", "time", "u", "var", "ref",
+];
+
+/// Elements without contents or an end tag, unless [`Options::void_tags`] says otherwise.
+pub const VOID_TAGS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// Attributes whose quoted values keep their newlines unless
+/// [`Options::keep_attr_newlines`] says otherwise: wrapped image candidates and SVG path
+/// and polygon data.
+pub const KEEP_NEWLINE_ATTRS: &[&str] = &["srcset", "d", "points"];
+
+/// Attributes that `--sort-attrs` puts first when it isn't given an order.
+pub const ATTR_ORDER: &[&str] = &["id", "class"];
+
+/// Elements treated as raw text unless [`Options::raw_tags`] says otherwise.
+pub const RAW_TEXT_TAGS: &[&str] = &["pre", "textarea", "script", "style", "xmp", "wpt"];
+
+fn is_raw_text(name: &[u8]) -> bool {
+    RAW_TEXT_TAGS.iter().any(|t| name.eq_ignore_ascii_case(t.as_bytes()))
+}
+
+/// Elements whose tags keep the indentation and newlines around them,
+/// unless [`Options::structural_tags`] says otherwise.
+pub const STRUCTURAL_TAGS: &[&str] = &[
+    "address", "article", "aside", "blockquote", "details", "dialog", "div", "dl", "dt", "dd", "fieldset",
+    "figcaption", "figure", "footer", "form", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hgroup", "hr", "main",
+    "menu", "nav", "ol", "p", "pre", "search", "section", "table", "thead", "tbody", "tfoot", "tr", "td", "th",
+    "caption", "colgroup", "ul", "li", "optgroup", "option", "ruby", "rt", "rp", "foreignobject",
+];
+
+/// Start tags that close an open `<p>`, as in the HTML parser: a `<p>` in button
+/// scope (see `BUTTON_SCOPE`) ends there, with everything still open inside it.
+const P_CLOSING_TAGS: &[&[u8]] = &[
+    b"address", b"article", b"aside", b"blockquote", b"center", b"details", b"dialog", b"dir", b"div", b"dl",
+    b"fieldset", b"figcaption", b"figure", b"footer", b"form", b"h1", b"h2", b"h3", b"h4", b"h5", b"h6", b"header",
+    b"hgroup", b"hr", b"li", b"dd", b"dt", b"listing", b"main", b"menu", b"nav", b"ol", b"p", b"plaintext", b"pre",
+    b"search", b"section", b"summary", b"table", b"ul", b"xmp",
+];
+
+/// Elements a `<p>` isn't closed across by `P_CLOSING_TAGS`, such as a `<table>` in
+/// a paragraph's `<button>`.
+const BUTTON_SCOPE: &[&[u8]] = &[
+    b"applet", b"button", b"caption", b"html", b"marquee", b"object", b"table", b"td", b"th", b"template",
+    b"foreignobject",
+];
+
+/// SVG elements whose tags keep the indentation and newlines around them with
+/// `ForeignContent::Reformat` (`a`, `script`, `style` and `title` are left to the HTML sets).
+const SVG_STRUCTURAL_TAGS: &[&[u8]] = &[
+    b"svg", b"g", b"defs", b"symbol", b"use", b"switch", b"path", b"rect", b"circle", b"ellipse", b"line",
+    b"polyline", b"polygon", b"image", b"marker", b"pattern", b"clippath", b"mask", b"lineargradient",
+    b"radialgradient", b"stop", b"filter", b"text", b"desc", b"metadata", b"view", b"feblend", b"fecolormatrix",
+    b"fecomposite", b"feflood", b"fegaussianblur", b"femerge", b"femergenode", b"feoffset",
+];
+
+/// Elements whose text runs to their end tag without parsing tags, but isn't raw
+/// text; `textarea` only counts when [`Options::raw_tags`] leaves it out.
+const RCDATA_TAGS: &[&[u8]] = &[b"title", b"textarea"];
+
+/// SVG elements whose contents keep their white space with `ForeignContent::Reformat`.
+const SVG_TEXT_TAGS: &[&[u8]] = &[b"text", b"desc", b"title"];
+
+/// Whether `name` (lowercase) is an RCDATA element: `<title>`, or in SVG with
+/// `ForeignContent::Reformat`, `<title>` and `<desc>`, whose text is kept verbatim.
+fn is_rcdata(name: &[u8], in_svg: bool, opts: &Options) -> bool {
+    if in_svg {
+        opts.foreign_content == ForeignContent::Reformat && matches!(name, b"title" | b"desc")
+    } else {
+        RCDATA_TAGS.contains(&name)
+    }
+}
+
+fn is_structural(name: &[u8]) -> bool {
+    STRUCTURAL_TAGS.iter().any(|t| name.eq_ignore_ascii_case(t.as_bytes()))
+}
+
+/* ============================ Utility predicates ========================= */
+
+#[inline]
+fn is_name_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b':'
+}
+
+#[inline]
+fn is_space_tab(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+#[inline]
+fn is_ws(b: u8) -> bool {
+    b == b' ' || b == b'\t' || b == b'\n' || b == b'\r'
+}
+
+fn matches_ignore_ascii_case(name: &[u8], set: &[&[u8]]) -> bool {
+    set.iter().any(|&s| name.eq_ignore_ascii_case(s))
+}
+
+fn trim_spaces(buf: &mut Vec<u8>) {
+    let mut start = 0usize;
+    while start < buf.len() && buf[start] == b' ' {
+        start += 1;
+    }
+    let mut end = buf.len();
+    while end > start && buf[end - 1] == b' ' {
+        end -= 1;
+    }
+    if start == 0 && end == buf.len() {
+        return;
+    }
+    let mut tmp = Vec::with_capacity(end - start);
+    tmp.extend_from_slice(&buf[start..end]);
+    *buf = tmp;
+}
+
+/* =============================== Tag parsing ============================= */
+
+/// What the reflow knows about a tag, as passed to `Options::skip_element`.
+#[derive(Clone, Copy, Debug)]
+pub struct TagInfo<'a> {
+    /// The tag name as written
+    pub name: &'a [u8],
+    /// `</name>`
+    pub is_end: bool,
+    /// Ends with `/>`
+    pub self_closing: bool,
+}
+
+/// Where `find_tag_end` is in a tag, after the HTML tokenizer's states.
+#[derive(Clone, Copy, PartialEq)]
+enum TagState {
+    TagName,
+    /// An attribute name
+    Name,
+    /// White space or `/` before an attribute name
+    BeforeName,
+    /// White space after an attribute name, where `=` may still come
+    AfterName,
+    /// After `=`, where a quote starts a quoted value
+    BeforeValue,
+    Quoted(u8),
+    Unquoted,
+}
+
+/// Find the '>' for a tag starting at `i` (s[i] == '<'), being quote-aware. Like
+/// in HTML, a quote only starts a quoted value right after `=`; anywhere else, as
+/// in `<span title=it's-fine>`, it is part of a name or value.
+fn find_tag_end(s: &[u8], i: usize) -> Option<usize> {
+    let mut state = TagState::TagName;
+    for (j, &b) in s.iter().enumerate().skip(i + 1) {
+        state = match (state, b) {
+            (TagState::Quoted(q), _) if b == q => TagState::BeforeName,
+            (TagState::Quoted(q), _) => TagState::Quoted(q),
+            (_, b'>') => return Some(j),
+            (TagState::BeforeValue, b'"' | b'\'') => TagState::Quoted(b),
+            (TagState::BeforeValue | TagState::AfterName | TagState::BeforeName, _) if is_ws(b) => state,
+            (TagState::BeforeValue, _) => TagState::Unquoted,
+            (TagState::Unquoted, _) if is_ws(b) => TagState::BeforeName,
+            (TagState::Unquoted, _) => TagState::Unquoted,
+            (TagState::TagName, _) if is_ws(b) || b == b'/' => TagState::BeforeName,
+            (TagState::TagName, _) => TagState::TagName,
+            (TagState::Name | TagState::AfterName, b'=') => TagState::BeforeValue,
+            (TagState::Name, _) if is_ws(b) => TagState::AfterName,
+            (_, b'/') => TagState::BeforeName,
+            _ => TagState::Name,
+        };
+    }
+    None
+}
+
+/// Extract tag name, end/self-closing flags from raw `<...>` bytes.
+fn parse_tag_info<'a>(tag: &'a [u8]) -> TagInfo<'a> {
+    let n = tag.len();
+    let mut i = 1;
+
+    let mut is_end = false;
+    if i < n && tag[i] == b'/' {
+        is_end = true;
+        i += 1;
+    }
+    while i < n && is_ws(tag[i]) {
+        i += 1;
+    }
+    let start = i;
+    while i < n && is_name_char(tag[i]) {
+        i += 1;
+    }
+    let name = &tag[start..i];
+
+    // self-closing? check before '>', unless the '/' ends an unquoted attribute
+    // value, as in `<a href=https://example.com/>`. An end tag never is.
+    let mut j = n - 1;
+    while j > 0 && is_ws(tag[j - 1]) {
+        j -= 1;
+    }
+    let self_closing = !is_end && j >= 2 && tag[j - 1] == b'/' && {
+        let body = &tag[..j];
+        let last_value = attrs(body).last().and_then(|a| a.value);
+        last_value.is_none_or(|v| v.as_ptr_range().end != body.as_ptr_range().end)
+    };
+
+    TagInfo {
+        name,
+        is_end,
+        self_closing,
+    }
+}
+
+/* ============================ Attribute scan ============================= */
+
+/// Bikeshed attributes on `<pre>`/`<xmp>` whose quoted values are whitespace-sensitive.
+const HIGHLIGHT_ATTRS: &[&[u8]] = &[b"highlight", b"line-numbers", b"line-start", b"line-highlight"];
+
+/// A raw-text start tag carrying Bikeshed highlighting attributes, e.g. `<pre highlight=js>`.
+fn is_highlighted_block(ti: &TagInfo, tag: &[u8]) -> bool {
+    !ti.is_end && is_raw_text(ti.name) && HIGHLIGHT_ATTRS.iter().any(|a| tag_has_attr(tag, a))
+}
+
+fn tag_has_attr(tag: &[u8], wanted: &[u8]) -> bool {
+    attrs(tag).any(|a| a.name.eq_ignore_ascii_case(wanted))
+}
+
+/// Value of the first attribute named `wanted` (ASCII case-insensitive), without quotes.
+fn tag_attr_value<'a>(tag: &'a [u8], wanted: &[u8]) -> Option<&'a [u8]> {
+    attrs(tag).find(|a| a.name.eq_ignore_ascii_case(wanted)).map(|a| a.value.unwrap_or(b""))
+}
+
+/// The tokens of a tag's `class` attribute, split on ASCII white space. Class names
+/// are compared case-sensitively.
+fn class_tokens(tag: &[u8]) -> impl Iterator<Item = &[u8]> {
+    tag_attr_value(tag, b"class")
+        .unwrap_or_default()
+        .split(|&b| is_ws(b) || b == b'\x0c')
+        .filter(|c| !c.is_empty())
+}
+
+/// One attribute of a tag. `value` excludes the quotes; `None` if there is no '='.
+#[derive(Clone, Copy, Debug)]
+struct Attr<'a> {
+    name: &'a [u8],
+    value: Option<&'a [u8]>,
+}
+
+/// Iterator over the attributes of raw `<...>` tag bytes (the tag name is skipped).
+struct Attrs<'a> {
+    tag: &'a [u8],
+    i: usize,
+}
+
+fn attrs(tag: &[u8]) -> Attrs<'_> {
+    let mut i = 1usize;
+    if i < tag.len() && tag[i] == b'/' {
+        i += 1;
+    }
+    while i < tag.len() && is_name_char(tag[i]) {
+        i += 1;
+    }
+    Attrs { tag, i }
+}
+
+impl<'a> Iterator for Attrs<'a> {
+    type Item = Attr<'a>;
+
+    fn next(&mut self) -> Option<Attr<'a>> {
+        // Robust attribute scanner: [name] ( '=' [value] )?
+        let tag = self.tag;
+        let len = tag.len();
+        let mut i = self.i;
+
+        while i < len && tag[i] != b'>' {
+            // skip whitespace and slashes
+            while i < len && (is_ws(tag[i]) || tag[i] == b'/') {
+                i += 1;
+            }
+            if i >= len || tag[i] == b'>' {
+                break;
+            }
+
+            // attribute name
+            if !is_name_char(tag[i]) {
+                // Not a valid name start; advance to avoid infinite loops.
+                i += 1;
+                continue;
+            }
+            let name_start = i;
+            i += 1;
+            while i < len && is_name_char(tag[i]) {
+                i += 1;
+            }
+            let name = &tag[name_start..i];
+
+            // skip whitespace
+            let mut j = i;
+            while j < len && is_ws(tag[j]) {
+                j += 1;
+            }
+
+            // optional "= value"
+            let mut value = None;
+            if j < len && tag[j] == b'=' {
+                i = j + 1;
+                // skip whitespace
+                while i < len && is_ws(tag[i]) {
+                    i += 1;
+                }
+                if i < len && (tag[i] == b'"' || tag[i] == b'\'') {
+                    // quoted value
+                    let q = tag[i];
+                    i += 1;
+                    let value_start = i;
+                    while i < len && tag[i] != q {
+                        i += 1;
+                    }
+                    value = Some(&tag[value_start..i]);
+                    if i < len && tag[i] == q {
+                        i += 1;
+                    }
+                } else {
+                    // unquoted value
+                    let value_start = i;
+                    while i < len && !is_ws(tag[i]) && tag[i] != b'>' {
+                        i += 1;
+                    }
+                    value = Some(&tag[value_start..i]);
+                }
+            }
+            self.i = i;
+            return Some(Attr { name, value });
+        }
+        self.i = len;
+        None
+    }
+}
+
+/* ======================== Inside-tag normalization ====================== */
+
+/// Collapse whitespace inside a tag. With `keep_quoted_values`, quoted attribute
+/// values are copied byte-for-byte instead of having newline runs collapsed. `opts`
+/// applies the tag style options; `foreign` says the tag is in SVG or MathML, where
+/// a self-closing `/` means something even on a void element name.
+fn normalize_inside_tag(
+    tag: &[u8],
+    out: &mut Vec<u8>,
+    keep_quoted_values: bool,
+    opts: Option<&Options>,
+    foreign: bool,
+) {
+    if tag.len() < 2 {
+        out.extend_from_slice(tag);
+        return;
+    }
+    let inner = &tag[1..tag.len() - 1];
+
+    let mut buf: Vec<u8> = Vec::with_capacity(inner.len());
+    let mut i = 0usize;
+    let n = inner.len();
+    let mut quote: u8 = 0;
+    // Whether the quoted value being copied keeps its newlines
+    let mut keep_value = false;
+
+    let push_space_once = |buf: &mut Vec<u8>| {
+        if !buf.last().map(|b| *b == b' ').unwrap_or(false) {
+            buf.push(b' ');
+        }
+    };
+
+    while i < n {
+        let b = inner[i];
+        if quote != 0 {
+            if b == quote {
+                buf.push(b);
+                quote = 0;
+                i += 1;
+            } else if keep_value {
+                buf.push(b);
+                i += 1;
+            } else if b == b'\n' || b == b'\r' || b == b' ' || b == b'\t' {
+                let mut j = i;
+                let mut saw_nl = false;
+                while j < n {
+                    let c = inner[j];
+                    if c == b'\n' || c == b'\r' || c == b' ' || c == b'\t' {
+                        if c == b'\n' {
+                            saw_nl = true;
+                        }
+                        j += 1;
+                    } else {
+                        break;
+                    }
+                }
+                if saw_nl {
+                    push_space_once(&mut buf);
+                } else {
+                    buf.extend_from_slice(&inner[i..j]);
+                }
+                i = j;
+            } else {
+                buf.push(b);
+                i += 1;
+            }
+            continue;
+        }
+
+        // A quote starts a value only after `=` (a DOCTYPE's identifiers aside).
+        if (b == b'"' || b == b'\'') && (tag[1] == b'!' || buf.trim_ascii_end().ends_with(b"=")) {
+            quote = b;
+            keep_value = keep_quoted_values || opts.is_some_and(|o| o.keeps_attr_newlines(value_attr_name(&buf)));
+            buf.push(b);
+            i += 1;
+            continue;
+        }
+
+        if is_ws(b) {
+            let mut j = i;
+            let mut saw_nl = false;
+            while j < n && is_ws(inner[j]) {
+                if inner[j] == b'\n' {
+                    saw_nl = true;
+                }
+                j += 1;
+            }
+            // Check neighbors around the run (outside quotes)
+            let mut p = i;
+            while p > 0 && is_ws(inner[p - 1]) {
+                p -= 1;
+            }
+            let left = if p > 0 { inner[p - 1] } else { 0 };
+            let mut q = j;
+            while q < n && is_ws(inner[q]) {
+                q += 1;
+            }
+            let right = if q < n { inner[q] } else { 0 };
+
+            if saw_nl && (left == b'=' || right == b'=') {
+                // newline-run touching '=' → no space
+            } else {
+                push_space_once(&mut buf);
+            }
+            i = j;
+            continue;
+        }
+
+        buf.push(b);
+        i += 1;
+    }
+
+    trim_spaces(&mut buf);
+    if let Some(opts) = opts {
+        apply_quote_style(&mut buf, opts.quote_attrs, opts.escape_quotes);
+        if let Some(order) = &opts.sort_attrs {
+            sort_attributes(&mut buf, order);
+        }
+        let ti = parse_tag_info(tag);
+        if opts.strip_void_slash && !foreign && ti.self_closing && opts.is_void(ti.name) && buf.last() == Some(&b'/') {
+            buf.pop();
+            trim_spaces(&mut buf);
+        }
+        if let Some(style) = opts.self_closing {
+            apply_self_closing_style(&mut buf, style);
+        }
+        let prefix = &out[memrchr(b'\n', out).map_or(0, |p| p + 1)..];
+        if let Some(width) = opts.wrap_attrs {
+            wrap_attributes(&mut buf, width, prefix);
+        }
+        // A tag that would still be too long on one line keeps its own line breaks.
+        if !buf.contains(&b'\n') && opts.keep_long_tags.is_some_and(|width| columns(prefix) + columns(&buf) + 2 > width) {
+            for (k, line) in tag.split(|&b| b == b'\n').enumerate() {
+                if k > 0 {
+                    out.push(b'\n');
+                }
+                let end = line.iter().rposition(|&b| !is_space_tab(b)).map_or(0, |p| p + 1);
+                out.extend_from_slice(&line[..end]);
+            }
+            return;
+        }
+    }
+
+    out.push(b'<');
+    out.extend_from_slice(&buf);
+    out.push(b'>');
+}
+
+/// Name of the attribute whose value starts after `buf`, tag contents normalized so
+/// far: `name=` or `name = `. Empty if `buf` doesn't end in one.
+fn value_attr_name(buf: &[u8]) -> &[u8] {
+    let Some(before) = buf.trim_ascii_end().strip_suffix(b"=") else { return b"" };
+    let before = before.trim_ascii_end();
+    let start = before.iter().rposition(|&b| is_ws(b)).map_or(0, |p| p + 1);
+    &before[start..]
+}
+
+/// Rewrite the quotes around attribute values in normalized tag contents (`buf` is
+/// what sits between `<` and `>`). A quoted value containing the preferred quote, or
+/// an unquoted value, is left alone unless `escape`.
+fn apply_quote_style(buf: &mut Vec<u8>, style: QuoteStyle, escape: bool) {
+    let (target, entity): (u8, &[u8]) = match style {
+        QuoteStyle::Keep => return,
+        QuoteStyle::Double => (b'"', b"&quot;"),
+        QuoteStyle::Single => (b'\'', b"&#39;"),
+    };
+    let n = buf.len();
+    // The tag name is copied as is.
+    let mut i = buf.iter().position(|&b| b == b' ').unwrap_or(n);
+    let mut out = buf[..i].to_vec();
+    while i < n {
+        let b = buf[i];
+        if b == b'"' || b == b'\'' {
+            // A stray quoted run outside a value, copied as is
+            let end = memchr(b, &buf[i + 1..]).map_or(n, |p| i + p + 2);
+            out.extend_from_slice(&buf[i..end]);
+            i = end;
+            continue;
+        }
+        out.push(b);
+        i += 1;
+        if b != b'=' {
+            continue;
+        }
+        while i < n && buf[i] == b' ' {
+            out.push(b' ');
+            i += 1;
+        }
+        let (value, end, quote) = match buf.get(i) {
+            Some(&q @ (b'"' | b'\'')) => match memchr(q, &buf[i + 1..]) {
+                Some(p) => (&buf[i + 1..i + 1 + p], i + p + 2, Some(q)),
+                None => (&buf[i..], n, None),
+            },
+            _ => {
+                let end = buf[i..].iter().position(|&b| b == b' ').map_or(n, |p| i + p);
+                (&buf[i..end], end, None)
+            }
+        };
+        let unterminated = quote.is_none() && value.first().is_some_and(|&b| b == b'"' || b == b'\'');
+        let rewrite = match quote {
+            Some(q) => q != target && (escape || !value.contains(&target)),
+            None => escape && !value.is_empty() && !unterminated,
+        };
+        if rewrite {
+            out.push(target);
+            for &c in value {
+                if c == target {
+                    out.extend_from_slice(entity);
+                } else {
+                    out.push(c);
+                }
+            }
+            out.push(target);
+        } else {
+            out.extend_from_slice(&buf[i..end]);
+        }
+        i = end;
+    }
+    *buf = out;
+}
+
+/// Width of `b` in columns, counting characters.
+fn columns(b: &[u8]) -> usize {
+    b.iter().filter(|&&c| !(0x80..0xc0).contains(&c)).count()
+}
+
+/// Normalized tag contents split into attributes, for rewriting them one by one.
+struct SplitTag<'a> {
+    name: &'a [u8],
+    /// Each attribute's name and its bytes, `name` or `name=value`
+    attrs: Vec<(&'a [u8], &'a [u8])>,
+    /// What follows the last attribute: nothing, or a final `/` and the space before it
+    tail: &'a [u8],
+}
+
+/// Split normalized tag contents (`buf` is what sits between `<` and `>`) into
+/// attributes. `None` for an end tag, a tag without attributes, or one with anything
+/// but `name`, `name=value` and a final `/` (template syntax, stray quotes).
+fn split_attributes(buf: &[u8]) -> Option<SplitTag<'_>> {
+    let n = buf.len();
+    let name_end = buf.iter().position(|&b| b == b' ')?;
+    if buf[0] == b'/' {
+        return None;
+    }
+    // Bytes that don't belong in an attribute name or unquoted value
+    let odd = |b: u8| matches!(b, b'"' | b'\'' | b'<' | b'=' | b'`' | b'{' | b'}' | b'%');
+    let mut attrs: Vec<(&[u8], &[u8])> = Vec::new();
+    let mut i = name_end;
+    loop {
+        let attr_end = i;
+        while i < n && buf[i] == b' ' {
+            i += 1;
+        }
+        if i == n || buf[i..].trim_ascii_end() == b"/" {
+            let tail = &buf[attr_end..];
+            return (!attrs.is_empty()).then(|| SplitTag { name: &buf[..name_end], attrs, tail });
+        }
+        let start = i;
+        while i < n && !matches!(buf[i], b' ' | b'=' | b'/') {
+            if odd(buf[i]) {
+                return None;
+            }
+            i += 1;
+        }
+        if i == start {
+            return None;
+        }
+        let name = &buf[start..i];
+        let mut k = i;
+        while k < n && buf[k] == b' ' {
+            k += 1;
+        }
+        if k < n && buf[k] == b'=' {
+            k += 1;
+            while k < n && buf[k] == b' ' {
+                k += 1;
+            }
+            match buf.get(k) {
+                Some(&q @ (b'"' | b'\'')) => {
+                    let p = memchr(q, &buf[k + 1..])?;
+                    i = k + p + 2;
+                }
+                Some(_) => {
+                    while k < n && buf[k] != b' ' {
+                        if odd(buf[k]) {
+                            return None;
+                        }
+                        k += 1;
+                    }
+                    i = k;
+                }
+                None => return None,
+            }
+        }
+        attrs.push((name, &buf[start..i]));
+    }
+}
+
+/// Reorder the attributes in normalized tag contents (`buf` is what sits between `<`
+/// and `>`): the names in `order` first, then the rest alphabetically, one space apart.
+/// Tags with a repeated attribute, or that `split_attributes` can't split, are left alone.
+fn sort_attributes(buf: &mut Vec<u8>, order: &[String]) {
+    let Some(tag) = split_attributes(buf) else { return };
+    let attrs = &tag.attrs;
+    for (a, (name, _)) in attrs.iter().enumerate() {
+        if attrs[..a].iter().any(|(other, _)| other.eq_ignore_ascii_case(name)) {
+            return;
+        }
+    }
+    let rank = |name: &[u8]| {
+        let first = order.iter().position(|o| name.eq_ignore_ascii_case(o.as_bytes()));
+        (first.unwrap_or(order.len()), if first.is_some() { Vec::new() } else { name.to_ascii_lowercase() })
+    };
+    if attrs.is_sorted_by_key(|(name, _)| rank(name)) {
+        return;
+    }
+    let mut sorted = attrs.clone();
+    sorted.sort_by_cached_key(|(name, _)| rank(name));
+    let mut out = tag.name.to_vec();
+    for (_, raw) in sorted {
+        out.push(b' ');
+        out.extend_from_slice(raw);
+    }
+    // The spacing before a final `/` stays as it was.
+    out.extend_from_slice(tag.tail);
+    *buf = out;
+}
+
+/// Put each attribute after the first on a line of its own, aligned under the first,
+/// if the tag on one line would end past column `width`. `prefix` is what precedes
+/// the tag on its line. Normalized tag contents (`buf` is what sits between `<` and
+/// `>`) are on one line, so a wrapped tag wraps the same way when formatted again;
+/// one whose quoted values keep their newlines is left alone.
+fn wrap_attributes(buf: &mut Vec<u8>, width: usize, prefix: &[u8]) {
+    if columns(prefix) + columns(buf) + 2 <= width || buf.contains(&b'\n') {
+        return;
+    }
+    let Some(tag) = split_attributes(buf) else { return };
+    if tag.attrs.len() < 2 {
+        return;
+    }
+    // Tabs stay tabs so the alignment holds at any tab width.
+    let mut indent: Vec<u8> = prefix
+        .iter()
+        .filter(|&&c| !(0x80..0xc0).contains(&c))
+        .map(|&c| if c == b'\t' { b'\t' } else { b' ' })
+        .collect();
+    indent.resize(indent.len() + columns(tag.name) + 2, b' ');
+    let mut out = tag.name.to_vec();
+    for (a, (_, raw)) in tag.attrs.iter().enumerate() {
+        if a == 0 {
+            out.push(b' ');
+        } else {
+            out.push(b'\n');
+            out.extend_from_slice(&indent);
+        }
+        out.extend_from_slice(raw);
+    }
+    out.extend_from_slice(tag.tail);
+    *buf = out;
+}
+
+/// Rewrite the space before a trailing self-closing `/` in normalized tag contents
+/// (`buf` is what sits between `<` and `>`). A slash that ends an unquoted attribute
+/// value, as in `<a href=foo/>`, belongs to the value and is left alone.
+fn apply_self_closing_style(buf: &mut Vec<u8>, style: SelfClosingStyle) {
+    if buf.last() != Some(&b'/') || buf.len() < 2 {
+        return;
+    }
+    let mut rest = buf.len() - 1;
+    let spaced = buf[rest - 1] == b' ';
+    while rest > 0 && buf[rest - 1] == b' ' {
+        rest -= 1;
+    }
+    if !spaced {
+        // Only quotes, or a token without '=', can directly precede a self-closing slash.
+        let last_token = &buf[..rest];
+        let token_start = memrchr(b' ', last_token).map_or(0, |p| p + 1);
+        let last = last_token.last().copied().unwrap_or(0);
+        if last != b'"' && last != b'\'' && last_token[token_start..].contains(&b'=') {
+            return;
+        }
+    }
+    buf.truncate(rest);
+    if style == SelfClosingStyle::Spaced {
+        buf.push(b' ');
+    }
+    buf.push(b'/');
+}
+
+/* ============================== Comments ================================ */
+
+/// True if `s[start..end]` has only spaces/tabs before it on its line and is followed by '\n'.
+fn is_standalone_span(s: &[u8], start: usize, end: usize) -> bool {
+    let line_start = memrchr(b'\n', &s[..start]).map(|x| x + 1).unwrap_or(0);
+    let only_ws = s[line_start..start].iter().all(|&c| c == b' ' || c == b'\t');
+    let next_is_lf = end < s.len() && s[end] == b'\n';
+    only_ws && next_is_lf
+}
+
+/// Return (end_index_after_terminator, is_standalone). The terminator is `-->`, or
+/// `--!>`, which HTML accepts as well. If unterminated, end_index = usize::MAX.
+fn scan_comment(s: &[u8], i: usize) -> (usize, bool) {
+    // Assumes s[i..].starts_with("<!--")
+    // `<!-->` and `<!--->` are (empty) comments too.
+    for (end, close) in [(i + 5, &b">"[..]), (i + 6, &b"->"[..])] {
+        if s[i + 4..].starts_with(close) {
+            return (end, is_standalone_span(s, i, end));
+        }
+    }
+    let mut k = i + 4;
+    while let Some(p) = memchr(b'-', &s[k..]) {
+        let j = k + p;
+        let close = if s[j..].starts_with(b"-->") {
+            Some(j + 3)
+        } else if s[j..].starts_with(b"--!>") {
+            Some(j + 4)
+        } else {
+            None
+        };
+        if let Some(end) = close {
+            // A reformahtml comment always stays on a line of its own.
+            let directive = i < DIRECTIVE_LIMIT && directive_text(&s[i..end]).is_some();
+            return (end, directive || is_standalone_span(s, i, end));
+        }
+        k = j + 1;
+        if k >= s.len() {
+            break;
+        }
+    }
+    (usize::MAX, false)
+}
+
+/// Return (end_index_after_terminator, is_standalone) for the CDATA section at `i`.
+/// If unterminated, end_index = usize::MAX.
+fn scan_cdata(s: &[u8], i: usize) -> (usize, bool) {
+    // Assumes s[i..].starts_with("<![CDATA[") (case-sensitive, as in the HTML spec)
+    match memchr::memmem::find(&s[i + 9..], b"]]>") {
+        Some(p) => {
+            let end = i + 9 + p + 3;
+            (end, is_standalone_span(s, i, end))
+        }
+        None => (usize::MAX, false),
+    }
+}
+
+/// True if `rest` starts with `<!DOCTYPE` (ASCII case-insensitive).
+fn starts_doctype(rest: &[u8]) -> bool {
+    rest.len() >= 9 && rest[..9].eq_ignore_ascii_case(b"<!DOCTYPE")
+}
+
+/// Return (end_index_after_terminator, has_internal_subset) for the DOCTYPE at `i`:
+/// the first `>` outside quoted identifiers and the `[...]` internal subset, whose
+/// markup declarations, comments and quoted literals may contain `>`. If
+/// unterminated, end_index = usize::MAX.
+fn scan_doctype(s: &[u8], i: usize) -> (usize, bool) {
+    // Assumes starts_doctype(&s[i..])
+    let mut k = i + 9;
+    let mut quote = None;
+    let mut in_subset = false;
+    let mut subset = false;
+    while k < s.len() {
+        let c = s[k];
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == b'"' || c == b'\'' => quote = Some(c),
+            None if in_subset && s[k..].starts_with(b"<!--") => {
+                let (end, _) = scan_comment(s, k);
+                if end == usize::MAX {
+                    break;
+                }
+                k = end - 1;
+            }
+            None if c == b'[' => (in_subset, subset) = (true, true),
+            None if c == b']' => in_subset = false,
+            None if c == b'>' && !in_subset => return (k + 1, subset),
+            None => {}
+        }
+        k += 1;
+    }
+    (usize::MAX, subset)
+}
+
+/// True if the `<` that `rest` starts with is text. The HTML tokenizer has it so
+/// unless an ASCII letter, `/`, `!` or `?` follows, e.g. in `x < y`, `a<3` or
+/// `<=`. In Markdown mode, an autolink such as `<https://example.com/>` is text too.
+fn is_text_lt(rest: &[u8], markdown: bool) -> bool {
+    !rest.get(1).is_some_and(|&c| c.is_ascii_alphabetic() || matches!(c, b'/' | b'!' | b'?'))
+        || (markdown && is_autolink(rest))
+}
+
+/// True if `rest` starts with a CommonMark URI autolink: `<`, a scheme of 2 to 32
+/// letters, digits, `+`, `.` or `-` starting with a letter, `:`, and anything but
+/// white space, `<` and `>` up to the `>`.
+fn is_autolink(rest: &[u8]) -> bool {
+    let scheme_char = |c: &&u8| c.is_ascii_alphanumeric() || matches!(c, b'+' | b'.' | b'-');
+    let scheme = rest[1..].iter().take_while(scheme_char).count();
+    if !rest[1].is_ascii_alphabetic() || !(2..=32).contains(&scheme) || rest.get(1 + scheme) != Some(&b':') {
+        return false;
+    }
+    let uri = &rest[2 + scheme..];
+    let end = uri.iter().position(|&c| c == b'>' || c == b'<' || is_ws(c) || c.is_ascii_control());
+    end.is_some_and(|p| uri[p] == b'>')
+}
+
+/// Index of the first `<` at or after `from` that isn't text (see `is_text_lt`),
+/// or `src.len()`.
+fn find_markup_start(src: &[u8], from: usize, markdown: bool) -> usize {
+    let mut k = from;
+    while let Some(p) = memchr(b'<', &src[k..]) {
+        if !is_text_lt(&src[k + p..], markdown) {
+            return k + p;
+        }
+        k += p + 1;
+    }
+    src.len()
+}
+
+/// True if `rest` starts what the HTML tokenizer makes a bogus comment: `<!` not
+/// followed by `--`, `DOCTYPE` or `[CDATA[`, or `</` not followed by a letter.
+fn starts_bogus_comment(rest: &[u8]) -> bool {
+    match rest {
+        [b'<', b'!', ..] => !(rest[2..].starts_with(b"--") || starts_doctype(rest) || rest[2..].starts_with(b"[CDATA[")),
+        [b'<', b'/', c, ..] => !c.is_ascii_alphabetic(),
+        _ => false,
+    }
+}
+
+/// Return (end_index_after_terminator, is_standalone) for the processing instruction
+/// at `i`, ended by the first `?>` outside quotes. If unterminated, end_index = usize::MAX.
+///
+/// HTML parses `<?...>` as a bogus comment ending at the first `>`; an XML mode
+/// would need that instead of the XML rule used here.
+fn scan_processing_instruction(s: &[u8], i: usize) -> (usize, bool) {
+    // Assumes s[i..].starts_with("<?")
+    let mut k = i + 2;
+    let mut quote = None;
+    while k < s.len() {
+        let c = s[k];
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == b'"' || c == b'\'' => quote = Some(c),
+            None if c == b'?' && s.get(k + 1) == Some(&b'>') => {
+                let end = k + 2;
+                return (end, is_standalone_span(s, i, end));
+            }
+            None => {}
+        }
+        k += 1;
+    }
+    (usize::MAX, false)
+}
+
+/// How far into a file a `<!-- reformahtml: ... -->` comment is looked for.
+const DIRECTIVE_LIMIT: usize = 512;
+
+/// The offset and the settings of the first `<!-- reformahtml: ... -->` comment
+/// starting in the first `DIRECTIVE_LIMIT` bytes of `src`.
+pub fn find_directive(src: &[u8]) -> Option<(usize, &str)> {
+    let mut at = 0;
+    while let Some(p) = memchr::memmem::find(&src[at..src.len().min(DIRECTIVE_LIMIT)], b"<!--") {
+        let start = at + p;
+        let (end, _) = scan_comment(src, start);
+        if end == usize::MAX {
+            return None;
+        }
+        if let Some(settings) = directive_text(&src[start..end]) {
+            return Some((start, settings));
+        }
+        at = end;
+        if at >= DIRECTIVE_LIMIT {
+            return None;
+        }
+    }
+    None
+}
+
+/// The text between `<!--` and `-->` (or `--!>`) of `comment`, if it has both.
+fn comment_text(comment: &[u8]) -> Option<&[u8]> {
+    let body = comment.strip_prefix(b"<!--")?;
+    body.strip_suffix(b"--!>").or_else(|| body.strip_suffix(b"-->"))
+}
+
+/// The settings in `comment` (including `<!--` and `-->`), if it is a reformahtml comment.
+fn directive_text(comment: &[u8]) -> Option<&str> {
+    let inner = std::str::from_utf8(comment_text(comment)?).ok()?;
+    inner.trim_start().strip_prefix("reformahtml:")
+}
+
+/// A standalone comment that changes what is formatted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CommentDirective {
+    /// `<!-- reformahtml off -->`: copy everything verbatim from here
+    Off,
+    /// `<!-- reformahtml on -->`: format again after an `Off`
+    On,
+    /// `<!-- reformahtml-ignore -->`: copy the next element or text verbatim
+    Ignore,
+}
+
+/// The directive `comment` (including `<!--` and `-->`) is, if any.
+fn comment_directive(comment: &[u8]) -> Option<CommentDirective> {
+    let inner = std::str::from_utf8(comment_text(comment)?).ok()?;
+    match inner.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+        ["reformahtml", "off"] => Some(CommentDirective::Off),
+        ["reformahtml", "on"] => Some(CommentDirective::On),
+        ["reformahtml-ignore"] => Some(CommentDirective::Ignore),
+        _ => None,
+    }
+}
+
+fn reflow_inline_comment(comment: &[u8], out: &mut Vec<u8>) {
+    // comment like <!-- ... --> (or <!-- ... --!>)
+    let Some(inner) = comment_text(comment) else {
+        out.extend_from_slice(comment);
+        return;
+    };
+    out.extend_from_slice(b"<!--");
+    let mut i = 0usize;
+    let n = inner.len();
+    while i < n {
+        let b = inner[i];
+        if b == b'\n' {
+            // collapse newline + adjoining ws to a single space
+            if !out.last().map(|b| *b == b' ').unwrap_or(false) {
+                out.push(b' ');
+            }
+            i += 1;
+            while i < n && (inner[i] == b' ' || inner[i] == b'\t' || inner[i] == b'\n') {
+                i += 1;
+            }
+        } else {
+            out.push(b);
+            i += 1;
+        }
+    }
+    out.extend_from_slice(&comment[4 + inner.len()..]);
+}
+
+/* ==================== Partially supported constructs =================== */
+
+/// Recognize a construct at `i` that the reflower does not fully support.
+/// Returns its kind and the index just past its end (`s.len()` if unterminated).
+fn scan_unsupported_construct(s: &[u8], i: usize) -> Option<(Construct, usize)> {
+    let rest = &s[i..];
+    let find_from = |start: usize, pat: &[u8]| {
+        memchr::memmem::find(&s[start..], pat).map_or(s.len(), |p| start + p + pat.len())
+    };
+    if (rest.len() >= 5 && rest[..5].eq_ignore_ascii_case(b"<?php")) || rest.starts_with(b"<?=") {
+        return Some((Construct::Php, find_from(i + 2, b"?>")));
+    }
+    None
+}
+
+/// Return the index just past the conditional comment at `i`: the
+/// downlevel-hidden `<!--[if IE]> ... <![endif]-->` (and `<!--<![endif]-->`), or
+/// the downlevel-revealed markers `<![if !IE]>` and `<![endif]>`. `None` if there
+/// is none or it is unterminated, which the comment and bogus comment code report.
+fn scan_conditional_comment(s: &[u8], i: usize) -> Option<usize> {
+    let rest = &s[i..];
+    if rest.starts_with(b"<!--[if") || rest.starts_with(b"<!--<![endif]") {
+        let (end, _) = scan_comment(s, i);
+        return (end != usize::MAX).then_some(end);
+    }
+    if rest.starts_with(b"<![if") || rest.starts_with(b"<![endif") {
+        return find_tag_end(s, i).map(|j| j + 1);
+    }
+    None
+}
+
+/* ======================== Markdown/Bikeshed reflow ====================== */
+
+#[derive(Clone, Copy)]
+struct Fence {
+    ch: u8,    // '`' or '~'
+    min: usize // min count
+}
+
+fn is_hr_line_stripped(s: &str) -> bool {
+    let mut c = '\0';
+    let mut count = 0usize;
+    for ch in s.chars() {
+        if ch == ' ' || ch == '\t' { continue; }
+        if c == '\0' {
+            if ch == '*' || ch == '-' || ch == '_' {
+                c = ch;
+                count = 1;
+            } else {
+                return false;
+            }
+        } else {
+            if ch != c { return false; }
+            count += 1;
+        }
+    }
+    count >= 3
+}
+
+fn is_setext_underline_stripped(s: &str) -> bool {
+    let mut c = '\0';
+    for ch in s.chars() {
+        if ch == ' ' || ch == '\t' { continue; }
+        if ch == '-' || ch == '=' {
+            if c == '\0' { c = ch; }
+            else if c != ch { return false; }
+        } else {
+            return false;
+        }
+    }
+    let count = s.chars().filter(|&ch| ch == '-' || ch == '=').count();
+    count >= 2
+}
+
+fn starts_with_bullet(line: &str) -> Option<(String, String)> {
+    // ^\s*[*-]\s+
+    let bytes = line.as_bytes();
+    let mut i = 0usize;
+    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
+    if i < bytes.len() && (bytes[i] == b'*' || bytes[i] == b'-') {
+        let marker = bytes[i] as char;
+        i += 1;
+        let mut j = i;
+        if j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') {
+            while j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') { j += 1; }
+            let prefix = format!("{}{} ", &line[..i-1], marker);
+            let first = line[j..].to_string();
+            return Some((prefix, first));
+        }
+    }
+    None
+}
+
+fn starts_with_ol(line: &str) -> Option<(String, String)> {
+    // ^\s*\d+\.\s+
+    let bytes = line.as_bytes();
+    let mut i = 0usize;
+    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
+    let indent = &line[..i];
+
+    let mut pos = i;
+    while pos < bytes.len() && bytes[pos].is_ascii_digit() { pos += 1; }
+    if pos == i { return None; }
+    if pos >= bytes.len() || bytes[pos] != b'.' { return None; }
+    let dot_pos = pos;
+    pos += 1; // skip '.'
+    if pos >= bytes.len() || !(bytes[pos] == b' ' || bytes[pos] == b'\t') { return None; }
+    while pos < bytes.len() && (bytes[pos] == b' ' || bytes[pos] == b'\t') { pos += 1; }
+
+    let digits = &line[i..dot_pos];
+    let prefix = format!("{indent}{digits}. ");
+    let first = line[pos..].to_string();
+    Some((prefix, first))
+}
+
+fn is_atx_heading(line: &str) -> bool {
+    // ^\s*#{1,6}\s+
+    let bytes = line.as_bytes();
+    let mut i = 0usize;
+    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
+    let mut count = 0usize;
+    while i < bytes.len() && bytes[i] == b'#' && count < 6 {
+        count += 1;
+        i += 1;
+    }
+    if count == 0 { return false; }
+    i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t')
+}
+
+fn is_blockquote(line: &str) -> bool {
+    // ^\s*>\s?
+    let bytes = line.as_bytes();
+    let mut i = 0usize;
+    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
+    if i < bytes.len() && bytes[i] == b'>' {
+        let j = i + 1;
+        if j == bytes.len() || bytes[j] == b' ' || bytes[j] == b'\t' { return true; }
+    }
+    false
+}
+
+fn parse_dt(line: &str) -> Option<(String, String)> {
+    let bytes = line.as_bytes();
+    let mut i = 0usize;
+    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
+    if i >= bytes.len() || bytes[i] != b':' { return None; }
+    let mut j = i + 1;
+    let has_extra_space = j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t');
+    if has_extra_space || j == bytes.len() {
+        if has_extra_space {
+            while j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') { j += 1; }
+        }
+        let space = if j == bytes.len() { "" } else { " " };
+        let prefix = format!("{}:{space}", &line[..i]);
+        let first = line[j..].to_string();
+        Some((prefix, first))
+    } else {
+        None
+    }
+}
+
+fn parse_dd(line: &str) -> Option<(String, String)> {
+    let bytes = line.as_bytes();
+    let mut i = 0usize;
+    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
+    if i + 1 >= bytes.len() || bytes[i] != b':' || bytes[i + 1] != b':' { return None; }
+    let mut j = i + 2;
+    let has_extra_space = j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t');
+    if has_extra_space || j == bytes.len() {
+        if has_extra_space {
+            while j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') { j += 1; }
+        }
+        let space = if j == bytes.len() { "" } else { " " };
+        let prefix = format!("{}::{space}", &line[..i]);
+        let first = line[j..].to_string();
+        Some((prefix, first))
+    } else {
+        None
+    }
+}
+
+fn fence_open(line: &str) -> Option<Fence> {
+    // ^\s*(```+|~~~+)
+    let bytes = line.as_bytes();
+    let mut i = 0usize;
+    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
+    if i >= bytes.len() { return None; }
+    if bytes[i] == b'`' || bytes[i] == b'~' {
+        let ch = bytes[i];
+        let mut j = i;
+        while j < bytes.len() && bytes[j] == ch { j += 1; }
+        if j - i >= 3 {
+            return Some(Fence { ch, min: j - i });
+        }
+    }
+    None
+}
+
+fn fence_close(line: &str, f: Fence) -> bool {
+    // ^\s*<ch>{min,}\s*$
+    let bytes = line.as_bytes();
+    let mut i = 0usize;
+    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
+    let mut count = 0usize;
+    while i < bytes.len() && bytes[i] == f.ch { count += 1; i += 1; }
+    if count < f.min { return false; }
+    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
+    i == bytes.len()
+}
+
+/// True if `line` starts (after indentation) with a start or end tag of a block-level
+/// HTML element, e.g. `<pre>`, `<div class=example>` or `</table>`.
+fn starts_html_block(line: &str) -> bool {
+    let rest = line.trim_start_matches([' ', '\t']).as_bytes();
+    if rest.first() != Some(&b'<') { return false; }
+    let mut i = 1usize;
+    if i < rest.len() && rest[i] == b'/' { i += 1; }
+    let start = i;
+    while i < rest.len() && is_name_char(rest[i]) { i += 1; }
+    if i == start { return false; }
+    if i < rest.len() && !(is_ws(rest[i]) || rest[i] == b'>' || rest[i] == b'/') { return false; }
+    let name = &rest[start..i];
+    is_structural(name) || is_raw_text(name)
+}
+
+/// True if `line` cannot continue the preceding list item or dt/dd: it is blank
+/// or starts a new Markdown block or an HTML block.
+fn ends_item_continuation(line: &str, stripped: &str, opts: &MarkdownOptions) -> bool {
+    stripped.is_empty()
+        || fence_open(line).is_some()
+        || is_atx_heading(line)
+        || starts_with_bullet(line).is_some()
+        || starts_with_ol(line).is_some()
+        || (opts.definition_lists && (parse_dt(line).is_some() || parse_dd(line).is_some()))
+        || is_blockquote(line)
+        || is_hr_line_stripped(stripped)
+        || is_setext_underline_stripped(stripped)
+        || starts_html_block(line)
+}
+
+/* ---------- Helpers to keep DT/DD on their own lines during reflow ---------- */
+
+#[inline]
+fn body_begins_with_dt_or_dd_after_single_lf(body: &[u8]) -> bool {
+    // Matches: "\n" + ws* + ":" [ ":" ] (space/tab or end)
+    if body.is_empty() || body[0] != b'\n' { return false; }
+    let mut j = 1usize;
+    while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
+    if j >= body.len() || body[j] != b':' { return false; }
+    j += 1;
+    if j < body.len() && body[j] == b':' { j += 1; }
+    if j >= body.len() { return true; }
+    body[j] == b' ' || body[j] == b'\t'
+}
+
+/// Like `body_begins_with_dt_or_dd_after_single_lf`, but for any Markdown block that
+/// must start on its own line: list items, dt/dd, headings, quotes and fences.
+fn body_begins_with_md_block_after_single_lf(body: &[u8]) -> bool {
+    if body_begins_with_dt_or_dd_after_single_lf(body) { return true; }
+    if body.is_empty() || body[0] != b'\n' { return false; }
+    let end = memchr(b'\n', &body[1..]).map_or(body.len(), |p| p + 1);
+    let Ok(line) = std::str::from_utf8(&body[1..end]) else { return false };
+    starts_with_bullet(line).is_some()
+        || starts_with_ol(line).is_some()
+        || is_atx_heading(line)
+        || is_blockquote(line)
+        || fence_open(line).is_some()
+}
+
+/// Return true if the **line containing `pos`** begins (after optional spaces/tabs)
+/// with `: ` or `:: ` — i.e., a DT/DD marker. This handles the case where `pos`
+/// points into the *same line* (e.g., at a `<` that follows the marker).
+fn line_at_pos_starts_with_dt_or_dd(src: &[u8], pos: usize) -> bool {
+    let n = src.len();
+    if pos > n { return false; }
+    let line_start = memrchr(b'\n', &src[..pos]).map(|x| x + 1).unwrap_or(0);
+    let mut i = line_start;
+    while i < n && (src[i] == b' ' || src[i] == b'\t') { i += 1; }
+    if i >= n { return false; }
+    if src[i] != b':' { return false; }
+    i += 1;
+    if i < n && src[i] == b':' { i += 1; }
+    if i >= n { return true; }
+    src[i] == b' ' || src[i] == b'\t'
+}
+
+/// If body starts with "\n"+indent+":"[":"], return index of the first ':' (end of indent).
+#[inline]
+fn leading_lf_indent_end_before_dt_or_dd(body: &[u8]) -> Option<usize> {
+    if body.is_empty() || body[0] != b'\n' { return None; }
+    let mut j = 1usize;
+    while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
+    if j >= body.len() || body[j] != b':' { return None; }
+    // optional second ':'
+    let mut k = j + 1;
+    if k < body.len() && body[k] == b':' { k += 1; }
+    if k < body.len() && !(body[k] == b' ' || body[k] == b'\t') {
+        return None;
+    }
+    Some(j)
+}
+
+/// Which Bikeshed additions to Markdown `reflow_markdown_text` recognizes.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct MarkdownOptions {
+    /// `: term` and `:: description` lines are definition list items
+    pub definition_lists: bool,
+}
+
+impl Default for MarkdownOptions {
+    fn default() -> MarkdownOptions {
+        MarkdownOptions { definition_lists: true }
+    }
+}
+
+impl MarkdownOptions {
+    pub fn definition_lists(mut self, definition_lists: bool) -> MarkdownOptions {
+        self.definition_lists = definition_lists;
+        self
+    }
+}
+
+/// `line` without its line ending (LF or CRLF), and the line ending.
+fn split_line_ending(line: &str) -> (&str, &str) {
+    if let Some(rest) = line.strip_suffix("\r\n") {
+        (rest, "\r\n")
+    } else if let Some(rest) = line.strip_suffix('\n') {
+        (rest, "\n")
+    } else {
+        (line, "")
+    }
+}
+
+/// Reflow Markdown `text` as text between tags is reflowed in Markdown mode: the
+/// lines of each paragraph, list item and definition list item are joined with
+/// single spaces, and all other lines are kept as they are.
+///
+/// A paragraph or item ends at a blank line or at a line that starts another
+/// block: a list item (`*`, `-` or `1.` followed by a space), a definition list
+/// item (`:` or `::`, unless `opts` turns them off), an ATX heading, a block quote,
+/// a thematic break, a Setext underline, a code fence or a line starting with a
+/// block-level or raw-text HTML tag. Fenced code is kept up to its closing fence,
+/// or to the end of `text` if it has none. Lines may end in LF or CRLF; a joined
+/// block keeps the line ending of its last line, except that a paragraph running
+/// to the end of `text` loses it. Never panics.
+///
+/// ```
+/// use reformahtml::{reflow_markdown_text, MarkdownOptions};
+///
+/// let text = "* One\n  item\n\nA\nparagraph\n";
+/// assert_eq!(reflow_markdown_text(text, &MarkdownOptions::default()), "* One item\n\nA paragraph");
+/// ```
+pub fn reflow_markdown_text(text: &str, opts: &MarkdownOptions) -> String {
+    if text.is_empty() {
+        return String::new();
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut para_parts: Vec<&str> = Vec::new();
+    // Line ending of the last line in `para_parts`
+    let mut para_end = "";
+    let mut in_fence: Option<Fence> = None;
+    let mut prev_nonblank_was_paragraph = false;
+
+    let mut lines_iter = text.split_inclusive('\n').peekable();
+
+    let flush_para = |ending: &str, out: &mut String, para_parts: &mut Vec<&str>| {
+        if para_parts.is_empty() { return; }
+        if para_parts.len() == 1 {
+            out.push_str(para_parts[0]);
+        } else {
+            let first = para_parts[0].trim_end_matches([' ', '\t']);
+            out.push_str(first);
+            for s in para_parts.iter().skip(1) {
+                let s2 = s.trim_start_matches([' ', '\t']);
+                out.push(' ');
+                out.push_str(s2);
+            }
+        }
+        out.push_str(ending);
+        para_parts.clear();
+    };
+    let item = |line: &str| {
+        starts_with_bullet(line)
+            .or_else(|| starts_with_ol(line))
+            .or_else(|| if opts.definition_lists { parse_dt(line).or_else(|| parse_dd(line)) } else { None })
+    };
+
+    while let Some(raw) = lines_iter.next() {
+        let (line_no_nl, _) = split_line_ending(raw);
+        let line_stripped_ws = line_no_nl.trim();
+
+        if let Some(f) = in_fence {
+            if fence_close(line_no_nl, f) {
+                flush_para("", &mut out, &mut para_parts);
+                out.push_str(raw);
+                in_fence = None;
+                prev_nonblank_was_paragraph = false;
+            } else {
+                out.push_str(raw);
+            }
+            continue;
+        }
+
+        if line_stripped_ws.is_empty() {
+            flush_para(para_end, &mut out, &mut para_parts);
+            out.push_str(raw);
+            prev_nonblank_was_paragraph = false;
+            continue;
+        }
+
+        if let Some(f) = fence_open(line_no_nl) {
+            flush_para("", &mut out, &mut para_parts);
+            in_fence = Some(f);
+            out.push_str(raw);
+            prev_nonblank_was_paragraph = false;
+            continue;
+        }
+
+        // List items and dt/dd take their continuation lines along.
+        if let Some((prefix, first_text)) = item(line_no_nl) {
+            flush_para(para_end, &mut out, &mut para_parts);
+            let mut joined = first_text.trim_end_matches([' ', '\t']).to_string();
+            let mut last_end = split_line_ending(raw).1;
+
+            while let Some(&nxt_raw) = lines_iter.peek() {
+                let (nxt, nxt_end) = split_line_ending(nxt_raw);
+                if ends_item_continuation(nxt, nxt.trim(), opts) { break; }
+                joined.push(' ');
+                joined.push_str(nxt.trim_start_matches([' ', '\t']));
+                last_end = nxt_end;
+                lines_iter.next();
+            }
+
+            out.push_str(&prefix);
+            out.push_str(&joined);
+            out.push_str(last_end);
+            prev_nonblank_was_paragraph = false;
+            continue;
+        }
+
+        // Generic structural lines
+        let is_structural_line =
+            is_atx_heading(line_no_nl) ||
+            is_blockquote(line_no_nl) ||
+            is_hr_line_stripped(line_stripped_ws) ||
+            starts_html_block(line_no_nl) ||
+            (is_setext_underline_stripped(line_stripped_ws) && prev_nonblank_was_paragraph);
+
+        if is_structural_line {
+            flush_para(para_end, &mut out, &mut para_parts);
+            out.push_str(raw);
+            prev_nonblank_was_paragraph = false;
+            continue;
+        }
+
+        // Regular paragraph line
+        para_parts.push(line_no_nl);
+        para_end = split_line_ending(raw).1;
+        prev_nonblank_was_paragraph = true;
+    }
+
+    // flush at end
+    if !para_parts.is_empty() {
+        let first = para_parts[0].trim_end_matches([' ', '\t']);
+        out.push_str(first);
+        for s in para_parts.iter().skip(1) {
+            out.push(' ');
+            out.push_str(s.trim_start_matches([' ', '\t']));
+        }
+    }
+
+    out
+}
+
+/// Reflow plain `text` as text between tags is reflowed outside Markdown mode:
+/// every line break (LF or CRLF), with the blank lines, spaces and tabs that
+/// follow it, becomes a single space, or nothing if the text before it already
+/// ends in a space. Never panics.
+///
+/// ```
+/// assert_eq!(reformahtml::reflow_plain_text("One\r\n  two \nthree"), "One two three");
+/// ```
+pub fn reflow_plain_text(text: &str) -> String {
+    if text.is_empty() {
+        return String::new();
+    }
+    let bytes = text.as_bytes();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0usize;
+    let mut seg_start = 0usize;
+    let is_crlf = |i: usize| bytes[i] == b'\r' && bytes.get(i + 1) == Some(&b'\n');
+
+    while i < bytes.len() {
+        if bytes[i] == b'\n' || is_crlf(i) {
+            if seg_start < i {
+                out.push_str(&text[seg_start..i]); // safe: char boundary
+            }
+            if !out.ends_with(' ') {
+                out.push(' ');
+            }
+            i += 1;
+            while i < bytes.len() && (bytes[i] == b'\n' || bytes[i] == b' ' || bytes[i] == b'\t' || is_crlf(i)) {
+                i += 1;
+            }
+            seg_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    if seg_start < bytes.len() {
+        out.push_str(&text[seg_start..]);
+    }
+    out
+}
+
+fn reflow_text(text: &str, opts: &Options) -> String {
+    if opts.markdown {
+        reflow_markdown_text(text, &MarkdownOptions::default())
+    } else {
+        reflow_plain_text(text)
+    }
+}
+
+/* ========================= Markdown documents ============================ */
+
+/// A block of a Markdown document that is copied as is.
+enum OpaqueBlock {
+    Fence(Fence),
+    /// Raw HTML, up to the line containing `end` (a comment or raw-text element),
+    /// or up to the next blank line if `end` is `None`
+    Html { end: Option<Vec<u8>> },
+    /// Indented code, up to the next line indented less than four columns
+    IndentedCode,
+}
+
+/// `--format markdown`: reflow a whole Markdown document with `reflow_markdown_text`,
+/// without tokenizing HTML, so `<` in code spans is just text. Fenced and indented
+/// code, table rows and raw HTML blocks are copied as is. Returns true if the
+/// document ends inside an unclosed fence or HTML block.
+fn transform_markdown_document(
+    src: &[u8],
+    out: &mut Vec<u8>,
+    opts: &Options,
+    mut stats: Option<&mut Stats>,
+    mut window: Option<&mut Window>,
+) -> bool {
+    let strip_trailing = opts.strip_trailing_whitespace;
+    let mut block: Option<OpaqueBlock> = None;
+    let mut text_start = 0usize; // start of the pending run of text lines
+    let mut prev_blank = true;
+    let mut pos = 0usize;
+
+    // Emit a run of text lines (reflowed) or an opaque line (as is).
+    let mut emit = |tok: Range<usize>, reflow: bool, out: &mut Vec<u8>| {
+        let text = &src[tok.clone()];
+        let start = out.len();
+        if !reflow {
+            out.extend_from_slice(text);
+        } else if !text.is_empty() {
+            // `reflow_markdown_text` doesn't end the last line; keep the trailing whitespace as is.
+            let end = text.len() - text.iter().rev().take_while(|&&b| is_ws(b)).count();
+            out.extend_from_slice(reflow_markdown_text(std::str::from_utf8(&text[..end]).unwrap(), &MarkdownOptions::default()).as_bytes());
+            out.extend_from_slice(&text[end..]);
+            if strip_trailing {
+                strip_trailing_whitespace(out, start, tok.end == src.len(), true);
+            }
+            if let Some(indent) = opts.indent {
+                // Code blocks are opaque lines, so no fences to track here.
+                convert_indentation(out, start, true, indent, None);
+            }
+            if let Some(stats) = stats.as_deref_mut() {
+                let newlines = |b: &[u8]| memchr::memchr_iter(b'\n', b).count();
+                stats.text_chunks_reflowed += 1;
+                stats.lines_joined += newlines(text).saturating_sub(newlines(&out[start..]));
+            }
+        }
+        if let Some(w) = window.as_deref_mut() {
+            w.token(src, tok, out, start);
+        }
+    };
+
+    for raw in src.split_inclusive(|&b| b == b'\n') {
+        let line_start = pos;
+        pos += raw.len();
+        let Ok(line) = std::str::from_utf8(raw) else {
+            // Not UTF-8: never reflowed.
+            emit(text_start..line_start, true, out);
+            emit(line_start..pos, false, out);
+            text_start = pos;
+            prev_blank = false;
+            continue;
+        };
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let blank = line.trim().is_empty();
+
+        let opaque = match &block {
+            Some(OpaqueBlock::Fence(f)) => {
+                if fence_close(line, *f) {
+                    block = None;
+                }
+                true
+            }
+            Some(OpaqueBlock::Html { end: Some(end) }) => {
+                if find_ignore_ascii_case(raw, end).is_some() {
+                    block = None;
+                }
+                true
+            }
+            Some(OpaqueBlock::Html { end: None }) if !blank => true,
+            Some(OpaqueBlock::IndentedCode) if blank || line.starts_with('\t') || line.starts_with("    ") => true,
+            _ => {
+                block = None;
+                if blank {
+                    false
+                } else if let Some(f) = fence_open(line) {
+                    block = Some(OpaqueBlock::Fence(f));
+                    true
+                } else if let Some(end) = html_block_end(line, opts) {
+                    block = match end {
+                        Some(end) if find_ignore_ascii_case(raw, &end).is_some() => None,
+                        end => Some(OpaqueBlock::Html { end }),
+                    };
+                    true
+                } else if prev_blank && (line.starts_with('\t') || line.starts_with("    ")) {
+                    block = Some(OpaqueBlock::IndentedCode);
+                    true
+                } else {
+                    line.trim_start().starts_with('|')
+                }
+            }
+        };
+        if opaque {
+            emit(text_start..line_start, true, out);
+            emit(line_start..pos, false, out);
+            text_start = pos;
+        }
+        prev_blank = blank;
+    }
+    emit(text_start..src.len(), true, out);
+    matches!(block, Some(OpaqueBlock::Fence(_) | OpaqueBlock::Html { end: Some(_) }))
+}
+
+/// `--trim-final-newlines` and `--ensure-final-newline` for the document in
+/// `out[start..]`, before line endings are converted.
+fn fix_final_newlines(out: &mut Vec<u8>, start: usize, opts: &Options) {
+    let doc = &out[start..];
+    if opts.trim_final_newlines && doc.ends_with(b"\n") {
+        // Drop whole blank lines (spaces and tabs only) before the final newline.
+        let mut keep = doc.len();
+        while let Some(nl) = memrchr(b'\n', &doc[..keep - 1]) {
+            if !doc[nl + 1..keep - 1].iter().all(|&b| b == b' ' || b == b'\t') {
+                break;
+            }
+            keep = nl + 1;
+        }
+        out.truncate(start + keep);
+    }
+    if opts.ensure_final_newline && out.len() > start && out.last() != Some(&b'\n') {
+        out.push(b'\n');
+    }
+}
+
+/// For a line starting a raw HTML block: the marker ending the block (`-->` or the
+/// raw-text end tag), or `None` if it ends at a blank line. `None` overall if the
+/// line doesn't start an HTML block.
+fn html_block_end(line: &str, opts: &Options) -> Option<Option<Vec<u8>>> {
+    let rest = line.trim_start_matches([' ', '\t']).as_bytes();
+    if rest.starts_with(b"<!--") {
+        return Some(Some(b"-->".to_vec()));
+    }
+    let name_end = rest.iter().skip(1).position(|&b| !is_name_char(b)).map_or(rest.len(), |p| p + 1);
+    let name = rest.get(1..name_end).unwrap_or_default();
+    let ends_name = rest.get(name_end).is_none_or(|&b| is_ws(b) || b == b'>' || b == b'/');
+    if rest.first() == Some(&b'<') && !name.is_empty() && ends_name && opts.is_raw_text(name) {
+        return Some(Some([b"</", name].concat()));
+    }
+    starts_html_block(line).then_some(None)
+}
+
+fn find_ignore_ascii_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w.eq_ignore_ascii_case(needle))
+}
+
+/* ==================== Structural boundary helper ======================== */
+
+fn prev_line_ends_with_structural_start(s: &[u8], mut boundary: usize, opts: &Options) -> bool {
+    loop {
+        let line_start = memrchr(b'\n', &s[..boundary]).map(|x| x + 1).unwrap_or(0);
+        if line_start >= boundary { return false; }
+        // Trim trailing spaces/tabs
+        let mut end = boundary;
+        while end > line_start && is_space_tab(s[end - 1]) { end -= 1; }
+        if end > line_start {
+            // non-empty after trim
+            if s[end - 1] != b'>' { return false; }
+            let lt = memrchr(b'<', &s[line_start..end]).map(|x| x + line_start);
+            let lt = match lt { Some(v) => v, None => return false };
+            let tag = &s[lt..end];
+            let ti = parse_tag_info(tag);
+            if ti.is_end { return false; }
+            return opts.is_structural(ti.name);
+        } else {
+            // empty line, go back
+            if line_start == 0 { return false; }
+            boundary = line_start - 1; // before the \n
+        }
+    }
+}
+
+fn has_single_lf(chunk: &[u8]) -> bool {
+    let mut count = 0usize;
+    for &c in chunk {
+        if c == b'\n' { count += 1; if count > 1 { return false; } }
+    }
+    count == 1
+}
+
+fn trailing_lf_count_ignoring_spaces(chunk: &[u8]) -> usize {
+    let mut i = chunk.len();
+    while i > 0 && (chunk[i - 1] == b' ' || chunk[i - 1] == b'\t') { i -= 1; }
+    let mut k = 0usize;
+    while i > 0 && chunk[i - 1] == b'\n' {
+        k += 1;
+        i -= 1;
+    }
+    k
+}
+
+/* ============================ Raw-text copying ========================== */
+
+/// Find the **matching** end tag `</name>` at or after `i`, skipping any other
+/// markup. Returns the offsets of its `<` and `>`.
+fn find_end_tag(src: &[u8], i: usize, name: &[u8]) -> Option<(usize, usize)> {
+    let mut j = i;
+    loop {
+        let pos = j + memchr(b'<', &src[j..])?;
+        // Not enough room for "</", or a literal '<'
+        if pos + 2 >= src.len() || src[pos + 1] != b'/' {
+            j = pos + 1;
+            continue;
+        }
+        let end = find_tag_end(src, pos)?;
+        if parse_tag_info(&src[pos..=end]).name.eq_ignore_ascii_case(name) {
+            return Some((pos, end));
+        }
+        j = end + 1;
+    }
+}
+
+/// Find the end tag of a `<script>` whose contents start at `i`, following HTML's
+/// script data states: after `<!--`, a `<script>` start tag means the next
+/// `</script>` belongs to it, up to `-->`. With `javascript`, also skip end tags
+/// inside strings, template literals, regular expression literals and comments;
+/// `None` then may just mean a quote in code we misread opened a string that never
+/// closed, and the caller falls back to the HTML states alone, as browsers use.
+fn find_script_end(src: &[u8], i: usize, javascript: bool) -> Option<(usize, usize)> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum State {
+        Code,
+        /// In a string or template literal closed by this quote
+        Quote(u8),
+        /// In a regular expression literal; true inside a `[...]` class
+        Regex(bool),
+        LineComment,
+        BlockComment,
+    }
+    // `script` at `k`, ending like a tag name does
+    let script_at = |k: usize| {
+        src.get(k..k + 6).is_some_and(|name| name.eq_ignore_ascii_case(b"script"))
+            && src.get(k + 6).is_some_and(|&b| is_ws(b) || b == b'\x0c' || b == b'/' || b == b'>')
+    };
+    let mut state = State::Code;
+    // Last non-white-space byte of code, which tells a regex `/` from a division
+    let mut prev = b';';
+    // The script data escaped and double escaped states
+    let (mut escaped, mut double) = (false, false);
+    let mut j = i;
+    while j < src.len() {
+        let b = src[j];
+        let next = src.get(j + 1).copied();
+        if b == b'<' {
+            if src[j..].starts_with(b"<!--") {
+                escaped = true;
+            } else if next == Some(b'/') && script_at(j + 2) {
+                if double {
+                    double = false;
+                } else if state == State::Code {
+                    return find_tag_end(src, j).map(|end| (j, end));
+                }
+            } else if escaped && script_at(j + 1) {
+                double = true;
+            }
+        } else if escaped && src[j..].starts_with(b"-->") {
+            (escaped, double) = (false, false);
+            // As in `//--></script>`, the escape's end ends any comment it is in.
+            state = State::Code;
+            j += 3;
+            continue;
+        }
+        if !javascript {
+            j += 1;
+            continue;
+        }
+        match state {
+            State::Code => match b {
+                b'\'' | b'"' | b'`' => state = State::Quote(b),
+                b'/' if next == Some(b'/') => state = State::LineComment,
+                b'/' if next == Some(b'*') => {
+                    state = State::BlockComment;
+                    j += 1;
+                }
+                b'/' if b"(,=:[!&|?{};+-*%<>~^".contains(&prev) => state = State::Regex(false),
+                _ => {}
+            },
+            State::Quote(q) => {
+                if b == b'\\' {
+                    j += 1;
+                } else if b == q || (b == b'\n' && q != b'`') {
+                    state = State::Code;
+                }
+            }
+            State::Regex(in_class) => match b {
+                b'\\' => j += 1,
+                b'[' => state = State::Regex(true),
+                b']' => state = State::Regex(false),
+                b'/' if !in_class => state = State::Code,
+                b'\n' => state = State::Code,
+                _ => {}
+            },
+            State::LineComment if b == b'\n' => state = State::Code,
+            State::BlockComment if b == b'*' && next == Some(b'/') => {
+                state = State::Code;
+                j += 1;
+            }
+            State::LineComment | State::BlockComment => {}
+        }
+        if state == State::Code && !is_ws(b) {
+            prev = b;
+        }
+        j += 1;
+    }
+    None
+}
+
+/// Whether a `<script>` start tag holds JavaScript (no `type`, a JavaScript MIME
+/// type or `module`), whose strings and comments `find_script_end` knows about.
+fn is_javascript(tag: &[u8]) -> bool {
+    let Some(ty) = tag_attr_value(tag, b"type") else { return true };
+    let ty = ty.trim_ascii().to_ascii_lowercase();
+    matches!(
+        ty.as_slice(),
+        b"" | b"module"
+            | b"text/javascript"
+            | b"application/javascript"
+            | b"application/ecmascript"
+            | b"application/x-javascript"
+            | b"text/ecmascript"
+            | b"text/x-javascript"
+    )
+}
+
+/// Rewrites the text of a raw-text or RCDATA element, given all of it.
+type Interior = fn(&[u8], &mut Vec<u8>);
+
+/// Copy bytes from `i` until the **matching** end tag `</name>` is found. For a
+/// `<script>`, `script` is `Some(javascript)` and `find_script_end` finds it.
+/// `interior` rewrites the text instead, if the end tag is found. In a `verbatim`
+/// region the end tag is copied as is too.
+/// Returns (new_index_after_end_tag, closed_found).
+#[allow(clippy::too_many_arguments)]
+fn copy_raw_text_until_end(
+    src: &[u8],
+    i: usize,
+    name: &[u8],
+    script: Option<bool>,
+    interior: Option<Interior>,
+    verbatim: bool,
+    out: &mut Vec<u8>,
+    stats: Option<&mut Stats>,
+) -> (usize, bool) {
+    let found = match script {
+        Some(true) => find_script_end(src, i, true).or_else(|| find_script_end(src, i, false)),
+        Some(false) => find_script_end(src, i, false),
+        None => find_end_tag(src, i, name),
+    };
+    let Some((pos, end)) = found else {
+        out.extend_from_slice(&src[i..]);
+        return (src.len(), false);
+    };
+    match interior {
+        Some(rewrite) => rewrite(&src[i..pos], out),
+        None => out.extend_from_slice(&src[i..pos]),
+    }
+    if verbatim {
+        out.extend_from_slice(&src[pos..=end]);
+        return (end + 1, true);
+    }
+    let start = out.len();
+    normalize_inside_tag(&src[pos..=end], out, false, None, false);
+    if let Some(stats) = stats {
+        stats.tags_normalized += usize::from(out[start..] != src[pos..=end]);
+    }
+    (end + 1, true)
+}
+
+/// RCDATA text is joined like plain text.
+fn reflow_rcdata(text: &[u8], out: &mut Vec<u8>) {
+    match std::str::from_utf8(text) {
+        Ok(text) => out.extend_from_slice(reflow_plain_text(text).as_bytes()),
+        Err(_) => out.extend_from_slice(text),
+    }
+}
+
+/// `--format-metadata`: tidy the text of a Bikeshed `<pre class=metadata>` block.
+/// The values of its `Key: value` lines start one column past the longest key's
+/// colon, trailing white space is trimmed and runs of blank lines become one.
+/// Indented continuation lines, the values themselves and the order are kept. Only
+/// whole lines are touched, not text on the lines of the start and end tags.
+fn format_metadata(text: &[u8], out: &mut Vec<u8>) {
+    let lines: Vec<&[u8]> = text.split(|&b| b == b'\n').collect();
+    let [first, body @ .., last] = &lines[..] else {
+        out.extend_from_slice(text);
+        return;
+    };
+    // The indentation of the keys; lines indented further continue a value.
+    let indent = body.iter().find(|l| !l.trim_ascii().is_empty()).map_or(&b""[..], |l| {
+        &l[..l.iter().position(|&b| !is_ws(b)).unwrap_or(l.len())]
+    });
+    let key_value = |line: &[u8]| -> Option<(usize, usize)> {
+        let rest = line.strip_prefix(indent)?;
+        if rest.first().is_none_or(|&b| is_ws(b)) {
+            return None;
+        }
+        let colon = memchr(b':', rest)?;
+        Some((indent.len() + colon, columns(&rest[..colon])))
+    };
+    let width = body.iter().filter_map(|l| key_value(l)).map(|(_, w)| w).max().unwrap_or(0);
+    out.extend_from_slice(first);
+    let mut blank = false;
+    for line in body {
+        let line = line.trim_ascii_end();
+        if line.is_empty() && blank {
+            continue;
+        }
+        blank = line.is_empty();
+        out.push(b'\n');
+        match key_value(line) {
+            Some((colon, key_width)) => {
+                out.extend_from_slice(&line[..=colon]);
+                let value = line[colon + 1..].trim_ascii_start();
+                if !value.is_empty() {
+                    out.resize(out.len() + width - key_width + 1, b' ');
+                    out.extend_from_slice(value);
+                }
+            }
+            None => out.extend_from_slice(line),
+        }
+    }
+    out.push(b'\n');
+    out.extend_from_slice(last);
+}
+
+/* ========================== Text chunk handling ========================= */
+
+fn classify_ahead(src: &[u8], next_lt: usize) -> (bool, bool, Option<TagInfo<'_>>) {
+    if next_lt >= src.len() { return (false, false, None); }
+    if src[next_lt..].starts_with(b"<!--") {
+        let (end, standalone) = scan_comment(src, next_lt);
+        if end == usize::MAX { return (false, false, None); }
+        return (standalone, !standalone, None);
+    }
+    if src[next_lt..].starts_with(b"<![CDATA[") {
+        let (end, standalone) = scan_cdata(src, next_lt);
+        if end == usize::MAX { return (false, false, None); }
+        return (standalone, !standalone, None);
+    }
+    if scan_conditional_comment(src, next_lt).is_some() {
+        // A boundary wherever it is, like a structural tag.
+        return (true, false, None);
+    }
+    if let Some((_, end)) = scan_unsupported_construct(src, next_lt) {
+        // Copied verbatim like a comment, so give it the same boundary treatment.
+        let standalone = is_standalone_span(src, next_lt, end);
+        return (standalone, !standalone, None);
+    }
+    if starts_doctype(&src[next_lt..]) {
+        let (end, _) = scan_doctype(src, next_lt);
+        if end == usize::MAX { return (false, false, None); }
+        let standalone = is_standalone_span(src, next_lt, end);
+        return (standalone, !standalone, None);
+    }
+    if starts_bogus_comment(&src[next_lt..]) {
+        let Some(end) = memchr(b'>', &src[next_lt..]).map(|p| next_lt + p + 1) else { return (false, false, None) };
+        let standalone = is_standalone_span(src, next_lt, end);
+        return (standalone, !standalone, None);
+    }
+    if src[next_lt..].starts_with(b"<?") {
+        let (end, standalone) = scan_processing_instruction(src, next_lt);
+        if end == usize::MAX { return (false, false, None); }
+        return (standalone, !standalone, None);
+    }
+    if src[next_lt] == b'<' {
+        if let Some(j) = find_tag_end(src, next_lt) {
+            let ti = parse_tag_info(&src[next_lt..=j]);
+            return (false, false, Some(ti));
+        }
+    }
+    (false, false, None)
+}
+
+/// The last line of `out[from..]`, without its newline.
+fn last_line(out: &[u8], from: usize) -> &[u8] {
+    let start = memrchr(b'\n', &out[from..]).map_or(from, |p| from + p + 1);
+    &out[start..]
+}
+
+/// `--tag-per-line`: if the last line of `out[from..]` has more than indentation on
+/// it, drop its trailing spaces and start a new line with the same indentation.
+fn break_line(out: &mut Vec<u8>, from: usize) {
+    let line = last_line(out, from);
+    if line.iter().all(|&b| is_ws(b)) {
+        return;
+    }
+    let indent = line[..line.iter().take_while(|&&b| is_space_tab(b)).count()].to_vec();
+    while out.last().is_some_and(|&b| is_space_tab(b)) {
+        out.pop();
+    }
+    out.push(b'\n');
+    out.extend_from_slice(&indent);
+}
+
+/// `--max-blank-lines`: in the reflowed text chunk at `out[start..]`, collapse runs
+/// of more than `max` blank lines in the leading and trailing whitespace (all of it
+/// for a whitespace-only chunk), keeping the indentation of the line that follows.
+fn collapse_blank_lines(out: &mut Vec<u8>, start: usize, max: usize) {
+    let chunk = &out[start..];
+    let lead = chunk.iter().take_while(|&&b| is_ws(b)).count();
+    let trail = if lead == chunk.len() { 0 } else { chunk.iter().rev().take_while(|&&b| is_ws(b)).count() };
+    let too_many = |ws: &[u8]| memchr::memchr_iter(b'\n', ws).count() > max + 1;
+    if !too_many(&chunk[..lead]) && !too_many(&chunk[chunk.len() - trail..]) {
+        return;
+    }
+    let tail = out.split_off(out.len() - trail);
+    let body = out.split_off(start + lead);
+    let head = out.split_off(start);
+    push_collapsed(out, &head, max);
+    out.extend_from_slice(&body);
+    push_collapsed(out, &tail, max);
+}
+
+/// `--strip-trailing-whitespace`: remove the spaces and tabs before each newline of
+/// the reflowed text at `out[start..]`, and at its end if it ends the document.
+/// With `markdown`, two or more spaces after text are a hard line break and stay.
+fn strip_trailing_whitespace(out: &mut Vec<u8>, start: usize, at_end: bool, markdown: bool) {
+    let text = out.split_off(start);
+    // The first line may continue a line of the output (after a tag).
+    let mut line_has_text = start > 0 && out[start - 1] != b'\n';
+    let mut lines = text.split_inclusive(|&b| b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        let (content, newline) = match line.strip_suffix(b"\n") {
+            Some(content) => (content, true),
+            None => (line, false),
+        };
+        let end = content.len() - content.iter().rev().take_while(|&&b| b == b' ' || b == b'\t').count();
+        let hard_break = markdown
+            && (end > 0 || line_has_text)
+            && content.len() - end >= 2
+            && content[end..].iter().all(|&b| b == b' ');
+        if (newline || (at_end && lines.peek().is_none())) && !hard_break {
+            out.extend_from_slice(&content[..end]);
+        } else {
+            out.extend_from_slice(content);
+        }
+        if newline {
+            out.push(b'\n');
+        }
+        line_has_text = false;
+    }
+}
+
+/// `--indent`: rewrite the leading whitespace of the lines in `out[start..]` in the
+/// `indent` style, keeping their width so that continuation lines stay aligned.
+/// The first line is only converted if it starts a line (`line_start`). With a
+/// `fence` to track (Markdown reflow), fenced code blocks are left as they are.
+fn convert_indentation(out: &mut Vec<u8>, start: usize, line_start: bool, indent: Indent, mut fence: Option<&mut Option<Fence>>) {
+    let text = out.split_off(start);
+    for (k, line) in text.split_inclusive(|&b| b == b'\n').enumerate() {
+        if k == 0 && !line_start {
+            out.extend_from_slice(line);
+            continue;
+        }
+        if let (Some(fence), Ok(s)) = (fence.as_deref_mut(), std::str::from_utf8(line)) {
+            let s = s.strip_suffix('\n').unwrap_or(s);
+            // The fence lines themselves are kept too.
+            let in_code = match *fence {
+                Some(f) => {
+                    if fence_close(s, f) {
+                        *fence = None;
+                    }
+                    true
+                }
+                None => {
+                    *fence = fence_open(s);
+                    fence.is_some()
+                }
+            };
+            if in_code {
+                out.extend_from_slice(line);
+                continue;
+            }
+        }
+        let lead = line.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+        let (Indent::Tabs(tab) | Indent::Spaces(tab)) = indent;
+        let columns = line[..lead].iter().fold(0, |col, &b| if b == b'\t' { col / tab * tab + tab } else { col + 1 });
+        match indent {
+            Indent::Tabs(_) => {
+                out.resize(out.len() + columns / tab, b'\t');
+                out.resize(out.len() + columns % tab, b' ');
+            }
+            Indent::Spaces(_) => out.resize(out.len() + columns, b' '),
+        }
+        out.extend_from_slice(&line[lead..]);
+    }
+}
+
+/// Copy the whitespace `ws`, keeping at most `max` blank lines in it.
+fn push_collapsed(out: &mut Vec<u8>, ws: &[u8], max: usize) {
+    // The first newline ends the current line; each further one ends a blank line.
+    match (memchr(b'\n', ws), memrchr(b'\n', ws)) {
+        (Some(first), Some(last)) if memchr::memchr_iter(b'\n', ws).count() > max + 1 => {
+            out.extend_from_slice(&ws[..first]);
+            out.resize(out.len() + max + 1, b'\n');
+            out.extend_from_slice(&ws[last + 1..]);
+        }
+        _ => out.extend_from_slice(ws),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn reflow_text_chunk(
+    chunk: &[u8],
+    src: &[u8],
+    next_lt: usize,
+    out: &mut Vec<u8>,
+    opts: &Options,
+    after_boundary: bool,
+    after_br: bool,
+    at_index_i: usize,
+) {
+    let use_markdown = opts.markdown;
+    let (ahead_is_standalone_comment, ahead_is_inline_comment, ahead_tag) = classify_ahead(src, next_lt);
+
+    let chunk_is_ws_only = chunk.iter().all(|&b| is_ws(b));
+    if chunk_is_ws_only {
+        // If we just emitted a structural boundary (including a standalone comment)
+        // or a <br>, preserve the whitespace verbatim. Standalone comments are
+        // structural on BOTH sides, so the immediately following newline must stay.
+        if after_boundary || after_br {
+            out.extend_from_slice(chunk);
+            return;
+        }
+
+        if next_lt < src.len() {
+            if ahead_is_standalone_comment {
+                out.extend_from_slice(chunk);
+            } else if ahead_is_inline_comment {
+                if has_single_lf(chunk) {
+                    if prev_line_ends_with_structural_start(src, next_lt, opts) {
+                        out.extend_from_slice(chunk);
+                    } else {
+                        out.push(b' ');
+                    }
+                } else {
+                    out.extend_from_slice(chunk);
+                }
+            } else if let Some(ti) = ahead_tag {
+                let structural_ahead = opts.is_structural(ti.name);
+                if structural_ahead {
+                    out.extend_from_slice(chunk);
+                } else if !ti.is_end && opts.is_inline(ti.name) {
+                    if has_single_lf(chunk) {
+                        if prev_line_ends_with_structural_start(src, next_lt, opts) {
+                            out.extend_from_slice(chunk);
+                        } else {
+                            out.push(b' ');
+                        }
+                    } else {
+                        out.extend_from_slice(chunk);
+                    }
+                } else {
+                    out.extend_from_slice(chunk);
+                }
+            } else {
+                out.extend_from_slice(chunk);
+            }
+        } else {
+            out.extend_from_slice(chunk);
+        }
+        return;
+    }
+
+    // Non-whitespace chunk
+    let mut preserve_trailing_suffix = false;
+    if next_lt < src.len() {
+        if ahead_is_standalone_comment {
+            preserve_trailing_suffix = true;
+        } else if let Some(ti) = ahead_tag {
+            if opts.is_structural(ti.name) {
+                preserve_trailing_suffix = true;
+            }
+        }
+    }
+
+    // If the line that contains `next_lt` (often a DT/DD line) begins with : or ::, keep suffix.
+    let boundary_end = at_index_i + chunk.len();
+    if use_markdown && line_at_pos_starts_with_dt_or_dd(src, boundary_end) {
+        preserve_trailing_suffix = true;
+    }
+
+    let preserve_leading_prefix = after_boundary || after_br;
+
+    if preserve_leading_prefix || preserve_trailing_suffix {
+        // prefix: leading whitespace
+        let mut left = 0usize;
+        if preserve_leading_prefix {
+            while left < chunk.len() && is_ws(chunk[left]) { left += 1; }
+            out.extend_from_slice(&chunk[..left]);
+        }
+        // suffix: ALL trailing whitespace (preserve exactly before structural/comment/DT/DD)
+        let mut idx = chunk.len();
+        while idx > left && is_ws(chunk[idx - 1]) {
+            idx -= 1;
+        }
+        let suffix_start = idx;
+        let body = &chunk[left..suffix_start];
+
+        if !body.is_empty() {
+            // SPECIAL: Keep DT/DD on their own line when body starts with "\n" + indent + ":"[":"]
+            if use_markdown {
+                if let Some(indent_end) = leading_lf_indent_end_before_dt_or_dd(body) {
+                    // Emit "\n" + indentation
+                    out.push(b'\n');
+                    out.extend_from_slice(&body[1..indent_end]); // indentation
+                    let rest = std::str::from_utf8(&body[indent_end..]).unwrap();
+                    let reflowed = reflow_text(rest, opts);
+                    out.extend_from_slice(reflowed.as_bytes());
+                } else if body.starts_with(b"\n") && (body.len() == 1 || body[1] != b'\n')
+                    && !prev_line_ends_with_structural_start(src, at_index_i, opts)
+                    && !after_br && !after_boundary
+                    && !(use_markdown && body_begins_with_md_block_after_single_lf(body))
+                {
+                    // Soft wrap single LF → space
+                    let mut j = 1usize;
+                    while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
+                    let rest = std::str::from_utf8(&body[j..]).unwrap();
+                    let mut body_str = String::with_capacity(1 + rest.len());
+                    body_str.push(' ');
+                    body_str.push_str(rest);
+                    let reflowed = reflow_text(&body_str, opts);
+                    out.extend_from_slice(reflowed.as_bytes());
+                } else {
+                    let body_str = std::str::from_utf8(body).unwrap();
+                    let reflowed = reflow_text(body_str, opts);
+                    out.extend_from_slice(reflowed.as_bytes());
+                }
+            } else {
+                // Plain text mode
+                if body.starts_with(b"\n") && (body.len() == 1 || body[1] != b'\n')
+                    && !prev_line_ends_with_structural_start(src, at_index_i, opts)
+                    && !after_br && !after_boundary
+                {
+                    let mut j = 1usize;
+                    while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
+                    let rest = std::str::from_utf8(&body[j..]).unwrap();
+                    let mut body_str = String::with_capacity(1 + rest.len());
+                    body_str.push(' ');
+                    body_str.push_str(rest);
+                    let reflowed = reflow_text(&body_str, opts);
+                    out.extend_from_slice(reflowed.as_bytes());
+                } else {
+                    let body_str = std::str::from_utf8(body).unwrap();
+                    let reflowed = reflow_text(body_str, opts);
+                    out.extend_from_slice(reflowed.as_bytes());
+                }
+            }
+        }
+
+        if preserve_trailing_suffix {
+            out.extend_from_slice(&chunk[suffix_start..]); // preserve spaces/newlines before DT/DD/comment/structural
+        } else if (ahead_tag.is_some_and(|ti| !ti.is_end && opts.is_inline(ti.name)) || ahead_is_inline_comment) && suffix_start < chunk.len() {
+            out.push(b' ');
+        }
+        return;
+    }
+
+    // Preserve non-newline edge spaces around tags:
+    let mut lead_len = 0usize;
+    while lead_len < chunk.len() && is_space_tab(chunk[lead_len]) { lead_len += 1; }
+    let mut trail_len = 0usize;
+    while trail_len < chunk.len() && is_space_tab(chunk[chunk.len() - 1 - trail_len]) {
+        trail_len += 1;
+    }
+    let body = &chunk[lead_len..chunk.len() - trail_len];
+
+    // SPECIAL: DT/DD must start on a new line — emit the newline + indentation, then reflow the rest.
+    if use_markdown {
+        if let Some(indent_end) = leading_lf_indent_end_before_dt_or_dd(body) {
+            out.extend_from_slice(&chunk[..lead_len]); // leading spaces (no newlines here)
+            out.push(b'\n');
+            out.extend_from_slice(&body[1..indent_end]); // indentation
+            let rest = std::str::from_utf8(&body[indent_end..]).unwrap();
+            let reflowed = reflow_text(rest, opts);
+            out.extend_from_slice(reflowed.as_bytes());
+            out.extend_from_slice(&chunk[chunk.len() - trail_len..]);
+            return;
+        }
+    }
+
+    // Soft-wrap at start-of-body — but NOT if that newline introduces a DT/DD line.
+    let mut tmp = String::new();
+    let body_str = if body.starts_with(b"\n") && (body.len() == 1 || body[1] != b'\n')
+        && !prev_line_ends_with_structural_start(src, at_index_i, opts)
+        && !after_br && !after_boundary
+        && !(use_markdown && body_begins_with_md_block_after_single_lf(body))
+    {
+        let mut j = 1usize;
+        while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
+        let rest = std::str::from_utf8(&body[j..]).unwrap();
+        tmp.push(' ');
+        tmp.push_str(rest);
+        &tmp
+    } else {
+        std::str::from_utf8(body).unwrap()
+    };
+
+    let mut reflowed = reflow_text(body_str, opts);
+
+    // If this chunk ends with exactly one LF (ignoring spaces) and next token is inline-start,
+    // collapse that single LF (+ indent) to a single space (unless prev line ended with structural start).
+    let trailing_lfs = trailing_lf_count_ignoring_spaces(chunk);
+    if let Some(ti) = ahead_tag {
+        if !ti.is_end && opts.is_inline(ti.name) && trailing_lfs == 1
+            && !prev_line_ends_with_structural_start(src, at_index_i + chunk.len(), opts)
+        {
+            while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
+            if reflowed.ends_with('\n') {
+                reflowed.pop();
+                while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
+            }
+            out.extend_from_slice(&chunk[..lead_len]); // leading spaces
+            out.extend_from_slice(reflowed.as_bytes());
+            out.push(b' ');
+            return;
+        }
+    } else if ahead_is_inline_comment {
+        if trailing_lfs == 1 && !prev_line_ends_with_structural_start(src, at_index_i + chunk.len(), opts) {
+            while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
+            if reflowed.ends_with('\n') {
+                reflowed.pop();
+                while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
+            }
+            out.extend_from_slice(&chunk[..lead_len]);
+            out.extend_from_slice(reflowed.as_bytes());
+            out.push(b' ');
+            return;
+        }
+    } else if ahead_tag.is_none() && !ahead_is_standalone_comment
+        && trailing_lfs == 1 && !prev_line_ends_with_structural_start(src, at_index_i + chunk.len(), opts)
+    {
+        while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
+        if reflowed.ends_with('\n') {
+            reflowed.pop();
+            while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
+        }
+        out.extend_from_slice(&chunk[..lead_len]);
+        out.extend_from_slice(reflowed.as_bytes());
+        return;
+    }
+
+    out.extend_from_slice(&chunk[..lead_len]);
+    out.extend_from_slice(reflowed.as_bytes());
+    out.extend_from_slice(&chunk[chunk.len() - trail_len..]);
+}
+
+/* ============================== Transform =============================== */
+
+/// Markdown-looking lines seen in text, used to warn about a likely wrong Markdown mode.
+#[derive(Default)]
+struct ModeHints {
+    lines: usize,
+    /// Offset and kind of the first one
+    first: Option<(usize, &'static str)>,
+}
+
+impl ModeHints {
+    /// Number of Markdown-looking lines needed before suggesting `--markdown`.
+    const THRESHOLD: usize = 2;
+
+    /// Look at the lines of the text `chunk` found at `start` in `src`.
+    fn scan(&mut self, src: &[u8], start: usize, chunk: &[u8]) {
+        let at_line_start = start == 0 || src[start - 1] == b'\n';
+        let mut offset = start;
+        for (idx, line) in chunk.split(|&b| b == b'\n').enumerate() {
+            if idx > 0 || at_line_start {
+                if let Some(kind) = std::str::from_utf8(line).ok().and_then(markdown_construct) {
+                    self.lines += 1;
+                    self.first.get_or_insert((offset, kind));
+                }
+            }
+            offset += line.len() + 1;
+        }
+    }
+
+    fn diagnostic(&self, use_markdown: bool) -> Option<Diagnostic> {
+        match (use_markdown, self.first) {
+            (false, Some((offset, kind))) if self.lines >= Self::THRESHOLD => Some(Diagnostic {
+                offset,
+                check: Check::MarkdownLikely,
+                message: format!(
+                    "text looks like Markdown ({kind} and {} more); pass --markdown if this is a Bikeshed document",
+                    self.lines - 1
+                ),
+            }),
+            (true, None) => Some(Diagnostic {
+                offset: 0,
+                check: Check::MarkdownUnused,
+                message: "Markdown mode is on but no Markdown constructs were found; pass --no-markdown if this is plain HTML"
+                    .to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of Markdown block construct `line` starts with, if any.
+fn markdown_construct(line: &str) -> Option<&'static str> {
+    if fence_open(line).is_some() {
+        Some("fenced code block")
+    } else if parse_dd(line).is_some() || parse_dt(line).is_some() {
+        Some("definition list marker")
+    } else if starts_with_bullet(line).is_some_and(|(_, rest)| !rest.is_empty()) {
+        Some("bullet list item")
+    } else if starts_with_ol(line).is_some() {
+        Some("numbered list item")
+    } else if is_atx_heading(line) {
+        Some("heading")
+    } else {
+        None
+    }
+}
+
+#[derive(Clone)]
+struct OpenElement {
+    name: Vec<u8>,
+    has_noreformat: bool,
+}
+
+/// Counts of what `transform` did to a document. Only text and tags that are
+/// actually reformatted are counted, never verbatim regions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Tags whose interior whitespace changed
+    pub tags_normalized: usize,
+    /// Text runs between tags that went through reflow
+    pub text_chunks_reflowed: usize,
+    /// Line breaks removed by text reflow
+    pub lines_joined: usize,
+    /// Inline comments whose contents changed
+    pub comments_reflowed: usize,
+}
+
+impl Stats {
+    /// Add the counts of `other` to these.
+    pub fn add(&mut self, other: &Stats) {
+        self.tags_normalized += other.tags_normalized;
+        self.text_chunks_reflowed += other.text_chunks_reflowed;
+        self.lines_joined += other.lines_joined;
+        self.comments_reflowed += other.comments_reflowed;
+    }
+}
+
+/// Reformat `src` into `out` (which is appended to) according to `opts`, and
+/// return what the transform found worth telling the user, in input order.
+/// Where a tag, comment or raw-text element doesn't end, the rest of `src` is
+/// copied as is and an `unterminated-*` diagnostic says where.
+pub fn transform(src: &[u8], out: &mut Vec<u8>, opts: &Options) -> Vec<Diagnostic> {
+    transform_counted(src, out, opts, None)
+}
+
+/// `transform`, also counting what was done into `stats` if given. Without
+/// stats, no extra work is done.
+pub fn transform_counted(src: &[u8], out: &mut Vec<u8>, opts: &Options, stats: Option<&mut Stats>) -> Vec<Diagnostic> {
+    transform_with(src, out, opts, stats, None)
+}
+
+/// `transform`, counting into `stats` and, with a `window`, only reformatting
+/// the tokens that overlap it.
+pub fn transform_with(
+    src: &[u8],
+    out: &mut Vec<u8>,
+    opts: &Options,
+    stats: Option<&mut Stats>,
+    window: Option<&mut Window>,
+) -> Vec<Diagnostic> {
+    transform_anchored(src, out, opts, stats, window, None)
+}
+
+/// `transform_with`, also returning where each token of `src` ended up in `out`.
+pub fn transform_mapped(
+    src: &[u8],
+    out: &mut Vec<u8>,
+    opts: &Options,
+    stats: Option<&mut Stats>,
+    window: Option<&mut Window>,
+) -> (Vec<Diagnostic>, SourceMap) {
+    let mut starts = Vec::new();
+    let diagnostics = transform_anchored(src, out, opts, stats, window, Some(&mut starts));
+    (diagnostics, SourceMap::new(src, out, starts))
+}
+
+/// `transform_with`, recording the offsets in `src` and `out` of every token start
+/// into `anchors` if given.
+fn transform_anchored(
+    src: &[u8],
+    out: &mut Vec<u8>,
+    opts: &Options,
+    stats: Option<&mut Stats>,
+    mut window: Option<&mut Window>,
+    mut anchors: Option<&mut Vec<(usize, usize)>>,
+) -> Vec<Diagnostic> {
+    // A UTF-8 byte order mark is copied as is, and the document is formatted as
+    // if it started right after it. Diagnostic offsets still refer to `src`.
+    if let Some(rest) = src.strip_prefix(UTF8_BOM) {
+        let bom = UTF8_BOM.len();
+        let first = anchors.as_deref_mut().map(|a| {
+            a.push((0, out.len()));
+            a.len()
+        });
+        out.extend_from_slice(UTF8_BOM);
+        if let Some(w) = window.as_deref_mut() {
+            w.map_requested(|o| o.saturating_sub(bom));
+        }
+        let mut diagnostics = transform_anchored(rest, out, opts, stats, window.as_deref_mut(), anchors.as_deref_mut());
+        for d in &mut diagnostics {
+            d.offset += bom;
+        }
+        if let (Some(a), Some(first)) = (anchors, first) {
+            for (input, _) in &mut a[first..] {
+                *input += bom;
+            }
+        }
+        if let Some(w) = window {
+            w.map_requested(|o| o + bom);
+            w.input = w.input.take().map(|r| r.start + bom..r.end + bom);
+        }
+        return diagnostics;
+    }
+
+    // The document is formatted with LF line endings; the output then gets the
+    // requested ones, or those of the first line, everywhere (verbatim regions too).
+    let crlf = match opts.newline {
+        Some(newline) => newline.is_crlf(),
+        None => memchr(b'\n', src).is_some_and(|i| i > 0 && src[i - 1] == b'\r'),
+    };
+    if !crlf && memchr(b'\r', src).is_none() {
+        return transform_lf(src, out, opts, stats, window, None, anchors);
+    }
+    let requested = window.as_deref().map(|w| w.requested.clone());
+    let (src, crlf_at) = crlf_to_lf(src);
+    // Offsets in the input map to the LF version by dropping the CRs before them,
+    // and back by adding them again.
+    let cr_at: Vec<usize> = crlf_at.iter().enumerate().map(|(k, &p)| p + k).collect();
+    let to_lf = |o: usize| o - cr_at.partition_point(|&c| c < o);
+    let from_lf = |o: usize| o + crlf_at.partition_point(|&p| p < o);
+    if let Some(w) = window.as_deref_mut() {
+        w.map_requested(to_lf);
+    }
+    let mut formatted = Vec::with_capacity(src.len() + src.len() / 20);
+    let first = anchors.as_deref().map_or(0, Vec::len);
+    let mut diagnostics = transform_lf(&src, &mut formatted, opts, stats, window.as_deref_mut(), None, anchors.as_deref_mut());
+    let out_base = out.len();
+    if crlf {
+        let mut rest = &formatted[..];
+        while let Some(nl) = memchr(b'\n', rest) {
+            out.extend_from_slice(&rest[..nl]);
+            out.extend_from_slice(b"\r\n");
+            rest = &rest[nl + 1..];
+        }
+        out.extend_from_slice(rest);
+    } else {
+        out.extend_from_slice(&formatted);
+    }
+    for d in &mut diagnostics {
+        d.offset = from_lf(d.offset);
+    }
+    if let Some(a) = anchors {
+        // Anchors are in order, so the newlines before each are counted once.
+        let (mut counted, mut newlines) = (0, 0);
+        for (input, output) in &mut a[first..] {
+            if crlf {
+                newlines += memchr::memchr_iter(b'\n', &formatted[counted..*output]).count();
+                counted = *output;
+            }
+            *input = from_lf(*input);
+            *output += out_base + newlines;
+        }
+    }
+    if let (Some(w), Some(r)) = (window, requested) {
+        let to_out = |o: usize| out_base + o + if crlf { memchr::memchr_iter(b'\n', &formatted[..o]).count() } else { 0 };
+        w.requested = r;
+        w.input = w.input.take().map(|r| from_lf(r.start)..from_lf(r.end));
+        w.output = w.output.take().map(|r| to_out(r.start)..to_out(r.end));
+    }
+    diagnostics
+}
+
+/// Reformat only the part of `src` that covers `range`, as an editor does for
+/// "format selection". The range is widened to the tags and text chunks it
+/// touches, and the whole prefix is scanned so raw text and `data-noreformat`
+/// subtrees are recognized. Returns the replacement bytes and the range of `src`
+/// they replace, or `None` if nothing there gets reformatted.
+pub fn format_range(src: &[u8], range: Range<usize>, opts: &Options) -> Option<(Vec<u8>, Range<usize>)> {
+    let mut window = Window::new(vec![range]);
+    let mut out = Vec::with_capacity(src.len() + src.len() / 20);
+    transform_with(src, &mut out, opts, None, Some(&mut window));
+    Some((out[window.output?].to_vec(), window.input?))
+}
+
+/// The parts of the input to reformat (`--lines`, `--range`, `--changed`); tokens
+/// that don't overlap them are copied verbatim, though the whole input is scanned
+/// to know the context.
+pub struct Window {
+    /// Requested byte ranges of the input
+    pub requested: Vec<Range<usize>>,
+    /// Input bytes actually reformatted: from the first to the last token (text
+    /// chunk, tag, comment) that a requested range touches. `None` if they touch none.
+    pub input: Option<Range<usize>>,
+    /// Where the replacement for `input` is in the output
+    pub output: Option<Range<usize>>,
+}
+
+impl Window {
+    /// A window over the `requested` byte ranges of the input.
+    pub fn new(requested: Vec<Range<usize>>) -> Window {
+        Window { requested, input: None, output: None }
+    }
+
+    fn map_requested(&mut self, f: impl Fn(usize) -> usize) {
+        for r in &mut self.requested {
+            *r = f(r.start)..f(r.end);
+        }
+    }
+
+    /// Called once `tok` of `src` has been transformed into `out[out_start..]`:
+    /// outside the window, the original bytes are put back.
+    fn token(&mut self, src: &[u8], tok: Range<usize>, out: &mut Vec<u8>, out_start: usize) {
+        if tok.is_empty() {
+            return;
+        }
+        // An empty request still selects the token it points into.
+        let overlaps = |wanted: &Range<usize>| tok.start < wanted.end.max(wanted.start + 1) && wanted.start < tok.end;
+        if self.requested.iter().any(overlaps) {
+            self.input = Some(self.input.take().map_or(tok.start, |r| r.start)..tok.end);
+            self.output = Some(self.output.take().map_or(out_start, |r| r.start)..out.len());
+        } else {
+            out.truncate(out_start);
+            out.extend_from_slice(&src[tok]);
+        }
+    }
+}
+
+/// Where the tokens of the input (tags, comments, text chunks, raw text) start in
+/// the output of `transform_mapped`, to map offsets between the two. Output
+/// offsets are those in the `out` buffer, which may have held something before.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    anchors: Vec<Anchor>,
+}
+
+/// The start of a token in the input and in the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Anchor {
+    pub input: usize,
+    pub output: usize,
+    /// The token is the same in the output, so offsets inside it map one to one;
+    /// inside a rewritten token, every offset maps to the start of the other side.
+    pub exact: bool,
+}
+
+impl SourceMap {
+    /// From the token starts recorded while transforming `src` into `out`.
+    fn new(src: &[u8], out: &[u8], starts: Vec<(usize, usize)>) -> SourceMap {
+        let ends = starts.iter().skip(1).copied().chain([(src.len(), out.len())]);
+        let anchors = starts
+            .iter()
+            .zip(ends)
+            .map(|(&(input, output), (input_end, output_end))| Anchor {
+                input,
+                output,
+                exact: out.get(output..output_end.max(output)) == Some(&src[input..input_end]),
+            })
+            .collect();
+        SourceMap { anchors }
+    }
+
+    pub fn anchors(&self) -> &[Anchor] {
+        &self.anchors
+    }
+
+    /// The input offset that produced output offset `output`.
+    pub fn map_to_input(&self, output: usize) -> usize {
+        let k = self.anchors.partition_point(|a| a.output <= output);
+        match k.checked_sub(1).map(|k| self.anchors[k]) {
+            Some(a) if a.exact => a.input + (output - a.output),
+            Some(a) => a.input,
+            None => 0,
+        }
+    }
+
+    /// The output offset that input offset `input` ended up at.
+    pub fn map_to_output(&self, input: usize) -> usize {
+        let k = self.anchors.partition_point(|a| a.input <= input);
+        match k.checked_sub(1).map(|k| self.anchors[k]) {
+            Some(a) if a.exact => a.output + (input - a.input),
+            Some(a) => a.output,
+            None => 0,
+        }
+    }
+}
+
+/// `src` with every CRLF turned into LF, and the offsets of those LFs in the result.
+fn crlf_to_lf(src: &[u8]) -> (Vec<u8>, Vec<usize>) {
+    let mut lf = Vec::with_capacity(src.len());
+    let mut crlf_at = Vec::new();
+    let mut rest = src;
+    while let Some(nl) = memchr(b'\n', rest) {
+        if nl > 0 && rest[nl - 1] == b'\r' {
+            lf.extend_from_slice(&rest[..nl - 1]);
+            crlf_at.push(lf.len());
+        } else {
+            lf.extend_from_slice(&rest[..nl]);
+        }
+        lf.push(b'\n');
+        rest = &rest[nl + 1..];
+    }
+    lf.extend_from_slice(rest);
+    (lf, crlf_at)
+}
+
+/// Bytes `reformat_stream` reads before it formats what it has.
+const STREAM_WINDOW: usize = 64 * 1024;
+
+/// Reformat what `reader` yields into `writer` according to `opts`, with the
+/// same result as `transform` on the whole input but holding only a bounded
+/// part of it in memory. The input is formatted a window at a time, up to the
+/// last tag at the start of a line outside raw text and `data-noreformat`
+/// subtrees; the rest is carried over to the next window, along with the
+/// elements open where it starts. A stretch without such a tag (a long `<pre>`,
+/// or a whole Markdown document) is buffered until it ends. Diagnostics are
+/// dropped.
+pub fn reformat_stream(reader: impl BufRead, writer: impl Write, opts: &Options) -> io::Result<()> {
+    reformat_stream_in(reader, writer, opts, STREAM_WINDOW)
+}
+
+fn reformat_stream_in(reader: impl BufRead, mut writer: impl Write, opts: &Options, window: usize) -> io::Result<()> {
+    let mut input = LfInput { reader, cr: false, first_crlf: None, eof: false };
+    // Unformatted input, LF only, starting where a transform can start.
+    let mut pending = Vec::new();
+    // Line endings are those of the first line unless requested, as in `transform_with`.
+    while !input.eof && opts.newline.is_none() && input.first_crlf.is_none() {
+        input.read(&mut pending, window)?;
+    }
+    let short = UTF8_BOM.len().saturating_sub(pending.len());
+    input.read(&mut pending, short)?;
+    if pending.starts_with(UTF8_BOM) {
+        writer.write_all(UTF8_BOM)?;
+        pending.drain(..UTF8_BOM.len());
+    }
+    let crlf = opts.newline.map_or(input.first_crlf == Some(true), Newline::is_crlf);
+
+    let mut out = Vec::new();
+    let mut resume = Resume::default();
+    let mut want = window;
+    loop {
+        input.read(&mut pending, want)?;
+        out.clear();
+        resume.cut = None;
+        transform_lf(&pending, &mut out, opts, None, None, Some(&mut resume), None);
+        if input.eof {
+            write_newlines(&mut writer, &out, crlf)?;
+            return writer.flush();
+        }
+        match resume.cut.take() {
+            Some((at, out_at, state)) => {
+                write_newlines(&mut writer, &out[..out_at], crlf)?;
+                pending.drain(..at);
+                resume.start = state;
+                want = window;
+            }
+            // Nowhere to stop yet: read more at once, so that a long stretch
+            // isn't formatted over and over.
+            None => want = want.saturating_mul(2),
+        }
+    }
+}
+
+/// Where `reformat_stream` restarts the transform.
+#[derive(Default)]
+struct Resume {
+    /// The state the input starts in
+    start: ResumeState,
+    /// The last token after the start of the input that the transform can restart
+    /// at: its offsets in `src` and `out`, and the state there
+    cut: Option<(usize, usize, ResumeState)>,
+}
+
+/// What a transform restarted at a tag needs to know about the input before it.
+#[derive(Clone, Default)]
+struct ResumeState {
+    /// The open elements, for the foreign content, `<template>` contents and
+    /// `data-noreformat` subtrees the rest is in
+    open_stack: Vec<OpenElement>,
+    after_br: bool,
+}
+
+/// The input of `reformat_stream`, with every CRLF turned into LF.
+struct LfInput<R> {
+    reader: R,
+    /// Whether the last byte read was a CR, not yet known to start a CRLF
+    cr: bool,
+    /// Whether the first line ended with CRLF, once it has been read
+    first_crlf: Option<bool>,
+    eof: bool,
+}
+
+impl<R: BufRead> LfInput<R> {
+    /// Append at least `want` more bytes to `buf`, unless the input ends first.
+    fn read(&mut self, buf: &mut Vec<u8>, want: usize) -> io::Result<()> {
+        let target = buf.len() + want;
+        while !self.eof && buf.len() < target {
+            let data = self.reader.fill_buf()?;
+            if data.is_empty() {
+                self.eof = true;
+                if self.cr {
+                    buf.push(b'\r');
+                }
+                break;
+            }
+            let mut rest = data;
+            if std::mem::take(&mut self.cr) {
+                if let Some(after) = rest.strip_prefix(b"\n") {
+                    self.first_crlf.get_or_insert(true);
+                    buf.push(b'\n');
+                    rest = after;
+                } else {
+                    buf.push(b'\r');
+                }
+            }
+            while let Some(nl) = memchr(b'\n', rest) {
+                let crlf = nl > 0 && rest[nl - 1] == b'\r';
+                self.first_crlf.get_or_insert(crlf);
+                buf.extend_from_slice(&rest[..nl - usize::from(crlf)]);
+                buf.push(b'\n');
+                rest = &rest[nl + 1..];
+            }
+            if let Some(head) = rest.strip_suffix(b"\r") {
+                self.cr = true;
+                rest = head;
+            }
+            buf.extend_from_slice(rest);
+            let len = data.len();
+            self.reader.consume(len);
+        }
+        Ok(())
+    }
+}
+
+/// Write `text`, with CRLF line endings if `crlf`.
+fn write_newlines(writer: &mut impl Write, text: &[u8], crlf: bool) -> io::Result<()> {
+    if !crlf {
+        return writer.write_all(text);
+    }
+    let mut rest = text;
+    while let Some(nl) = memchr(b'\n', rest) {
+        writer.write_all(&rest[..nl])?;
+        writer.write_all(b"\r\n")?;
+        rest = &rest[nl + 1..];
+    }
+    writer.write_all(rest)
+}
+
+/// The transform proper, on a document without CRLF line endings. With `resume`,
+/// from the state it holds, and recording the last token `reformat_stream` can
+/// restart at; with `anchors`, the offsets in `src` and `out` of every token.
+fn transform_lf(
+    src: &[u8],
+    out: &mut Vec<u8>,
+    opts: &Options,
+    mut stats: Option<&mut Stats>,
+    mut window: Option<&mut Window>,
+    mut resume: Option<&mut Resume>,
+    mut anchors: Option<&mut Vec<(usize, usize)>>,
+) -> Vec<Diagnostic> {
+    let out_start = out.len();
+    if opts.markdown_document {
+        if let Some(a) = anchors {
+            a.push((0, out_start));
+        }
+        let windowed = window.is_some();
+        if !transform_markdown_document(src, out, opts, stats, window) && !windowed {
+            fix_final_newlines(out, out_start, opts);
+        }
+        return Vec::new();
+    }
+    let mut i = 0usize;
+    let n = src.len();
+    let use_markdown = opts.markdown;
+    let mut diagnostics = Vec::new();
+
+    // Stacks/state
+    let mut raw_stack: Vec<Vec<u8>> = Vec::new();        // names of raw-text tags in lowercase
+    let mut raw_start = 0usize;                           // offset of the innermost one's start tag
+    let mut raw_interior: Option<Interior> = None;        // how its text is rewritten, if it is
+    let mut raw_script = None;                            // for a <script>, whether it holds JavaScript
+    let start = resume.as_ref().map(|r| r.start.clone()).unwrap_or_default();
+    let mut open_stack: Vec<OpenElement> = start.open_stack;
+    let mut after_boundary = false;
+    let mut after_br = start.after_br;
+    let mut structure = opts.lint_structure.then(StructureLint::default);
+    let mut mode_hints = ModeHints::default();
+    // Whether the input ended in a construct that was left open and copied as is
+    let mut verbatim_tail = false;
+    // Fenced code block open in the reflowed text, whose indentation `--indent` keeps
+    let mut fence: Option<Fence> = None;
+    // Offset of the `<!-- reformahtml off -->` comment whose region is being copied
+    let mut off: Option<usize> = None;
+    // Whether a `<!-- reformahtml-ignore -->` applies to the next element or text
+    let mut ignore_next = false;
+
+    // Start of the token being transformed, in `src` and `out`, for `window`.
+    let mut token = (i, out.len());
+    while i < n {
+        if let Some(w) = window.as_deref_mut() {
+            w.token(src, token.0..i, out, token.1);
+            token = (i, out.len());
+        }
+        if let Some(a) = anchors.as_deref_mut() {
+            a.push((i, out.len()));
+        }
+
+        // If inside a RAW-TEXT element, copy verbatim until its matching end tag.
+        if let Some(current_raw) = raw_stack.last() {
+            let verbatim = off.is_some() || open_stack.iter().any(|e| e.has_noreformat);
+            let (new_i, closed) = copy_raw_text_until_end(
+                src,
+                i,
+                current_raw,
+                raw_script,
+                raw_interior,
+                verbatim,
+                out,
+                stats.as_deref_mut(),
+            );
+            i = new_i;
+            after_boundary = false;
+            after_br = false;
+            if closed {
+                raw_stack.pop();
+                open_stack.pop();
+            }
+            continue;
+        }
+
+        // CDATA sections: verbatim, and a boundary on a line of their own like comments
+        if src[i..].starts_with(b"<![CDATA[") {
+            let (end, standalone) = scan_cdata(src, i);
+            if end == usize::MAX {
+                out.extend_from_slice(&src[i..]);
+                diagnostics.push(Diagnostic {
+                    offset: i,
+                    check: Check::UnterminatedCdata,
+                    message: "CDATA section is not closed; the rest of the file was copied verbatim".to_string(),
+                });
+                verbatim_tail = true;
+                break;
+            }
+            out.extend_from_slice(&src[i..end]);
+            ignore_next = false;
+            after_boundary = standalone;
+            after_br = false;
+            i = end;
+            continue;
+        }
+
+        // Conditional comments: verbatim, and a boundary on both sides even in the
+        // middle of a line, so the indentation of the markup around them survives
+        if let Some(end) = scan_conditional_comment(src, i) {
+            out.extend_from_slice(&src[i..end]);
+            ignore_next = false;
+            after_boundary = true;
+            after_br = false;
+            i = end;
+            continue;
+        }
+
+        // Partially supported constructs: verbatim + diagnostic unless best-effort was requested
+        if let Some((kind, end)) = scan_unsupported_construct(src, i) {
+            if off.is_some() || std::mem::take(&mut ignore_next) || !opts.best_effort.contains(&kind) {
+                out.extend_from_slice(&src[i..end]);
+                if off.is_none() && !open_stack.iter().any(|e| e.has_noreformat) {
+                    diagnostics.push(Diagnostic {
+                        offset: i,
+                        check: Check::VerbatimConstruct,
+                        message: format!("{} copied verbatim", kind.describe()),
+                    });
+                }
+                after_boundary = is_standalone_span(src, i, end);
+                after_br = false;
+                i = end;
+                continue;
+            }
+        }
+
+        // Processing instructions (`<?xml-stylesheet ...?>`): verbatim, and a boundary
+        // on a line of their own like comments
+        if src[i..].starts_with(b"<?") {
+            let (end, standalone) = scan_processing_instruction(src, i);
+            if end == usize::MAX {
+                out.extend_from_slice(&src[i..]);
+                diagnostics.push(Diagnostic {
+                    offset: i,
+                    check: Check::UnterminatedTag,
+                    message: "processing instruction is not closed with `?>`; the rest of the file was copied verbatim"
+                        .to_string(),
+                });
+                verbatim_tail = true;
+                break;
+            }
+            out.extend_from_slice(&src[i..end]);
+            ignore_next = false;
+            after_boundary = standalone;
+            after_br = false;
+            i = end;
+            continue;
+        }
+
+        // DOCTYPE: verbatim unless collapsed, and a boundary on a line of its own like comments
+        if starts_doctype(&src[i..]) {
+            let (end, subset) = scan_doctype(src, i);
+            if end == usize::MAX {
+                out.extend_from_slice(&src[i..]);
+                diagnostics.push(Diagnostic {
+                    offset: i,
+                    check: Check::UnterminatedTag,
+                    message: "DOCTYPE is not closed; the rest of the file was copied verbatim".to_string(),
+                });
+                verbatim_tail = true;
+                break;
+            }
+            let decl = &src[i..end];
+            let ignored = std::mem::take(&mut ignore_next);
+            let is_verbatim = off.is_some() || ignored || open_stack.iter().any(|e| e.has_noreformat);
+            if opts.collapse_doctype && !subset && !is_verbatim {
+                normalize_inside_tag(decl, out, false, None, false);
+            } else {
+                out.extend_from_slice(decl);
+            }
+            after_boundary = is_standalone_span(src, i, end);
+            after_br = false;
+            i = end;
+            continue;
+        }
+
+        // Comments
+        if src[i..].starts_with(b"<!--") {
+            let (end, standalone) = scan_comment(src, i);
+            if end == usize::MAX {
+                out.extend_from_slice(&src[i..]);
+                diagnostics.push(Diagnostic {
+                    offset: i,
+                    check: Check::UnterminatedComment,
+                    message: "comment is not closed; the rest of the file was copied verbatim".to_string(),
+                });
+                verbatim_tail = true;
+                break;
+            }
+            let seg = &src[i..end]; // includes "-->"
+            let is_verbatim = off.is_some() || open_stack.iter().any(|e| e.has_noreformat);
+            if seg.ends_with(b"--!>") && !is_verbatim {
+                diagnostics.push(Diagnostic {
+                    offset: end - 4,
+                    check: Check::IncorrectlyClosedComment,
+                    message: "comment is closed with `--!>`, which HTML accepts as a parse error; use `-->`".to_string(),
+                });
+            }
+            // A comment is the next thing after a `reformahtml-ignore` too.
+            let ignored = std::mem::take(&mut ignore_next);
+            let directive = if standalone { comment_directive(seg) } else { None };
+            if let Some(directive) = directive {
+                // Kept as is, and a boundary like any standalone comment.
+                out.extend_from_slice(seg);
+                match directive {
+                    CommentDirective::Off => {
+                        off.get_or_insert(i);
+                    }
+                    CommentDirective::On => off = None,
+                    CommentDirective::Ignore => ignore_next = !is_verbatim,
+                }
+                after_boundary = true;
+                after_br = false;
+            } else if is_verbatim || ignored {
+                out.extend_from_slice(seg);
+            } else if standalone {
+                out.extend_from_slice(seg);
+                after_boundary = true;
+            } else {
+                let start = out.len();
+                reflow_inline_comment(seg, out);
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats.comments_reflowed += usize::from(out[start..] != *seg);
+                }
+                after_boundary = false;
+                if opts.is_break_comment(seg) {
+                    // Explicit break token: keep an immediately following '\n'.
+                    i = end;
+                    if i < n && src[i] == b'\n' {
+                        out.push(b'\n');
+                        i += 1;
+                    }
+                    after_br = true;
+                    continue;
+                }
+            }
+            i = end;
+            continue;
+        }
+
+        // Bogus comments (`<!foo>`, `</ >`, `</%x>`): verbatim up to the next `>`,
+        // and a boundary on a line of their own like comments
+        if starts_bogus_comment(&src[i..]) {
+            let closed = memchr(b'>', &src[i..]).map(|p| i + p + 1);
+            let end = closed.unwrap_or(n);
+            out.extend_from_slice(&src[i..end]);
+            if off.is_none() && !open_stack.iter().any(|e| e.has_noreformat) {
+                let opener = if src[i + 1] == b'!' { "<!" } else { "</" };
+                diagnostics.push(Diagnostic {
+                    offset: i,
+                    check: Check::BogusComment,
+                    message: format!("`{opener}` starts a bogus comment here, which HTML ignores; copied verbatim"),
+                });
+            }
+            ignore_next = false;
+            // Unclosed, it runs to the end of the input like in HTML.
+            verbatim_tail |= closed.is_none();
+            after_boundary = is_standalone_span(src, i, end);
+            after_br = false;
+            i = end;
+            continue;
+        }
+
+        // Tags; a `<` that is text is left to the text run below
+        if src[i] == b'<' && !is_text_lt(&src[i..], use_markdown) {
+            let Some(j) = find_tag_end(src, i) else {
+                out.extend_from_slice(&src[i..]);
+                diagnostics.push(Diagnostic {
+                    offset: i,
+                    check: Check::UnterminatedTag,
+                    message: "tag is not closed; the rest of the file was copied verbatim".to_string(),
+                });
+                verbatim_tail = true;
+                break;
+            };
+            let tag = &src[i..=j];
+            let ti = parse_tag_info(tag);
+
+            // A tag at the start of a line outside any verbatim region, fenced code
+            // block or break is somewhere a transform can start again with the open
+            // elements: nothing before it looks past it, and nothing after it looks
+            // back beyond the start of its line.
+            if let Some(resume) = resume.as_deref_mut().filter(|_| i > 0) {
+                let verbatim = off.is_some() || ignore_next || open_stack.iter().any(|e| e.has_noreformat);
+                // `--tag-per-line` looks back at the output's line, so that has to be empty.
+                let blank = !opts.tag_per_line || last_line(out, out_start).iter().all(|&b| is_ws(b));
+                if (i == 0 || src[i - 1] == b'\n')
+                    && !verbatim
+                    && fence.is_none()
+                    && !opts.is_break_tag(ti.name)
+                    && blank
+                {
+                    let state = ResumeState { open_stack: open_stack.clone(), after_br };
+                    resume.cut = Some((i, out.len(), state));
+                }
+            }
+
+            let mut name_lower = ti.name.to_vec();
+            name_lower.make_ascii_lowercase();
+            // Implied closes come first: the tag is outside the elements it ends.
+            if !ti.is_end {
+                if matches_ignore_ascii_case(&name_lower, P_CLOSING_TAGS) {
+                    let scope = open_stack.iter().rposition(|e| e.name == b"p" || BUTTON_SCOPE.contains(&&e.name[..]));
+                    if let Some(p) = scope.filter(|&p| open_stack[p].name == b"p") {
+                        open_stack.truncate(p);
+                    }
+                }
+                if name_lower == b"li" {
+                    if let Some(top) = open_stack.last() {
+                        if top.name == b"li" {
+                            open_stack.pop();
+                        }
+                    }
+                } else if name_lower == b"dt" || name_lower == b"dd" {
+                    if let Some(top) = open_stack.last() {
+                        if top.name == b"dt" || top.name == b"dd" {
+                            open_stack.pop();
+                        }
+                    }
+                }
+            }
+
+            let skipped = || opts.skip_element.as_ref().is_some_and(|skip| skip(&ti, tag));
+            // An end tag right after `reformahtml-ignore` has nothing to ignore.
+            let ignored = std::mem::take(&mut ignore_next);
+            // Inline SVG and MathML, unless reformatted, are kept whole.
+            let foreign_root = opts.foreign_content == ForeignContent::Verbatim
+                && matches_ignore_ascii_case(ti.name, &[b"svg", b"math"]);
+            let has_this_noreformat = !ti.is_end && (ignored || foreign_root || opts.skips(tag) || skipped());
+            let is_verbatim = off.is_some() || open_stack.iter().any(|e| e.has_noreformat) || has_this_noreformat;
+            if is_verbatim {
+                out.extend_from_slice(tag);
+            } else {
+                // HTML ignores attributes on an end tag; they only get their white
+                // space collapsed, never the tag style options.
+                if ti.is_end && attrs(tag).next().is_some() {
+                    diagnostics.push(Diagnostic {
+                        offset: i,
+                        check: Check::EndTagWithAttributes,
+                        message: format!(
+                            "end tag `</{}>` has attributes, which HTML ignores",
+                            String::from_utf8_lossy(ti.name)
+                        ),
+                    });
+                }
+                let start = out.len();
+                let foreign = open_stack
+                    .iter()
+                    .rev()
+                    .take_while(|e| e.name != b"foreignobject")
+                    .any(|e| e.name == b"svg" || e.name == b"math");
+                if opts.tag_per_line && opts.is_structural(&name_lower) {
+                    break_line(out, out_start);
+                }
+                let style = (!ti.is_end).then_some(opts);
+                normalize_inside_tag(tag, out, is_highlighted_block(&ti, tag), style, foreign);
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats.tags_normalized += usize::from(out[start..] != *tag);
+                }
+            }
+
+            // open_stack handling
+            if let Some(lint) = &mut structure {
+                lint.tag(&ti, &name_lower, &open_stack, i, &mut diagnostics);
+            }
+            // In SVG (outside <foreignObject>), text content keeps its white space.
+            let in_svg = !ti.is_end
+                && open_stack.iter().rev().take_while(|e| e.name != b"foreignobject").any(|e| e.name == b"svg");
+            if ti.is_end {
+                // Template contents are a tree of their own: other end tags never
+                // close the innermost <template> or anything outside it.
+                let innermost_template = open_stack.iter().rposition(|e| e.name == b"template");
+                let floor = match innermost_template {
+                    Some(t) if name_lower != b"template" => t + 1,
+                    _ => 0,
+                };
+                // It closes the innermost open element of its name and everything
+                // inside that; a stray one, with no such element open, closes nothing.
+                match open_stack[floor..].iter().rposition(|e| e.name == name_lower) {
+                    Some(p) => open_stack.truncate(floor + p),
+                    None if off.is_none() && !open_stack.iter().any(|e| e.has_noreformat) => {
+                        diagnostics.push(Diagnostic {
+                            offset: i,
+                            check: Check::StrayEndTag,
+                            message: format!(
+                                "end tag `</{}>` has no open element to close; ignored",
+                                String::from_utf8_lossy(&name_lower)
+                            ),
+                        });
+                    }
+                    None => {}
+                }
+            } else if !ti.self_closing && !opts.is_void(ti.name) {
+                let svg_text = in_svg && matches_ignore_ascii_case(&name_lower, SVG_TEXT_TAGS);
+                open_stack.push(OpenElement {
+                    name: name_lower.clone(),
+                    has_noreformat: has_this_noreformat || svg_text || opts.preserves_white_space(tag),
+                });
+            }
+
+            // raw-text tracking
+            if opts.is_raw_text(ti.name) && !ti.is_end && !ti.self_closing {
+                raw_stack.push(name_lower.clone());
+                raw_start = i;
+                raw_script = (name_lower == b"script").then(|| is_javascript(tag));
+                raw_interior = (opts.format_metadata
+                    && name_lower == b"pre"
+                    && class_tokens(tag).any(|c| c == b"metadata")
+                    && off.is_none()
+                    && !open_stack.iter().any(|e| e.has_noreformat))
+                    .then_some(format_metadata as Interior);
+            } else if !ti.is_end && !ti.self_closing && is_rcdata(&name_lower, in_svg, opts) {
+                // Scanned like raw text, so a `<` in a title never opens a tag.
+                raw_stack.push(name_lower.clone());
+                raw_start = i;
+                raw_script = None;
+                raw_interior = (!in_svg
+                    && opts.rcdata == Rcdata::Reflow
+                    && off.is_none()
+                    && !open_stack.iter().any(|e| e.has_noreformat))
+                    .then_some(reflow_rcdata as Interior);
+            }
+
+            // <br> rule (and any other configured break tag)
+            if !ti.is_end && opts.is_break_tag(ti.name) {
+                if j + 1 < n && src[j + 1] == b'\n' {
+                    out.push(b'\n');
+                    i = j + 2;
+                    after_br = true;
+                    continue;
+                } else {
+                    after_br = true;
+                }
+            }
+
+            // Set after_boundary for structural start tags
+            after_boundary = !ti.is_end && opts.is_structural(&name_lower);
+
+            i = j + 1;
+            continue;
+        }
+
+        // Text run, including any `<` that doesn't start markup
+        let next_lt = find_markup_start(src, i, use_markdown);
+        let chunk = &src[i..next_lt];
+
+        // White space before what `reformahtml-ignore` applies to is formatted as usual.
+        let ignored = ignore_next && !chunk.iter().all(|&b| is_ws(b));
+        ignore_next &= !ignored;
+        let is_verbatim = off.is_some() || ignored || open_stack.iter().any(|e| e.has_noreformat);
+        if is_verbatim {
+            out.extend_from_slice(chunk);
+        } else {
+            if !open_stack.iter().any(|e| e.name == b"pre") {
+                mode_hints.scan(src, i, chunk);
+            }
+            let start = out.len();
+            reflow_text_chunk(
+                chunk,
+                src,
+                next_lt,
+                out,
+                opts,
+                after_boundary,
+                after_br,
+                i,
+            );
+            if let Some(max) = opts.max_blank_lines {
+                collapse_blank_lines(out, start, max);
+            }
+            if opts.strip_trailing_whitespace {
+                strip_trailing_whitespace(out, start, next_lt == n, use_markdown);
+            }
+            if let Some(indent) = opts.indent {
+                let line_start = start == out_start || out[start - 1] == b'\n';
+                convert_indentation(out, start, line_start, indent, use_markdown.then_some(&mut fence));
+            }
+            if let Some(stats) = stats.as_deref_mut() {
+                let newlines = |b: &[u8]| memchr::memchr_iter(b'\n', b).count();
+                stats.text_chunks_reflowed += 1;
+                stats.lines_joined += newlines(chunk).saturating_sub(newlines(&out[start..]));
+            }
+        }
+
+        after_boundary = false;
+        after_br = false;
+        i = next_lt;
+    }
+    // The end of an unterminated raw-text or data-noreformat element is part of it.
+    verbatim_tail |= !raw_stack.is_empty() || open_stack.iter().any(|e| e.has_noreformat);
+    if let Some(at) = off {
+        verbatim_tail = true;
+        let d = Diagnostic {
+            offset: at,
+            check: Check::UnmatchedOff,
+            message: "`reformahtml off` has no matching `reformahtml on`; the rest of the file was copied verbatim".to_string(),
+        };
+        let at = diagnostics.partition_point(|e: &Diagnostic| e.offset <= d.offset);
+        diagnostics.insert(at, d);
+    }
+    if let Some(name) = raw_stack.last() {
+        diagnostics.push(Diagnostic {
+            offset: raw_start,
+            check: Check::UnterminatedRawText,
+            message: format!("<{}> is not closed; the rest of the file was copied verbatim", String::from_utf8_lossy(name)),
+        });
+    }
+    if let Some(w) = window {
+        // A `break` above copied the rest of the input.
+        w.token(src, token.0..n, out, token.1);
+    } else if !verbatim_tail {
+        fix_final_newlines(out, out_start, opts);
+    }
+    if let Some(d) = mode_hints.diagnostic(use_markdown) {
+        let at = diagnostics.partition_point(|e: &Diagnostic| e.offset <= d.offset);
+        diagnostics.insert(at, d);
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, DirEntry};
+    use std::path::Path;
+
+    fn format_with(src: &str, opts: &Options) -> String {
+        let mut out = Vec::new();
+        transform(src.as_bytes(), &mut out, opts);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn newline_option_converts_every_line_ending() {
+        let crlf = Options { newline: Some(Newline::Crlf), ..Options::default() };
+        assert_eq!(format_with("<p>a\nb</p>\n<pre>\nx\n</pre>\n", &crlf), "<p>a b</p>\r\n<pre>\r\nx\r\n</pre>\r\n");
+        let lf = Options { newline: Some(Newline::Lf), ..Options::default() };
+        assert_eq!(format_with("<p>a\r\nb</p>\r\n<pre>\r\nx\n</pre>\r\n", &lf), "<p>a b</p>\n<pre>\nx\n</pre>\n");
+        // By default the first line ending decides.
+        assert_eq!(format_with("<p>a\r\nb</p>\n<p>c</p>\n", &Options::default()), "<p>a b</p>\r\n<p>c</p>\r\n");
+
+        let src = "<p>a</p>\r\n<p>b</p>\r\n<?php x ?>\r\n";
+        let diagnostics = transform(src.as_bytes(), &mut Vec::new(), &Options::default());
+        assert_eq!(line_col(src.as_bytes(), diagnostics[0].offset), (3, 1));
+    }
+
+    #[test]
+    fn final_newline_options() {
+        let ensure = Options { ensure_final_newline: true, ..Options::default() };
+        let trim = Options { trim_final_newlines: true, ..Options::default() };
+        let both = Options { ensure_final_newline: true, trim_final_newlines: true, ..Options::default() };
+        assert_eq!(format_with("<p>a\nb</p>", &ensure), "<p>a b</p>\n");
+        assert_eq!(format_with("<p>a</p>\n", &ensure), "<p>a</p>\n");
+        assert_eq!(format_with("", &ensure), "");
+        assert_eq!(format_with("<p>a</p>\n\n \n\t\n", &trim), "<p>a</p>\n");
+        assert_eq!(format_with("<p>a</p>", &trim), "<p>a</p>");
+        assert_eq!(format_with("<p>a</p>\r\n\r\n", &both), "<p>a</p>\r\n");
+        assert_eq!(format_with("<p>a</p>\r\n<p>b</p>", &both), "<p>a</p>\r\n<p>b</p>\r\n");
+
+        // Unterminated constructs copied verbatim keep their end as is.
+        for src in ["<p>a</p>\n<pre>\nx\n\n", "<p>a</p>\n<!-- x", "<p>a</p>\n<a href=\"x\n\n", "<div data-noreformat>\nx\n\n"] {
+            assert_eq!(format_with(src, &both), src);
+        }
+        let md = Options { markdown_document: true, ..both };
+        assert_eq!(format_with("one\ntwo\n\n\n", &md), "one two\n");
+        assert_eq!(format_with("```\ncode\n\n", &md), "```\ncode\n\n");
+    }
+
+    #[test]
+    fn trailing_whitespace_is_stripped_outside_verbatim_regions() {
+        let strip = Options { strip_trailing_whitespace: true, ..Options::default() };
+        let src = "<h2>Title</h2>   \n<p>one\ntwo</p>\t\n  \n<div>\n<p>three</p> \n</div>\n<pre>\nkeep  \n</pre>\n<div data-noreformat>\nkeep \n</div>\n";
+        assert_eq!(
+            format_with(src, &strip),
+            "<h2>Title</h2>\n<p>one two</p>\n\n<div>\n<p>three</p>\n</div>\n<pre>\nkeep  \n</pre>\n<div data-noreformat>\nkeep \n</div>\n"
+        );
+        assert_eq!(format_with("<p>a</p>\n  \t", &strip), "<p>a</p>\n");
+        assert_eq!(format_with("<p>a</p>\r\n<p>b</p> \r\n", &strip), "<p>a</p>\r\n<p>b</p>\r\n");
+
+        // Two spaces after text are a Markdown hard break.
+        let md = Options { markdown: true, ..strip };
+        assert_eq!(format_with("# Title \n\nLast line  \n\n", &md), "# Title\n\nLast line  \n\n");
+        let doc = Options { markdown_document: true, strip_trailing_whitespace: true, ..Options::default() };
+        assert_eq!(format_with("Hard break  \n\n```\ncode  \n```\nend \t", &doc), "Hard break  \n\n```\ncode  \n```\nend");
+    }
+
+    #[test]
+    fn byte_order_mark_is_kept_outside_the_document() {
+        let md = Options { markdown: true, ..Options::default() };
+        assert_eq!(format_with("\u{FEFF}* one\n  two\n\n# Title", &md), "\u{FEFF}* one two\n\n# Title");
+
+        let src = "\u{FEFF}<?php x ?>\n";
+        let diagnostics = transform(src.as_bytes(), &mut Vec::new(), &Options::default());
+        assert_eq!(line_col(src.as_bytes(), diagnostics[0].offset), (1, 1));
+    }
+
+    #[test]
+    fn white_space_hints_keep_contents() {
+        let src = "<div style=\"color: red; White-Space: pre-wrap !important\">a\n  b</div>\n<p class=\"x pre-wrap\">c\nd</p>\n<p>e\nf</p>\n";
+        let opts = Options { white_space_hints: true, pre_classes: vec!["pre-wrap".into()], ..Options::default() };
+        assert_eq!(
+            format_with(src, &opts),
+            "<div style=\"color: red; White-Space: pre-wrap !important\">a\n  b</div>\n<p class=\"x pre-wrap\">c\nd</p>\n<p>e f</p>\n"
+        );
+        // Opt-in only.
+        assert_eq!(
+            format_with(src, &Options::default()),
+            "<div style=\"color: red; White-Space: pre-wrap !important\">a b</div>\n<p class=\"x pre-wrap\">c d</p>\n<p>e f</p>\n"
+        );
+    }
+
+    #[test]
+    fn skip_element_keeps_subtrees_verbatim() {
+        let skip: SkipElement = Box::new(|ti, tag| ti.name == b"div" && tag_attr_value(tag, b"class") == Some(b"example"));
+        let opts = Options::default().skip_element(Some(skip));
+        let src = concat!(
+            "<div class=\"example\">\n  <p>Kept\n  as is</p>\n  <div>\n  </div>\n  <pre>\n</div>\n  </pre>\n  <p>Still\n  kept</p>\n</div>\n",
+            "<div class=\"note\">\n  <p>Reflowed\n  text</p>\n</div>\n",
+        );
+        let expected = src.replace("Reflowed\n  text", "Reflowed text");
+        assert_eq!(format_with(src, &opts), expected);
+    }
+
+    #[test]
+    fn list_items_stop_at_html_blocks() {
+        let text = "1. First item\n   wraps here\n   <pre>\n   code\n   </pre>\n1. Second\n   <span>inline</span> joins\n";
+        assert_eq!(
+            reflow_markdown_text(text, &MarkdownOptions::default()),
+            "1. First item wraps here\n   <pre>\n   code\n   </pre>\n1. Second <span>inline</span> joins\n"
+        );
+    }
+
+    #[test]
+    fn markdown_reflow_on_its_own() {
+        let md = MarkdownOptions::default();
+        let text = "Para\r\ngraph\r\n\r\n* item\r\n  wraps\r\n: term\r\n  wraps\r\n```\r\nkept\r\nas is\r\n";
+        assert_eq!(reflow_markdown_text(text, &md), "Para graph\r\n\r\n* item wraps\r\n: term wraps\r\n```\r\nkept\r\nas is\r\n");
+        // Without definition lists, `:` lines are paragraph text.
+        let dl = "* item\n: not a term\n";
+        assert_eq!(reflow_markdown_text(dl, &md), "* item\n: not a term\n");
+        assert_eq!(reflow_markdown_text(dl, &md.definition_lists(false)), "* item : not a term\n");
+        assert_eq!(reflow_plain_text("a\r\n\r\n  b\rc\n"), "a b\rc ");
+
+        // No input makes either panic; try many made of the characters that matter.
+        let alphabet = ["*", "-", "1.", ":", "::", "#", ">", "```", "~~~", "=", "<div>", "<pre>", " ", "\t", "\n", "\r\n", "\r", "é", "x"];
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..2000 {
+            let mut text = String::new();
+            for _ in 0..seed % 24 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                text.push_str(alphabet[(seed % alphabet.len() as u64) as usize]);
+            }
+            reflow_markdown_text(&text, &md);
+            reflow_markdown_text(&text, &md.definition_lists(false));
+            reflow_plain_text(&text);
+        }
+    }
+
+    #[test]
+    fn utf8_stays_utf8() {
+        let alphabet = [
+            "é", "€", "𝄞", "\u{a0}", "\n", "\r\n", " ", "\t", "x", "<p>", "</p>", "<br>", "<pre>", "</pre>", "<div data-noreformat>",
+            "</div>", "<!--", "-->", "<a href=\"", "\"", "'", "=", "/>", "* ", "1. ", ": ", ":: ", "```", "# ", "> ",
+        ];
+        let mut seed = 0x2554_9e37_79b9_7f4au64;
+        for round in 0..3000 {
+            let mut text = String::new();
+            for _ in 0..seed % 32 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                text.push_str(alphabet[(seed % alphabet.len() as u64) as usize]);
+            }
+            let opts = Options::default()
+                .markdown(round % 2 == 0)
+                .markdown_document(round % 5 == 0)
+                .strip_trailing_whitespace(round % 3 == 0)
+                .max_blank_lines((round % 4 == 0).then_some(0))
+                .indent((round % 7 == 0).then_some(Indent::Tabs(2)));
+            let mut bytes = Vec::new();
+            transform(text.as_bytes(), &mut bytes, &opts);
+            assert_eq!(reformat_str(&text, &opts).as_bytes(), bytes, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn configured_break_tokens_keep_newlines() {
+        let src = "<p>one<wbr>\ntwo <!-- keep-break -->\nthree<!--other-->\nfour\n</p>\n";
+        let mut opts = Options { break_comments: vec!["keep-break".into()], ..Options::default() };
+        opts.break_tags.push("wbr".into());
+        assert_eq!(format_with(src, &opts), "<p>one<wbr>\ntwo <!-- keep-break -->\nthree<!--other--> four\n</p>\n");
+        assert_eq!(format_with(src, &Options::default()), "<p>one<wbr> two <!-- keep-break --> three<!--other--> four\n</p>\n");
+    }
+
+    #[test]
+    fn class_tokens_and_skip_classes() {
+        let tokens = |tag: &str| class_tokens(tag.as_bytes()).map(|c| String::from_utf8_lossy(c).into_owned()).collect::<Vec<_>>();
+        assert_eq!(tokens("<table class=\"a  manual-format\tb\">"), ["a", "manual-format", "b"]);
+        assert_eq!(tokens("<table id=x CLASS='\n a\x0cb \n'>"), ["a", "b"]);
+        assert_eq!(tokens("<table class=manual-format>"), ["manual-format"]);
+        assert!(tokens("<table class>").is_empty() && tokens("<table data-class=a>").is_empty());
+
+        let opts = Options::default().skip_classes(vec!["manual-format".into()]);
+        let src = "<table class=\"x manual-format\">\n<tr><td>a\n  b</td></tr>\n</table>\n<table class='manual-formatting Manual-Format'>\n<tr><td>c\n  d</td></tr>\n</table>\n";
+        assert_eq!(
+            format_with(src, &opts),
+            "<table class=\"x manual-format\">\n<tr><td>a\n  b</td></tr>\n</table>\n<table class='manual-formatting Manual-Format'>\n<tr><td>c d</td></tr>\n</table>\n"
+        );
+    }
+
+    #[test]
+    fn configured_structural_tags() {
+        let src = "<spec-section>\n  <p>Intro\n  text.</p>\n  Some\n  prose.\n</spec-section>\n<ISSUE-BLOCK>\n    Fix\n    this.\n</issue-block>\n";
+        let mut opts = Options::default();
+        opts.structural_tags.extend(["spec-section".into(), "Issue-Block".into()]);
+        assert_eq!(
+            format_with(src, &opts),
+            "<spec-section>\n  <p>Intro text.</p> Some prose.\n</spec-section>\n<ISSUE-BLOCK>\n    Fix this.\n</issue-block>\n"
+        );
+        assert_eq!(
+            format_with(src, &Options::default()),
+            "<spec-section>\n  <p>Intro text.</p> Some prose. </spec-section>\n<ISSUE-BLOCK> Fix this. </issue-block>\n"
+        );
+        // An inline tag on the next line doesn't join a line that ends with a custom structural start tag.
+        assert_eq!(
+            format_with("Text\n<spec-section>\n  <a href=x>link</a>\n</spec-section>\n", &opts),
+            "Text\n<spec-section>\n  <a href=x>link</a>\n</spec-section>\n"
+        );
+    }
+
+    #[test]
+    fn configured_raw_tags() {
+        let mut opts = Options::default().markdown_document(true);
+        opts.raw_tags.push("Code-Example".into());
+        let src = "<code-example>\n  a\n\n  b\n</CODE-EXAMPLE>\n\nSome\ntext.\n";
+        assert_eq!(format_with(src, &opts), "<code-example>\n  a\n\n  b\n</CODE-EXAMPLE>\n\nSome text.\n");
+        let opts = Options::default().raw_tags(vec!["pre".into()]);
+        assert_eq!(format_with("<script>a\nb</script>\n", &opts), "<script>a b</script>\n");
+    }
+
+    #[test]
+    fn collapse_doctype() {
+        let xhtml = fs::read_to_string("tests/fixtures/inputs/doctype-xhtml.html").unwrap();
+        let collapse = Options::default().collapse_doctype(true);
+        assert!(format_with(&xhtml, &collapse).starts_with(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">\n<html"
+        ));
+        let subset = fs::read_to_string("tests/fixtures/inputs/doctype-internal-subset.html").unwrap();
+        let expected = fs::read_to_string("tests/fixtures/expected/doctype-internal-subset.html").unwrap();
+        assert_eq!(format_with(&subset, &collapse), expected);
+        assert_eq!(scan_doctype(b"<!DOCTYPE x [ <!ENTITY a '>'> ]", 0), (usize::MAX, true));
+    }
+
+    #[test]
+    fn self_closing_style() {
+        let src = "<svg><path d=\"M0\"/><rect\n  /><a href=foo/>x</a><br/></svg>\n";
+        let reformat = || Options::default().foreign_content(ForeignContent::Reformat);
+        let spaced = Options { self_closing: Some(SelfClosingStyle::Spaced), ..reformat() };
+        assert_eq!(format_with(src, &spaced), "<svg><path d=\"M0\" /><rect /><a href=foo/>x</a><br /></svg>\n");
+        let compact = Options { self_closing: Some(SelfClosingStyle::Compact), ..reformat() };
+        assert_eq!(format_with(src, &compact), "<svg><path d=\"M0\"/><rect/><a href=foo/>x</a><br/></svg>\n");
+    }
+
+    #[test]
+    fn self_closing_detection() {
+        let closing = |tag: &str| parse_tag_info(tag.as_bytes()).self_closing;
+        assert!(closing("<br/>") && closing("<rect x=1 />") && closing("<a b/>") && closing("<a b=\"c/\"/>"));
+        assert!(!closing("<a href=https://example.com/>") && !closing("<a b=/>") && !closing("<a b= c/ >"));
+        assert!(!closing("<a b='c/'>") && !closing("</a/>") && !closing("</a b />"));
+    }
+
+    #[test]
+    fn tag_end_quotes() {
+        let end = |tag: &str| find_tag_end(tag.as_bytes(), 0);
+        assert_eq!(end("<span title=it's-fine>'>"), Some(21));
+        assert_eq!(end("<a b=\"x>y\" c='\"'>"), Some(16));
+        assert_eq!(end("<b c=x\"y>\">"), Some(8));
+        assert_eq!(end("<i =\"z>\">"), Some(6));
+        assert_eq!(end("<a b='c"), None);
+    }
+
+    #[test]
+    fn keep_attr_newlines() {
+        let src = "<img srcset=\"a.png 1x,\n     b.png 2x\" alt=\"An\n     image\">\n";
+        assert_eq!(format_with(src, &Options::default()), "<img srcset=\"a.png 1x,\n     b.png 2x\" alt=\"An image\">\n");
+        let opts = Options::default().keep_attr_newlines(vec!["alt".to_string()]);
+        assert_eq!(format_with(src, &opts), "<img srcset=\"a.png 1x, b.png 2x\" alt=\"An\n     image\">\n");
+        assert_eq!(value_attr_name(b"path fill=none d = "), b"d");
+        assert_eq!(value_attr_name(b"path d"), b"");
+    }
+
+    #[test]
+    fn wrap_attrs() {
+        let opts = Options::default().wrap_attrs(Some(40));
+        let src = "<div>\n\t<input type=text name=q placeholder=\"Search the spec\" required>\n</div>\n";
+        let wrapped = "<div>\n\t<input type=text\n\t       name=q\n\t       placeholder=\"Search the spec\"\n\t       required>\n</div>\n";
+        assert_eq!(format_with(src, &opts), wrapped);
+        assert_eq!(format_with(wrapped, &opts), wrapped);
+        // Short tags, and tags with a single attribute, stay on one line.
+        let short = "<p id=a class=b>x</p>\n<img src=\"a-very-long-file-name-for-an-image.png\">\n";
+        assert_eq!(format_with(short, &opts), short);
+    }
+
+    #[test]
+    fn tag_per_line() {
+        let opts = Options::default().tag_per_line(true);
+        let src = "<table>\n  <tr><td>a</td><td>b\n  c</td></tr>\n</table>\n<p>One</p><p>two <b>bold</b></p>\n";
+        let broken = "<table>\n  <tr>\n  <td>a\n  </td>\n  <td>b c\n  </td>\n  </tr>\n</table>\n<p>One\n</p>\n<p>two <b>bold</b>\n</p>\n";
+        assert_eq!(format_with(src, &opts), broken);
+        assert_eq!(format_with(broken, &opts), broken);
+        // Raw text and verbatim regions are left alone.
+        let kept = "<pre>a</pre><p>x</p>\n<div data-noreformat><p>b</p><p>c</p></div>\n";
+        assert_eq!(format_with(kept, &opts), "<pre>a</pre>\n<p>x\n</p>\n<div data-noreformat><p>b</p><p>c</p></div>\n");
+    }
+
+    #[test]
+    fn structure_lint() {
+        let src = "<h2>A</h2>\n<h4>B</h4>\n<dl><dd>x<dt>y<dd>z</dl>\n<li>stray\n<ol><li>ok</ol>\n<h3>C</h3>\n";
+        let opts = Options { lint_structure: true, ..Options::default() };
+        let messages: Vec<String> = transform(src.as_bytes(), &mut Vec::new(), &opts)
+            .into_iter()
+            .map(|d| format!("{:?} {}", line_col(src.as_bytes(), d.offset), d.message))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "(2, 1) heading level skipped: <h4> follows <h2>",
+                "(3, 5) <dd> without a preceding <dt>",
+                "(4, 1) <li> outside of <ul>, <ol> or <menu>",
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_mode_hints() {
+        let messages = |src: &str, markdown: bool| -> Vec<String> {
+            let opts = Options { markdown, ..Options::default() };
+            transform(src.as_bytes(), &mut Vec::new(), &opts).into_iter().map(|d| d.message).collect()
+        };
+        let md = "<p>Intro</p>\n* one\n* two\n<pre>\n- code\n- code\n</pre>\n";
+        assert_eq!(
+            messages(md, false),
+            ["text looks like Markdown (bullet list item and 1 more); pass --markdown if this is a Bikeshed document"]
+        );
+        assert!(messages(md, true).is_empty());
+        assert!(messages("<p>Just\nHTML</p>\n", false).is_empty());
+        assert_eq!(messages("<p>Just\nHTML</p>\n", true).len(), 1);
+    }
+
+    #[test]
+    fn unterminated_constructs_are_reported() {
+        let found = |src: &[u8]| -> Vec<(Check, usize)> {
+            transform(src, &mut Vec::new(), &Options::default()).into_iter().map(|d| (d.check, d.offset)).collect()
+        };
+        let comment = fs::read("tests/fixtures/inputs/unterminated-comment.html").unwrap();
+        assert_eq!(found(&comment), [(Check::UnterminatedComment, 35)]);
+        let tag = fs::read("tests/fixtures/inputs/unterminated-tag.html").unwrap();
+        assert_eq!(found(&tag), [(Check::UnterminatedTag, 38)]);
+        assert_eq!(found(b"<p>Code:</p>\n<pre>\nfn main() {}\n"), [(Check::UnterminatedRawText, 13)]);
+        assert!(found(b"<p>Code:</p>\n<pre>\nfn main() {}\n</pre>\n").is_empty());
+        let cdata = fs::read("tests/fixtures/inputs/unterminated-cdata.html").unwrap();
+        assert_eq!(found(&cdata), [(Check::UnterminatedCdata, 29)]);
+        // A closed CDATA section is copied verbatim without a diagnostic, even with the old best-effort setting.
+        let cdata = fs::read("tests/fixtures/inputs/cdata.html").unwrap();
+        assert!(found(&cdata).is_empty());
+        let mut out = Vec::new();
+        transform(&cdata, &mut out, &Options::default().best_effort(vec![Construct::Cdata]));
+        assert_eq!(out, fs::read("tests/fixtures/expected/cdata.html").unwrap());
+        assert_eq!(found(b"<p>a</p>\n<?xml-stylesheet href=\"a?>\n"), [(Check::UnterminatedTag, 9)]);
+        assert!(found(&fs::read("tests/fixtures/inputs/conditional-comments.html").unwrap()).is_empty());
+        let end_tags = fs::read("tests/fixtures/inputs/end-tag-attributes.html").unwrap();
+        assert_eq!(found(&end_tags), [24, 65, 104].map(|at| (Check::EndTagWithAttributes, at)));
+        let stray = fs::read("tests/fixtures/inputs/stray-end-tags.html").unwrap();
+        assert_eq!(found(&stray), [(Check::StrayEndTag, 182)]);
+        let bogus = fs::read("tests/fixtures/inputs/bogus-comments.html").unwrap();
+        assert_eq!(found(&bogus), [19, 34, 65, 79, 169].map(|at| (Check::BogusComment, at)));
+        assert_eq!(found(b"<p>a <!-->b <!--->c</p>\n"), []);
+        let bang = fs::read("tests/fixtures/inputs/comment-bang-close.html").unwrap();
+        assert_eq!(found(&bang), [29, 115].map(|at| (Check::IncorrectlyClosedComment, at)));
+        let off = fs::read("tests/fixtures/inputs/unmatched-off.html").unwrap();
+        assert_eq!(found(&off), [(Check::UnmatchedOff, 22)]);
+        assert!(found(&fs::read("tests/fixtures/inputs/reformahtml-off.html").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn formatter_is_shared_across_threads() {
+        let formatter = std::sync::Arc::new(Formatter::new(Options::default().markdown(true)));
+        let threads: Vec<_> = (0..4)
+            .map(|k| {
+                let formatter = formatter.clone();
+                std::thread::spawn(move || formatter.format(format!("<p>Doc\n{k}</p>\n").as_bytes()))
+            })
+            .collect();
+        for (k, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap(), format!("<p>Doc {k}</p>\n").as_bytes());
+        }
+    }
+
+    #[test]
+    fn tag_sets_agree_with_the_lists() {
+        let opts = || {
+            let mut opts = Options::default()
+                .foreign_content(ForeignContent::Reformat)
+                .strip_void_slash(true)
+                .skip_classes(vec!["Keep".to_string()])
+                .skip_attrs(vec!["data-noreformat".to_string(), "Translate=no".to_string()]);
+            opts.structural_tags.push("My-Card".to_string());
+            opts.inline_tags.retain(|t| t != "code");
+            opts.void_tags.push("X-Icon".to_string());
+            opts
+        };
+        let formatter = Formatter::new(opts());
+        for entry in fs::read_dir("tests/fixtures/inputs").unwrap() {
+            let path = entry.unwrap().path();
+            let src = fs::read(&path).unwrap();
+            let mut expected = Vec::new();
+            transform(&src, &mut expected, &opts());
+            assert!(formatter.format(&src) == expected, "{} differs", path.display());
+        }
+        let src = "<p>a\n<MY-CARD><X-ICON/><B translate=no>a\nb</B>\n<CODE>c</CODE> <i class=Keep>c\nd</i></MY-CARD>\n";
+        let expected = "<p>a\n<MY-CARD><X-ICON><B translate=no>a\nb</B>\n<CODE>c</CODE> <i class=Keep>c\nd</i></MY-CARD>\n";
+        assert_eq!(format_with(src, &opts()), expected);
+        assert_eq!(formatter.format(src.as_bytes()), expected.as_bytes());
+    }
+
+    #[test]
+    fn source_map_anchors_tokens() {
+        for newline in ["\n", "\r\n"] {
+            let src = "\u{feff}<p>One\ntwo</p>\n<pre>\n  keep\n</pre>\n".replace('\n', newline);
+            let mut out = b"before".to_vec();
+            let (_, map) = transform_mapped(src.as_bytes(), &mut out, &Options::default(), None, None);
+            let out = String::from_utf8(out).unwrap();
+            assert_eq!(out, format!("before{}", src.replace(&format!("One{newline}two"), "One two")));
+            // Verbatim: exactly.
+            let (keep_in, keep_out) = (src.find("keep").unwrap(), out.find("keep").unwrap());
+            assert_eq!(map.map_to_input(keep_out + 2), keep_in + 2);
+            assert_eq!(map.map_to_output(keep_in + 2), keep_out + 2);
+            // Rewritten: to the start of the chunk.
+            let (one_in, one_out) = (src.find("One").unwrap(), out.find("One").unwrap());
+            assert_eq!(map.map_to_input(out.find("two").unwrap()), one_in);
+            assert_eq!(map.map_to_output(src.find("two").unwrap()), one_out);
+            assert_eq!(map.anchors()[0], Anchor { input: 0, output: 6, exact: true });
+        }
+    }
+
+    #[test]
+    fn format_range_returns_the_replacement() {
+        let src = "<pre>\na\nb\n</pre>\n<p data-noreformat>c\n<b>d\ne</b></p>\n<p>f\ng</p>\n";
+        // Inside raw text or data-noreformat, the replacement is the original.
+        for offset in [7, src.find("d\ne").unwrap()] {
+            let (replacement, range) = format_range(src.as_bytes(), offset..offset + 1, &Options::default()).unwrap();
+            assert_eq!(replacement, src[range].as_bytes());
+        }
+        let at = src.find("f\ng").unwrap();
+        assert_eq!(format_range(src.as_bytes(), at + 1..at + 2, &Options::default()), Some((b"f g".to_vec(), at..at + 3)));
+    }
+
+    #[test]
+    fn stats_skip_verbatim_regions() {
+        let src = "<p  class=a>one\ntwo\nthree <!-- x\n y --></p>\n<div data-noreformat><p  class=b>four\nfive</p></div>\n<style >a\nb</style >\n";
+        let mut stats = Stats::default();
+        transform_counted(src.as_bytes(), &mut Vec::new(), &Options::default(), Some(&mut stats));
+        assert_eq!(stats.lines_joined, 2);
+        assert_eq!(stats.tags_normalized, 3); // <p  class=a>, <style >, </style >
+        assert_eq!(stats.comments_reflowed, 1);
+    }
+
+    #[test]
+    fn max_blank_lines_fixtures() {
+        let inputs_dir = Path::new("tests/fixtures/max-blank-lines/inputs");
+        let expected_dir = Path::new("tests/fixtures/max-blank-lines/expected");
+        let update_expected = std::env::var("UPDATE_EXPECTED").is_ok();
+
+        for entry in fs::read_dir(inputs_dir).unwrap() {
+            let input_path = entry.unwrap().path();
+            let stem = input_path.file_stem().unwrap().to_str().unwrap();
+            let src = fs::read(&input_path).unwrap();
+            let markdown = input_path.extension().is_some_and(|e| e == "bs");
+            let opts = Options { markdown, max_blank_lines: Some(1), ..Options::default() };
+            let mut out = Vec::new();
+            transform(&src, &mut out, &opts);
+
+            let expected_path = expected_dir.join(input_path.file_name().unwrap());
+            if update_expected {
+                fs::create_dir_all(expected_dir).unwrap();
+                fs::write(&expected_path, &out).unwrap();
+            } else {
+                assert_eq!(out, fs::read(&expected_path).unwrap(), "Mismatch for test: {stem}");
+            }
+        }
+    }
+
+    #[test]
+    fn indent_fixtures_round_trip() {
+        let inputs_dir = Path::new("tests/fixtures/indent/inputs");
+        let expected_dir = Path::new("tests/fixtures/indent/expected");
+        let update_expected = std::env::var("UPDATE_EXPECTED").is_ok();
+
+        for entry in fs::read_dir(inputs_dir).unwrap() {
+            let input_path = entry.unwrap().path();
+            // Named after the style: `tabs.bs`, `spaces-2.html` (the width defaults to 4).
+            let stem = input_path.file_stem().unwrap().to_str().unwrap();
+            let indent = parse_indent(&stem.replace('-', ":")).unwrap();
+            let opposite = match indent {
+                Indent::Tabs(w) => Indent::Spaces(w),
+                Indent::Spaces(w) => Indent::Tabs(w),
+            };
+            let markdown = input_path.extension().is_some_and(|e| e == "bs");
+            let format = |src: &[u8], indent| {
+                let mut out = Vec::new();
+                transform(src, &mut out, &Options { markdown, indent: Some(indent), ..Options::default() });
+                out
+            };
+            let out = format(&fs::read(&input_path).unwrap(), indent);
+            assert_eq!(format(&format(&out, opposite), indent), out, "{stem} doesn't round-trip");
+
+            let expected_path = expected_dir.join(input_path.file_name().unwrap());
+            if update_expected {
+                fs::create_dir_all(expected_dir).unwrap();
+                fs::write(&expected_path, &out).unwrap();
+            } else {
+                assert_eq!(out, fs::read(&expected_path).unwrap(), "Mismatch for test: {stem}");
+            }
+        }
+    }
+
+    #[test]
+    fn stream_matches_transform() {
+        let dirs = ["inputs", "indent/inputs", "max-blank-lines/inputs"];
+        for dir in dirs.map(|d| Path::new("tests/fixtures").join(d)) {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                let ext = path.extension().unwrap_or_default();
+                let base = || Options::default().markdown(ext == "bs").markdown_document(ext == "md");
+                let all = || base().max_blank_lines(Some(1)).strip_trailing_whitespace(true).indent(Some(Indent::Tabs(4)));
+                // Tag styles, which depend on the elements open around the tag.
+                let tags = || {
+                    base()
+                        .foreign_content(ForeignContent::Reformat)
+                        .strip_void_slash(true)
+                        .tag_per_line(true)
+                        .self_closing(Some(SelfClosingStyle::Spaced))
+                        .quote_attrs(QuoteStyle::Double)
+                };
+                let lf = fs::read(&path).unwrap();
+                // The same document with CRLF line endings, and with a CR split from its LF.
+                let crlf: Vec<u8> = lf.iter().flat_map(|&b| if b == b'\n' { b"\r\n".to_vec() } else { vec![b] }).collect();
+                for (src, opts) in [(&lf, base()), (&lf, all()), (&lf, tags()), (&crlf, base()), (&crlf, all().newline(Some(Newline::Lf)))] {
+                    let mut expected = Vec::new();
+                    transform(src, &mut expected, &opts);
+                    for window in [1, 7, 64, 4096] {
+                        for capacity in [1, 2, 5, 8192] {
+                            let mut out = Vec::new();
+                            reformat_stream_in(io::BufReader::with_capacity(capacity, &src[..]), &mut out, &opts, window).unwrap();
+                            assert!(out == expected, "{} differs with window {window}, capacity {capacity}", path.display());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn regression_tests() {
+        let inputs_dir = Path::new("tests/fixtures/inputs");
+        let expected_dir = Path::new("tests/fixtures/expected");
+        let update_expected = std::env::var("UPDATE_EXPECTED").is_ok();
+
+        if !inputs_dir.exists() {
+            return; // No fixtures yet, skip
+        }
+
+        let entries: Vec<DirEntry> = fs::read_dir(inputs_dir).unwrap().map(|e| e.unwrap()).collect();
+
+        for entry in entries {
+            let input_path = entry.path();
+            let ext = input_path.extension().unwrap_or_default().to_str().unwrap_or("");
+            if ext != "bs" && ext != "html" && ext != "md" {
+                continue;
+            }
+
+            let stem = input_path.file_stem().unwrap().to_str().unwrap();
+            let expected_path = expected_dir.join(format!("{}.{}", stem, ext));
+
+            let src = fs::read(&input_path).unwrap();
+            if find_directive(&src).is_some() {
+                continue; // Settings in the file are the CLI's business (see `directive_fixtures` there)
+            }
+            // Markdown for .bs, not for .html, .md as a Markdown document
+            let out = if ext == "md" {
+                let mut out = Vec::new();
+                transform(&src, &mut out, &Options { markdown_document: true, ..Options::default() });
+                out
+            } else {
+                reformat(&src, ext == "bs")
+            };
+
+            let actual = String::from_utf8(out).unwrap();
+
+            if update_expected {
+                fs::create_dir_all(expected_dir).unwrap();
+                fs::write(&expected_path, actual.as_bytes()).unwrap();
+            } else {
+                let expected = fs::read_to_string(&expected_path).unwrap_or_else(|_| panic!("Expected file not found: {:?}", expected_path));
+                assert_eq!(actual, expected, "Mismatch for test: {}", stem);
+            }
+        }
+    }
+}
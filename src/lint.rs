@@ -0,0 +1,69 @@
+// src/lint.rs
+//
+// `reformahtml lint`: run the formatter over a batch of files without writing
+// anything and report its diagnostics. With `--structure`, the library's
+// structural checks (src/structure.rs) are reported too.
+
+use crate::checks::{Finding, Level, LintLevels};
+use crate::color::{self, ColorChoice};
+use crate::walk;
+use crate::{default_options, display_path, line_col, read_input, transform, Status};
+use clap::{ArgAction, Args};
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub struct LintArgs {
+    /// Check document structure: skipped heading levels, <dd> without a preceding <dt>,
+    /// and list items outside lists
+    #[arg(long, action = ArgAction::SetTrue)]
+    structure: bool,
+
+    /// Color the warnings: auto (if stdout is a terminal and NO_COLOR is unset), always or never
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    #[command(flatten)]
+    levels: LintLevels,
+
+    /// Files or directories (walked recursively) to check
+    #[arg(required = true, value_name = "PATHS")]
+    paths: Vec<PathBuf>,
+}
+
+pub fn run(args: &LintArgs) -> io::Result<Status> {
+    let mut files = Vec::new();
+    for path in &args.paths {
+        if path.is_dir() {
+            files.extend(walk::collect_files(path, walk::DEFAULT_EXTENSIONS, &[], walk::Symlinks::default())?);
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    let color = args.color.enabled(&io::stdout());
+    let mut problems = 0usize;
+    let mut files_with_problems = 0usize;
+    let mut failing = 0usize;
+    for path in &files {
+        let src = read_input(path)?;
+        let opts = default_options(path).lint_structure(args.structure);
+        let mut out = Vec::with_capacity(src.len());
+        let diagnostics: Vec<Finding> = transform(&src, &mut out, &opts)
+            .into_iter()
+            .filter_map(|d| {
+                let level = args.levels.level(d.check)?;
+                let (line, column) = line_col(&src, d.offset);
+                Some(Finding { line, column, check: d.check, level, message: d.message })
+            })
+            .collect();
+        for d in &diagnostics {
+            println!("{}", color::diagnostic(&format!("{}:{}:{}", display_path(path), d.line, d.column), d, color));
+            failing += usize::from(args.levels.fails(d.level, Level::Note));
+        }
+        problems += diagnostics.len();
+        files_with_problems += usize::from(!diagnostics.is_empty());
+    }
+    eprintln!("{problems} problem(s) in {files_with_problems} of {} file(s)", files.len());
+    Ok(if failing > 0 { Status::Changed } else { Status::Unchanged })
+}
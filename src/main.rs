@@ -1,1548 +1,1608 @@
 // src/main.rs
 //
-// reformahtml — fast HTML/Bikeshed reflower
+// reformahtml — command line interface over the library in src/lib.rs (see there
+// for what the reflow does)
 //
-// - Collapses intra-paragraph line breaks while preserving indentation/blank lines
-//   around structural HTML tags and standalone comments.
-// - Inside tags:
-//     • Outside quotes: collapse any whitespace runs → single space, EXCEPT when a newline-run
-//       is immediately before/after '=' → insert nothing.
-//     • Inside quotes: collapse only runs that include a newline → single space.
-// - HTML comments:
-//     • Standalone (only whitespace before on its line, and next char after '-->' is '\n'):
-//         keep verbatim and treat as a structural boundary on BOTH sides.
-//     • Otherwise: reflow the comment inline (collapse newline-including runs inside it).
-// - Elements with data-noreformat: copy their entire subtree verbatim.
-// - RAW-TEXT tags (verbatim): pre, textarea, script, style, xmp, wpt.
-// - Bikeshed/Markdown-aware reflow in text nodes (bullets, ordered lists, dt/dd, quotes,
-//   hr, ATX/Setext headings, fenced code blocks). List items and dt/dd items reflow wrapped lines.
-// - INLINE start tags at start-of-line soft-join into previous text unless exceptions apply.
-// - <br> preserves an immediately following '\n'.
-// - UTF-8 safe.
+// In-file settings: a <!-- reformahtml: markdown=off max-blank-lines=1 --> comment in the first
+// 512 bytes overrides the config file for that file (flags still win); it stays on its own line.
+//
+// Subcommands:
+//   report DIR      : format everything under DIR in memory and print a JSON/HTML impact report
+//   lint [--structure] PATHS : report diagnostics (and structural problems) without writing
+//   merge-driver O A B : git merge driver; formats all three versions before a line-based merge
+//   bench PATHS [--iterations N] : time formatting per file (allocations with --features count-allocs)
 //
 // CLI flags:
+//   --format html|markdown : markdown reflows a whole Markdown document without HTML tokenization
+//                     (default for .md/.markdown); raw HTML blocks and code are kept as is
 //   --markdown      : force-enable Markdown/Bikeshed reflow
 //   --no-markdown   : force-disable Markdown/Bikeshed reflow
-// Default: Markdown is enabled iff input file extension is ".bs" (case-insensitive).
-
-use clap::{ArgAction, Parser};
-use memchr::{memchr, memrchr};
+//   --ext-mode EXT=html|markdown : Markdown mode for an extension (repeatable; also [ext-mode] in config)
+//   --honor-white-space : keep contents of elements styled white-space: pre* verbatim
+//   --pre-class NAME: keep contents of elements with class NAME verbatim
+//   --skip-attr NAME[=VALUE] : keep elements with this attribute verbatim, like data-noreformat (repeatable)
+//   --skip-class NAME : keep elements with class NAME verbatim, like data-noreformat (repeatable)
+//   --structural-tag NAME : keep the indentation and newlines around <NAME>, like <div> (repeatable)
+//   --not-structural-tag NAME : don't treat <NAME> as structural (e.g. ruby)
+//   --inline-tag NAME : a line starting with <NAME> joins the previous line, like <a> (repeatable)
+//   --not-inline-tag NAME : a line starting with <NAME> stays on its own line (e.g. ref)
+//   --raw-tag NAME  : copy the contents of <NAME> verbatim, like <pre> (repeatable)
+//   --not-raw-tag NAME : reflow <NAME> even though it is raw text by default (e.g. style)
+//   --keep-attr-newlines NAME : keep the line breaks in quoted NAME="..." values, like srcset, d
+//                     and points (repeatable)
+//   --break-tag NAME: a newline right after <NAME> is kept, like after <br>
+//   --void-tag NAME : <NAME> has no end tag, like <br> (e.g. a custom <x-icon>)
+//   --break-comment TEXT : a newline right after <!-- TEXT --> is kept
+//   --self-closing-style spaced|compact : normalize the space before '/>'
+//   --no-self-closing-void : write <br/> and <img .../> as <br> and <img ...> (not in SVG/MathML)
+//   --quote-attrs double|single|keep : quote attribute values consistently where that is safe
+//   --escape-quotes : with --quote-attrs, also quote unquoted values and escape quotes in values
+//   --wrap-attrs WIDTH : put the attributes of a tag ending past column WIDTH on lines of their own
+//   --keep-long-tags WIDTH : copy a tag that would end past column WIDTH on one line as it is
+//   --tag-per-line  : start a new line before a structural tag that follows other content on its line
+//   --sort-attrs[=ORDER] : order attributes, ORDER's names first (default: id,class), then the rest
+//                     alphabetically
+//   --foreign-content verbatim|reformat : keep inline <svg>/<math> as is (default) or reformat them
+//                     with SVG-aware tag sets
+//   --rcdata reflow|verbatim : join the lines of <title> text (default) or keep them; a '<' in it
+//                     never starts a tag
+//   --format-metadata : align the values of the Bikeshed <pre class=metadata> block, trim trailing
+//                     white space in it and collapse its blank lines
+//   --collapse-doctype : join the lines of a DOCTYPE without an internal subset (default: kept verbatim)
+//   --newline lf|crlf|native : line endings of the output (default: those of the first line)
+//   --ensure-final-newline : end the output with a newline
+//   --trim-final-newlines : drop blank lines at the end of the output
+//   --strip-trailing-whitespace : remove spaces/tabs at the end of reflowed lines (not in raw text,
+//                     data-noreformat elements or Markdown hard breaks)
+//   --indent tabs|spaces[:WIDTH] : convert the indentation of reflowed lines, keeping its width
+//                     (raw text, data-noreformat and fenced code are left alone)
+//   --max-blank-lines N : collapse longer runs of blank lines before/after text and between tags to N
+//                     (neither touches a file ending in an unterminated tag, comment or raw text)
+//   --config FILE   : read settings from FILE instead of the nearest .reformahtml.toml
+//   --no-config     : don't look for configuration files (.editorconfig included)
+//   -r, --recursive : INPUT may be a directory; format every .bs/.html file below it
+//   --ext EXT       : in recursive mode, only format files with this extension (repeatable)
+//   --follow-symlinks : also walk symlinked directories (symlinked files are always rewritten through the link)
+//   --no-follow-symlinks : skip symlinked inputs and symlinks met while walking, with a warning
+//   --exclude GLOB  : skip matching paths while walking (repeatable); .reformahtmlignore at the root too
+//   --stdin-filepath PATH : read stdin, write stdout, choose defaults as if formatting PATH
+//   --encoding E    : auto (BOM / <meta charset> / UTF-8 if valid, else windows-1252), utf-8,
+//                     windows-1252 or latin1; output is re-encoded, unencodable characters become &#N;
+//   --lines START:END : only reformat those lines (snapped to whole tags/chunks); the rest is copied,
+//                     and the lines actually reformatted are noted on stderr
+//   --range START..END : like --lines, with byte offsets into a UTF-8 input
+//   --changed       : only reformat the chunks around lines that `git diff -U0 HEAD` reports
+//   --since REF     : like --changed, comparing with REF
+//   -j, --jobs N    : format N files in parallel (default: number of CPUs); output stays in input order
+//   --watch         : keep running; reformat inputs in place whenever they are saved
+//   --check         : write nothing; list files that would change, exit 1 if any
+//   --diff          : write nothing; print a unified diff per changed file, exit 1 if any
+//   --list-changed  : print only the paths of changed files, in input order (with --check: that would change)
+//   --print0        : end each --list-changed path with NUL instead of a newline
+//   --output-dir DIR : write each result to DIR/<input path> instead of overwriting the input
+//   --backup[=SUFFIX] : copy the original to INPUT+SUFFIX (default .orig) before rewriting it
+//   --force-backup  : let --backup replace an existing backup
+//   --preserve-mtime : restore a rewritten file's modification time (unchanged files are never touched)
+//   --stdout        : print the result to stdout; the input file is never written
+//   --archive FILE  : write the result into a tar archive instead of a file
+//   --skip-eol-only : don't write files whose only changes are line endings / final newline
+//   --best-effort K : reformat partially supported constructs of kind K instead of copying
+//                     them verbatim with a warning (php)
+//   -v, --verbose   : per-file log on stderr (mode, sizes, modified?); -vv adds transform counts
+//   --stats         : print lines joined, tags normalized, comments reflowed and sizes per file and in total
+//   --report json [--report-file FILE] : machine-readable per-file results (stdout unless FILE)
+//   --sourcemap FILE : write where each input token starts in the output, as JSON (one UTF-8 input)
+//   --color auto|always|never : color diffs and warnings (auto: if the stream is a terminal and
+//                     NO_COLOR is unset); formatted output and reports are never colored
+//   --allow CHECK / --deny CHECK : drop a check's diagnostics, or report them as errors (repeatable)
+//   --fail-on note|warning|error : exit 1 if a diagnostic of that level or higher is reported (default: error)
+//   --exit-zero     : exit with 0 even if files changed (errors still exit with 2 or 3)
+// Exit status: 0 = nothing to change, 1 = files were (or would be) reformatted, 2 = usage error,
+//              3 = a file couldn't be read, formatted or written.
+// Default: Markdown is enabled iff input file extension is ".bs" (case-insensitive), or the input
+// is a Markdown document (".md", ".markdown").
+mod bench;
+mod color;
+mod config;
+mod diff;
+mod editorconfig;
+mod encoding;
+mod git;
+mod lint;
+mod merge;
+mod pool;
+mod report;
+mod sink;
+mod walk;
+mod watch;
+
+#[cfg(feature = "count-allocs")]
+#[global_allocator]
+static ALLOCATOR: bench::counting::CountingAlloc = bench::counting::CountingAlloc;
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use reformahtml::checks::{self, Check, Finding, LintLevels};
+use reformahtml::{
+    find_directive, line_col, parse_indent, reformat_cow_with, transform, transform_mapped, Construct, Diagnostic,
+    Formatter, ForeignContent, Indent, InputFormat, Newline, Options, QuoteStyle, Rcdata, SelfClosingStyle, SourceMap,
+    Stats, Window,
+};
+use color::ColorChoice;
+use config::{Config, ConfigCache};
+use editorconfig::EditorConfigCache;
+use encoding::Encoding;
+use sink::{DirSink, FileSink, OutputSink, StdoutSink, TarSink};
+use std::borrow::Cow;
 use std::fs;
-use std::io;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
 
 /// CLI flags
 #[derive(Parser)]
-#[command(author, version, about)]
+#[command(author, version, about, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// Force-enable Bikeshed/Markdown-aware reflow
-    #[arg(long, action = ArgAction::SetTrue)]
-    markdown: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Force-disable Bikeshed/Markdown-aware reflow
-    #[arg(long = "no-markdown", action = ArgAction::SetTrue)]
-    no_markdown: bool,
+    #[command(flatten)]
+    format: FormatArgs,
 
-    /// Input file
-    input: PathBuf,
+    /// Leave the file untouched if the only changes are line endings or the final newline
+    #[arg(long = "skip-eol-only", action = ArgAction::SetTrue)]
+    skip_eol_only: bool,
 
-    /// Output file (default: overwrite input)
-    output: Option<PathBuf>,
-}
+    /// Format every matching file under a directory INPUT
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    recursive: bool,
 
-fn main() -> io::Result<()> {
-    let cli = Cli::parse();
+    /// File extension to format in recursive mode (repeatable; default: bs, html)
+    #[arg(long, value_name = "EXT")]
+    ext: Vec<String>,
 
-    let src = fs::read(&cli.input)?;
-    let mut out = Vec::with_capacity(src.len() + src.len() / 20 + 2048);
+    /// Walk into symlinked directories too (each directory is visited once)
+    #[arg(long = "follow-symlinks", action = ArgAction::SetTrue, conflicts_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
 
-    // Default: enable markdown if input ends with ".bs"
-    let default_md = cli
-        .input
-        .extension()
-        .map_or(false, |e| e.to_string_lossy().eq_ignore_ascii_case("bs"));
+    /// Skip symlinked inputs, and symlinks found while walking, with a warning
+    #[arg(long = "no-follow-symlinks", action = ArgAction::SetTrue)]
+    no_follow_symlinks: bool,
 
-    // Precedence: explicit flags override default; --no-markdown wins if both are present.
-    let use_markdown = if cli.no_markdown {
-        false
-    } else if cli.markdown {
-        true
-    } else {
-        default_md
-    };
+    /// Skip files and directories matching this gitignore-style pattern while walking (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
 
-    transform(&src, &mut out, use_markdown);
+    /// Write the result into a tar archive instead of the output file
+    #[arg(long, value_name = "FILE")]
+    archive: Option<PathBuf>,
 
-    let out_path = cli.output.as_ref().unwrap_or(&cli.input);
-    fs::write(out_path, out)?;
-    Ok(())
-}
+    /// Write each result under DIR at the input's relative path instead of overwriting it
+    #[arg(
+        long = "output-dir",
+        value_name = "DIR",
+        conflicts_with_all = ["stdout", "archive", "check", "diff", "watch", "stdin_filepath", "backup", "preserve_mtime"]
+    )]
+    output_dir: Option<PathBuf>,
 
-/* =============================== Core sets =============================== */
+    /// Before overwriting an input, copy its original bytes to INPUT+SUFFIX
+    #[arg(long, value_name = "SUFFIX", num_args = 0..=1, require_equals = true, default_missing_value = ".orig")]
+    backup: Option<String>,
 
-fn is_inline(name: &[u8]) -> bool {
-    matches_ignore_ascii_case(
-        name,
-        &[
-            b"a", b"abbr", b"b", b"bdi", b"bdo", b"cite", b"code", b"data", b"del", b"dfn", b"em",
-            b"i", b"ins", b"kbd", b"mark", b"q", b"s", b"samp", b"small", b"span", b"strong",
-            b"sub", b"sup", b"time", b"u", b"var", b"ref",
-        ],
-    )
-}
+    /// Let --backup replace an existing backup file
+    #[arg(long = "force-backup", action = ArgAction::SetTrue, requires = "backup")]
+    force_backup: bool,
 
-fn is_void(name: &[u8]) -> bool {
-    matches_ignore_ascii_case(
-        name,
-        &[
-            b"area", b"base", b"br", b"col", b"embed", b"hr", b"img", b"input", b"link", b"meta",
-            b"param", b"source", b"track", b"wbr",
-        ],
-    )
-}
+    /// Keep the modification time a file had before it was rewritten
+    #[arg(long = "preserve-mtime", action = ArgAction::SetTrue, conflicts_with_all = ["stdout", "archive"])]
+    preserve_mtime: bool,
 
-fn is_raw_text(name: &[u8]) -> bool {
-    matches_ignore_ascii_case(
-        name,
-        &[b"pre", b"textarea", b"script", b"style", b"xmp", b"wpt"],
-    )
-}
+    /// Print the result to stdout instead of overwriting the input
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "archive")]
+    stdout: bool,
 
-fn is_structural(name: &[u8]) -> bool {
-    matches_ignore_ascii_case(
-        name,
-        &[
-            b"address", b"article", b"aside", b"blockquote", b"details", b"dialog", b"div",
-            b"dl", b"dt", b"dd", b"fieldset", b"figcaption", b"figure", b"footer", b"form", b"h1",
-            b"h2", b"h3", b"h4", b"h5", b"h6", b"header", b"hgroup", b"hr", b"main", b"menu",
-            b"nav", b"ol", b"p", b"pre", b"search", b"section", b"table", b"thead", b"tbody",
-            b"tfoot", b"tr", b"td", b"th", b"caption", b"colgroup", b"ul", b"li", b"optgroup",
-            b"option", b"ruby", b"rt", b"rp", b"foreignobject",
-        ],
-    )
-}
+    /// Keep running and reformat files in place whenever they are saved (directories are watched recursively)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["check", "diff", "stdout", "archive"])]
+    watch: bool,
 
-/* ============================ Utility predicates ========================= */
+    /// Don't write anything; list files that would be reformatted and exit with status 1 if any
+    #[arg(long, action = ArgAction::SetTrue)]
+    check: bool,
 
-#[inline]
-fn is_name_char(b: u8) -> bool {
-    b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b':'
-}
+    /// Print only the paths of files that were (or with --check would be) reformatted, one per line, in input order
+    #[arg(long = "list-changed", action = ArgAction::SetTrue, conflicts_with_all = ["diff", "watch", "stdout", "stdin_filepath"])]
+    list_changed: bool,
 
-#[inline]
-fn is_space_tab(b: u8) -> bool {
-    b == b' ' || b == b'\t'
-}
+    /// Separate the --list-changed paths with NUL instead of newlines
+    #[arg(long, action = ArgAction::SetTrue, requires = "list_changed")]
+    print0: bool,
 
-#[inline]
-fn is_ws(b: u8) -> bool {
-    b == b' ' || b == b'\t' || b == b'\n' || b == b'\r'
-}
+    /// Don't write anything; print a unified diff of the changes and exit with status 1 if any
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["check", "archive"])]
+    diff: bool,
 
-fn matches_ignore_ascii_case(name: &[u8], set: &[&[u8]]) -> bool {
-    set.iter().any(|&s| name.eq_ignore_ascii_case(s))
-}
+    /// Read stdin, write stdout, and pick defaults (Markdown mode, config file,
+    /// diagnostics label) as if the input were this file
+    #[arg(long = "stdin-filepath", value_name = "PATH", conflicts_with_all = ["check", "diff", "watch", "archive"])]
+    stdin_filepath: Option<PathBuf>,
 
-fn trim_spaces(buf: &mut Vec<u8>) {
-    let mut start = 0usize;
-    while start < buf.len() && buf[start] == b' ' {
-        start += 1;
-    }
-    let mut end = buf.len();
-    while end > start && buf[end - 1] == b' ' {
-        end -= 1;
-    }
-    if start == 0 && end == buf.len() {
-        return;
-    }
-    let mut tmp = Vec::with_capacity(end - start);
-    tmp.extend_from_slice(&buf[start..end]);
-    *buf = tmp;
-}
+    /// Character encoding of the inputs; the output is written in the same encoding
+    #[arg(long, value_enum, value_name = "ENCODING", default_value_t = Encoding::Auto)]
+    encoding: Encoding,
 
-/* =============================== Tag parsing ============================= */
+    /// Only reformat lines START to END (1-based, inclusive), snapped outward to whole tags and text chunks
+    #[arg(long, value_name = "START:END", value_parser = parse_lines, conflicts_with = "watch")]
+    lines: Option<(usize, usize)>,
 
-#[derive(Clone, Copy, Debug)]
-struct TagInfo<'a> {
-    name: &'a [u8],
-    is_end: bool,
-    self_closing: bool,
-}
+    /// Only reformat the bytes START..END of a UTF-8 input, snapped outward to whole tags and text chunks
+    #[arg(long, value_name = "START..END", value_parser = parse_range, conflicts_with_all = ["watch", "lines"])]
+    range: Option<Range<usize>>,
 
-/// Find the '>' for a tag starting at `i` (s[i] == '<'), being quote-aware.
-fn find_tag_end(s: &[u8], mut i: usize) -> Option<usize> {
-    let n = s.len();
-    i += 1;
-    let mut quote: u8 = 0;
-    while i < n {
-        let b = s[i];
-        if quote != 0 {
-            if b == quote {
-                quote = 0;
-            }
-        } else if b == b'"' || b == b'\'' {
-            quote = b;
-        } else if b == b'>' {
-            return Some(i);
-        }
-        i += 1;
-    }
-    None
-}
+    /// Only reformat the chunks around lines that differ from HEAD, according to `git diff`
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["watch", "lines", "range", "stdin_filepath"])]
+    changed: bool,
 
-/// Extract tag name, end/self-closing flags from raw `<...>` bytes.
-fn parse_tag_info<'a>(tag: &'a [u8]) -> TagInfo<'a> {
-    let n = tag.len();
-    let mut i = 1;
+    /// Like --changed, comparing with REF instead of HEAD
+    #[arg(long, value_name = "REF", conflicts_with_all = ["watch", "lines", "range", "stdin_filepath", "changed"])]
+    since: Option<String>,
 
-    let mut is_end = false;
-    if i < n && tag[i] == b'/' {
-        is_end = true;
-        i += 1;
-    }
-    while i < n && is_ws(tag[i]) {
-        i += 1;
-    }
-    let start = i;
-    while i < n && is_name_char(tag[i]) {
-        i += 1;
-    }
-    let name = &tag[start..i];
+    /// Number of files formatted in parallel (default: number of CPUs)
+    #[arg(short, long, value_name = "N")]
+    jobs: Option<NonZeroUsize>,
 
-    // self-closing? check before '>'
-    let mut j = n - 1;
-    while j > 0 && is_ws(tag[j - 1]) {
-        j -= 1;
-    }
-    let self_closing = j >= 2 && tag[j - 1] == b'/';
+    /// Log each file to stderr: mode, sizes, whether it changed (-vv: also what was done)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
 
-    TagInfo {
-        name,
-        is_end,
-        self_closing,
-    }
-}
+    /// Print what the reflow did to each file, and a total, on stderr
+    #[arg(long, action = ArgAction::SetTrue)]
+    stats: bool,
 
-/* ====================== data-noreformat attribute scan =================== */
+    /// Describe every processed file in a machine-readable report (on stdout unless --report-file is given)
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    report: Option<ResultsFormat>,
 
-fn tag_has_noreformat_attr(tag: &[u8]) -> bool {
-    // Robust attribute scanner: [name] ( '=' [value] )?
-    let len = tag.len();
-    if len < 2 {
-        return false;
-    }
-    let mut i = 1usize;
+    /// Write the --report document to this file
+    #[arg(long = "report-file", value_name = "FILE", requires = "report")]
+    report_file: Option<PathBuf>,
 
-    while i < len && tag[i] != b'>' {
-        // skip whitespace and slashes
-        while i < len && (is_ws(tag[i]) || tag[i] == b'/') {
-            i += 1;
-        }
-        if i >= len || tag[i] == b'>' {
-            break;
-        }
+    /// Write a JSON map between byte offsets of the input and of the output to this file (one UTF-8 input)
+    #[arg(long, value_name = "FILE", conflicts_with = "watch")]
+    sourcemap: Option<PathBuf>,
 
-        // attribute name
-        if !is_name_char(tag[i]) {
-            // Not a valid name start; advance to avoid infinite loops.
-            i += 1;
-            continue;
-        }
-        let name_start = i;
-        i += 1;
-        while i < len && is_name_char(tag[i]) {
-            i += 1;
-        }
-        let name = &tag[name_start..i];
-        if name.eq_ignore_ascii_case(b"data-noreformat") {
-            return true;
-        }
+    /// Color diffs and warnings: auto (if the stream is a terminal and NO_COLOR is unset), always or never
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
 
-        // skip whitespace
-        while i < len && is_ws(tag[i]) {
-            i += 1;
-        }
+    #[command(flatten)]
+    levels: LintLevels,
 
-        // optional "= value"
-        if i < len && tag[i] == b'=' {
-            i += 1;
-            // skip whitespace
-            while i < len && is_ws(tag[i]) {
-                i += 1;
-            }
-            if i >= len || tag[i] == b'>' {
-                break;
-            }
+    /// Exit with status 0 even if files were (or would be) reformatted; errors still exit with 2 or 3
+    #[arg(long = "exit-zero", action = ArgAction::SetTrue)]
+    exit_zero: bool,
 
-            // quoted value
-            if tag[i] == b'"' || tag[i] == b'\'' {
-                let q = tag[i];
-                i += 1;
-                while i < len && tag[i] != q {
-                    i += 1;
-                }
-                if i < len && tag[i] == q {
-                    i += 1;
-                }
-            } else {
-                // unquoted value
-                while i < len && !is_ws(tag[i]) && tag[i] != b'>' {
-                    i += 1;
-                }
-            }
-        }
-        // loop continues to parse next attribute
-    }
-    false
+    /// INPUT [OUTPUT], or several inputs in --check mode ("-" is stdin/stdout)
+    #[arg(required_unless_present = "stdin_filepath", value_name = "PATHS")]
+    paths: Vec<PathBuf>,
 }
 
-/* ======================== Inside-tag normalization ====================== */
-
-fn normalize_inside_tag(tag: &[u8], out: &mut Vec<u8>) {
-    if tag.len() < 2 {
-        out.extend_from_slice(tag);
-        return;
-    }
-    let inner = &tag[1..tag.len() - 1];
+/// Flags that change how documents are formatted, shared by the subcommands that format.
+#[derive(Args)]
+struct FormatArgs {
+    /// Read inputs as HTML/Bikeshed or as Markdown documents (default: markdown for .md and .markdown)
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    format: Option<InputFormat>,
 
-    let mut buf: Vec<u8> = Vec::with_capacity(inner.len());
-    let mut i = 0usize;
-    let n = inner.len();
-    let mut quote: u8 = 0;
+    /// Force-enable Bikeshed/Markdown-aware reflow
+    #[arg(long, action = ArgAction::SetTrue)]
+    markdown: bool,
 
-    let push_space_once = |buf: &mut Vec<u8>| {
-        if !buf.last().map(|b| *b == b' ').unwrap_or(false) {
-            buf.push(b' ');
-        }
-    };
+    /// Force-disable Bikeshed/Markdown-aware reflow
+    #[arg(long = "no-markdown", action = ArgAction::SetTrue)]
+    no_markdown: bool,
 
-    while i < n {
-        let b = inner[i];
-        if quote != 0 {
-            if b == quote {
-                buf.push(b);
-                quote = 0;
-                i += 1;
-            } else if b == b'\n' || b == b'\r' || b == b' ' || b == b'\t' {
-                let mut j = i;
-                let mut saw_nl = false;
-                while j < n {
-                    let c = inner[j];
-                    if c == b'\n' || c == b'\r' || c == b' ' || c == b'\t' {
-                        if c == b'\n' {
-                            saw_nl = true;
-                        }
-                        j += 1;
-                    } else {
-                        break;
-                    }
-                }
-                if saw_nl {
-                    push_space_once(&mut buf);
-                } else {
-                    buf.extend_from_slice(&inner[i..j]);
-                }
-                i = j;
-            } else {
-                buf.push(b);
-                i += 1;
-            }
-            continue;
-        }
+    /// Markdown mode for files with extension EXT, e.g. `inc=markdown` (repeatable)
+    #[arg(long = "ext-mode", value_name = "EXT=MODE", value_parser = parse_ext_mode)]
+    ext_mode: Vec<(String, ExtMode)>,
+
+    /// Reformat this kind of partially supported construct instead of copying it verbatim
+    #[arg(long = "best-effort", value_enum)]
+    best_effort: Vec<Construct>,
+
+    /// Keep the contents of elements styled with `white-space: pre` (or pre-wrap, pre-line,
+    /// break-spaces) verbatim
+    #[arg(long = "honor-white-space", action = ArgAction::SetTrue)]
+    honor_white_space: bool,
+
+    /// Keep the contents of elements with this class verbatim (repeatable)
+    #[arg(long = "pre-class", value_name = "NAME")]
+    pre_class: Vec<String>,
+
+    /// Keep elements with this attribute verbatim, like `data-noreformat`; with `=VALUE`,
+    /// only where the attribute has that value, e.g. `data-format=off` (repeatable)
+    #[arg(long = "skip-attr", value_name = "NAME[=VALUE]")]
+    skip_attr: Vec<String>,
+
+    /// Keep elements with this class verbatim, like `data-noreformat` (repeatable)
+    #[arg(long = "skip-class", value_name = "NAME")]
+    skip_class: Vec<String>,
+
+    /// Treat this element like <div>: keep the indentation and newlines around its tags (repeatable)
+    #[arg(long = "structural-tag", value_name = "NAME")]
+    structural_tag: Vec<String>,
+
+    /// Don't treat this element as structural, e.g. `ruby` (repeatable)
+    #[arg(long = "not-structural-tag", value_name = "NAME")]
+    not_structural_tag: Vec<String>,
+
+    /// Treat this element like <a>: a line starting with it joins the previous line (repeatable)
+    #[arg(long = "inline-tag", value_name = "NAME")]
+    inline_tag: Vec<String>,
+
+    /// Don't treat this element as inline, e.g. `ref` (repeatable)
+    #[arg(long = "not-inline-tag", value_name = "NAME")]
+    not_inline_tag: Vec<String>,
+
+    /// Treat this element like <pre>: its contents are copied verbatim up to its end tag (repeatable)
+    #[arg(long = "raw-tag", value_name = "NAME")]
+    raw_tag: Vec<String>,
+
+    /// Don't treat this element as raw text, e.g. `style` (repeatable)
+    #[arg(long = "not-raw-tag", value_name = "NAME")]
+    not_raw_tag: Vec<String>,
+
+    /// Keep the newlines and indentation in quoted values of this attribute, on top of
+    /// srcset, d and points (repeatable)
+    #[arg(long = "keep-attr-newlines", value_name = "NAME")]
+    keep_attr_newlines: Vec<String>,
+
+    /// Treat this start tag like <br>: a newline right after it survives joining (repeatable)
+    #[arg(long = "break-tag", value_name = "NAME")]
+    break_tag: Vec<String>,
+
+    /// Treat this element like <br>, as having no contents or end tag (repeatable)
+    #[arg(long = "void-tag", value_name = "NAME")]
+    void_tag: Vec<String>,
+
+    /// Treat a comment with exactly this text (e.g. "keep-break") as a line break token (repeatable)
+    #[arg(long = "break-comment", value_name = "TEXT")]
+    break_comment: Vec<String>,
+
+    /// Write self-closing tags consistently as `<foo />` (spaced) or `<foo/>` (compact)
+    #[arg(long = "self-closing-style", value_enum, value_name = "STYLE")]
+    self_closing_style: Option<SelfClosingStyle>,
+
+    /// Drop the `/` from self-closing void elements, `<br/>` becoming `<br>` (not in SVG or MathML)
+    #[arg(long = "no-self-closing-void", action = ArgAction::SetTrue)]
+    no_self_closing_void: bool,
+
+    /// Write quoted attribute values with double or single quotes, unless the value
+    /// contains that quote (default: keep)
+    #[arg(long = "quote-attrs", value_enum, value_name = "STYLE")]
+    quote_attrs: Option<QuoteStyle>,
+
+    /// With --quote-attrs, also quote unquoted values, and rewrite values containing the
+    /// preferred quote with it escaped as `&quot;` or `&#39;`
+    #[arg(long = "escape-quotes", action = ArgAction::SetTrue, requires = "quote_attrs")]
+    escape_quotes: bool,
+
+    /// Reorder the attributes of each tag: the comma-separated names in ORDER first
+    /// (default: id,class), then the rest alphabetically. Tags with a repeated attribute
+    /// or template syntax are left alone
+    #[arg(
+        long = "sort-attrs",
+        value_name = "ORDER",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "id,class",
+        value_delimiter = ','
+    )]
+    sort_attrs: Option<Vec<String>>,
+
+    /// Put the attributes of a tag that would end past column WIDTH on lines of their own,
+    /// aligned under the first
+    #[arg(long = "wrap-attrs", value_name = "WIDTH")]
+    wrap_attrs: Option<usize>,
+
+    /// Keep the line breaks of a tag that would end past column WIDTH on one line (and
+    /// that --wrap-attrs doesn't wrap), only trimming spaces at the ends of its lines
+    #[arg(long = "keep-long-tags", value_name = "WIDTH")]
+    keep_long_tags: Option<usize>,
+
+    /// Start a new line, indented like the current one, before a structural start or end tag
+    /// that follows other content on its line
+    #[arg(long = "tag-per-line", action = ArgAction::SetTrue)]
+    tag_per_line: bool,
+
+    /// Keep inline <svg> and <math> verbatim (the default), or reformat them with SVG-aware tag sets
+    #[arg(long = "foreign-content", value_enum, value_name = "MODE")]
+    foreign_content: Option<ForeignContent>,
+
+    /// Join the lines of the text in <title> like other text (the default), or keep them
+    /// verbatim; a `<` in it never starts a tag either way
+    #[arg(long, value_enum, value_name = "MODE")]
+    rcdata: Option<Rcdata>,
+
+    /// Align the values of the Bikeshed `<pre class=metadata>` block, trim trailing white
+    /// space in it and collapse its runs of blank lines
+    #[arg(long = "format-metadata", action = ArgAction::SetTrue)]
+    format_metadata: bool,
+
+    /// Join the lines of a multi-line DOCTYPE like those of a tag (one with an internal
+    /// subset is always kept verbatim)
+    #[arg(long = "collapse-doctype", action = ArgAction::SetTrue)]
+    collapse_doctype: bool,
+
+    /// Line endings to write (default: those of the first line of each file)
+    #[arg(long, value_enum, value_name = "EOL")]
+    newline: Option<Newline>,
+
+    /// End the output with a newline if it doesn't already
+    #[arg(long = "ensure-final-newline", action = ArgAction::SetTrue)]
+    ensure_final_newline: bool,
+
+    /// Collapse blank lines at the end of the output, leaving a single newline
+    #[arg(long = "trim-final-newlines", action = ArgAction::SetTrue)]
+    trim_final_newlines: bool,
+
+    /// Collapse runs of more than N blank lines around tags and text to N (default: unlimited)
+    #[arg(long = "max-blank-lines", value_name = "N")]
+    max_blank_lines: Option<usize>,
+
+    /// Remove spaces and tabs at the end of lines (not in raw text, data-noreformat
+    /// elements or Markdown hard breaks)
+    #[arg(long = "strip-trailing-whitespace", action = ArgAction::SetTrue)]
+    strip_trailing_whitespace: bool,
+
+    /// Convert the indentation of each line to tabs or spaces (`tabs`, `spaces`, `spaces:2`; the width defaults to 4)
+    #[arg(long, value_name = "STYLE", value_parser = parse_indent)]
+    indent: Option<Indent>,
+
+    /// Read settings from this file instead of looking for .reformahtml.toml next to each input
+    #[arg(long, value_name = "FILE", conflicts_with = "no_config")]
+    config: Option<PathBuf>,
+
+    /// Don't look for .reformahtml.toml / reformahtml.toml or .editorconfig files
+    #[arg(long = "no-config", action = ArgAction::SetTrue)]
+    no_config: bool,
+
+    #[arg(skip)]
+    configs: ConfigCache,
+
+    #[arg(skip)]
+    editorconfigs: EditorConfigCache,
+
+    /// A `Formatter` for each config file seen (`None` for no config file)
+    #[arg(skip)]
+    formatters: FormatterCache,
+}
 
-        if b == b'"' || b == b'\'' {
-            quote = b;
-            buf.push(b);
-            i += 1;
-            continue;
-        }
+/// The formatters built so far, by config file and the settings of the input.
+type FormatterCache = Mutex<Vec<(Option<Arc<Config>>, PerInput, Arc<Formatter>)>>;
 
-        if is_ws(b) {
-            let mut j = i;
-            let mut saw_nl = false;
-            while j < n && is_ws(inner[j]) {
-                if inner[j] == b'\n' {
-                    saw_nl = true;
-                }
-                j += 1;
-            }
-            // Check neighbors around the run (outside quotes)
-            let mut p = i;
-            while p > 0 && is_ws(inner[p - 1]) {
-                p -= 1;
-            }
-            let left = if p > 0 { inner[p - 1] } else { 0 };
-            let mut q = j;
-            while q < n && is_ws(inner[q]) {
-                q += 1;
-            }
-            let right = if q < n { inner[q] } else { 0 };
+/// The settings that depend on the input itself, not only on the flags and its
+/// config file.
+#[derive(Clone, Copy, PartialEq)]
+struct PerInput {
+    markdown: bool,
+    format: InputFormat,
+    newline: Option<Newline>,
+    ensure_final_newline: bool,
+    strip_trailing_whitespace: bool,
+}
 
-            if saw_nl && (left == b'=' || right == b'=') {
-                // newline-run touching '=' → no space
-            } else {
-                push_space_once(&mut buf);
-            }
-            i = j;
-            continue;
+impl FormatArgs {
+    /// The configuration file that applies to `input`, if any.
+    fn config_for(&self, input: &Path) -> io::Result<Option<Arc<Config>>> {
+        if self.no_config {
+            Ok(None)
+        } else if let Some(path) = &self.config {
+            self.configs.file(path)
+        } else {
+            self.configs.for_input(input)
         }
-
-        buf.push(b);
-        i += 1;
     }
 
-    trim_spaces(&mut buf);
-
-    out.push(b'<');
-    out.extend_from_slice(&buf);
-    out.push(b'>');
-}
+    /// Transform options for one input file.
+    fn options_for(&self, input: &Path) -> io::Result<Options> {
+        let file = self.config_for(input)?;
+        let config = file.as_deref().unwrap_or(&Config::EMPTY);
+        Ok(self.options(config, self.per_input(input, config)?))
+    }
 
-/* ============================== Comments ================================ */
-
-/// Return (end_index_of_dash_in_terminator, is_standalone). If unterminated, end_index = usize::MAX.
-fn scan_comment(s: &[u8], i: usize) -> (usize, bool) {
-    // Assumes s[i..].starts_with("<!--")
-    let mut k = i + 4;
-    while let Some(p) = memchr(b'-', &s[k..]) {
-        let j = k + p;
-        if j + 2 < s.len() && s[j + 1] == b'-' && s[j + 2] == b'>' {
-            // standalone if only spaces/tabs since line start AND next char after '-->' is '\n'
-            let line_start = memrchr(b'\n', &s[..i]).map(|x| x + 1).unwrap_or(0);
-            let mut only_ws = true;
-            for &c in &s[line_start..i] {
-                if !(c == b' ' || c == b'\t') {
-                    only_ws = false;
-                    break;
-                }
-            }
-            let next_is_lf = if j + 3 < s.len() { s[j + 3] == b'\n' } else { false };
-            return (j, only_ws && next_is_lf);
+    /// The formatter for one input file, with the settings of its
+    /// `<!-- reformahtml: ... -->` comment between the flags and the config file.
+    /// Inputs with the same config file share one, unless they have a directive.
+    fn formatter_for(&self, input: &Path, directive: Option<&Config>) -> io::Result<Arc<Formatter>> {
+        let file = self.config_for(input)?;
+        let config = file.as_deref().unwrap_or(&Config::EMPTY);
+        if let Some(directive) = directive {
+            let merged = directive.or(config);
+            let per_input = self.per_input(input, &merged)?;
+            return Ok(Arc::new(Formatter::new(self.options(&merged, per_input))));
         }
-        k = j + 1;
-        if k >= s.len() {
-            break;
+        let per_input = self.per_input(input, config)?;
+        let mut cache = self.formatters.lock().unwrap();
+        let same = |(seen, seen_input, _): &&(Option<Arc<Config>>, PerInput, Arc<Formatter>)| {
+            let same_file = match (seen, &file) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            };
+            same_file && *seen_input == per_input
+        };
+        if let Some((_, _, formatter)) = cache.iter().find(same) {
+            return Ok(formatter.clone());
         }
+        let formatter = Arc::new(Formatter::new(self.options(config, per_input)));
+        cache.push((file.clone(), per_input, formatter.clone()));
+        Ok(formatter)
     }
-    (usize::MAX, false)
-}
 
-fn reflow_inline_comment(comment: &[u8], out: &mut Vec<u8>) {
-    // comment like <!-- ... -->
-    if comment.len() < 7 {
-        out.extend_from_slice(comment);
-        return;
+    /// The settings of `input` that its name and .editorconfig files decide.
+    fn per_input(&self, input: &Path, config: &Config) -> io::Result<PerInput> {
+        // .editorconfig properties come after both flags and .reformahtml.toml.
+        let editor = if self.no_config { Default::default() } else { self.editorconfigs.properties(input)? };
+        // Precedence: explicit flags, then --ext-mode, then the config file's
+        // [ext-mode] table and `markdown` key, then the default; --no-markdown wins
+        // if both flags are present.
+        let for_ext = |modes: &[(String, ExtMode)]| {
+            let ext = input.extension()?.to_string_lossy();
+            let (_, mode) = modes.iter().rev().find(|(e, _)| e.eq_ignore_ascii_case(&ext))?;
+            Some(*mode == ExtMode::Markdown)
+        };
+        let markdown = if self.no_markdown {
+            false
+        } else if self.markdown {
+            true
+        } else {
+            for_ext(&self.ext_mode)
+                .or_else(|| config.ext_mode.as_deref().and_then(for_ext))
+                .or(config.markdown)
+                .unwrap_or_else(|| markdown_default(input))
+        };
+        Ok(PerInput {
+            markdown,
+            format: self.format.or(config.format).unwrap_or_else(|| input_format_default(input)),
+            newline: self.newline.or(config.newline).or(editor.end_of_line),
+            ensure_final_newline: self.ensure_final_newline
+                || config.ensure_final_newline.or(editor.insert_final_newline).unwrap_or(false),
+            strip_trailing_whitespace: self.strip_trailing_whitespace
+                || config.strip_trailing_whitespace.or(editor.trim_trailing_whitespace).unwrap_or(false),
+        })
     }
-    let inner = &comment[4..comment.len() - 3];
-    out.extend_from_slice(b"<!--");
-    let mut i = 0usize;
-    let n = inner.len();
-    while i < n {
-        let b = inner[i];
-        if b == b'\n' {
-            // collapse newline + adjoining ws to a single space
-            if !out.last().map(|b| *b == b' ').unwrap_or(false) {
-                out.push(b' ');
-            }
-            i += 1;
-            while i < n && (inner[i] == b' ' || inner[i] == b'\t' || inner[i] == b'\n') {
-                i += 1;
-            }
+
+    /// The options of the flags on top of those of `config`, for an input with
+    /// the settings `per_input`.
+    fn options(&self, config: &Config, per_input: PerInput) -> Options {
+        let list = Self::list;
+        let best_effort = if self.best_effort.is_empty() {
+            config.best_effort.clone().unwrap_or_default()
         } else {
-            out.push(b);
-            i += 1;
-        }
+            self.best_effort.clone()
+        };
+        let mut opts = Options::default()
+            .markdown(per_input.markdown)
+            .markdown_document(per_input.format == InputFormat::Markdown)
+            .best_effort(best_effort)
+            .white_space_hints(self.honor_white_space || config.honor_white_space.unwrap_or(false))
+            .pre_classes(list(&self.pre_class, &config.pre_class))
+            .skip_classes(list(&self.skip_class, &config.skip_class))
+            .break_comments(list(&self.break_comment, &config.break_comment))
+            .self_closing(self.self_closing_style.or(config.self_closing_style))
+            .strip_void_slash(self.no_self_closing_void || config.no_self_closing_void.unwrap_or(false))
+            .quote_attrs(self.quote_attrs.or(config.quote_attrs).unwrap_or_default())
+            .escape_quotes(self.escape_quotes || config.escape_quotes.unwrap_or(false))
+            .sort_attrs(self.sort_attrs.clone().or_else(|| config.sort_attrs.clone().flatten()))
+            .wrap_attrs(self.wrap_attrs.or(config.wrap_attrs))
+            .keep_long_tags(self.keep_long_tags.or(config.keep_long_tags))
+            .tag_per_line(self.tag_per_line || config.tag_per_line.unwrap_or(false))
+            .foreign_content(self.foreign_content.or(config.foreign_content).unwrap_or_default())
+            .rcdata(self.rcdata.or(config.rcdata).unwrap_or_default())
+            .format_metadata(self.format_metadata || config.format_metadata.unwrap_or(false))
+            .collapse_doctype(self.collapse_doctype || config.collapse_doctype.unwrap_or(false))
+            .newline(per_input.newline)
+            .ensure_final_newline(per_input.ensure_final_newline)
+            .trim_final_newlines(self.trim_final_newlines || config.trim_final_newlines.unwrap_or(false))
+            .max_blank_lines(self.max_blank_lines.or(config.max_blank_lines))
+            .strip_trailing_whitespace(per_input.strip_trailing_whitespace)
+            .indent(self.indent.or(config.indent));
+        // The configured structural, inline and raw tags come on top of the defaults, less the removed ones.
+        let adjust = |tags: &mut Vec<String>, added: Vec<String>, removed: Vec<String>| {
+            tags.extend(added);
+            tags.retain(|t| !removed.iter().any(|n| n.eq_ignore_ascii_case(t)));
+        };
+        adjust(
+            &mut opts.structural_tags,
+            list(&self.structural_tag, &config.structural_tag),
+            list(&self.not_structural_tag, &config.not_structural_tag),
+        );
+        adjust(
+            &mut opts.inline_tags,
+            list(&self.inline_tag, &config.inline_tag),
+            list(&self.not_inline_tag, &config.not_inline_tag),
+        );
+        adjust(&mut opts.raw_tags, list(&self.raw_tag, &config.raw_tag), list(&self.not_raw_tag, &config.not_raw_tag));
+        // The configured skip attributes come on top of `data-noreformat`, break tags on top of `br`,
+        // void tags on top of HTML's, attributes keeping their newlines on top of `srcset`, `d` and `points`.
+        opts.skip_attrs.extend(list(&self.skip_attr, &config.skip_attr));
+        opts.break_tags.extend(list(&self.break_tag, &config.break_tag));
+        opts.void_tags.extend(list(&self.void_tag, &config.void_tag));
+        opts.keep_attr_newlines.extend(list(&self.keep_attr_newlines, &config.keep_attr_newlines));
+        opts
+    }
+
+    /// A list given on the command line replaces the one from the config file.
+    fn list(flag: &[String], configured: &Option<Vec<String>>) -> Vec<String> {
+        if flag.is_empty() { configured.clone().unwrap_or_default() } else { flag.to_vec() }
     }
-    out.extend_from_slice(b"-->");
 }
 
-/* ======================== Markdown/Bikeshed reflow ====================== */
+/// Formats for `--report`.
+#[derive(Clone, Copy, ValueEnum)]
+enum ResultsFormat {
+    Json,
+}
 
-#[derive(Clone, Copy)]
-struct Fence {
-    ch: u8,    // '`' or '~'
-    min: usize // min count
+/// Positional paths resolved against the selected mode.
+struct Targets {
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
 }
 
-fn is_hr_line_stripped(s: &str) -> bool {
-    let mut c = '\0';
-    let mut count = 0usize;
-    for ch in s.chars() {
-        if ch == ' ' || ch == '\t' { continue; }
-        if c == '\0' {
-            if ch == '*' || ch == '-' || ch == '_' {
-                c = ch;
-                count = 1;
-            } else {
-                return false;
+impl Cli {
+    /// Interpret the positional paths. Read-only modes take any number of inputs;
+    /// otherwise the historical `INPUT [OUTPUT]` form applies.
+    fn targets(&self) -> io::Result<Targets> {
+        let usage = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+        if self.stdin_filepath.is_some() {
+            return match self.paths.as_slice() {
+                [] | [_] if self.paths.iter().all(|p| is_stdin(p)) => {
+                    Ok(Targets { inputs: vec![PathBuf::from("-")], output: None })
+                }
+                _ => Err(usage("--stdin-filepath reads stdin; don't pass input files with it")),
+            };
+        }
+        if self.watch && self.paths.iter().any(|p| is_stdin(p)) {
+            return Err(usage("--watch cannot read from stdin"));
+        }
+        if self.check || self.diff || self.watch || self.output_dir.is_some() {
+            return Ok(Targets { inputs: self.paths.clone(), output: None });
+        }
+        match self.paths.as_slice() {
+            [input] => Ok(Targets { inputs: vec![input.clone()], output: None }),
+            [input, output] => {
+                if self.stdout {
+                    Err(usage("an OUTPUT path cannot be combined with --stdout"))
+                } else if self.archive.is_some() {
+                    Err(usage("an OUTPUT path cannot be combined with --archive"))
+                } else if input.is_dir() {
+                    Err(usage("an OUTPUT path cannot be combined with a directory input"))
+                } else if output.is_dir() {
+                    Err(usage(&format!(
+                        "OUTPUT '{}' is a directory; use --output-dir to write results into a directory",
+                        output.display()
+                    )))
+                } else {
+                    Ok(Targets { inputs: vec![input.clone()], output: Some(output.clone()) })
+                }
             }
-        } else {
-            if ch != c { return false; }
-            count += 1;
+            _ => Err(usage("expected INPUT [OUTPUT]; several inputs are only accepted with --check, --diff or --output-dir")),
         }
     }
-    count >= 3
-}
 
-fn is_setext_underline_stripped(s: &str) -> bool {
-    let mut c = '\0';
-    for ch in s.chars() {
-        if ch == ' ' || ch == '\t' { continue; }
-        if ch == '-' || ch == '=' {
-            if c == '\0' { c = ch; }
-            else if c != ch { return false; }
-        } else {
-            return false;
+    fn report_on_stdout(&self) -> bool {
+        self.report.is_some() && self.report_file.is_none()
+    }
+
+    /// The path `input` stands for: the `--stdin-filepath` hint for stdin, else itself.
+    fn name_of<'a>(&'a self, input: &'a Path) -> &'a Path {
+        match &self.stdin_filepath {
+            Some(hint) if is_stdin(input) => hint,
+            _ => input,
         }
     }
-    let count = s.chars().filter(|&ch| ch == '-' || ch == '=').count();
-    count >= 2
-}
 
-fn starts_with_bullet(line: &str) -> Option<(String, String)> {
-    // ^\s*[*-]\s+
-    let bytes = line.as_bytes();
-    let mut i = 0usize;
-    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
-    if i < bytes.len() && (bytes[i] == b'*' || bytes[i] == b'-') {
-        let marker = bytes[i] as char;
-        i += 1;
-        let mut j = i;
-        if j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') {
-            while j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') { j += 1; }
-            let prefix = format!("{}{} ", &line[..i-1], marker);
-            let first = line[j..].to_string();
-            return Some((prefix, first));
+    /// Expand directory inputs (in recursive mode) into the files below them.
+    fn expand(&self, inputs: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+        let exts: Vec<&str> = if self.ext.is_empty() {
+            walk::DEFAULT_EXTENSIONS.to_vec()
+        } else {
+            self.ext.iter().map(|e| e.trim_start_matches('.')).collect()
+        };
+        let symlinks = if self.follow_symlinks {
+            walk::Symlinks::Follow
+        } else if self.no_follow_symlinks {
+            walk::Symlinks::Skip
+        } else {
+            walk::Symlinks::Files
+        };
+        let excludes = walk::Ignore::new(self.exclude.iter().map(String::as_str));
+        let mut files = Vec::new();
+        for input in inputs {
+            if symlinks == walk::Symlinks::Skip && fs::symlink_metadata(input).is_ok_and(|m| m.is_symlink()) {
+                eprintln!("{}: warning: skipping symbolic link", input.display());
+            } else if is_stdin(input) || !input.is_dir() {
+                // Files named on the command line are formatted even if excluded.
+                let rel = input.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+                if self.verbose > 0 && excludes.is_ignored(rel.trim_start_matches("./"), false) {
+                    eprintln!("{}: note: matches --exclude but was named explicitly", input.display());
+                }
+                files.push(input.clone());
+            } else if self.recursive || self.watch {
+                files.extend(walk::collect_files(input, &exts, &self.exclude, symlinks)?);
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' is a directory; pass --recursive to format the files in it", input.display()),
+                ));
+            }
         }
+        Ok(files)
     }
-    None
 }
 
-fn starts_with_ol(line: &str) -> Option<(String, String)> {
-    // ^\s*\d+\.\s+
-    let bytes = line.as_bytes();
-    let mut i = 0usize;
-    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
-    let indent = &line[..i];
-
-    let mut pos = i;
-    while pos < bytes.len() && bytes[pos].is_ascii_digit() { pos += 1; }
-    if pos == i { return None; }
-    if pos >= bytes.len() || bytes[pos] != b'.' { return None; }
-    let dot_pos = pos;
-    pos += 1; // skip '.'
-    if pos >= bytes.len() || !(bytes[pos] == b' ' || bytes[pos] == b'\t') { return None; }
-    while pos < bytes.len() && (bytes[pos] == b' ' || bytes[pos] == b'\t') { pos += 1; }
-
-    let digits = &line[i..dot_pos];
-    let prefix = format!("{indent}{digits}. ");
-    let first = line[pos..].to_string();
-    Some((prefix, first))
+#[derive(Subcommand)]
+enum Command {
+    /// Format every file under a directory in memory and report what would change
+    Report(report::ReportArgs),
+    /// Check files without writing them and report diagnostics
+    Lint(lint::LintArgs),
+    /// Git merge driver: format %O %A %B, merge them line by line, and write the result to %A
+    MergeDriver(merge::MergeArgs),
+    /// Time formatting of each file (and count allocations with the count-allocs feature)
+    Bench(Box<bench::BenchArgs>),
 }
 
-fn is_atx_heading(line: &str) -> bool {
-    // ^\s*#{1,6}\s+
-    let bytes = line.as_bytes();
-    let mut i = 0usize;
-    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
-    let mut count = 0usize;
-    while i < bytes.len() && bytes[i] == b'#' && count < 6 {
-        count += 1;
-        i += 1;
-    }
-    if count == 0 { return false; }
-    i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t')
+/// Outcome of a run, reported as the exit status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    /// Nothing needed to change
+    Unchanged = 0,
+    /// Files were reformatted, or would be (--check, --diff); for `lint`, problems
+    /// were reported, and for `merge-driver`, conflicts remain
+    Changed = 1,
+    /// Invalid flags or paths; clap exits with this status too
+    Usage = 2,
+    /// A file couldn't be read, formatted or written
+    Error = 3,
 }
 
-fn is_blockquote(line: &str) -> bool {
-    // ^\s*>\s?
-    let bytes = line.as_bytes();
-    let mut i = 0usize;
-    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
-    if i < bytes.len() && bytes[i] == b'>' {
-        let j = i + 1;
-        if j == bytes.len() || bytes[j] == b' ' || bytes[j] == b'\t' { return true; }
+impl Status {
+    fn from_counts(changed: usize, failures: usize) -> Status {
+        if failures > 0 {
+            Status::Error
+        } else if changed > 0 {
+            Status::Changed
+        } else {
+            Status::Unchanged
+        }
     }
-    false
 }
 
-fn parse_dt(line: &str) -> Option<(String, String)> {
-    let bytes = line.as_bytes();
-    let mut i = 0usize;
-    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
-    if i >= bytes.len() || bytes[i] != b':' { return None; }
-    let mut j = i + 1;
-    let has_extra_space = j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t');
-    if has_extra_space || j == bytes.len() {
-        if has_extra_space {
-            while j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') { j += 1; }
-        }
-        let space = if j == bytes.len() { "" } else { " " };
-        let prefix = format!("{}:{space}", &line[..i]);
-        let first = line[j..].to_string();
-        Some((prefix, first))
-    } else {
-        None
-    }
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let status = run(&cli).unwrap_or_else(|e| {
+        eprintln!("error: {e}");
+        if e.kind() == io::ErrorKind::InvalidInput { Status::Usage } else { Status::Error }
+    });
+    let status = if cli.exit_zero && status == Status::Changed { Status::Unchanged } else { status };
+    ExitCode::from(status as u8)
 }
 
-fn parse_dd(line: &str) -> Option<(String, String)> {
-    let bytes = line.as_bytes();
-    let mut i = 0usize;
-    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
-    if i + 1 >= bytes.len() || bytes[i] != b':' || bytes[i + 1] != b':' { return None; }
-    let mut j = i + 2;
-    let has_extra_space = j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t');
-    if has_extra_space || j == bytes.len() {
-        if has_extra_space {
-            while j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') { j += 1; }
+fn run(cli: &Cli) -> io::Result<Status> {
+    match &cli.command {
+        Some(Command::Report(args)) => {
+            report::run(args)?;
+            return Ok(Status::Unchanged);
         }
-        let space = if j == bytes.len() { "" } else { " " };
-        let prefix = format!("{}::{space}", &line[..i]);
-        let first = line[j..].to_string();
-        Some((prefix, first))
-    } else {
-        None
+        Some(Command::Lint(args)) => return lint::run(args),
+        Some(Command::MergeDriver(args)) => return merge::run(args),
+        Some(Command::Bench(args)) => {
+            bench::run(args)?;
+            return Ok(Status::Unchanged);
+        }
+        None => {}
+    }
+    let targets = cli.targets()?;
+    if cli.watch {
+        watch::run(cli, &targets.inputs)?;
+        return Ok(Status::Unchanged);
+    }
+    let inputs = cli.expand(&targets.inputs)?;
+
+    let writes_stdout = cli.stdout
+        || targets.output.as_deref().is_some_and(is_stdin)
+        || (targets.output.is_none() && cli.output_dir.is_none() && inputs.iter().any(|p| is_stdin(p)));
+    if cli.report_on_stdout() && (cli.diff || cli.list_changed || (writes_stdout && !cli.check)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the --report document can't share stdout with formatted output, diffs or --list-changed; use --report-file",
+        ));
+    }
+    if cli.sourcemap.is_some() && inputs.len() != 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--sourcemap needs exactly one input"));
     }
+    if cli.list_changed && writes_stdout && !cli.check {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--list-changed can't share stdout with formatted output"));
+    }
+
+    if cli.check {
+        return check(cli, &inputs);
+    }
+    if cli.diff {
+        return print_diffs(cli, &inputs);
+    }
+
+    let mut sink: Box<dyn OutputSink> = match (&cli.archive, &cli.output_dir) {
+        (Some(archive), _) => Box::new(TarSink::create(archive)?),
+        _ if writes_stdout => Box::new(StdoutSink::new()),
+        (None, Some(dir)) => Box::new(DirSink { dir: dir.clone(), verbose: cli.verbose > 0 }),
+        (None, None) => Box::new(FileSink { output: targets.output.clone(), preserve_mtime: cli.preserve_mtime, atomic: false }),
+    };
+
+    let in_place = targets.output.is_none() && !writes_stdout && cli.archive.is_none() && cli.output_dir.is_none();
+    let walked = targets.inputs.iter().any(|p| p.is_dir());
+    let mut changed = 0usize;
+    let tally = format_all(cli, &inputs, |input, f| {
+        if cli.skip_eol_only && f.changed() && differs_only_in_line_endings(&f.src, f.out()) {
+            eprintln!("{}: skipped (line-ending-only changes)", display_path(cli.name_of(input)));
+            return Ok(());
+        }
+        // An input that is already formatted is left alone, mtime included.
+        if in_place && !f.changed() {
+            return Ok(());
+        }
+        if let Some(suffix) = cli.backup.as_deref().filter(|_| in_place) {
+            write_backup(input, &f.src, suffix, cli.force_backup)?;
+        }
+        sink.emit(input, f.out())?;
+        if f.changed() {
+            changed += 1;
+            if cli.list_changed {
+                io::stdout().lock().write_all(&list_entry(cli, input))?;
+            } else if walked && !writes_stdout && !cli.report_on_stdout() {
+                println!("reformatted {}", input.display());
+            }
+        }
+        Ok(())
+    });
+    sink.finish()?;
+    Ok(tally.status(changed))
 }
 
-fn fence_open(line: &str) -> Option<Fence> {
-    // ^\s*(```+|~~~+)
-    let bytes = line.as_bytes();
-    let mut i = 0usize;
-    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
-    if i >= bytes.len() { return None; }
-    if bytes[i] == b'`' || bytes[i] == b'~' {
-        let ch = bytes[i];
-        let mut j = i;
-        while j < bytes.len() && bytes[j] == ch { j += 1; }
-        if j - i >= 3 {
-            return Some(Fence { ch, min: j - i });
+/// `--check`: report files whose formatting would change, without writing anything.
+fn check(cli: &Cli, inputs: &[PathBuf]) -> io::Result<Status> {
+    let mut changed = 0usize;
+    let tally = format_all(cli, inputs, |input, f| {
+        if f.changed() {
+            changed += 1;
+            // With the report on stdout, it lists the changed files instead.
+            if cli.list_changed {
+                io::stdout().lock().write_all(&list_entry(cli, input))?;
+            } else if !cli.report_on_stdout() {
+                println!("{}", display_path(cli.name_of(input)));
+            }
         }
+        Ok(())
+    });
+    if !cli.list_changed {
+        let noun = if inputs.len() == 1 { "file" } else { "files" };
+        eprintln!("{changed} of {} {noun} would be reformatted", inputs.len());
     }
-    None
+    Ok(tally.status(changed))
 }
 
-fn fence_close(line: &str, f: Fence) -> bool {
-    // ^\s*<ch>{min,}\s*$
-    let bytes = line.as_bytes();
-    let mut i = 0usize;
-    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
-    let mut count = 0usize;
-    while i < bytes.len() && bytes[i] == f.ch { count += 1; i += 1; }
-    if count < f.min { return false; }
-    while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') { i += 1; }
-    i == bytes.len()
+/// One `--list-changed` record: the path as given, ended by a newline, or by NUL
+/// with `--print0` (then non-UTF-8 paths are written as they are).
+fn list_entry(cli: &Cli, input: &Path) -> Vec<u8> {
+    let name = cli.name_of(input);
+    let mut entry = if cli.print0 && !is_stdin(name) {
+        name.as_os_str().as_encoded_bytes().to_vec()
+    } else {
+        display_path(name).into_owned().into_bytes()
+    };
+    entry.push(if cli.print0 { b'\0' } else { b'\n' });
+    entry
 }
 
-/* ---------- Helpers to keep DT/DD on their own lines during reflow ---------- */
-
-#[inline]
-fn body_begins_with_dt_or_dd_after_single_lf(body: &[u8]) -> bool {
-    // Matches: "\n" + ws* + ":" [ ":" ] (space/tab or end)
-    if body.is_empty() || body[0] != b'\n' { return false; }
-    let mut j = 1usize;
-    while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
-    if j >= body.len() || body[j] != b':' { return false; }
-    j += 1;
-    if j < body.len() && body[j] == b':' { j += 1; }
-    if j >= body.len() { return true; }
-    body[j] == b' ' || body[j] == b'\t'
+/// Number of unchanged lines shown around each change by `--diff`.
+const DIFF_CONTEXT: usize = 3;
+
+/// `--diff`: print a unified diff per changed input instead of writing anything.
+fn print_diffs(cli: &Cli, inputs: &[PathBuf]) -> io::Result<Status> {
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    let mut changed = 0usize;
+    let color = cli.color.enabled(&io::stdout());
+    let tally = format_all(cli, inputs, |input, f| {
+        let patch = diff::unified(&display_path(cli.name_of(input)), &f.src, f.out(), DIFF_CONTEXT, color);
+        changed += usize::from(!patch.is_empty());
+        stdout.write_all(&patch)
+    });
+    stdout.flush()?;
+    Ok(tally.status(changed))
 }
 
-/// Return true if the **line containing `pos`** begins (after optional spaces/tabs)
-/// with `: ` or `:: ` — i.e., a DT/DD marker. This handles the case where `pos`
-/// points into the *same line* (e.g., at a `<` that follows the marker).
-fn line_at_pos_starts_with_dt_or_dd(src: &[u8], pos: usize) -> bool {
-    let n = src.len();
-    if pos > n { return false; }
-    let line_start = memrchr(b'\n', &src[..pos]).map(|x| x + 1).unwrap_or(0);
-    let mut i = line_start;
-    while i < n && (src[i] == b' ' || src[i] == b'\t') { i += 1; }
-    if i >= n { return false; }
-    if src[i] != b':' { return false; }
-    i += 1;
-    if i < n && src[i] == b':' { i += 1; }
-    if i >= n { return true; }
-    src[i] == b' ' || src[i] == b'\t'
+/// One input and its formatted version.
+struct Formatted {
+    src: Vec<u8>,
+    /// The formatted bytes, or `None` if they are identical to `src`, which
+    /// `reformahtml::reformat_cow_with` tells without copying them
+    out: Option<Vec<u8>>,
+    /// Diagnostics left after `--allow`/`--deny`, printed by `format_all` in input order
+    diagnostics: Vec<Finding>,
+    markdown: bool,
+    stats: Stats,
+    /// With `--lines` or `--range`: what was reformatted, or `None` if the range
+    /// held nothing to reformat
+    reformatted: Option<Option<Reformatted>>,
 }
 
-/// If body starts with "\n"+indent+":"[":"], return index of the first ':' (end of indent).
-#[inline]
-fn leading_lf_indent_end_before_dt_or_dd(body: &[u8]) -> Option<usize> {
-    if body.is_empty() || body[0] != b'\n' { return None; }
-    let mut j = 1usize;
-    while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
-    if j >= body.len() || body[j] != b':' { return None; }
-    // optional second ':'
-    let mut k = j + 1;
-    if k < body.len() && body[k] == b':' { k += 1; }
-    if k < body.len() && !(body[k] == b' ' || body[k] == b'\t') {
-        return None;
+impl Formatted {
+    /// The formatted bytes.
+    fn out(&self) -> &[u8] {
+        self.out.as_deref().unwrap_or(&self.src)
     }
-    Some(j)
-}
 
-fn reflow_markdown_text(text: &str) -> String {
-    if text.is_empty() {
-        return String::new();
+    fn changed(&self) -> bool {
+        self.out.is_some()
     }
 
-    let mut out = String::with_capacity(text.len());
-    let mut para_parts: Vec<String> = Vec::new();
-    let mut in_fence: Option<Fence> = None;
-    let mut prev_nonblank_was_paragraph = false;
-
-    let mut lines_iter = text.split_inclusive('\n').peekable();
-
-    let flush_para = |add_trailing_nl: bool, out: &mut String, para_parts: &mut Vec<String>| {
-        if para_parts.is_empty() { return; }
-        if para_parts.len() == 1 {
-            out.push_str(&para_parts[0]);
-        } else {
-            let first = para_parts[0].trim_end_matches([' ', '\t']);
-            out.push_str(first);
-            for s in para_parts.iter().skip(1) {
-                let s2 = s.trim_start_matches([' ', '\t']);
-                out.push(' ');
-                out.push_str(s2);
-            }
-        }
-        if add_trailing_nl { out.push('\n'); }
-        para_parts.clear();
-    };
-
-    while let Some(raw) = lines_iter.next() {
-        let had_nl = raw.ends_with('\n');
-        let line_no_nl = if had_nl { &raw[..raw.len()-1] } else { raw };
-        let line_stripped_ws = line_no_nl.trim();
-
-        if let Some(f) = in_fence {
-            if fence_close(line_no_nl, f) {
-                flush_para(false, &mut out, &mut para_parts);
-                out.push_str(raw);
-                in_fence = None;
-                prev_nonblank_was_paragraph = false;
-            } else {
-                out.push_str(raw);
-            }
-            continue;
-        }
-
-        if line_stripped_ws.is_empty() {
-            flush_para(true, &mut out, &mut para_parts);
-            out.push_str(raw);
-            prev_nonblank_was_paragraph = false;
-            continue;
-        }
-
-        if let Some(f) = fence_open(line_no_nl) {
-            flush_para(false, &mut out, &mut para_parts);
-            in_fence = Some(f);
-            out.push_str(raw);
-            prev_nonblank_was_paragraph = false;
-            continue;
-        }
+    fn into_out(self) -> Vec<u8> {
+        self.out.unwrap_or(self.src)
+    }
+}
 
-        // Handle UL/OL/DT/DD first
-        if let Some((prefix, first_text)) = starts_with_bullet(line_no_nl) {
-            flush_para(true, &mut out, &mut para_parts);
-            let mut contents: Vec<String> = vec![first_text];
-            let mut last_had_nl = had_nl;
-
-            while let Some(peek) = lines_iter.peek() {
-                let nxt_raw = *peek;
-                let nxt_had_nl = nxt_raw.ends_with('\n');
-                let nxt = if nxt_had_nl { &nxt_raw[..nxt_raw.len()-1] } else { nxt_raw };
-                let nxt_stripped = nxt.trim();
-
-                if nxt_stripped.is_empty() { break; }
-                if fence_open(nxt).is_some()
-                    || is_atx_heading(nxt)
-                    || starts_with_bullet(nxt).is_some()
-                    || starts_with_ol(nxt).is_some()
-                    || parse_dt(nxt).is_some() || parse_dd(nxt).is_some()
-                    || is_blockquote(nxt)
-                    || is_hr_line_stripped(nxt_stripped)
-                    || is_setext_underline_stripped(nxt_stripped)
-                { break; }
-                contents.push(nxt.trim_start_matches([' ', '\t']).to_string());
-                last_had_nl = nxt_had_nl;
-                lines_iter.next();
-            }
+/// The part of an input that `--lines` or `--range` reformatted.
+struct Reformatted {
+    input: Range<usize>,
+    output: Range<usize>,
+    /// First and last line (1-based) of `input` and of `output`
+    lines: ((usize, usize), (usize, usize)),
+}
 
-            let mut joined = contents.remove(0).trim_end_matches([' ', '\t']).to_string();
-            for c in contents {
-                joined.push(' ');
-                joined.push_str(c.trim_start_matches([' ', '\t']));
-            }
-            out.push_str(&prefix);
-            out.push_str(&joined);
-            if last_had_nl { out.push('\n'); }
-            prev_nonblank_was_paragraph = false;
-            continue;
+/// Read and transform `input` with the options that apply to it.
+fn format_file(cli: &Cli, input: &Path) -> io::Result<Formatted> {
+    let src = read_input(input)?;
+    let encoding = cli.encoding.resolve(&src).context("decode", input)?;
+    let text = encoding.decode(&src).context("decode", input)?;
+    let name = cli.name_of(input);
+    let directive = find_directive(&text).map(|(offset, settings)| (offset, Config::from_directive(settings)));
+    let formatter = cli.format.formatter_for(name, directive.as_ref().map(|(_, (config, _))| config))?;
+    let opts = formatter.options();
+    let mut stats = Stats::default();
+    let counting = cli.stats || cli.verbose > 1;
+    let requested = match (cli.lines, &cli.range) {
+        (Some((start, end)), _) => {
+            let lines = line_offset(&text, start)..line_offset(&text, end + 1);
+            Some(vec![lines])
         }
-
-        if let Some((prefix, first_text)) = starts_with_ol(line_no_nl) {
-            flush_para(true, &mut out, &mut para_parts);
-            let mut contents: Vec<String> = vec![first_text];
-            let mut last_had_nl = had_nl;
-
-            while let Some(peek) = lines_iter.peek() {
-                let nxt_raw = *peek;
-                let nxt_had_nl = nxt_raw.ends_with('\n');
-                let nxt = if nxt_had_nl { &nxt_raw[..nxt_raw.len()-1] } else { nxt_raw };
-                let nxt_stripped = nxt.trim();
-
-                if nxt_stripped.is_empty() { break; }
-                if fence_open(nxt).is_some()
-                    || is_atx_heading(nxt)
-                    || starts_with_bullet(nxt).is_some()
-                    || starts_with_ol(nxt).is_some()
-                    || parse_dt(nxt).is_some() || parse_dd(nxt).is_some()
-                    || is_blockquote(nxt)
-                    || is_hr_line_stripped(nxt_stripped)
-                    || is_setext_underline_stripped(nxt_stripped)
-                { break; }
-                contents.push(nxt.trim_start_matches([' ', '\t']).to_string());
-                last_had_nl = nxt_had_nl;
-                lines_iter.next();
+        (None, Some(range)) => {
+            // Offsets are those of the file, so they must not move when decoding.
+            if encoding != Encoding::Utf8 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--range only works on UTF-8 input"));
             }
-
-            let mut joined = contents.remove(0).trim_end_matches([' ', '\t']).to_string();
-            for c in contents {
-                joined.push(' ');
-                joined.push_str(c.trim_start_matches([' ', '\t']));
+            if range.end > text.len() {
+                let message = format!("--range {}..{} ends past the end of the input ({} bytes)", range.start, range.end, text.len());
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
             }
-            out.push_str(&prefix);
-            out.push_str(&joined);
-            if last_had_nl { out.push('\n'); }
-            prev_nonblank_was_paragraph = false;
-            continue;
+            Some(vec![range.clone()])
         }
-
-        if let Some((prefix, first_text)) = parse_dt(line_no_nl) {
-            // Definition term
-            flush_para(true, &mut out, &mut para_parts);
-            let mut contents: Vec<String> = vec![first_text];
-            let mut last_had_nl = had_nl;
-
-            while let Some(peek) = lines_iter.peek() {
-                let nxt_raw = *peek;
-                let nxt_had_nl = nxt_raw.ends_with('\n');
-                let nxt = if nxt_had_nl { &nxt_raw[..nxt_raw.len()-1] } else { nxt_raw };
-                let nxt_stripped = nxt.trim();
-
-                if nxt_stripped.is_empty() { break; }
-                if fence_open(nxt).is_some()
-                    || is_atx_heading(nxt)
-                    || starts_with_bullet(nxt).is_some()
-                    || starts_with_ol(nxt).is_some()
-                    || parse_dt(nxt).is_some() || parse_dd(nxt).is_some()
-                    || is_blockquote(nxt)
-                    || is_hr_line_stripped(nxt_stripped)
-                    || is_setext_underline_stripped(nxt_stripped)
-                { break; }
-                contents.push(nxt.trim_start_matches([' ', '\t']).to_string());
-                last_had_nl = nxt_had_nl;
-                lines_iter.next();
+        (None, None) => match cli.since.as_deref().or(cli.changed.then_some("HEAD")) {
+            Some(_) if is_stdin(input) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--changed and --since need files, not stdin"));
             }
-
-            let mut joined = contents.remove(0).trim_end_matches([' ', '\t']).to_string();
-            for c in contents {
-                joined.push(' ');
-                joined.push_str(c.trim_start_matches([' ', '\t']));
+            // An untracked file is formatted as a whole.
+            Some(rev) => git::changed_lines(input, rev)?.map(|lines| {
+                lines.into_iter().map(|(start, end)| line_offset(&text, start)..line_offset(&text, end + 1)).collect()
+            }),
+            None => None,
+        },
+    };
+    let mut window = requested.map(Window::new);
+    let (out, diagnostics) = match &cli.sourcemap {
+        Some(path) => {
+            // Offsets are those of the file, as for --range.
+            if encoding != Encoding::Utf8 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--sourcemap only works on UTF-8 input"));
             }
-            out.push_str(&prefix);
-            out.push_str(&joined);
-            if last_had_nl { out.push('\n'); }
-            prev_nonblank_was_paragraph = false;
-            continue;
+            let mut out = Vec::with_capacity(text.len() + text.len() / 20 + 2048);
+            let (diagnostics, map) = transform_mapped(&text, &mut out, opts, counting.then_some(&mut stats), window.as_mut());
+            let json = report::render_source_map_json(&display_path(name), &map);
+            fs::write(path, json).context("write source map", path)?;
+            let out = if out == *text { Cow::Borrowed(&text[..]) } else { Cow::Owned(out) };
+            (out, diagnostics)
         }
+        None => reformat_cow_with(&text, opts, counting.then_some(&mut stats), window.as_mut()),
+    };
+    let reformatted = window.map(|w| {
+        let (input, output) = (w.input?, w.output?);
+        let line_span = |bytes: &[u8], r: &Range<usize>| (line_col(bytes, r.start).0, line_col(bytes, r.end.max(r.start + 1) - 1).0);
+        let lines = (line_span(&text, &input), line_span(&out, &output));
+        Some(Reformatted { input, output, lines })
+    });
+    let warnings = directive.into_iter().flat_map(|(offset, (_, warnings))| {
+        warnings.into_iter().map(move |message| Diagnostic { offset, check: Check::InvalidSetting, message })
+    });
+    let diagnostics = warnings
+        .chain(diagnostics)
+        .filter_map(|d| {
+            let level = cli.levels.level(d.check)?;
+            let (line, column) = line_col(&text, d.offset);
+            Some(Finding { line, column, check: d.check, level, message: d.message })
+        })
+        .collect();
+    // Decoding and encoding round-trip, so unchanged text means unchanged bytes.
+    let out = match out {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(out) => Some(encoding.encode(out)),
+    };
+    Ok(Formatted { src, out, diagnostics, markdown: opts.markdown, stats, reformatted })
+}
 
-        if let Some((prefix, first_text)) = parse_dd(line_no_nl) {
-            // Definition description
-            flush_para(true, &mut out, &mut para_parts);
-            let mut contents: Vec<String> = vec![first_text];
-            let mut last_had_nl = had_nl;
-
-            while let Some(peek) = lines_iter.peek() {
-                let nxt_raw = *peek;
-                let nxt_had_nl = nxt_raw.ends_with('\n');
-                let nxt = if nxt_had_nl { &nxt_raw[..nxt_raw.len()-1] } else { nxt_raw };
-                let nxt_stripped = nxt.trim();
-
-                if nxt_stripped.is_empty() { break; }
-                if fence_open(nxt).is_some()
-                    || is_atx_heading(nxt)
-                    || starts_with_bullet(nxt).is_some()
-                    || starts_with_ol(nxt).is_some()
-                    || parse_dt(nxt).is_some() || parse_dd(nxt).is_some()
-                    || is_blockquote(nxt)
-                    || is_hr_line_stripped(nxt_stripped)
-                    || is_setext_underline_stripped(nxt_stripped)
-                { break; }
-                contents.push(nxt.trim_start_matches([' ', '\t']).to_string());
-                last_had_nl = nxt_had_nl;
-                lines_iter.next();
+/// Format `inputs` on `--jobs` threads and hand each result to `each` in input order,
+/// after printing its diagnostics. A file that fails to read, panics, or makes `each`
+/// fail is reported on stderr without stopping the others.
+fn format_all(cli: &Cli, inputs: &[PathBuf], mut each: impl FnMut(&Path, Formatted) -> io::Result<()>) -> Tally {
+    let jobs = cli.jobs.map_or_else(pool::default_jobs, NonZeroUsize::get);
+    let (mut failures, mut failing) = (0usize, 0usize);
+    let (mut total, mut total_in, mut total_out) = (Stats::default(), 0usize, 0usize);
+    let mut results: Vec<report::FileResult> = Vec::new();
+    let color = cli.color.enabled(&io::stderr());
+    pool::for_each_ordered(
+        inputs,
+        jobs,
+        |input| format_file(cli, input),
+        |input, result| {
+            let name = display_path(cli.name_of(input)).into_owned();
+            let mut entry = report::FileResult { path: name.clone(), ..report::FileResult::default() };
+            let result = match result {
+                Ok(Ok(f)) => {
+                    for d in &f.diagnostics {
+                        eprintln!("{}", color::diagnostic(&format!("{name}:{}:{}", d.line, d.column), d, color));
+                        failing += usize::from(cli.levels.fails(d.level, checks::Level::Error));
+                    }
+                    if cli.report.is_some() {
+                        entry.changed = f.changed();
+                        entry.bytes_in = f.src.len();
+                        entry.bytes_out = f.out().len();
+                        entry.markdown = f.markdown;
+                        entry.diagnostics = f.diagnostics.clone();
+                    }
+                    match (&f.reformatted, cli.lines, &cli.range) {
+                        _ if cli.list_changed => {}
+                        (Some(Some(r)), Some(_), _) => {
+                            let ((a, b), (c, d)) = r.lines;
+                            eprintln!("{name}: reformatted lines {a}:{b} ({c}:{d} in the output)");
+                        }
+                        (Some(Some(r)), None, Some(_)) => {
+                            let (input, output) = (&r.input, &r.output);
+                            eprintln!("{name}: reformatted bytes {input:?} ({output:?} in the output)");
+                        }
+                        (Some(None), Some((start, end)), _) => eprintln!("{name}: nothing to reformat in lines {start}:{end}"),
+                        (Some(None), None, Some(range)) => eprintln!("{name}: nothing to reformat in bytes {range:?}"),
+                        _ => {}
+                    }
+                    if cli.verbose > 0 {
+                        log_file(cli, input, &f);
+                    }
+                    if cli.stats {
+                        eprintln!("{}: {}", display_path(cli.name_of(input)), stats_summary(&f.stats, f.src.len(), f.out().len()));
+                        total.add(&f.stats);
+                        total_in += f.src.len();
+                        total_out += f.out().len();
+                    }
+                    each(input, f)
+                }
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(io::Error::other(format!("internal error while formatting '{name}'"))),
+            };
+            if let Err(e) = result {
+                eprintln!("error: {e}");
+                entry.error = Some(e.to_string());
+                failures += 1;
             }
-
-            let mut joined = contents.remove(0).trim_end_matches([' ', '\t']).to_string();
-            for c in contents {
-                joined.push(' ');
-                joined.push_str(c.trim_start_matches([' ', '\t']));
+            if cli.report.is_some() {
+                results.push(entry);
             }
-            out.push_str(&prefix);
-            out.push_str(&joined);
-            if last_had_nl { out.push('\n'); }
-            prev_nonblank_was_paragraph = false;
-            continue;
+        },
+    );
+    if cli.report.is_some() {
+        let json = report::render_results_json(&results);
+        let written = match &cli.report_file {
+            Some(path) => fs::write(path, json).context("write report", path),
+            None => io::stdout().lock().write_all(json.as_bytes()),
+        };
+        if let Err(e) = written {
+            eprintln!("error: {e}");
+            failures += 1;
         }
+    }
+    if cli.stats && inputs.len() > 1 {
+        eprintln!("total ({} files): {}", inputs.len(), stats_summary(&total, total_in, total_out));
+    }
+    if failing > 0 {
+        eprintln!("{failing} diagnostic(s) at or above the --fail-on level");
+    }
+    Tally { failures, failing }
+}
 
-        // Generic structural lines
-        let is_structural_line =
-            is_atx_heading(line_no_nl) ||
-            is_blockquote(line_no_nl) ||
-            is_hr_line_stripped(line_stripped_ws) ||
-            (is_setext_underline_stripped(line_stripped_ws) && prev_nonblank_was_paragraph);
-
-        if is_structural_line {
-            flush_para(true, &mut out, &mut para_parts);
-            out.push_str(raw);
-            prev_nonblank_was_paragraph = false;
-            continue;
-        }
+/// What `format_all` counted besides the files it handed on.
+struct Tally {
+    /// Files that couldn't be read, formatted or handled
+    failures: usize,
+    /// Diagnostics at or above the `--fail-on` level
+    failing: usize,
+}
 
-        // Regular paragraph line
-        para_parts.push(line_no_nl.to_string());
-        prev_nonblank_was_paragraph = true;
+impl Tally {
+    /// Exit status of a run that changed (or would change) `changed` files.
+    /// Failing diagnostics exit with 1, like changes do.
+    fn status(&self, changed: usize) -> Status {
+        Status::from_counts(changed + self.failing, self.failures)
     }
+}
 
-    // flush at end
-    if !para_parts.is_empty() {
-        let mut buf = String::new();
-        let first = para_parts[0].trim_end_matches([' ', '\t']);
-        buf.push_str(first);
-        for s in para_parts.iter().skip(1) {
-            buf.push(' ');
-            buf.push_str(s.trim_start_matches([' ', '\t']));
-        }
-        out.push_str(&buf);
-    }
+/// `--stats` line for one file or the total.
+fn stats_summary(stats: &Stats, bytes_in: usize, bytes_out: usize) -> String {
+    format!(
+        "{} lines joined, {} tags normalized, {} comments reflowed, {bytes_in} -> {bytes_out} bytes",
+        stats.lines_joined, stats.tags_normalized, stats.comments_reflowed
+    )
+}
 
-    out
+/// `-v`: one line per file on stderr; `-vv` adds what the transform did.
+fn log_file(cli: &Cli, input: &Path, f: &Formatted) {
+    let mut line = format!(
+        "{}: {} mode, {} -> {} bytes, {}",
+        display_path(cli.name_of(input)),
+        if f.markdown { "markdown" } else { "html" },
+        f.src.len(),
+        f.out().len(),
+        if f.changed() { "modified" } else { "unchanged" },
+    );
+    if cli.verbose > 1 {
+        line += &format!(
+            "; {} tags normalized, {} text chunks reflowed",
+            f.stats.tags_normalized, f.stats.text_chunks_reflowed
+        );
+    }
+    eprintln!("{line}");
 }
 
-// UTF-8 safe plain-text reflow: collapse newline-including runs to a single space.
-fn reflow_plain_text(text: &str) -> String {
-    if text.is_empty() {
-        return String::new();
+/// Copy the original bytes of `input` to `input` + `suffix`. An existing backup is
+/// only replaced when `force` is set.
+fn write_backup(input: &Path, original: &[u8], suffix: &str, force: bool) -> io::Result<()> {
+    let mut name = input.as_os_str().to_owned();
+    name.push(suffix);
+    let path = PathBuf::from(name);
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
     }
-    let bytes = text.as_bytes();
-    let mut out = String::with_capacity(text.len());
-    let mut i = 0usize;
-    let mut seg_start = 0usize;
-
-    while i < bytes.len() {
-        if bytes[i] == b'\n' {
-            if seg_start < i {
-                out.push_str(&text[seg_start..i]); // safe: char boundary
-            }
-            if !out.ends_with(' ') {
-                out.push(' ');
-            }
-            i += 1;
-            while i < bytes.len() && (bytes[i] == b'\n' || bytes[i] == b' ' || bytes[i] == b'\t') {
-                i += 1;
-            }
-            seg_start = i;
+    let mut file = options.open(&path).map_err(|e| {
+        if e.kind() == io::ErrorKind::AlreadyExists {
+            io::Error::new(e.kind(), format!("backup {} already exists; pass --force-backup to replace it", path.display()))
         } else {
-            i += 1;
+            e
         }
-    }
-    if seg_start < bytes.len() {
-        out.push_str(&text[seg_start..]);
-    }
-    out
+    })?;
+    file.write_all(original)
 }
 
-fn reflow_text(text: &str, use_markdown: bool) -> String {
-    if use_markdown {
-        reflow_markdown_text(text)
-    } else {
-        reflow_plain_text(text)
-    }
+fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
 }
 
-/* ==================== Structural boundary helper ======================== */
-
-fn prev_line_ends_with_structural_start(s: &[u8], mut boundary: usize) -> bool {
-    loop {
-        let line_start = memrchr(b'\n', &s[..boundary]).map(|x| x + 1).unwrap_or(0);
-        if line_start >= boundary { return false; }
-        // Trim trailing spaces/tabs
-        let mut end = boundary;
-        while end > line_start && is_space_tab(s[end - 1]) { end -= 1; }
-        if end > line_start {
-            // non-empty after trim
-            if s[end - 1] != b'>' { return false; }
-            let lt = memrchr(b'<', &s[line_start..end]).map(|x| x + line_start);
-            let lt = match lt { Some(v) => v, None => return false };
-            let tag = &s[lt..end];
-            let ti = parse_tag_info(tag);
-            if ti.is_end { return false; }
-            return is_structural(ti.name);
-        } else {
-            // empty line, go back
-            if line_start == 0 { return false; }
-            boundary = line_start - 1; // before the \n
-        }
-    }
+fn display_path(path: &Path) -> std::borrow::Cow<'_, str> {
+    if is_stdin(path) { "<stdin>".into() } else { path.to_string_lossy() }
 }
 
-fn has_single_lf(chunk: &[u8]) -> bool {
-    let mut count = 0usize;
-    for &c in chunk {
-        if c == b'\n' { count += 1; if count > 1 { return false; } }
+/// Read an input file, or stdin for "-".
+fn read_input(path: &Path) -> io::Result<Vec<u8>> {
+    if is_stdin(path) {
+        let mut buf = Vec::new();
+        io::Read::read_to_end(&mut io::stdin().lock(), &mut buf).context("read input", path)?;
+        Ok(buf)
+    } else if path.is_dir() {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is a directory, not a file; pass --recursive to format the files in it", path.display()),
+        ))
+    } else {
+        fs::read(path).context("read input", path)
     }
-    count == 1
 }
 
-fn trailing_lf_count_ignoring_spaces(chunk: &[u8]) -> usize {
-    let mut i = chunk.len();
-    while i > 0 && (chunk[i - 1] == b' ' || chunk[i - 1] == b'\t') { i -= 1; }
-    let mut k = 0usize;
-    while i > 0 && chunk[i - 1] == b'\n' {
-        k += 1;
-        i -= 1;
+/// Describe a failed I/O operation: "failed to read input 'spec.bs': No such file
+/// or directory". The error kind is kept.
+trait Context<T> {
+    fn context(self, operation: &str, path: &Path) -> io::Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, operation: &str, path: &Path) -> io::Result<T> {
+        self.map_err(|e| io::Error::new(e.kind(), format!("failed to {operation} '{}': {e}", display_path(path))))
     }
-    k
 }
 
-/* ============================ Raw-text copying ========================== */
+/// Default Markdown mode for a path: enabled iff the extension is ".bs".
+fn markdown_default(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.to_string_lossy().eq_ignore_ascii_case("bs"))
+        || input_format_default(path) == InputFormat::Markdown
+}
 
-/// Copy bytes from `i` until the **matching** end tag `</name>` is found.
-/// Returns (new_index_after_end_tag, closed_found).
-fn copy_raw_text_until_end(src: &[u8], i: usize, name: &[u8], out: &mut Vec<u8>) -> (usize, bool) {
-    let n = src.len();
-    let lower_name = name.to_ascii_lowercase();
-    let name_ref = lower_name.as_slice();
+/// Options with only the per-path defaults, for the subcommands that ignore
+/// flags and configuration files (`lint`, `report`, `merge-driver`).
+fn default_options(path: &Path) -> Options {
+    Options::default().markdown(markdown_default(path)).markdown_document(input_format_default(path) == InputFormat::Markdown)
+}
 
-    let mut j = i;
-    loop {
-        if j >= n {
-            return (n, false);
-        }
-        let Some(pos) = memchr(b'<', &src[j..]).map(|off| j + off) else {
-            out.extend_from_slice(&src[j..]);
-            return (n, false);
-        };
-        // emit text between j and pos verbatim
-        out.extend_from_slice(&src[j..pos]);
-
-        // Not enough room for "</"
-        if pos + 2 >= n || src[pos + 1] != b'/' {
-            // literal '<'
-            out.push(b'<');
-            j = pos + 1;
-            continue;
-        }
+/// Default input format for a path: Markdown for ".md" and ".markdown", else HTML.
+fn input_format_default(path: &Path) -> InputFormat {
+    if walk::has_extension(path, &["md", "markdown"]) { InputFormat::Markdown } else { InputFormat::Html }
+}
 
-        // Try to parse an end tag
-        if let Some(end) = find_tag_end(src, pos) {
-            let ti = parse_tag_info(&src[pos..=end]);
-            if ti.name.eq_ignore_ascii_case(name_ref) {
-                normalize_inside_tag(&src[pos..=end], out);
-                return (end + 1, true);
-            } else {
-                out.extend_from_slice(&src[pos..=end]);
-                j = end + 1;
-                continue;
-            }
-        } else {
-            out.extend_from_slice(&src[pos..]);
-            return (n, false);
+/// True if `a` and `b` are equal once CRLF is treated as LF and trailing newlines are ignored.
+fn differs_only_in_line_endings(a: &[u8], b: &[u8]) -> bool {
+    fn normalized(s: &[u8]) -> impl Iterator<Item = u8> + '_ {
+        let mut end = s.len();
+        while end > 0 && (s[end - 1] == b'\n' || s[end - 1] == b'\r') {
+            end -= 1;
         }
+        let s = &s[..end];
+        s.iter()
+            .enumerate()
+            .filter(move |&(i, &c)| !(c == b'\r' && s.get(i + 1) == Some(&b'\n')))
+            .map(|(_, &c)| c)
     }
+    normalized(a).eq(normalized(b))
 }
 
-/* ========================== Text chunk handling ========================= */
-
-fn classify_ahead(src: &[u8], next_lt: usize) -> (bool, bool, Option<TagInfo<'_>>) {
-    if next_lt >= src.len() { return (false, false, None); }
-    if src[next_lt..].starts_with(b"<!--") {
-        let (j_end, standalone) = scan_comment(src, next_lt);
-        if j_end == usize::MAX { return (false, false, None); }
-        return (standalone, !standalone, None);
-    }
-    if src[next_lt] == b'<' {
-        if let Some(j) = find_tag_end(src, next_lt) {
-            let ti = parse_tag_info(&src[next_lt..=j]);
-            return (false, false, Some(ti));
-        }
-    }
-    (false, false, None)
+/// Markdown mode for an extension, set with `--ext-mode EXT=MODE`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum ExtMode {
+    /// Markdown reflow off, like `.html`
+    Html,
+    /// Markdown reflow on, like `.bs`
+    Markdown,
 }
 
-fn reflow_text_chunk(
-    chunk: &[u8],
-    src: &[u8],
-    next_lt: usize,
-    out: &mut Vec<u8>,
-    use_markdown: bool,
-    after_boundary: bool,
-    after_br: bool,
-    at_index_i: usize,
-) {
-    let (ahead_is_standalone_comment, ahead_is_inline_comment, ahead_tag) = classify_ahead(src, next_lt);
-
-    let chunk_is_ws_only = chunk.iter().all(|&b| is_ws(b));
-    if chunk_is_ws_only {
-        // If we just emitted a structural boundary (including a standalone comment)
-        // or a <br>, preserve the whitespace verbatim. Standalone comments are
-        // structural on BOTH sides, so the immediately following newline must stay.
-        if after_boundary || after_br {
-            out.extend_from_slice(chunk);
-            return;
-        }
+fn parse_ext_mode(arg: &str) -> Result<(String, ExtMode), String> {
+    let (ext, mode) = arg.split_once('=').ok_or_else(|| format!("expected EXT=MODE, found `{arg}`"))?;
+    let mode = ExtMode::from_str(mode, true).map_err(|_| format!("unknown mode `{mode}`; expected html or markdown"))?;
+    Ok((ext.trim_start_matches('.').to_string(), mode))
+}
 
-        if next_lt < src.len() {
-            if ahead_is_standalone_comment {
-                out.extend_from_slice(chunk);
-            } else if ahead_is_inline_comment {
-                if has_single_lf(chunk) {
-                    if prev_line_ends_with_structural_start(src, next_lt) {
-                        out.extend_from_slice(chunk);
-                    } else {
-                        out.push(b' ');
-                    }
-                } else {
-                    out.extend_from_slice(chunk);
-                }
-            } else if let Some(ti) = ahead_tag {
-                let structural_ahead = is_structural(ti.name);
-                if structural_ahead {
-                    out.extend_from_slice(chunk);
-                } else if !ti.is_end && is_inline(ti.name) {
-                    if has_single_lf(chunk) {
-                        if prev_line_ends_with_structural_start(src, next_lt) {
-                            out.extend_from_slice(chunk);
-                        } else {
-                            out.push(b' ');
-                        }
-                    } else {
-                        out.extend_from_slice(chunk);
-                    }
-                } else {
-                    out.extend_from_slice(chunk);
-                }
-            } else {
-                out.extend_from_slice(chunk);
-            }
-        } else {
-            out.extend_from_slice(chunk);
-        }
-        return;
+fn parse_lines(arg: &str) -> Result<(usize, usize), String> {
+    let (start, end) = arg.split_once(':').ok_or_else(|| format!("expected START:END, found `{arg}`"))?;
+    let number = |n: &str| n.parse::<usize>().ok().filter(|&n| n > 0).ok_or_else(|| format!("invalid line number `{n}`"));
+    let (start, end) = (number(start)?, number(end)?);
+    if start > end {
+        return Err(format!("the range {start}:{end} ends before it starts"));
     }
+    Ok((start, end))
+}
 
-    // Non-whitespace chunk
-    let mut preserve_trailing_suffix = false;
-    if next_lt < src.len() {
-        if ahead_is_standalone_comment {
-            preserve_trailing_suffix = true;
-        } else if let Some(ti) = ahead_tag {
-            if is_structural(ti.name) {
-                preserve_trailing_suffix = true;
-            }
-        }
+fn parse_range(arg: &str) -> Result<Range<usize>, String> {
+    let (start, end) = arg.split_once("..").ok_or_else(|| format!("expected START..END, found `{arg}`"))?;
+    let number = |n: &str| n.parse::<usize>().map_err(|_| format!("invalid byte offset `{n}`"));
+    let (start, end) = (number(start)?, number(end)?);
+    if start > end {
+        return Err(format!("the range {start}..{end} ends before it starts"));
     }
+    Ok(start..end)
+}
 
-    // If the line that contains `next_lt` (often a DT/DD line) begins with : or ::, keep suffix.
-    let boundary_end = at_index_i + chunk.len();
-    if use_markdown && line_at_pos_starts_with_dt_or_dd(src, boundary_end) {
-        preserve_trailing_suffix = true;
+/// Byte offset of the start of 1-based `line` in `text`, or its length past the last line.
+fn line_offset(text: &[u8], line: usize) -> usize {
+    match line.checked_sub(2) {
+        None => 0,
+        Some(n) => memchr::memchr_iter(b'\n', text).nth(n).map_or(text.len(), |i| i + 1),
     }
+}
 
-    let preserve_leading_prefix = after_boundary || after_br;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reformahtml::transform_with;
+    use std::fs;
+    use std::path::Path;
 
-    if preserve_leading_prefix || preserve_trailing_suffix {
-        // prefix: leading whitespace
-        let mut left = 0usize;
-        if preserve_leading_prefix {
-            while left < chunk.len() && is_ws(chunk[left]) { left += 1; }
-            out.extend_from_slice(&chunk[..left]);
-        }
-        // suffix: ALL trailing whitespace (preserve exactly before structural/comment/DT/DD)
-        let mut idx = chunk.len();
-        while idx > left && is_ws(chunk[idx - 1]) {
-            idx -= 1;
-        }
-        let suffix_start = idx;
-        let body = &chunk[left..suffix_start];
-
-        if !body.is_empty() {
-            // SPECIAL: Keep DT/DD on their own line when body starts with "\n" + indent + ":"[":"]
-            if use_markdown {
-                if let Some(indent_end) = leading_lf_indent_end_before_dt_or_dd(body) {
-                    // Emit "\n" + indentation
-                    out.push(b'\n');
-                    out.extend_from_slice(&body[1..indent_end]); // indentation
-                    let rest = std::str::from_utf8(&body[indent_end..]).unwrap();
-                    let reflowed = reflow_text(rest, use_markdown);
-                    out.extend_from_slice(reflowed.as_bytes());
-                } else if body.starts_with(b"\n") && (body.len() == 1 || body[1] != b'\n')
-                    && !prev_line_ends_with_structural_start(src, at_index_i)
-                    && !after_br && !after_boundary
-                    && !(use_markdown && body_begins_with_dt_or_dd_after_single_lf(body))
-                {
-                    // Soft wrap single LF → space
-                    let mut j = 1usize;
-                    while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
-                    let rest = std::str::from_utf8(&body[j..]).unwrap();
-                    let mut body_str = String::with_capacity(1 + rest.len());
-                    body_str.push(' ');
-                    body_str.push_str(rest);
-                    let reflowed = reflow_text(&body_str, use_markdown);
-                    out.extend_from_slice(reflowed.as_bytes());
-                } else {
-                    let body_str = std::str::from_utf8(body).unwrap();
-                    let reflowed = reflow_text(body_str, use_markdown);
-                    out.extend_from_slice(reflowed.as_bytes());
-                }
-            } else {
-                // Plain text mode
-                if body.starts_with(b"\n") && (body.len() == 1 || body[1] != b'\n')
-                    && !prev_line_ends_with_structural_start(src, at_index_i)
-                    && !after_br && !after_boundary
-                {
-                    let mut j = 1usize;
-                    while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
-                    let rest = std::str::from_utf8(&body[j..]).unwrap();
-                    let mut body_str = String::with_capacity(1 + rest.len());
-                    body_str.push(' ');
-                    body_str.push_str(rest);
-                    let reflowed = reflow_text(&body_str, use_markdown);
-                    out.extend_from_slice(reflowed.as_bytes());
-                } else {
-                    let body_str = std::str::from_utf8(body).unwrap();
-                    let reflowed = reflow_text(body_str, use_markdown);
-                    out.extend_from_slice(reflowed.as_bytes());
-                }
-            }
-        }
+    #[test]
+    fn line_window_only_reformats_the_tokens_it_touches() {
+        let window = |src: &str, lines: (usize, usize), opts: &Options| {
+            let src = src.as_bytes();
+            let requested = line_offset(src, lines.0)..line_offset(src, lines.1 + 1);
+            let mut w = Window::new(vec![requested]);
+            let mut out = Vec::new();
+            transform_with(src, &mut out, opts, None, Some(&mut w));
+            (String::from_utf8(out).unwrap(), w.input, w.output)
+        };
+        let src = "<p>a\nb</p>\n<p>c\nd</p>\n<p>e\nf</p>\n";
+        let (out, input, output) = window(src, (4, 4), &Options::default());
+        assert_eq!(out, "<p>a\nb</p>\n<p>c d</p>\n<p>e\nf</p>\n");
+        // Snapped out to the text chunk "c\nd" starting on line 3.
+        assert_eq!(&src[input.unwrap()], "c\nd</p>\n");
+        assert_eq!(&out[output.unwrap()], "c d</p>\n");
+
+        // Offsets stay right with CRLF and a byte order mark.
+        let crlf = "\u{FEFF}<p>a\r\nb</p>\r\n<p>c\r\nd</p>\r\n";
+        let (out, input, output) = window(crlf, (3, 4), &Options::default());
+        assert_eq!(out, "\u{FEFF}<p>a\r\nb</p>\r\n<p>c d</p>\r\n");
+        assert_eq!(&crlf[input.unwrap()], "<p>c\r\nd</p>\r\n");
+        assert_eq!(&out[output.unwrap()], "<p>c d</p>\r\n");
+
+        let md = Options::default().markdown_document(true);
+        let (out, _, _) = window("one\ntwo\n\n```\nx\n```\n\nthree\nfour\n", (8, 8), &md);
+        assert_eq!(out, "one\ntwo\n\n```\nx\n```\n\nthree four\n");
+
+        assert_eq!(parse_range("5120..9300"), Ok(5120..9300));
+        assert!(parse_range("9..5").is_err() && parse_range("5-9").is_err());
+        assert_eq!(parse_lines("120:180"), Ok((120, 180)));
+        assert!(parse_lines("0:3").is_err() && parse_lines("5:4").is_err() && parse_lines("7").is_err());
+    }
 
-        if preserve_trailing_suffix {
-            out.extend_from_slice(&chunk[suffix_start..]); // preserve spaces/newlines before DT/DD/comment/structural
-        } else if (ahead_tag.map_or(false, |ti| !ti.is_end && is_inline(ti.name)) || ahead_is_inline_comment) && suffix_start < chunk.len() {
-            out.push(b' ');
-        }
-        return;
+    #[test]
+    fn formatters_are_built_once_per_config() {
+        let args = ["reformahtml", "--no-config", "--inline-tag", "x-ref", "--not-inline-tag", "code", "x"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        let html = cli.format.formatter_for(Path::new("a.html"), None).unwrap();
+        let bs = cli.format.formatter_for(Path::new("b.bs"), None).unwrap();
+        let inline = &html.options().inline_tags;
+        assert!(inline.iter().any(|t| t == "x-ref") && !inline.iter().any(|t| t == "code"));
+        assert!(Arc::ptr_eq(&html, &cli.format.formatter_for(Path::new("c.html"), None).unwrap()));
+        // Markdown is on for .bs files, so they get their own.
+        assert!(bs.options().markdown && !html.options().markdown && bs.options().inline_tags == *inline);
+        assert_eq!(cli.format.formatters.lock().unwrap().len(), 2);
+        // A directive's options are its own.
+        let (directive, _) = Config::from_directive("structural-tag=x-card");
+        let card = cli.format.formatter_for(Path::new("d.html"), Some(&directive)).unwrap();
+        assert!(card.options().structural_tags.iter().any(|t| t == "x-card") && card.options().inline_tags == *inline);
+        assert!(!html.options().structural_tags.iter().any(|t| t == "x-card"));
+        assert_eq!(cli.format.formatters.lock().unwrap().len(), 2);
     }
 
-    // Preserve non-newline edge spaces around tags:
-    let mut lead_len = 0usize;
-    while lead_len < chunk.len() && is_space_tab(chunk[lead_len]) { lead_len += 1; }
-    let mut trail_len = 0usize;
-    while trail_len < chunk.len() && is_space_tab(chunk[chunk.len() - 1 - trail_len]) {
-        trail_len += 1;
+    #[test]
+    fn markdown_mode_resolution_order() {
+        let config = std::env::temp_dir().join(format!("reformahtml-ext-mode-{}.toml", std::process::id()));
+        fs::write(&config, "markdown = true\n[ext-mode]\ninc = \"markdown\"\ntmpl = \"html\"\nbs = \"html\"\n").unwrap();
+        let markdown = |args: &[&str], input: &str| {
+            let cli = Cli::try_parse_from(["reformahtml", "--config", config.to_str().unwrap()].iter().chain(args).chain(["x"].iter()))
+                .unwrap();
+            cli.format.options_for(Path::new(input)).unwrap().markdown
+        };
+        // The config's [ext-mode] table beats its `markdown` key and the built-in default.
+        assert!(markdown(&[], "a.inc"));
+        assert!(!markdown(&[], "a.tmpl"));
+        assert!(!markdown(&[], "a.bs"));
+        assert!(markdown(&[], "a.html"));
+        // --ext-mode beats the config file; the last one given for an extension wins.
+        assert!(markdown(&["--ext-mode", "tmpl=markdown"], "a.TMPL"));
+        assert!(!markdown(&["--ext-mode", ".inc=markdown", "--ext-mode", "inc=html"], "a.inc"));
+        // --markdown/--no-markdown beat everything.
+        assert!(!markdown(&["--ext-mode", "inc=markdown", "--no-markdown"], "a.inc"));
+        assert!(markdown(&["--markdown"], "a.tmpl"));
+        fs::remove_file(&config).unwrap();
+
+        // Without configuration, unknown extensions keep the built-in default.
+        let cli = Cli::try_parse_from(["reformahtml", "--no-config", "--ext-mode", "inc=markdown", "x"]).unwrap();
+        assert!(!cli.format.options_for(Path::new("a.tmpl")).unwrap().markdown);
+        assert!(cli.format.options_for(Path::new("a.bs")).unwrap().markdown);
+        assert!(Cli::try_parse_from(["reformahtml", "--ext-mode", "inc", "x"]).is_err());
     }
-    let body = &chunk[lead_len..chunk.len() - trail_len];
-
-    // SPECIAL: DT/DD must start on a new line — emit the newline + indentation, then reflow the rest.
-    if use_markdown {
-        if let Some(indent_end) = leading_lf_indent_end_before_dt_or_dd(body) {
-            out.extend_from_slice(&chunk[..lead_len]); // leading spaces (no newlines here)
-            out.push(b'\n');
-            out.extend_from_slice(&body[1..indent_end]); // indentation
-            let rest = std::str::from_utf8(&body[indent_end..]).unwrap();
-            let reflowed = reflow_text(rest, use_markdown);
-            out.extend_from_slice(reflowed.as_bytes());
-            out.extend_from_slice(&chunk[chunk.len() - trail_len..]);
-            return;
-        }
+
+    #[test]
+    fn escape_quotes_needs_quote_attrs() {
+        assert!(Cli::try_parse_from(["reformahtml", "--escape-quotes", "x"]).is_err());
+        let cli = Cli::try_parse_from(["reformahtml", "--no-config", "--escape-quotes", "--quote-attrs", "double", "x"]).unwrap();
+        assert!(cli.format.options_for(Path::new("a.html")).unwrap().escape_quotes);
     }
 
-    // Soft-wrap at start-of-body — but NOT if that newline introduces a DT/DD line.
-    let mut tmp = String::new();
-    let body_str = if body.starts_with(b"\n") && (body.len() == 1 || body[1] != b'\n')
-        && !prev_line_ends_with_structural_start(src, at_index_i)
-        && !after_br && !after_boundary
-        && !(use_markdown && body_begins_with_dt_or_dd_after_single_lf(body))
-    {
-        let mut j = 1usize;
-        while j < body.len() && (body[j] == b' ' || body[j] == b'\t') { j += 1; }
-        let rest = std::str::from_utf8(&body[j..]).unwrap();
-        tmp.push(' ');
-        tmp.push_str(rest);
-        &tmp
-    } else {
-        std::str::from_utf8(body).unwrap()
-    };
+    #[test]
+    fn positional_paths_depend_on_mode() {
+        let cli = Cli::try_parse_from(["reformahtml", "a.html", "b.html"]).unwrap();
+        let t = cli.targets().unwrap();
+        assert_eq!(t.inputs, [PathBuf::from("a.html")]);
+        assert_eq!(t.output, Some(PathBuf::from("b.html")));
+
+        let cli = Cli::try_parse_from(["reformahtml", "--check", "a.html", "b.html", "-"]).unwrap();
+        let t = cli.targets().unwrap();
+        assert_eq!(t.inputs.len(), 3);
+        assert_eq!(t.output, None);
+
+        let cli = Cli::try_parse_from(["reformahtml", "a.html", "b.html", "c.html"]).unwrap();
+        assert!(cli.targets().is_err());
+
+        // --stdin-filepath only names stdin.
+        let cli = Cli::try_parse_from(["reformahtml", "--stdin-filepath", "spec/index.bs"]).unwrap();
+        assert_eq!(cli.targets().unwrap().inputs, [PathBuf::from("-")]);
+        assert_eq!(cli.name_of(Path::new("-")), Path::new("spec/index.bs"));
+        let cli = Cli::try_parse_from(["reformahtml", "--stdin-filepath", "spec/index.bs", "a.bs"]).unwrap();
+        assert!(cli.targets().is_err());
+
+        // --stdout never silently drops an OUTPUT.
+        let cli = Cli::try_parse_from(["reformahtml", "--stdout", "a.html", "b.html"]).unwrap();
+        assert!(cli.targets().is_err());
+    }
 
-    let mut reflowed = reflow_text(body_str, use_markdown);
-
-    // If this chunk ends with exactly one LF (ignoring spaces) and next token is inline-start,
-    // collapse that single LF (+ indent) to a single space (unless prev line ended with structural start).
-    let trailing_lfs = trailing_lf_count_ignoring_spaces(chunk);
-    if let Some(ti) = ahead_tag {
-        if !ti.is_end && is_inline(ti.name) && trailing_lfs == 1
-            && !prev_line_ends_with_structural_start(src, at_index_i + chunk.len())
-        {
-            while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
-            if reflowed.ends_with('\n') {
-                reflowed.pop();
-                while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
-            }
-            out.extend_from_slice(&chunk[..lead_len]); // leading spaces
-            out.extend_from_slice(reflowed.as_bytes());
-            out.push(b' ');
-            return;
+    #[test]
+    fn backups_are_not_overwritten() {
+        let dir = std::env::temp_dir().join(format!("reformahtml-backup-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("a.html");
+        write_backup(&input, b"one\r\n", ".bak", false).unwrap();
+        assert_eq!(fs::read(dir.join("a.html.bak")).unwrap(), b"one\r\n");
+        assert!(write_backup(&input, b"two", ".bak", false).is_err());
+        write_backup(&input, b"two", ".bak", true).unwrap();
+        assert_eq!(fs::read(dir.join("a.html.bak")).unwrap(), b"two");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn formatted_files_are_not_rewritten() {
+        let dir = std::env::temp_dir().join(format!("reformahtml-rewrite-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for entry in fs::read_dir("tests/fixtures/inputs").unwrap() {
+            let path = entry.unwrap().path();
+            fs::copy(&path, dir.join(path.file_name().unwrap())).unwrap();
         }
-    } else if ahead_is_inline_comment {
-        if trailing_lfs == 1 && !prev_line_ends_with_structural_start(src, at_index_i + chunk.len()) {
-            while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
-            if reflowed.ends_with('\n') {
-                reflowed.pop();
-                while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
-            }
-            out.extend_from_slice(&chunk[..lead_len]);
-            out.extend_from_slice(reflowed.as_bytes());
-            out.push(b' ');
-            return;
+        let args = ["reformahtml", "--no-config", "-r", "--ext", "bs", "--ext", "html", "--ext", "md", "--backup"];
+        let cli = Cli::try_parse_from(args.into_iter().chain([dir.to_str().unwrap()])).unwrap();
+        run(&cli).unwrap();
+
+        // Any write in the second pass would move a file's mtime or add a backup.
+        let past = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        let listing = || {
+            let mut files: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+            files.sort();
+            files
+        };
+        let files = listing();
+        for path in &files {
+            fs::File::options().write(true).open(path).unwrap().set_modified(past).unwrap();
         }
-    } else if ahead_tag.is_none() && !ahead_is_standalone_comment {
-        if trailing_lfs == 1 && !prev_line_ends_with_structural_start(src, at_index_i + chunk.len()) {
-            while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
-            if reflowed.ends_with('\n') {
-                reflowed.pop();
-                while reflowed.ends_with(' ') || reflowed.ends_with('\t') { reflowed.pop(); }
-            }
-            out.extend_from_slice(&chunk[..lead_len]);
-            out.extend_from_slice(reflowed.as_bytes());
-            return;
+        assert_eq!(run(&cli).unwrap(), Status::Unchanged);
+        assert_eq!(listing(), files);
+        for path in &files {
+            assert_eq!(fs::metadata(path).unwrap().modified().unwrap(), past, "{} was rewritten", path.display());
         }
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    out.extend_from_slice(&chunk[..lead_len]);
-    out.extend_from_slice(reflowed.as_bytes());
-    out.extend_from_slice(&chunk[chunk.len() - trail_len..]);
-}
-
-/* ============================== Transform =============================== */
-
-#[derive(Clone)]
-struct OpenElement {
-    name: Vec<u8>,
-    has_noreformat: bool,
-}
+    #[test]
+    fn allow_and_deny_change_the_exit_status() {
+        let path = std::env::temp_dir().join(format!("reformahtml-levels-{}.html", std::process::id()));
+        fs::write(&path, "<p>Count: <?php echo $n; ?></p>\n").unwrap();
+        let status = |flags: &[&str]| {
+            let args = ["reformahtml", "--no-config", "--check"].iter().chain(flags).copied().chain([path.to_str().unwrap()]);
+            let cli = Cli::try_parse_from(args).unwrap();
+            (run(&cli).unwrap(), format_file(&cli, &path).unwrap().diagnostics)
+        };
+        let (default, diagnostics) = status(&[]);
+        assert_eq!(default, Status::Unchanged);
+        assert_eq!((diagnostics[0].check, diagnostics[0].level), (Check::VerbatimConstruct, checks::Level::Warning));
+        let (denied, diagnostics) = status(&["--deny", "verbatim-construct"]);
+        assert_eq!(denied, Status::Changed);
+        assert_eq!(diagnostics[0].level, checks::Level::Error);
+        assert_eq!(status(&["--fail-on", "warning"]).0, Status::Changed);
+        let (allowed, diagnostics) = status(&["--allow", "verbatim-construct", "--fail-on", "note"]);
+        assert_eq!(allowed, Status::Unchanged);
+        assert!(diagnostics.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
 
-fn transform(src: &[u8], out: &mut Vec<u8>, use_markdown: bool) {
-    let mut i = 0usize;
-    let n = src.len();
-
-    // Stacks/state
-    let mut raw_stack: Vec<Vec<u8>> = Vec::new();        // names of raw-text tags in lowercase
-    let mut open_stack: Vec<OpenElement> = Vec::new();
-    let mut after_boundary = false;
-    let mut after_br = false;
-
-    let p_closing: &[&[u8]] = &[
-        b"address", b"article", b"aside", b"blockquote", b"center", b"details", b"dialog", b"dir",
-        b"div", b"dl", b"fieldset", b"figcaption", b"figure", b"footer", b"header", b"hgroup",
-        b"main", b"menu", b"nav", b"ol", b"p", b"search", b"section", b"summary", b"ul",
-    ];
-
-    while i < n {
-        // If inside a RAW-TEXT element, copy verbatim until its matching end tag.
-        if let Some(current_raw) = raw_stack.last() {
-            let (new_i, closed) = copy_raw_text_until_end(src, i, current_raw, out);
-            i = new_i;
-            after_boundary = false;
-            after_br = false;
-            if closed {
-                raw_stack.pop();
-                open_stack.pop();
-            }
-            continue;
-        }
+    #[test]
+    fn unterminated_tags_fail_on_warning() {
+        // Already formatted, so only the diagnostic can fail the run.
+        let path = "tests/fixtures/expected/unterminated-tag.html";
+        let status = |flags: &[&str]| {
+            let args = ["reformahtml", "--no-config", "--check"].iter().chain(flags).copied().chain([path]);
+            run(&Cli::try_parse_from(args).unwrap()).unwrap()
+        };
+        assert_eq!(status(&[]), Status::Unchanged);
+        assert_eq!(status(&["--fail-on", "warning"]), Status::Changed);
+    }
 
-        // Comments
-        if src[i..].starts_with(b"<!--") {
-            let (j_end, standalone) = scan_comment(src, i);
-            if j_end == usize::MAX {
-                out.extend_from_slice(&src[i..]);
-                return;
-            }
-            let seg = &src[i..=j_end + 2]; // includes "-->"
-            let is_verbatim = open_stack.iter().any(|e| e.has_noreformat);
-            if is_verbatim {
-                out.extend_from_slice(seg);
-            } else if standalone {
-                out.extend_from_slice(seg);
-                after_boundary = true;
-            } else {
-                reflow_inline_comment(seg, out);
-                after_boundary = false;
-            }
-            i = j_end + 3;
-            continue;
-        }
+    #[test]
+    fn sourcemap_is_written_as_json() {
+        let map = std::env::temp_dir().join(format!("reformahtml-sourcemap-{}.json", std::process::id()));
+        let args = ["reformahtml", "--no-config", "--check", "--sourcemap", map.to_str().unwrap(), "tests/fixtures/inputs/br.html"];
+        run(&Cli::try_parse_from(args).unwrap()).unwrap();
+        let json = fs::read_to_string(&map).unwrap();
+        assert!(json.starts_with("{\n  \"path\": \"tests/fixtures/inputs/br.html\",\n  \"anchors\": [\n    {\"input\": 0, \"output\": 0, \"exact\": true},\n"), "{json}");
+        fs::remove_file(&map).unwrap();
+
+        let two = ["reformahtml", "--check", "--sourcemap", "map.json", "a.html", "b.html"];
+        assert!(run(&Cli::try_parse_from(two).unwrap()).is_err());
+    }
 
-        // Tags
-        if src[i] == b'<' {
-            let Some(j) = find_tag_end(src, i) else {
-                out.extend_from_slice(&src[i..]);
-                return;
-            };
-            let tag = &src[i..=j];
-            let ti = parse_tag_info(tag);
+    #[test]
+    fn list_changed_entries() {
+        let cli = Cli::try_parse_from(["reformahtml", "--check", "--list-changed", "a.bs", "-"]).unwrap();
+        assert_eq!(list_entry(&cli, Path::new("a.bs")), b"a.bs\n");
+        assert_eq!(list_entry(&cli, Path::new("-")), b"<stdin>\n");
+        let cli = Cli::try_parse_from(["reformahtml", "--list-changed", "--print0", "a b.bs"]).unwrap();
+        assert_eq!(list_entry(&cli, Path::new("a b.bs")), b"a b.bs\0");
+        assert!(Cli::try_parse_from(["reformahtml", "--print0", "a.bs"]).is_err());
+        assert!(Cli::try_parse_from(["reformahtml", "--list-changed", "--diff", "a.bs"]).is_err());
+    }
 
-            let has_this_noreformat = tag_has_noreformat_attr(tag);
-            let is_verbatim = open_stack.iter().any(|e| e.has_noreformat) || (!ti.is_end && has_this_noreformat);
-            if is_verbatim {
-                out.extend_from_slice(tag);
-            } else {
-                normalize_inside_tag(tag, out);
-            }
+    #[test]
+    fn errors_name_the_operation_and_path() {
+        let e = read_input(Path::new("no/such/spec.bs")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().starts_with("failed to read input 'no/such/spec.bs': "), "{e}");
+        let e = read_input(Path::new("tests")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("is a directory"), "{e}");
+    }
 
-            // open_stack handling
-            let mut name_lower = ti.name.to_vec();
-            name_lower.make_ascii_lowercase();
-            if ti.is_end {
-                while let Some(top) = open_stack.last() {
-                    if top.name == name_lower {
-                        open_stack.pop();
-                        break;
-                    } else {
-                        open_stack.pop();
-                    }
-                }
-            } else if !ti.self_closing && !is_void(ti.name) {
-                // implied closes
-                if name_lower == b"li" {
-                    if let Some(top) = open_stack.last() {
-                        if top.name == b"li" {
-                            open_stack.pop();
-                        }
-                    }
-                } else if name_lower == b"dt" || name_lower == b"dd" {
-                    if let Some(top) = open_stack.last() {
-                        if top.name == b"dt" || top.name == b"dd" {
-                            open_stack.pop();
-                        }
-                    }
-                } else if matches_ignore_ascii_case(&name_lower, p_closing) {
-                    if let Some(top) = open_stack.last() {
-                        if top.name == b"p" {
-                            open_stack.pop();
-                        }
-                    }
-                }
+    #[test]
+    fn eol_only_differences() {
+        assert!(differs_only_in_line_endings(b"a\r\nb\r\n", b"a\nb"));
+        assert!(differs_only_in_line_endings(b"a\nb", b"a\nb\n\n"));
+        assert!(!differs_only_in_line_endings(b"a\nb", b"a b"));
+        assert!(!differs_only_in_line_endings(b"a\r\n\r\nb", b"a\nb"));
+    }
 
-                open_stack.push(OpenElement {
-                    name: name_lower.clone(),
-                    has_noreformat: has_this_noreformat,
-                });
-            }
+    /// Fixtures in tests/fixtures/encodings are named after their encoding. Formatting
+    /// goes through decode/encode, and only whitespace may differ from the input.
+    #[test]
+    fn encoding_round_trips() {
+        let inputs_dir = Path::new("tests/fixtures/encodings/inputs");
+        let expected_dir = Path::new("tests/fixtures/encodings/expected");
+        let update_expected = std::env::var("UPDATE_EXPECTED").is_ok();
 
-            // raw-text tracking
-            if is_raw_text(ti.name) && !ti.is_end && !ti.self_closing {
-                raw_stack.push(name_lower.clone());
-            }
+        for entry in fs::read_dir(inputs_dir).unwrap() {
+            let input_path = entry.unwrap().path();
+            let stem = input_path.file_stem().unwrap().to_str().unwrap();
+            let encoding = Encoding::from_str(stem, true).unwrap();
+            let src = fs::read(&input_path).unwrap();
+            assert_eq!(Encoding::Auto.resolve(&src).unwrap() == Encoding::Utf8, encoding == Encoding::Utf8);
 
-            // <br> rule
-            if !ti.is_end && ti.name.eq_ignore_ascii_case(b"br") {
-                if j + 1 < n && src[j + 1] == b'\n' {
-                    out.push(b'\n');
-                    i = j + 2;
-                    after_br = true;
-                    continue;
-                } else {
-                    after_br = true;
-                }
-            }
+            let mut out = Vec::new();
+            transform(&encoding.decode(&src).unwrap(), &mut out, &Options::default());
+            let out = encoding.encode(out);
+            let non_ws = |s: &[u8]| s.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect::<Vec<u8>>();
+            assert_eq!(non_ws(&out), non_ws(&src), "non-whitespace bytes changed in {stem}");
 
-            // Set after_boundary for structural start tags
-            if !ti.is_end && is_structural(&name_lower) {
-                after_boundary = true;
+            let expected_path = expected_dir.join(input_path.file_name().unwrap());
+            if update_expected {
+                fs::create_dir_all(expected_dir).unwrap();
+                fs::write(&expected_path, &out).unwrap();
             } else {
-                after_boundary = false;
+                assert_eq!(out, fs::read(&expected_path).unwrap(), "Mismatch for test: {stem}");
             }
-
-            i = j + 1;
-            continue;
         }
-
-        // Text run
-        let next_lt = memchr(b'<', &src[i..]).map(|off| i + off).unwrap_or(n);
-        let chunk = &src[i..next_lt];
-
-        let is_verbatim = open_stack.iter().any(|e| e.has_noreformat);
-        if is_verbatim {
-            out.extend_from_slice(chunk);
-        } else {
-            reflow_text_chunk(
-                chunk,
-                src,
-                next_lt,
-                out,
-                use_markdown,
-                after_boundary,
-                after_br,
-                i,
-            );
-        }
-
-        after_boundary = false;
-        after_br = false;
-        i = next_lt;
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::{self, DirEntry};
-    use std::path::Path;
 
+    /// The fixtures carrying a reformahtml comment, which the CLI applies; the
+    /// library's `regression_tests` cover the others.
     #[test]
-    fn regression_tests() {
-        let inputs_dir = Path::new("tests/fixtures/inputs");
+    fn directive_fixtures() {
         let expected_dir = Path::new("tests/fixtures/expected");
         let update_expected = std::env::var("UPDATE_EXPECTED").is_ok();
 
-        if !inputs_dir.exists() {
-            return; // No fixtures yet, skip
-        }
-
-        let entries: Vec<DirEntry> = fs::read_dir(inputs_dir).unwrap().map(|e| e.unwrap()).collect();
-
-        for entry in entries {
-            let input_path = entry.path();
-            let ext = input_path.extension().unwrap_or_default().to_str().unwrap_or("");
-            if ext != "bs" && ext != "html" {
+        for entry in fs::read_dir("tests/fixtures/inputs").unwrap() {
+            let input_path = entry.unwrap().path();
+            if find_directive(&fs::read(&input_path).unwrap()).is_none() {
                 continue;
             }
+            // Formatted like `reformahtml --no-config FILE`.
+            let cli = Cli::try_parse_from(["reformahtml".as_ref(), "--no-config".as_ref(), input_path.as_os_str()]).unwrap();
+            let out = format_file(&cli, &input_path).unwrap().into_out();
 
-            let stem = input_path.file_stem().unwrap().to_str().unwrap();
-            let expected_path = expected_dir.join(format!("{}.{}", stem, ext));
-
-            let src = fs::read(&input_path).unwrap();
-            let mut out = Vec::new();
-
-            // Enable markdown for .bs, disable for .html
-            let use_markdown = ext == "bs";
-
-            transform(&src, &mut out, use_markdown);
-
-            let actual = String::from_utf8(out).unwrap();
-
+            let expected_path = expected_dir.join(input_path.file_name().unwrap());
             if update_expected {
-                fs::create_dir_all(expected_dir).unwrap();
-                fs::write(&expected_path, actual.as_bytes()).unwrap();
+                fs::write(&expected_path, &out).unwrap();
             } else {
-                let expected = fs::read_to_string(&expected_path).unwrap_or_else(|_| panic!("Expected file not found: {:?}", expected_path));
-                assert_eq!(actual, expected, "Mismatch for test: {}", stem);
+                assert_eq!(out, fs::read(&expected_path).unwrap(), "Mismatch for test: {}", input_path.display());
             }
         }
     }
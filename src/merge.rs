@@ -0,0 +1,187 @@
+// src/merge.rs
+//
+// `reformahtml merge-driver %O %A %B`: a git merge driver. All three versions
+// are formatted first so that reflow-only differences between branches vanish,
+// then merged line by line, and the merged result is formatted once more.
+//
+// Install with:
+//   git config merge.reformahtml.driver 'reformahtml merge-driver %O %A %B --path %P'
+//   echo '*.bs merge=reformahtml' >> .gitattributes
+
+use crate::diff::{self, Tag};
+use crate::{default_options, transform, Context, Status};
+use clap::Args;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub struct MergeArgs {
+    /// Common ancestor version (%O)
+    base: PathBuf,
+
+    /// Current version (%A); receives the merge result
+    ours: PathBuf,
+
+    /// Other branch's version (%B)
+    theirs: PathBuf,
+
+    /// Path of the file in the repository (%P), used to pick Markdown mode
+    #[arg(long, value_name = "PATH")]
+    path: Option<PathBuf>,
+}
+
+pub fn run(args: &MergeArgs) -> io::Result<Status> {
+    let path = args.path.as_deref().unwrap_or(&args.ours);
+    let opts = default_options(path);
+    let format = |src: &[u8]| {
+        let mut out = Vec::with_capacity(src.len());
+        transform(src, &mut out, &opts);
+        out
+    };
+    let base = format(&fs::read(&args.base).context("read", &args.base)?);
+    let ours = format(&fs::read(&args.ours).context("read", &args.ours)?);
+    let theirs = format(&fs::read(&args.theirs).context("read", &args.theirs)?);
+
+    let (merged, conflicts) = merge3(&base, &ours, &theirs);
+    if conflicts > 0 {
+        // Formatting would reflow the conflict markers into the surrounding text.
+        fs::write(&args.ours, merged).context("write", &args.ours)?;
+        eprintln!("{}: {conflicts} conflict(s)", path.display());
+        return Ok(Status::Changed);
+    }
+    fs::write(&args.ours, format(&merged)).context("write", &args.ours)?;
+    Ok(Status::Unchanged)
+}
+
+/// A changed region of one side: `old` lines of the base replaced by `new` lines.
+struct Change {
+    old: Range<usize>,
+    new: Range<usize>,
+}
+
+/// Collapse an edit script into changed regions.
+fn changes(ops: &[diff::DiffOp]) -> Vec<Change> {
+    let mut out: Vec<Change> = Vec::new();
+    let mut pending: Option<Change> = None;
+    for op in ops {
+        match op.tag {
+            Tag::Equal => out.extend(pending.take()),
+            Tag::Delete => {
+                let c = pending.get_or_insert(Change { old: op.old_index..op.old_index, new: op.new_index..op.new_index });
+                c.old.end = op.old_index + op.len;
+            }
+            Tag::Insert => {
+                let c = pending.get_or_insert(Change { old: op.old_index..op.old_index, new: op.new_index..op.new_index });
+                c.new.end = op.new_index + op.len;
+            }
+        }
+    }
+    out.extend(pending);
+    out
+}
+
+/// Line-based three-way merge. Returns the merged text and the number of
+/// conflicts, which are written with `<<<<<<<`/`=======`/`>>>>>>>` markers.
+pub fn merge3(base: &[u8], ours: &[u8], theirs: &[u8]) -> (Vec<u8>, usize) {
+    let base_lines = diff::split_lines(base);
+    let sides = [diff::split_lines(ours), diff::split_lines(theirs)];
+    let side_changes = [
+        changes(&diff::diff_lines(&base_lines, &sides[0])),
+        changes(&diff::diff_lines(&base_lines, &sides[1])),
+    ];
+
+    // All changes in base order, tagged with their side.
+    let mut all: Vec<(usize, &Change)> = side_changes
+        .iter()
+        .enumerate()
+        .flat_map(|(side, cs)| cs.iter().map(move |c| (side, c)))
+        .collect();
+    all.sort_by_key(|(_, c)| (c.old.start, c.old.end));
+
+    let mut out = Vec::with_capacity(ours.len().max(theirs.len()));
+    let mut conflicts = 0usize;
+    // Line offset of each side relative to the base, before the current position.
+    let mut delta = [0isize; 2];
+    let mut pos = 0usize;
+    let mut idx = 0usize;
+    while idx < all.len() {
+        // Group changes that overlap or touch in the base. Changes from the same side
+        // are always separated by unchanged lines, so touching means both sides.
+        let start = all[idx].1.old.start;
+        let mut end = all[idx].1.old.end;
+        let mut touched = [false; 2];
+        let mut group_delta = delta;
+        while idx < all.len() && all[idx].1.old.start <= end {
+            let (side, c) = all[idx];
+            end = end.max(c.old.end);
+            touched[side] = true;
+            group_delta[side] += c.new.len() as isize - c.old.len() as isize;
+            idx += 1;
+        }
+
+        for line in &base_lines[pos..start] {
+            out.extend_from_slice(line);
+        }
+        let side_range = |side: usize| {
+            let from = (start as isize + delta[side]) as usize;
+            let to = (end as isize + group_delta[side]) as usize;
+            &sides[side][from..to]
+        };
+        match touched {
+            [true, false] => push_lines(&mut out, side_range(0)),
+            [false, true] => push_lines(&mut out, side_range(1)),
+            _ if side_range(0) == side_range(1) => push_lines(&mut out, side_range(0)),
+            _ => {
+                conflicts += 1;
+                out.extend_from_slice(b"<<<<<<< ours\n");
+                push_lines(&mut out, side_range(0));
+                terminate_line(&mut out);
+                out.extend_from_slice(b"=======\n");
+                push_lines(&mut out, side_range(1));
+                terminate_line(&mut out);
+                out.extend_from_slice(b">>>>>>> theirs\n");
+            }
+        }
+        delta = group_delta;
+        pos = end;
+    }
+    for line in &base_lines[pos..] {
+        out.extend_from_slice(line);
+    }
+    (out, conflicts)
+}
+
+fn push_lines(out: &mut Vec<u8>, lines: &[&[u8]]) {
+    for line in lines {
+        out.extend_from_slice(line);
+    }
+}
+
+/// Make sure a conflict marker written next starts on its own line.
+fn terminate_line(out: &mut Vec<u8>) {
+    if out.last().is_some_and(|&b| b != b'\n') {
+        out.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_disjoint_changes_and_marks_conflicts() {
+        let base = b"a\nb\nc\nd\ne\n";
+        let (merged, conflicts) = merge3(base, b"A\nb\nc\nd\ne\n", b"a\nb\nc\nd\nE\nf\n");
+        assert_eq!(conflicts, 0);
+        assert_eq!(merged, b"A\nb\nc\nd\nE\nf\n");
+
+        let (merged, conflicts) = merge3(base, b"a\nB\nc\nd\ne\n", b"a\nX\nc\nd\ne\n");
+        assert_eq!(conflicts, 1);
+        assert_eq!(merged, b"a\n<<<<<<< ours\nB\n=======\nX\n>>>>>>> theirs\nc\nd\ne\n");
+
+        // Identical changes on both sides are not a conflict.
+        assert_eq!(merge3(base, b"a\nb\nZ\nd\ne\n", b"a\nb\nZ\nd\ne\n"), (b"a\nb\nZ\nd\ne\n".to_vec(), 0));
+    }
+}
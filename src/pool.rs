@@ -0,0 +1,103 @@
+// src/pool.rs
+//
+// A minimal scoped thread pool for `--jobs`: items are processed in parallel,
+// results are handed back on the calling thread in input order.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+// With aborting panics, one failing file would take every other one down with it.
+#[cfg(panic = "abort")]
+compile_error!("reformahtml must be built with `panic = \"unwind\"`: --jobs relies on catching panics");
+
+/// Default worker count: the number of CPUs.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Run `work` on every item with up to `jobs` threads. `done` is called on the
+/// calling thread with each item's result, in input order, as soon as all earlier
+/// items are done. A panic in `work` is caught and passed to `done` as `Err`, so
+/// it doesn't affect other items.
+pub fn for_each_ordered<T, R, W, D>(items: &[T], jobs: usize, work: W, mut done: D)
+where
+    T: Sync,
+    R: Send,
+    W: Fn(&T) -> R + Sync,
+    D: FnMut(&T, Result<R, Box<dyn Any + Send>>),
+{
+    let jobs = jobs.clamp(1, items.len().max(1));
+    if jobs == 1 {
+        for item in items {
+            done(item, panic::catch_unwind(AssertUnwindSafe(|| work(item))));
+        }
+        return;
+    }
+
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let tx = tx.clone();
+            let (next, work) = (&next, &work);
+            scope.spawn(move || loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(idx) else { break };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| work(item)));
+                if tx.send((idx, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let mut pending = HashMap::new();
+        let mut emitted = 0usize;
+        for (idx, result) in rx {
+            pending.insert(idx, result);
+            while let Some(result) = pending.remove(&emitted) {
+                done(&items[emitted], result);
+                emitted += 1;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_arrive_in_order_and_panics_are_isolated() {
+        let items: Vec<usize> = (0..50).collect();
+        let mut seen = Vec::new();
+        for_each_ordered(
+            &items,
+            4,
+            |&i| {
+                if i == 7 {
+                    panic!("boom");
+                }
+                i * 2
+            },
+            |&i, r| seen.push((i, r.ok())),
+        );
+        let expected: Vec<(usize, Option<usize>)> = items.iter().map(|&i| (i, (i != 7).then_some(i * 2))).collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn release_profile_unwinds() {
+        // Tests always unwind; the binary people install is built with this profile.
+        let manifest = include_str!("../Cargo.toml");
+        let release = manifest.split("[profile.release]").nth(1).unwrap();
+        let release = release.split("\n[").next().unwrap();
+        let panic = release.lines().find_map(|l| l.trim().strip_prefix("panic")).unwrap();
+        assert!(panic.contains("'unwind'") || panic.contains("\"unwind\""), "{panic}");
+    }
+}
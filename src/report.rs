@@ -0,0 +1,329 @@
+// src/report.rs
+//
+// `reformahtml report DIR`: format every file under DIR in memory and
+// summarize what adopting the tool would change, without writing anything.
+
+use crate::checks::Finding;
+use crate::diff::{self, Tag};
+use crate::walk;
+use crate::{default_options, line_col, transform, Context, SourceMap};
+use clap::{Args, ValueEnum};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+
+/// Number of hunks listed per file.
+const LARGEST_HUNKS: usize = 3;
+
+#[derive(Args)]
+pub struct ReportArgs {
+    /// Report format
+    #[arg(long, value_enum, default_value_t = ReportFormat::Json)]
+    format: ReportFormat,
+
+    /// Write the report to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Directory to scan
+    dir: PathBuf,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Json,
+    Html,
+}
+
+struct HunkSummary {
+    old_start: usize,
+    old_lines: usize,
+    new_start: usize,
+    new_lines: usize,
+}
+
+struct FileReport {
+    path: PathBuf,
+    lines: usize,
+    /// Input lines deleted or replaced
+    changed_lines: usize,
+    /// Deleted plus inserted lines, i.e. what a reviewer has to read
+    review_lines: usize,
+    largest_hunks: Vec<HunkSummary>,
+    idempotent: bool,
+    diagnostics: Vec<Finding>,
+}
+
+impl FileReport {
+    fn changed(&self) -> bool {
+        self.review_lines > 0
+    }
+
+    fn change_percent(&self) -> f64 {
+        if self.lines == 0 {
+            0.0
+        } else {
+            self.changed_lines as f64 * 100.0 / self.lines as f64
+        }
+    }
+}
+
+pub fn run(args: &ReportArgs) -> io::Result<()> {
+    let files = walk::collect_files(&args.dir, walk::DEFAULT_EXTENSIONS, &[], walk::Symlinks::default())?;
+    let mut reports = Vec::with_capacity(files.len());
+    for path in files {
+        reports.push(analyze(&path)?);
+    }
+
+    let text = match args.format {
+        ReportFormat::Json => render_json(&args.dir, &reports),
+        ReportFormat::Html => render_html(&args.dir, &reports),
+    };
+    match &args.output {
+        Some(path) => fs::write(path, text).context("write report", path),
+        None => io::stdout().lock().write_all(text.as_bytes()),
+    }
+}
+
+fn analyze(path: &Path) -> io::Result<FileReport> {
+    let src = fs::read(path).context("read input", path)?;
+    let opts = default_options(path);
+    let mut out = Vec::with_capacity(src.len());
+    let diagnostics = transform(&src, &mut out, &opts)
+        .into_iter()
+        .map(|d| {
+            let (line, column) = line_col(&src, d.offset);
+            Finding { line, column, check: d.check, level: d.check.default_level(), message: d.message }
+        })
+        .collect();
+
+    let mut again = Vec::with_capacity(out.len());
+    transform(&out, &mut again, &opts);
+
+    let old_lines = diff::split_lines(&src);
+    let new_lines = diff::split_lines(&out);
+    let ops = diff::diff_lines(&old_lines, &new_lines);
+    let changed_lines = ops.iter().filter(|op| op.tag == Tag::Delete).map(|op| op.len).sum();
+    let review_lines = ops.iter().filter(|op| op.tag != Tag::Equal).map(|op| op.len).sum();
+
+    let mut hunks = diff::hunks(&ops, 0);
+    hunks.sort_by_key(|h| std::cmp::Reverse(h.changed_lines()));
+    let largest_hunks = hunks
+        .iter()
+        .take(LARGEST_HUNKS)
+        .map(|h| HunkSummary {
+            old_start: h.old_range().start + 1,
+            old_lines: h.old_range().len(),
+            new_start: h.new_range().start + 1,
+            new_lines: h.new_range().len(),
+        })
+        .collect();
+
+    Ok(FileReport {
+        path: path.to_path_buf(),
+        lines: old_lines.len(),
+        changed_lines,
+        review_lines,
+        largest_hunks,
+        idempotent: again == out,
+        diagnostics,
+    })
+}
+
+/* ================================ JSON ================================== */
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn render_json(root: &Path, reports: &[FileReport]) -> String {
+    let mut s = String::new();
+    s.push_str("{\n");
+    let _ = writeln!(s, "  \"root\": {},", json_string(&root.display().to_string()));
+    s.push_str("  \"files\": [");
+    for (idx, r) in reports.iter().enumerate() {
+        s.push_str(if idx == 0 { "\n" } else { ",\n" });
+        s.push_str("    {");
+        let _ = write!(s, "\"path\": {}, ", json_string(&r.path.display().to_string()));
+        let _ = write!(s, "\"changed\": {}, ", r.changed());
+        let _ = write!(s, "\"lines\": {}, ", r.lines);
+        let _ = write!(s, "\"changed_lines\": {}, ", r.changed_lines);
+        let _ = write!(s, "\"change_percent\": {:.1}, ", r.change_percent());
+        let _ = write!(s, "\"review_lines\": {}, ", r.review_lines);
+        let _ = write!(s, "\"idempotent\": {}, ", r.idempotent);
+        s.push_str("\"largest_hunks\": [");
+        for (h_idx, h) in r.largest_hunks.iter().enumerate() {
+            if h_idx > 0 {
+                s.push_str(", ");
+            }
+            let _ = write!(
+                s,
+                "{{\"old_start\": {}, \"old_lines\": {}, \"new_start\": {}, \"new_lines\": {}}}",
+                h.old_start, h.old_lines, h.new_start, h.new_lines
+            );
+        }
+        s.push_str("], \"diagnostics\": [");
+        push_diagnostics(&mut s, &r.diagnostics);
+        s.push_str("]}");
+    }
+    s.push_str(if reports.is_empty() { "],\n" } else { "\n  ],\n" });
+
+    let names = |pred: &dyn Fn(&FileReport) -> bool| {
+        reports
+            .iter()
+            .filter(|r| pred(r))
+            .map(|r| json_string(&r.path.display().to_string()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    s.push_str("  \"summary\": {\n");
+    let _ = writeln!(s, "    \"files\": {},", reports.len());
+    let _ = writeln!(s, "    \"changed_files\": {},", reports.iter().filter(|r| r.changed()).count());
+    let _ = writeln!(s, "    \"review_lines\": {},", reports.iter().map(|r| r.review_lines).sum::<usize>());
+    let _ = writeln!(s, "    \"not_idempotent\": [{}],", names(&|r| !r.idempotent));
+    let _ = writeln!(s, "    \"with_diagnostics\": [{}]", names(&|r| !r.diagnostics.is_empty()));
+    s.push_str("  }\n}\n");
+    s
+}
+
+/* ============================ Run results =============================== */
+
+/// Version of the `--report json` document; bumped on incompatible changes.
+pub const RESULTS_SCHEMA_VERSION: u32 = 1;
+
+/// What happened to one input of a formatting run, for `--report json`.
+#[derive(Clone, Debug, Default)]
+pub struct FileResult {
+    pub path: String,
+    pub changed: bool,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub markdown: bool,
+    pub diagnostics: Vec<Finding>,
+    /// Set if the file could not be processed; the other fields are then empty
+    pub error: Option<String>,
+}
+
+pub fn render_results_json(results: &[FileResult]) -> String {
+    let mut s = String::new();
+    s.push_str("{\n");
+    let _ = writeln!(s, "  \"schema_version\": {RESULTS_SCHEMA_VERSION},");
+    s.push_str("  \"files\": [");
+    for (idx, r) in results.iter().enumerate() {
+        s.push_str(if idx == 0 { "\n" } else { ",\n" });
+        s.push_str("    {");
+        let _ = write!(s, "\"path\": {}, ", json_string(&r.path));
+        if let Some(error) = &r.error {
+            let _ = write!(s, "\"error\": {}}}", json_string(error));
+            continue;
+        }
+        let _ = write!(s, "\"changed\": {}, ", r.changed);
+        let _ = write!(s, "\"bytes_in\": {}, ", r.bytes_in);
+        let _ = write!(s, "\"bytes_out\": {}, ", r.bytes_out);
+        let _ = write!(s, "\"mode\": \"{}\", ", if r.markdown { "markdown" } else { "html" });
+        s.push_str("\"diagnostics\": [");
+        push_diagnostics(&mut s, &r.diagnostics);
+        s.push_str("]}");
+    }
+    s.push_str(if results.is_empty() { "]\n" } else { "\n  ]\n" });
+    s.push_str("}\n");
+    s
+}
+
+/// The `--sourcemap` document: the token anchors of `map` for the input `path`.
+pub fn render_source_map_json(path: &str, map: &SourceMap) -> String {
+    let mut s = String::new();
+    s.push_str("{\n");
+    let _ = writeln!(s, "  \"path\": {},", json_string(path));
+    s.push_str("  \"anchors\": [");
+    for (idx, a) in map.anchors().iter().enumerate() {
+        s.push_str(if idx == 0 { "\n" } else { ",\n" });
+        let _ = write!(s, "    {{\"input\": {}, \"output\": {}, \"exact\": {}}}", a.input, a.output, a.exact);
+    }
+    s.push_str(if map.anchors().is_empty() { "]\n" } else { "\n  ]\n" });
+    s.push_str("}\n");
+    s
+}
+
+/// Append `diagnostics` as JSON objects, separated by commas.
+fn push_diagnostics(s: &mut String, diagnostics: &[Finding]) {
+    for (idx, d) in diagnostics.iter().enumerate() {
+        if idx > 0 {
+            s.push_str(", ");
+        }
+        let _ = write!(
+            s,
+            "{{\"line\": {}, \"column\": {}, \"check\": \"{}\", \"level\": \"{}\", \"message\": {}}}",
+            d.line,
+            d.column,
+            d.check.name(),
+            d.level.name(),
+            json_string(&d.message)
+        );
+    }
+}
+
+/* ================================ HTML ================================== */
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn render_html(root: &Path, reports: &[FileReport]) -> String {
+    let root = html_escape(&root.display().to_string());
+    let changed = reports.iter().filter(|r| r.changed()).count();
+    let review: usize = reports.iter().map(|r| r.review_lines).sum();
+
+    let mut s = String::new();
+    s.push_str("<!DOCTYPE html>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(s, "<title>reformahtml report for {root}</title>");
+    let _ = writeln!(s, "<h1>reformahtml report for <code>{root}</code></h1>");
+    let _ = writeln!(
+        s,
+        "<p>{changed} of {} files would change; about {review} lines to review.</p>",
+        reports.len()
+    );
+    s.push_str("<table>\n <thead>\n  <tr><th>File<th>Lines<th>Changed<th>Review lines<th>Largest hunks<th>Idempotent<th>Diagnostics\n <tbody>\n");
+    for r in reports {
+        let hunks = r
+            .largest_hunks
+            .iter()
+            .map(|h| format!("-{},{} +{},{}", h.old_start, h.old_lines, h.new_start, h.new_lines))
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let diagnostics = r
+            .diagnostics
+            .iter()
+            .map(|d| format!("{}:{}: {}", d.line, d.column, html_escape(&d.message)))
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let _ = writeln!(
+            s,
+            "  <tr><td><code>{}</code><td>{}<td>{:.1}%<td>{}<td>{hunks}<td>{}<td>{diagnostics}",
+            html_escape(&r.path.display().to_string()),
+            r.lines,
+            r.change_percent(),
+            r.review_lines,
+            if r.idempotent { "yes" } else { "<strong>no</strong>" },
+        );
+    }
+    s.push_str("</table>\n");
+    s
+}
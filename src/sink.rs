@@ -0,0 +1,335 @@
+// src/sink.rs
+//
+// Output sinks: where formatted documents go once `transform` is done.
+
+use crate::Context;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Destination for formatted documents.
+pub trait OutputSink {
+    /// Emit the formatted bytes produced from `input`.
+    fn emit(&mut self, input: &Path, bytes: &[u8]) -> io::Result<()>;
+
+    /// Flush and finalize; called once after the last `emit`.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes each document to a file: the input itself, or an explicit output path.
+/// Existing files are truncated and rewritten in place rather than replaced, so
+/// their permission bits (and ownership) are kept.
+pub struct FileSink {
+    pub output: Option<PathBuf>,
+    /// Give a rewritten file back the modification time it had before
+    pub preserve_mtime: bool,
+    /// Write a temporary file next to the target and rename it into place, so an
+    /// interrupted write leaves the old file whole. A symlink is followed and the
+    /// file it points to replaced, so the link stays. The permission bits, and on
+    /// Unix the owner and group, are copied over; a file whose owner can't be set
+    /// is rewritten in place instead.
+    pub atomic: bool,
+}
+
+impl OutputSink for FileSink {
+    fn emit(&mut self, input: &Path, bytes: &[u8]) -> io::Result<()> {
+        let path = self.output.as_deref().unwrap_or(input);
+        let before = fs::metadata(path).ok();
+        if !self.atomic {
+            return self.write(path, path, bytes, before.as_ref());
+        }
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let temp = temp_sibling(&path);
+        let replace = || -> io::Result<()> {
+            self.write(&temp, &path, bytes, before.as_ref())?;
+            if before.as_ref().is_some_and(|before| !set_owner(&temp, before)) {
+                fs::remove_file(&temp).context("remove", &temp)?;
+                return self.write(&path, &path, bytes, before.as_ref());
+            }
+            fs::rename(&temp, &path).context("replace", &path)
+        };
+        replace().inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })
+    }
+}
+
+impl FileSink {
+    /// Write `bytes` to `target`, which becomes `path`, and give it the metadata
+    /// `path` had `before`.
+    fn write(&self, target: &Path, path: &Path, bytes: &[u8], before: Option<&fs::Metadata>) -> io::Result<()> {
+        fs::write(target, bytes).context("write", target)?;
+        if let Some(before) = before {
+            let restore = || {
+                let file = File::options().write(true).open(target)?;
+                if file.metadata()?.permissions() != before.permissions() {
+                    file.set_permissions(before.permissions())?;
+                }
+                if self.preserve_mtime {
+                    file.set_modified(before.modified()?)?;
+                }
+                Ok(())
+            };
+            restore().context("restore the metadata of", path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Give `temp` the owner and group of `before`, the file it replaces. False if
+/// that isn't allowed.
+#[cfg(unix)]
+fn set_owner(temp: &Path, before: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(now) = fs::metadata(temp) else { return false };
+    if (now.uid(), now.gid()) == (before.uid(), before.gid()) {
+        return true;
+    }
+    // Changing the owner may clear the setuid and setgid bits.
+    std::os::unix::fs::chown(temp, Some(before.uid()), Some(before.gid())).is_ok()
+        && fs::set_permissions(temp, before.permissions()).is_ok()
+}
+
+#[cfg(not(unix))]
+fn set_owner(_temp: &Path, _before: &fs::Metadata) -> bool {
+    true
+}
+
+/// A hidden file next to `path` for its new contents, with a name no input
+/// extension matches.
+fn temp_sibling(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".reformahtml-{}", std::process::id()));
+    path.with_file_name(name)
+}
+
+/// Writes each document under `dir`, at the input's path relative to the
+/// current directory. Intermediate directories are created as needed.
+pub struct DirSink {
+    pub dir: PathBuf,
+    /// Log each written path on stderr
+    pub verbose: bool,
+}
+
+impl DirSink {
+    /// Where the result for `input` goes. Inputs that would land outside `dir`
+    /// (through `..` or an absolute path elsewhere) are refused.
+    pub fn target(&self, input: &Path) -> io::Result<PathBuf> {
+        let cwd = std::env::current_dir()?;
+        let relative = input.strip_prefix(&cwd).unwrap_or(input);
+        let mut parts: Vec<&std::ffi::OsStr> = Vec::new();
+        for c in relative.components() {
+            match c {
+                Component::Normal(p) => parts.push(p),
+                Component::CurDir => {}
+                Component::ParentDir if parts.pop().is_some() => {}
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} would be written outside {}", input.display(), self.dir.display()),
+                    ))
+                }
+            }
+        }
+        if parts.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "stdin can't be written to --output-dir"));
+        }
+        Ok(parts.into_iter().fold(self.dir.clone(), |path, p| path.join(p)))
+    }
+}
+
+impl OutputSink for DirSink {
+    fn emit(&mut self, input: &Path, bytes: &[u8]) -> io::Result<()> {
+        let target = self.target(input)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).context("create directory", parent)?;
+        }
+        fs::write(&target, bytes).context("write", &target)?;
+        if self.verbose {
+            eprintln!("{}: wrote {}", input.display(), target.display());
+        }
+        Ok(())
+    }
+}
+
+/// Streams documents to stdout through a locked, buffered writer.
+pub struct StdoutSink {
+    out: BufWriter<io::StdoutLock<'static>>,
+}
+
+impl StdoutSink {
+    pub fn new() -> StdoutSink {
+        StdoutSink { out: BufWriter::new(io::stdout().lock()) }
+    }
+}
+
+impl OutputSink for StdoutSink {
+    fn emit(&mut self, _input: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Collects documents into a ustar archive, one entry per input path.
+pub struct TarSink<W: Write> {
+    out: W,
+}
+
+impl TarSink<BufWriter<File>> {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(TarSink { out: BufWriter::new(File::create(path).context("create archive", path)?) })
+    }
+}
+
+impl<W: Write> OutputSink for TarSink<W> {
+    fn emit(&mut self, input: &Path, bytes: &[u8]) -> io::Result<()> {
+        let name = archive_name(input);
+        let header = tar_header(&name, bytes.len() as u64)?;
+        self.out.write_all(&header)?;
+        self.out.write_all(bytes)?;
+        let pad = (512 - bytes.len() % 512) % 512;
+        self.out.write_all(&[0u8; 512][..pad])
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // End-of-archive marker: two zero blocks.
+        self.out.write_all(&[0u8; 1024])?;
+        self.out.flush()
+    }
+}
+
+/// Entry name for `path`: relative, '/'-separated, without root or `..` components.
+fn archive_name(path: &Path) -> String {
+    let parts: Vec<String> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(p) => Some(p.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    parts.join("/")
+}
+
+fn tar_header(name: &str, size: u64) -> io::Result<[u8; 512]> {
+    let mut h = [0u8; 512];
+    let bytes = name.as_bytes();
+    // Names over 100 bytes go into the 155-byte prefix field, split at a '/'.
+    let (prefix, base) = if bytes.len() <= 100 {
+        (&[][..], bytes)
+    } else {
+        let split = bytes[..bytes.len().min(156)]
+            .iter()
+            .rposition(|&b| b == b'/')
+            .filter(|&p| bytes.len() - p - 1 <= 100)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("path too long for tar archive: {name}")))?;
+        (&bytes[..split], &bytes[split + 1..])
+    };
+    let mtime = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+    h[..base.len()].copy_from_slice(base);
+    octal(&mut h[100..108], 0o644);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], mtime);
+    h[156] = b'0';
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[345..345 + prefix.len()].copy_from_slice(prefix);
+
+    // Checksum is computed with the checksum field itself filled with spaces.
+    h[148..156].fill(b' ');
+    let sum: u64 = h.iter().map(|&b| b as u64).sum();
+    octal(&mut h[148..155], sum);
+    Ok(h)
+}
+
+/// Write `value` as zero-padded octal, leaving the last byte as NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{value:0width$o}");
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tar_entries_are_block_aligned() {
+        let mut sink = TarSink { out: Vec::new() };
+        sink.emit(Path::new("../specs/index.bs"), b"hello\n").unwrap();
+        sink.finish().unwrap();
+        let out = sink.out;
+        assert_eq!(out.len(), 512 + 512 + 1024);
+        assert_eq!(&out[..15], b"specs/index.bs\0");
+        assert_eq!(&out[124..136], b"00000000006\0");
+        assert_eq!(&out[512..518], b"hello\n");
+    }
+
+    #[test]
+    fn dir_sink_mirrors_relative_paths() {
+        let sink = DirSink { dir: PathBuf::from("out"), verbose: false };
+        assert_eq!(sink.target(Path::new("./specs/a/index.bs")).unwrap(), Path::new("out/specs/a/index.bs"));
+        assert_eq!(sink.target(Path::new("specs/../b.html")).unwrap(), Path::new("out/b.html"));
+        assert!(sink.target(Path::new("../outside.bs")).is_err());
+        assert!(sink.target(Path::new("specs/../../outside.bs")).is_err());
+        assert!(sink.target(Path::new("/elsewhere/x.bs")).is_err());
+        let inside = std::env::current_dir().unwrap().join("specs/c.bs");
+        assert_eq!(sink.target(&inside).unwrap(), Path::new("out/specs/c.bs"));
+    }
+
+    #[test]
+    fn file_sink_keeps_mode_and_optionally_mtime() {
+        let path = std::env::temp_dir().join(format!("reformahtml-sink-{}.html", std::process::id()));
+        fs::write(&path, "old").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+        }
+
+        FileSink { output: None, preserve_mtime: true, atomic: false }.emit(&path, b"new").unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.modified().unwrap(), mtime);
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o777, 0o750);
+
+        FileSink { output: None, preserve_mtime: false, atomic: false }.emit(&path, b"newer").unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_ne!(meta.modified().unwrap(), mtime);
+        assert_eq!(meta.len(), 5);
+
+        // Replaced through a temporary file, with the same metadata kept.
+        File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        FileSink { output: None, preserve_mtime: true, atomic: true }.emit(&path, b"newest").unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        let temp = temp_sibling(&path);
+        // The owner and group are kept too, where the test may change them.
+        #[cfg(unix)]
+        let owner = std::os::unix::fs::chown(&path, Some(1), Some(1)).is_ok().then(|| {
+            use std::os::unix::fs::MetadataExt;
+            FileSink { output: None, preserve_mtime: false, atomic: true }.emit(&path, b"owned").unwrap();
+            let meta = fs::metadata(&path).unwrap();
+            (meta.uid(), meta.gid())
+        });
+        fs::remove_file(&path).unwrap();
+        assert!(!temp.exists(), "{} is left behind", temp.display());
+        assert_eq!(meta.modified().unwrap(), mtime);
+        assert_eq!(meta.len(), 6);
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o777, 0o750);
+        #[cfg(unix)]
+        assert!(owner.is_none_or(|owner| owner == (1, 1)), "owner {owner:?}");
+    }
+}
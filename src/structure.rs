@@ -0,0 +1,60 @@
+// src/structure.rs
+//
+// Cheap structural checks for `reformahtml lint --structure`, using the element
+// stack the formatter already maintains.
+
+use crate::checks::Check;
+use crate::{Diagnostic, OpenElement, TagInfo};
+
+/// Structural checks fed by `transform` with each start tag it sees.
+#[derive(Default)]
+pub struct StructureLint {
+    /// Level of the previous heading in the document
+    last_heading: Option<u8>,
+    /// One entry per open `<dl>`: whether a `<dt>` has been seen in it yet
+    dl_has_dt: Vec<bool>,
+}
+
+impl StructureLint {
+    /// Inspect a tag before `transform` updates `open_stack` for it.
+    pub fn tag(
+        &mut self,
+        ti: &TagInfo,
+        name_lower: &[u8],
+        open_stack: &[OpenElement],
+        offset: usize,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        if ti.is_end {
+            if name_lower == b"dl" {
+                self.dl_has_dt.pop();
+            }
+            return;
+        }
+        let mut report = |check: Check, message: String| diagnostics.push(Diagnostic { offset, check, message });
+        match name_lower {
+            [b'h', d @ b'1'..=b'6'] => {
+                let level = d - b'0';
+                if let Some(last) = self.last_heading {
+                    if level > last + 1 {
+                        report(Check::HeadingLevel, format!("heading level skipped: <h{level}> follows <h{last}>"));
+                    }
+                }
+                self.last_heading = Some(level);
+            }
+            b"dl" => self.dl_has_dt.push(false),
+            b"dt" => {
+                if let Some(seen) = self.dl_has_dt.last_mut() {
+                    *seen = true;
+                }
+            }
+            b"dd" if !self.dl_has_dt.last().copied().unwrap_or(false) => {
+                report(Check::DdWithoutDt, "<dd> without a preceding <dt>".to_string());
+            }
+            b"li" if !open_stack.iter().any(|e| matches!(e.name.as_slice(), b"ul" | b"ol" | b"menu")) => {
+                report(Check::LiOutsideList, "<li> outside of <ul>, <ol> or <menu>".to_string());
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,313 @@
+// src/walk.rs
+//
+// Directory walking: collect the HTML/Bikeshed files under a directory,
+// honoring `--exclude` patterns and a `.reformahtmlignore` file at the root.
+
+use crate::Context;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Directory names skipped while walking (in addition to hidden ones).
+const SKIPPED_DIRS: &[&str] = &["node_modules"];
+
+/// Extensions processed by default when walking a directory.
+pub const DEFAULT_EXTENSIONS: &[&str] = &["bs", "html"];
+
+/// Name of the ignore file read from the root of a walk.
+pub const IGNORE_FILE: &str = ".reformahtmlignore";
+
+/// How symbolic links met while walking (or given as inputs) are treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Links to files are formatted (the target is rewritten, the link kept);
+    /// links to directories are not entered
+    #[default]
+    Files,
+    /// Links to directories are walked too; each real directory is visited once,
+    /// so cycles end
+    Follow,
+    /// Links are skipped with a warning
+    Skip,
+}
+
+/// Recursively collect files under `root` whose extension is in `exts`
+/// (ASCII case-insensitive), in sorted order. Hidden entries and junk
+/// directories like `node_modules` are skipped, as are paths matched by
+/// `root/.reformahtmlignore` or by `excludes` (which take precedence).
+pub fn collect_files(root: &Path, exts: &[&str], excludes: &[String], symlinks: Symlinks) -> io::Result<Vec<PathBuf>> {
+    let mut ignore = match fs::read_to_string(root.join(IGNORE_FILE)) {
+        Ok(text) => Ignore::new(text.lines()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ignore::default(),
+        Err(e) => return Err(e).context("read", &root.join(IGNORE_FILE)),
+    };
+    ignore.extend(excludes.iter().map(String::as_str));
+    let mut walker = Walker { exts, ignore, symlinks, visited: HashSet::new(), files: Vec::new() };
+    if symlinks == Symlinks::Follow {
+        walker.visited.insert(fs::canonicalize(root).context("resolve", root)?);
+    }
+    walker.walk(root, "")?;
+    Ok(walker.files)
+}
+
+struct Walker<'a> {
+    exts: &'a [&'a str],
+    ignore: Ignore,
+    symlinks: Symlinks,
+    /// Canonical paths of the directories entered, with `Symlinks::Follow`
+    visited: HashSet<PathBuf>,
+    files: Vec<PathBuf>,
+}
+
+impl Walker<'_> {
+    fn walk(&mut self, dir: &Path, rel: &str) -> io::Result<()> {
+        let mut entries: Vec<fs::DirEntry> =
+            fs::read_dir(dir).and_then(Iterator::collect).context("read directory", dir)?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            let rel = if rel.is_empty() { name.to_string() } else { format!("{rel}/{name}") };
+            let mut file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                if self.symlinks == Symlinks::Skip {
+                    eprintln!("{}: warning: skipping symbolic link", path.display());
+                    continue;
+                }
+                // A dangling link is left to fail when it is read.
+                if let Ok(meta) = fs::metadata(&path) {
+                    file_type = meta.file_type();
+                }
+                if file_type.is_dir()
+                    && (self.symlinks != Symlinks::Follow || self.visited.contains(&fs::canonicalize(&path).context("resolve", &path)?))
+                {
+                    continue;
+                }
+            }
+            if self.ignore.is_ignored(&rel, file_type.is_dir()) {
+                continue;
+            }
+            if file_type.is_dir() {
+                if SKIPPED_DIRS.contains(&name.as_ref()) {
+                    continue;
+                }
+                if self.symlinks == Symlinks::Follow && !self.visited.insert(fs::canonicalize(&path).context("resolve", &path)?) {
+                    continue;
+                }
+                self.walk(&path, &rel)?;
+            } else if has_extension(&path, self.exts) {
+                self.files.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn has_extension(path: &Path, exts: &[&str]) -> bool {
+    path.extension()
+        .is_some_and(|e| exts.iter().any(|x| e.to_string_lossy().eq_ignore_ascii_case(x)))
+}
+
+/* ===== Ignore patterns ===== */
+
+/// gitignore-style patterns. The last matching pattern decides, so a later
+/// `!pattern` re-includes what an earlier one excluded. Files inside an
+/// excluded directory can't be re-included, because the walk never enters it.
+#[derive(Default)]
+pub struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+struct Pattern {
+    glob: Vec<u8>,
+    negated: bool,
+    /// Trailing `/`: only matches directories
+    dir_only: bool,
+    /// Contains a `/` other than a trailing one: matched against the whole
+    /// relative path instead of the file name alone
+    anchored: bool,
+}
+
+impl Ignore {
+    /// Parse patterns, one per line. Blank lines and `#` comments are skipped.
+    pub fn new<'a>(lines: impl IntoIterator<Item = &'a str>) -> Ignore {
+        let mut ignore = Ignore::default();
+        ignore.extend(lines);
+        ignore
+    }
+
+    pub fn extend<'a>(&mut self, lines: impl IntoIterator<Item = &'a str>) {
+        self.patterns.extend(lines.into_iter().filter_map(Pattern::parse));
+    }
+
+    /// Whether `rel`, a `/`-separated path relative to the ignore root, is excluded.
+    pub fn is_ignored(&self, rel: &str, is_dir: bool) -> bool {
+        let name = rel.rsplit('/').next().unwrap_or(rel);
+        self.patterns
+            .iter()
+            .rev()
+            .find(|p| {
+                (is_dir || !p.dir_only)
+                    && glob_match(&p.glob, if p.anchored { rel } else { name }.as_bytes())
+            })
+            .is_some_and(|p| !p.negated)
+    }
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Pattern> {
+        let line = line.trim_end_matches(['\r', ' ']);
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').filter(|r| r.starts_with(['#', '!'])).unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        Some(Pattern { glob: line.as_bytes().to_vec(), negated, dir_only, anchored })
+    }
+}
+
+/// Match `s` against a glob: `*` and `?` don't cross `/`, `**` does (and `**/`
+/// also matches no directory at all), `[a-z]`/`[!a-z]` are character classes,
+/// and `\` escapes the next character.
+pub fn glob_match(p: &[u8], s: &[u8]) -> bool {
+    match p {
+        [] => s.is_empty(),
+        [b'*', b'*', rest @ ..] => match rest.strip_prefix(b"/") {
+            Some(rest) => (0..=s.len()).any(|i| (i == 0 || s[i - 1] == b'/') && glob_match(rest, &s[i..])),
+            None => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        },
+        [b'*', rest @ ..] => (0..=s.len())
+            .take_while(|&i| i == 0 || s[i - 1] != b'/')
+            .any(|i| glob_match(rest, &s[i..])),
+        [b'?', rest @ ..] => matches!(s, [c, ..] if *c != b'/') && glob_match(rest, &s[1..]),
+        [b'[', class @ ..] => match class_match(class, s.first().copied()) {
+            Some((true, len)) => glob_match(&class[len..], &s[1..]),
+            Some((false, _)) => false,
+            None => s.first() == Some(&b'[') && glob_match(class, &s[1..]),
+        },
+        [b'\\', c, rest @ ..] => s.first() == Some(c) && glob_match(rest, &s[1..]),
+        [c, rest @ ..] => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+/// Match one byte against the class starting after `[`. Returns whether it
+/// matched and the length of the class including `]`, or `None` if the class
+/// isn't closed (the `[` is then literal).
+fn class_match(class: &[u8], c: Option<u8>) -> Option<(bool, usize)> {
+    let (negated, start) = match class.first() {
+        Some(b'!' | b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    // A `]` right at the start is a member, not the end of the class.
+    let end = start + 1 + class.get(start + 1..)?.iter().position(|&b| b == b']')?;
+    let members = &class[start..end];
+    let c = match c {
+        Some(c) if c != b'/' => c,
+        _ => return Some((false, end + 1)),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < members.len() {
+        if i + 2 < members.len() && members[i + 1] == b'-' {
+            found |= (members[i]..=members[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= members[i] == c;
+            i += 1;
+        }
+    }
+    Some((found != negated, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_patterns_in_nested_directories() {
+        let ignore = Ignore::new(
+            "# generated output\nbuild/\n/draft.bs\ndocs/**/old-*.html\n*.tmp.html\n".lines(),
+        );
+        assert!(ignore.is_ignored("build", true));
+        assert!(ignore.is_ignored("src/build", true));
+        assert!(!ignore.is_ignored("build", false));
+        assert!(ignore.is_ignored("draft.bs", false));
+        assert!(!ignore.is_ignored("sub/draft.bs", false));
+        assert!(ignore.is_ignored("docs/old-a.html", false));
+        assert!(ignore.is_ignored("docs/x/y/old-b.html", false));
+        assert!(!ignore.is_ignored("other/old-a.html", false));
+        assert!(ignore.is_ignored("a/b/c/page.tmp.html", false));
+        assert!(!ignore.is_ignored("a/b/c/page.html", false));
+    }
+
+    #[test]
+    fn ignore_negation_last_match_wins() {
+        let ignore = Ignore::new(["*.html", "!keep/*.html", "keep/drop.html", "\\!bang.bs", "[a-c]?.bs"]);
+        assert!(ignore.is_ignored("index.html", false));
+        assert!(!ignore.is_ignored("keep/index.html", false));
+        assert!(ignore.is_ignored("keep/drop.html", false));
+        assert!(ignore.is_ignored("keep/deeper/index.html", false));
+        assert!(ignore.is_ignored("!bang.bs", false));
+        assert!(ignore.is_ignored("b1.bs", false));
+        assert!(!ignore.is_ignored("d1.bs", false));
+    }
+
+    #[test]
+    fn collect_files_honors_ignore_file_and_excludes() {
+        let root = std::env::temp_dir().join(format!("reformahtml-walk-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["a/gen", "b"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in ["a/x.bs", "a/gen/y.bs", "b/z.html", "b/keep.html", "top.html"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        fs::write(root.join(IGNORE_FILE), "gen/\nb/*\n!b/keep.html\n").unwrap();
+        let files = collect_files(&root, DEFAULT_EXTENSIONS, &["top.html".to_string()], Symlinks::Files).unwrap();
+        let rel: Vec<_> = files.iter().map(|f| f.strip_prefix(&root).unwrap().to_path_buf()).collect();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(rel, [PathBuf::from("a/x.bs"), PathBuf::from("b/keep.html")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_files_and_directories() {
+        use std::os::unix::fs::symlink;
+        let root = std::env::temp_dir().join(format!("reformahtml-links-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("real")).unwrap();
+        fs::write(root.join("real/doc.html"), "").unwrap();
+        symlink("real/doc.html", root.join("file-link.html")).unwrap();
+        symlink("real", root.join("dir-link")).unwrap();
+        // A link back to the root would loop forever if followed naively.
+        symlink("..", root.join("real/loop")).unwrap();
+
+        let walk = |symlinks| {
+            let files = collect_files(&root, DEFAULT_EXTENSIONS, &[], symlinks).unwrap();
+            files.iter().map(|f| f.strip_prefix(&root).unwrap().to_string_lossy().into_owned()).collect::<Vec<_>>()
+        };
+        let by_default = walk(Symlinks::Files);
+        let followed = walk(Symlinks::Follow);
+        let skipped = walk(Symlinks::Skip);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(by_default, ["file-link.html", "real/doc.html"]);
+        assert_eq!(followed, ["dir-link/doc.html", "file-link.html"]);
+        assert_eq!(skipped, ["real/doc.html"]);
+    }
+}
@@ -0,0 +1,214 @@
+// src/watch.rs
+//
+// `--watch`: poll the inputs (and the files below directory inputs) and
+// reformat each file in place once it has stopped changing.
+
+use crate::sink::{FileSink, OutputSink};
+use crate::{color, display_path, format_file, write_backup, Cli};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long a file must stay unchanged before it is reformatted, so that
+/// editors that save in several steps are handled once.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Modification time and size: cheap to read, and changes on every save.
+type Stamp = (SystemTime, u64);
+
+#[derive(Default)]
+struct Watched {
+    stamp: Option<Stamp>,
+    /// When a change was last seen and not yet handled
+    changed_at: Option<Instant>,
+    /// What we last wrote, so our own writes don't trigger another run
+    written: Option<Vec<u8>>,
+    /// Whether `--backup` has saved the original this session; later saves
+    /// aren't backed up, so the backup keeps the file as it was before watching
+    backed_up: bool,
+}
+
+/// Watch `roots` until the process is interrupted. On Unix, Ctrl-C (SIGINT) or
+/// SIGTERM stops it once the file being formatted is written, and a second one
+/// ends it at once. Elsewhere they end it at once; a file is replaced through a
+/// temporary file, so one interrupted while it is written is left as it was,
+/// but the `.NAME.reformahtml-PID` file next to it may be left behind.
+pub fn run(cli: &Cli, roots: &[PathBuf]) -> io::Result<()> {
+    interrupt::install();
+    let mut files: HashMap<PathBuf, Watched> = HashMap::new();
+    for path in cli.expand(roots)? {
+        let stamp = stamp(&path);
+        files.insert(path, Watched { stamp, ..Watched::default() });
+    }
+    eprintln!("watching {} file(s); press Ctrl-C to stop", files.len());
+
+    while !interrupt::requested() {
+        thread::sleep(POLL_INTERVAL);
+        // Directories are walked again each time so new files are picked up. One
+        // that is briefly missing, as during a `git checkout`, is tried again.
+        let paths = match cli.expand(roots) {
+            Ok(paths) => paths,
+            Err(e) => {
+                eprintln!("error: {e}");
+                continue;
+            }
+        };
+        for path in paths {
+            if interrupt::requested() {
+                break;
+            }
+            let entry = files.entry(path.clone()).or_default();
+            let now = stamp(&path);
+            if now != entry.stamp {
+                entry.stamp = now;
+                entry.changed_at = now.map(|_| Instant::now());
+            }
+            if entry.changed_at.is_some_and(|t| t.elapsed() >= DEBOUNCE) {
+                entry.changed_at = None;
+                if let Err(e) = reformat(cli, &path, entry) {
+                    eprintln!("error: {e}");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn reformat(cli: &Cli, path: &Path, entry: &mut Watched) -> io::Result<()> {
+    let f = format_file(cli, path)?;
+    if entry.written.as_ref() == Some(&f.src) {
+        return Ok(());
+    }
+    // A save since the poll that saw the file wins; the next poll picks it up.
+    if stamp(path) != entry.stamp {
+        return Ok(());
+    }
+    for d in &f.diagnostics {
+        let location = format!("{}:{}:{}", display_path(path), d.line, d.column);
+        eprintln!("{}", color::diagnostic(&location, d, cli.color.enabled(&io::stderr())));
+    }
+    if f.changed() {
+        if let Some(suffix) = cli.backup.as_ref().filter(|_| !entry.backed_up) {
+            write_backup(path, &f.src, suffix, cli.force_backup)?;
+            entry.backed_up = true;
+        }
+        FileSink { output: None, preserve_mtime: cli.preserve_mtime, atomic: true }.emit(path, f.out())?;
+        entry.stamp = stamp(path);
+        println!("reformatted {}", path.display());
+    }
+    entry.written = Some(f.into_out());
+    Ok(())
+}
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// SIGINT and SIGTERM, noted by the handler for the loop to stop between files.
+#[cfg(unix)]
+mod interrupt {
+    use std::os::raw::c_int;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+    pub const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_signal(signum: c_int) {
+        REQUESTED.store(true, Ordering::SeqCst);
+        // The next one ends the process, in case the current file takes long.
+        // Both calls are async-signal-safe.
+        unsafe { signal(signum, SIG_DFL) };
+    }
+
+    pub fn install() {
+        let handler = on_signal as extern "C" fn(c_int) as usize;
+        unsafe {
+            signal(SIGINT, handler);
+            signal(SIGTERM, handler);
+        }
+    }
+
+    pub fn requested() -> bool {
+        REQUESTED.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(not(unix))]
+mod interrupt {
+    pub fn install() {}
+
+    pub fn requested() -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn backs_up_only_the_first_reformat() {
+        let path = std::env::temp_dir().join(format!("reformahtml-watch-backup-{}.html", std::process::id()));
+        let backup = path.with_extension("html.orig");
+        fs::write(&path, "<p>One\ntwo</p>\n").unwrap();
+        let cli = Cli::try_parse_from(["reformahtml", "--no-config", "--backup", path.to_str().unwrap()]).unwrap();
+        let mut entry = Watched { stamp: stamp(&path), ..Watched::default() };
+        reformat(&cli, &path, &mut entry).unwrap();
+        fs::write(&path, "<p>Three\nfour</p>\n").unwrap();
+        entry.stamp = stamp(&path);
+        let second = reformat(&cli, &path, &mut entry);
+        let (out, orig) = (fs::read_to_string(&path).unwrap(), fs::read_to_string(&backup).unwrap());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup).unwrap();
+        second.unwrap();
+        assert_eq!(out, "<p>Three four</p>\n");
+        assert_eq!(orig, "<p>One\ntwo</p>\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rewrites_a_symlinked_input_through_the_link() {
+        let dir = std::env::temp_dir().join(format!("reformahtml-watch-link-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (target, link) = (dir.join("target.html"), dir.join("link.html"));
+        fs::write(&target, "<p>One\ntwo</p>\n").unwrap();
+        std::os::unix::fs::symlink("target.html", &link).unwrap();
+        let cli = Cli::try_parse_from(["reformahtml", "--no-config", link.to_str().unwrap()]).unwrap();
+        let mut entry = Watched { stamp: stamp(&link), ..Watched::default() };
+        reformat(&cli, &link, &mut entry).unwrap();
+        let is_link = fs::symlink_metadata(&link).unwrap().file_type().is_symlink();
+        let out = fs::read_to_string(&target).unwrap();
+        let mut left: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        left.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(is_link);
+        assert_eq!(out, "<p>One two</p>\n");
+        assert_eq!(left, ["link.html", "target.html"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ctrl_c_is_noted_instead_of_ending_the_process() {
+        extern "C" {
+            fn raise(signum: std::os::raw::c_int) -> std::os::raw::c_int;
+        }
+        interrupt::install();
+        assert_eq!(unsafe { raise(interrupt::SIGINT) }, 0);
+        assert!(interrupt::requested());
+    }
+}
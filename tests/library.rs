@@ -0,0 +1,24 @@
+// tests/library.rs
+//
+// The library on its own, as a dependency with `default-features = false` sees
+// it: only the public API, and nothing that needs the `cli` feature.
+
+use reformahtml::{reformat, reformat_cow_with, reformat_str, transform, Formatter, Options};
+use std::borrow::Cow;
+
+#[test]
+fn public_api_without_the_cli() {
+    let src = "<p>One\ntwo</p>\n<pre>\nkept\n  as is\n</pre>\n";
+    let expected = "<p>One two</p>\n<pre>\nkept\n  as is\n</pre>\n";
+    assert_eq!(reformat(src.as_bytes(), false), expected.as_bytes());
+    assert_eq!(reformat_str(src, &Options::default()), expected);
+    assert_eq!(Formatter::new(Options::default()).format(src.as_bytes()), expected.as_bytes());
+
+    let mut out = Vec::new();
+    let diagnostics = transform(b"<p>Unclosed <!-- comment\n", &mut out, &Options::default());
+    assert_eq!(diagnostics[0].check.name(), "unterminated-comment");
+    let (cow, diagnostics) = reformat_cow_with(b"<p>Unclosed <!-- comment\n", &Options::default(), None, None);
+    assert_eq!((cow.as_ref(), diagnostics[0].check.name()), (&out[..], "unterminated-comment"));
+    let (cow, diagnostics) = reformat_cow_with(expected.as_bytes(), &Options::default(), None, None);
+    assert!(matches!(cow, Cow::Borrowed(_)) && diagnostics.is_empty());
+}